@@ -17,8 +17,10 @@ done
   
 >&2 echo "Postgres is up"
 
-# Prepare the Ria schema. (This will delete any existing data!)
-DATABASE_URL="postgres://${POSTGRES_USER}:${POSTGRES_PASSWORD}@${POSTGRES_HOST}/${POSTGRES_DB}" sea-orm-cli migrate fresh
+# Create the Ria schema if missing, and apply any pending migrations.
+/app/target/release/ria --database-type PostgreSQL --database-user ${POSTGRES_USER} \
+  --database-password ${POSTGRES_PASSWORD} --database-name ${POSTGRES_HOST}/${POSTGRES_DB} \
+  db migrate
 
 # Technically the log file doesn't have to exist, but by touching it we avoid a
 # potentially confusing warning when it doesn't exist.
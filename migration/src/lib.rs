@@ -1,3 +1,6 @@
+// Iden enums name their columns after the table, ie `Audio::AudioId`.
+#![allow(clippy::enum_variant_names)]
+
 pub use sea_orm_migration::prelude::*;
 
 mod m20220101_000001_create_audio_table;
@@ -10,6 +13,31 @@ mod m20220101_000007_create_musicbrainzqueue_table;
 mod m20220101_000008_create_directory_table;
 mod m20220101_000009_create_artistdirectory_table;
 mod m20220101_000010_create_audiodirectory_table;
+mod m20220101_000011_alter_audio_table;
+mod m20220101_000012_create_scancheckpoint_table;
+mod m20220101_000013_create_album_table;
+mod m20220101_000014_create_albumaudio_table;
+mod m20220101_000015_alter_album_table;
+mod m20220101_000016_alter_image_table;
+mod m20220101_000017_create_albumimage_table;
+mod m20220101_000018_create_artistimage_table;
+mod m20220101_000019_alter_image_table;
+mod m20220101_000020_create_audioimage_table;
+mod m20220101_000021_alter_audiotag_table;
+mod m20220101_000022_alter_artist_table;
+mod m20220101_000023_create_artistcandidate_table;
+mod m20220101_000024_create_artistpin_table;
+mod m20220101_000025_alter_album_table;
+mod m20220101_000026_alter_audio_table;
+mod m20220101_000027_alter_musicbrainzqueue_table;
+mod m20220101_000028_create_audiofingerprint_table;
+mod m20220101_000029_create_audioacoustid_table;
+mod m20220101_000030_alter_audiofingerprint_table;
+mod m20220101_000031_alter_audio_table;
+mod m20220101_000032_create_audioverification_table;
+mod m20220101_000033_alter_audio_table;
+mod m20220101_000034_create_audiofeature_table;
+mod m20220101_000035_alter_audioverification_table;
 
 pub struct Migrator;
 
@@ -27,6 +55,31 @@ impl MigratorTrait for Migrator {
             Box::new(m20220101_000008_create_directory_table::Migration),
             Box::new(m20220101_000009_create_artistdirectory_table::Migration),
             Box::new(m20220101_000010_create_audiodirectory_table::Migration),
+            Box::new(m20220101_000011_alter_audio_table::Migration),
+            Box::new(m20220101_000012_create_scancheckpoint_table::Migration),
+            Box::new(m20220101_000013_create_album_table::Migration),
+            Box::new(m20220101_000014_create_albumaudio_table::Migration),
+            Box::new(m20220101_000015_alter_album_table::Migration),
+            Box::new(m20220101_000016_alter_image_table::Migration),
+            Box::new(m20220101_000017_create_albumimage_table::Migration),
+            Box::new(m20220101_000018_create_artistimage_table::Migration),
+            Box::new(m20220101_000019_alter_image_table::Migration),
+            Box::new(m20220101_000020_create_audioimage_table::Migration),
+            Box::new(m20220101_000021_alter_audiotag_table::Migration),
+            Box::new(m20220101_000022_alter_artist_table::Migration),
+            Box::new(m20220101_000023_create_artistcandidate_table::Migration),
+            Box::new(m20220101_000024_create_artistpin_table::Migration),
+            Box::new(m20220101_000025_alter_album_table::Migration),
+            Box::new(m20220101_000026_alter_audio_table::Migration),
+            Box::new(m20220101_000027_alter_musicbrainzqueue_table::Migration),
+            Box::new(m20220101_000028_create_audiofingerprint_table::Migration),
+            Box::new(m20220101_000029_create_audioacoustid_table::Migration),
+            Box::new(m20220101_000030_alter_audiofingerprint_table::Migration),
+            Box::new(m20220101_000031_alter_audio_table::Migration),
+            Box::new(m20220101_000032_create_audioverification_table::Migration),
+            Box::new(m20220101_000033_alter_audio_table::Migration),
+            Box::new(m20220101_000034_create_audiofeature_table::Migration),
+            Box::new(m20220101_000035_alter_audioverification_table::Migration),
         ]
     }
 }
@@ -38,7 +38,7 @@ impl MigrationTrait for Migration {
 
 /// Learn more at https://docs.rs/sea-query#iden
 #[derive(Iden)]
-enum Image {
+pub(crate) enum Image {
     Table,
     ImageId,
     Uri,
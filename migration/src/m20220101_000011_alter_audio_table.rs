@@ -0,0 +1,66 @@
+// Track enough about each audio file to detect when it changes, moves, or is deleted.
+
+use sea_orm_migration::prelude::*;
+
+use super::m20220101_000001_create_audio_table::Audio;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // SQLite only supports one alter option per statement.
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Audio::Table)
+                    .add_column(
+                        ColumnDef::new(AudioFile::Size)
+                            .big_integer()
+                            .not_null()
+                            .default(0),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Audio::Table)
+                    .add_column(ColumnDef::new(AudioFile::Modified).timestamp().null())
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Audio::Table)
+                    .add_column(ColumnDef::new(AudioFile::Hash).string().null())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for column in [AudioFile::Size, AudioFile::Modified, AudioFile::Hash] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Audio::Table)
+                        .drop_column(column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+pub(crate) enum AudioFile {
+    Size,
+    Modified,
+    Hash,
+}
@@ -0,0 +1,69 @@
+// Track how far an interrupted scan got, so the next scan can resume from there.
+
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Create the scan_checkpoint table.
+        manager
+            .create_table(
+                Table::create()
+                    .table(ScanCheckpoint::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(ScanCheckpoint::ScanCheckpointId)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(ScanCheckpoint::Created)
+                            .timestamp()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(ScanCheckpoint::Updated)
+                            .timestamp()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(ScanCheckpoint::Library)
+                            .string()
+                            .not_null()
+                            .unique_key(),
+                    )
+                    // The last file scanned, all files walked before it have been scanned.
+                    .col(ColumnDef::new(ScanCheckpoint::Path).string().not_null())
+                    .col(
+                        ColumnDef::new(ScanCheckpoint::Files)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(ScanCheckpoint::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+pub(crate) enum ScanCheckpoint {
+    Table,
+    ScanCheckpointId,
+    Created,
+    Updated,
+    Library,
+    Path,
+    Files,
+}
@@ -0,0 +1,66 @@
+// Albums are built from the Album, AlbumArtist and Date tags of the audio files they contain.
+// Audio files without an AlbumArtist tag are grouped by the directory they're in.
+
+use super::m20220101_000005_create_artist_table::Artist;
+
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Create the album table.
+        manager
+            .create_table(
+                Table::create()
+                    .table(Album::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(Album::AlbumId)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(Album::Created).timestamp().not_null())
+                    .col(ColumnDef::new(Album::Updated).timestamp().not_null())
+                    .col(ColumnDef::new(Album::Name).string().not_null())
+                    // The AlbumArtist tag, if any.
+                    .col(ColumnDef::new(Album::ArtistName).string().null())
+                    .col(ColumnDef::new(Album::ArtistId).integer().null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-album-artistid")
+                            .from(Album::Table, Album::ArtistId)
+                            .to(Artist::Table, Artist::ArtistId),
+                    )
+                    .col(ColumnDef::new(Album::Date).string().null())
+                    // The directory the album was found in.
+                    .col(ColumnDef::new(Album::Path).string().not_null())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(Album::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+pub(crate) enum Album {
+    Table,
+    AlbumId,
+    Created,
+    Updated,
+    Name,
+    ArtistName,
+    ArtistId,
+    Date,
+    Path,
+}
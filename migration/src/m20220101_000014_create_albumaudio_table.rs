@@ -0,0 +1,68 @@
+// Each track belongs to one album, on a disc and at a position taken from its tags.
+
+use super::m20220101_000001_create_audio_table::Audio;
+use super::m20220101_000013_create_album_table::Album;
+
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Create the album_audio table.
+        manager
+            .create_table(
+                Table::create()
+                    .table(AlbumAudio::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(AlbumAudio::AlbumAudioId)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(AlbumAudio::Created).timestamp().not_null())
+                    .col(ColumnDef::new(AlbumAudio::Updated).timestamp().not_null())
+                    .col(ColumnDef::new(AlbumAudio::AlbumId).integer().not_null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-albumaudio-albumid")
+                            .from(AlbumAudio::Table, AlbumAudio::AlbumId)
+                            .to(Album::Table, Album::AlbumId),
+                    )
+                    .col(ColumnDef::new(AlbumAudio::AudioId).integer().not_null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-albumaudio-audioid")
+                            .from(AlbumAudio::Table, AlbumAudio::AudioId)
+                            .to(Audio::Table, Audio::AudioId),
+                    )
+                    .col(ColumnDef::new(AlbumAudio::DiscNumber).integer().null())
+                    .col(ColumnDef::new(AlbumAudio::TrackNumber).integer().null())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(AlbumAudio::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+pub(crate) enum AlbumAudio {
+    Table,
+    AlbumAudioId,
+    Created,
+    Updated,
+    AlbumId,
+    AudioId,
+    DiscNumber,
+    TrackNumber,
+}
@@ -0,0 +1,39 @@
+// Track how many discs an album has, so multi-disc albums are listed and played disc by disc.
+
+use sea_orm_migration::prelude::*;
+
+use super::m20220101_000013_create_album_table::Album;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Album::Table)
+                    .add_column(ColumnDef::new(AlbumDiscs::TotalDiscs).integer().null())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Album::Table)
+                    .drop_column(AlbumDiscs::TotalDiscs)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+pub(crate) enum AlbumDiscs {
+    TotalDiscs,
+}
@@ -0,0 +1,63 @@
+// Classify images as front covers, back covers, artist photos or other artwork, and track
+// their size to help choose the best cover.
+
+use sea_orm_migration::prelude::*;
+
+use super::m20220101_000003_create_image_table::Image;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // SQLite only supports one alter option per statement.
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Image::Table)
+                    .add_column(
+                        ColumnDef::new(ImageFile::Kind)
+                            .string()
+                            .not_null()
+                            .default("other"),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Image::Table)
+                    .add_column(
+                        ColumnDef::new(ImageFile::Size)
+                            .big_integer()
+                            .not_null()
+                            .default(0),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for column in [ImageFile::Kind, ImageFile::Size] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Image::Table)
+                        .drop_column(column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+pub(crate) enum ImageFile {
+    Kind,
+    Size,
+}
@@ -0,0 +1,60 @@
+// Images found in an album's directory, including those in its disc subdirectories.
+
+use super::m20220101_000003_create_image_table::Image;
+use super::m20220101_000013_create_album_table::Album;
+
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Create the album_image table.
+        manager
+            .create_table(
+                Table::create()
+                    .table(AlbumImage::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(AlbumImage::AlbumImageId)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(AlbumImage::AlbumId).integer().not_null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-albumimage-albumid")
+                            .from(AlbumImage::Table, AlbumImage::AlbumId)
+                            .to(Album::Table, Album::AlbumId),
+                    )
+                    .col(ColumnDef::new(AlbumImage::ImageId).integer().not_null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-albumimage-imageid")
+                            .from(AlbumImage::Table, AlbumImage::ImageId)
+                            .to(Image::Table, Image::ImageId),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(AlbumImage::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+pub(crate) enum AlbumImage {
+    Table,
+    AlbumImageId,
+    AlbumId,
+    ImageId,
+}
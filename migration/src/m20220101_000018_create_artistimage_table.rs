@@ -0,0 +1,61 @@
+// Artist photos found in an album's directory, and images found in a directory containing
+// albums by a single artist.
+
+use super::m20220101_000003_create_image_table::Image;
+use super::m20220101_000005_create_artist_table::Artist;
+
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Create the artist_image table.
+        manager
+            .create_table(
+                Table::create()
+                    .table(ArtistImage::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(ArtistImage::ArtistImageId)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(ArtistImage::ArtistId).integer().not_null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-artistimage-artistid")
+                            .from(ArtistImage::Table, ArtistImage::ArtistId)
+                            .to(Artist::Table, Artist::ArtistId),
+                    )
+                    .col(ColumnDef::new(ArtistImage::ImageId).integer().not_null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-artistimage-imageid")
+                            .from(ArtistImage::Table, ArtistImage::ImageId)
+                            .to(Image::Table, Image::ImageId),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(ArtistImage::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+pub(crate) enum ArtistImage {
+    Table,
+    ArtistImageId,
+    ArtistId,
+    ImageId,
+}
@@ -0,0 +1,58 @@
+// Pictures embedded in audio files are extracted to the artwork cache, and stored once per
+// unique picture using a hash of its contents.
+
+use sea_orm_migration::prelude::*;
+
+use super::m20220101_000003_create_image_table::Image;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // SQLite only supports one alter option per statement.
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Image::Table)
+                    .add_column(ColumnDef::new(ImageEmbedded::Hash).string().null())
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Image::Table)
+                    .add_column(
+                        ColumnDef::new(ImageEmbedded::Embedded)
+                            .boolean()
+                            .not_null()
+                            .default(false),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for column in [ImageEmbedded::Hash, ImageEmbedded::Embedded] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Image::Table)
+                        .drop_column(column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+pub(crate) enum ImageEmbedded {
+    Hash,
+    Embedded,
+}
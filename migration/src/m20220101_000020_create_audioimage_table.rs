@@ -0,0 +1,61 @@
+// Pictures embedded in each audio file. The same picture is often embedded in every track of
+// an album, and is only stored once.
+
+use super::m20220101_000001_create_audio_table::Audio;
+use super::m20220101_000003_create_image_table::Image;
+
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Create the audio_image table.
+        manager
+            .create_table(
+                Table::create()
+                    .table(AudioImage::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(AudioImage::AudioImageId)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(AudioImage::AudioId).integer().not_null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-audioimage-audioid")
+                            .from(AudioImage::Table, AudioImage::AudioId)
+                            .to(Audio::Table, Audio::AudioId),
+                    )
+                    .col(ColumnDef::new(AudioImage::ImageId).integer().not_null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-audioimage-imageid")
+                            .from(AudioImage::Table, AudioImage::ImageId)
+                            .to(Image::Table, Image::ImageId),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(AudioImage::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+pub(crate) enum AudioImage {
+    Table,
+    AudioImageId,
+    AudioId,
+    ImageId,
+}
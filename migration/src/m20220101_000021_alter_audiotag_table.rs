@@ -0,0 +1,89 @@
+// Store every tag, keeping the key exactly as it's found in the audio file along with the
+// standard name, and the order of tags with more than one value.
+
+use sea_orm_migration::prelude::*;
+
+use super::m20220101_000002_create_audiotag_table::AudioTag;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // SQLite only supports one alter option per statement.
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(AudioTag::Table)
+                    .add_column(
+                        ColumnDef::new(AudioTagKey::Key)
+                            .string()
+                            .not_null()
+                            .default(""),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(AudioTag::Table)
+                    .add_column(
+                        ColumnDef::new(AudioTagKey::Position)
+                            .integer()
+                            .not_null()
+                            .default(0),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        // Tags stored before raw keys were kept only have their standard name.
+        manager
+            .exec_stmt(
+                Query::update()
+                    .table(AudioTag::Table)
+                    .value(AudioTagKey::Key, Expr::col(AudioTag::Name))
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-audiotag-name")
+                    .table(AudioTag::Table)
+                    .col(AudioTag::Name)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_index(
+                Index::drop()
+                    .name("idx-audiotag-name")
+                    .table(AudioTag::Table)
+                    .to_owned(),
+            )
+            .await?;
+        for column in [AudioTagKey::Key, AudioTagKey::Position] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(AudioTag::Table)
+                        .drop_column(column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+pub(crate) enum AudioTagKey {
+    Key,
+    Position,
+}
@@ -0,0 +1,68 @@
+// Track how confidently an artist was matched in MusicBrainz, so uncertain matches can be
+// reviewed instead of silently assigning the wrong artist.
+
+use sea_orm_migration::prelude::*;
+
+use super::m20220101_000005_create_artist_table::Artist;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // SQLite only supports one alter option per statement.
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Artist::Table)
+                    .add_column(
+                        ColumnDef::new(ArtistMatch::MatchStatus)
+                            .string()
+                            .not_null()
+                            .default("matched"),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Artist::Table)
+                    .add_column(ColumnDef::new(ArtistMatch::MatchScore).integer().null())
+                    .to_owned(),
+            )
+            .await?;
+        // Artists stored without a MusicBrainz ID were not found.
+        manager
+            .exec_stmt(
+                Query::update()
+                    .table(Artist::Table)
+                    .value(ArtistMatch::MatchStatus, "not_found")
+                    .and_where(Expr::col(Artist::MusicbrainzId).eq(""))
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for column in [ArtistMatch::MatchStatus, ArtistMatch::MatchScore] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Artist::Table)
+                        .drop_column(column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+pub(crate) enum ArtistMatch {
+    MatchStatus,
+    MatchScore,
+}
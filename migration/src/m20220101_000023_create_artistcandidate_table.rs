@@ -0,0 +1,88 @@
+// MusicBrainz artists that may be the same as an artist that couldn't be confidently matched,
+// kept for later review.
+
+use super::m20220101_000005_create_artist_table::Artist;
+
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Create the artist_candidate table.
+        manager
+            .create_table(
+                Table::create()
+                    .table(ArtistCandidate::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(ArtistCandidate::ArtistCandidateId)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(ArtistCandidate::ArtistId)
+                            .integer()
+                            .not_null(),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-artistcandidate-artistid")
+                            .from(ArtistCandidate::Table, ArtistCandidate::ArtistId)
+                            .to(Artist::Table, Artist::ArtistId),
+                    )
+                    .col(
+                        ColumnDef::new(ArtistCandidate::MusicbrainzId)
+                            .string()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(ArtistCandidate::Name).string().not_null())
+                    .col(
+                        ColumnDef::new(ArtistCandidate::SortName)
+                            .string()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(ArtistCandidate::DisambiguationComment)
+                            .string()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(ArtistCandidate::SearchScore)
+                            .integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(ArtistCandidate::Confidence)
+                            .integer()
+                            .not_null(),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(ArtistCandidate::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+pub(crate) enum ArtistCandidate {
+    Table,
+    ArtistCandidateId,
+    ArtistId,
+    MusicbrainzId,
+    Name,
+    SortName,
+    DisambiguationComment,
+    SearchScore,
+    Confidence,
+}
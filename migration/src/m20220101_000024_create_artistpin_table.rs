@@ -0,0 +1,74 @@
+// Artists chosen by hand for a name, optionally only in one directory, which take precedence
+// over matching by name or in MusicBrainz so they survive rescans.
+
+use super::m20220101_000005_create_artist_table::Artist;
+
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Create the artist_pin table.
+        manager
+            .create_table(
+                Table::create()
+                    .table(ArtistPin::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(ArtistPin::ArtistPinId)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(ArtistPin::Created).date_time().not_null())
+                    .col(ColumnDef::new(ArtistPin::Name).string().not_null())
+                    .col(
+                        ColumnDef::new(ArtistPin::Path)
+                            .string()
+                            .not_null()
+                            .default(""),
+                    )
+                    .col(ColumnDef::new(ArtistPin::ArtistId).integer().not_null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-artistpin-artistid")
+                            .from(ArtistPin::Table, ArtistPin::ArtistId)
+                            .to(Artist::Table, Artist::ArtistId),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-artistpin-name-path")
+                    .table(ArtistPin::Table)
+                    .col(ArtistPin::Name)
+                    .col(ArtistPin::Path)
+                    .unique()
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(ArtistPin::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+pub(crate) enum ArtistPin {
+    Table,
+    ArtistPinId,
+    Created,
+    Name,
+    Path,
+    ArtistId,
+}
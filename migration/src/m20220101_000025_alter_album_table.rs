@@ -0,0 +1,72 @@
+// Match albums to MusicBrainz releases, storing the release and release group IDs along with
+// the canonical title. A NULL release ID hasn't been looked up yet, an empty one wasn't found.
+
+use sea_orm_migration::prelude::*;
+
+use super::m20220101_000013_create_album_table::Album;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // SQLite only supports one alter option per statement.
+        for mut column in [
+            ColumnDef::new(AlbumRelease::MusicbrainzReleaseId)
+                .string()
+                .null()
+                .to_owned(),
+            ColumnDef::new(AlbumRelease::MusicbrainzReleaseGroupId)
+                .string()
+                .null()
+                .to_owned(),
+            ColumnDef::new(AlbumRelease::MusicbrainzTitle)
+                .string()
+                .null()
+                .to_owned(),
+            ColumnDef::new(AlbumRelease::MusicbrainzScore)
+                .integer()
+                .null()
+                .to_owned(),
+        ] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Album::Table)
+                        .add_column(&mut column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for column in [
+            AlbumRelease::MusicbrainzReleaseId,
+            AlbumRelease::MusicbrainzReleaseGroupId,
+            AlbumRelease::MusicbrainzTitle,
+            AlbumRelease::MusicbrainzScore,
+        ] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Album::Table)
+                        .drop_column(column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+pub(crate) enum AlbumRelease {
+    MusicbrainzReleaseId,
+    MusicbrainzReleaseGroupId,
+    MusicbrainzTitle,
+    MusicbrainzScore,
+}
@@ -0,0 +1,58 @@
+// Match audio files to MusicBrainz recordings, and the tracks of releases they're on, along
+// with the canonical title. A NULL recording ID hasn't been looked up yet, an empty one wasn't
+// found.
+
+use sea_orm_migration::prelude::*;
+
+use super::m20220101_000001_create_audio_table::Audio;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // SQLite only supports one alter option per statement.
+        for column in [
+            AudioRecording::MusicbrainzRecordingId,
+            AudioRecording::MusicbrainzTrackId,
+            AudioRecording::MusicbrainzTitle,
+        ] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Audio::Table)
+                        .add_column(ColumnDef::new(column).string().null())
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for column in [
+            AudioRecording::MusicbrainzRecordingId,
+            AudioRecording::MusicbrainzTrackId,
+            AudioRecording::MusicbrainzTitle,
+        ] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Audio::Table)
+                        .drop_column(column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+pub(crate) enum AudioRecording {
+    MusicbrainzRecordingId,
+    MusicbrainzTrackId,
+    MusicbrainzTitle,
+}
@@ -0,0 +1,79 @@
+// Track attempts at processing queue items, so failed lookups are retried with a growing delay
+// and eventually set aside as dead letters instead of being retried forever.
+
+use sea_orm_migration::prelude::*;
+
+use super::m20220101_000007_create_musicbrainzqueue_table::MusicbrainzQueue;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // SQLite only supports one alter option per statement.
+        for mut column in [
+            ColumnDef::new(QueueRetry::Status)
+                .string()
+                .not_null()
+                .default("pending")
+                .to_owned(),
+            ColumnDef::new(QueueRetry::Attempts)
+                .integer()
+                .not_null()
+                .default(0)
+                .to_owned(),
+            ColumnDef::new(QueueRetry::NextAttemptAt)
+                .timestamp()
+                .null()
+                .to_owned(),
+        ] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(MusicbrainzQueue::Table)
+                        .add_column(&mut column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        // Items were previously left started forever when processing them failed, retry them.
+        manager
+            .exec_stmt(
+                Query::update()
+                    .table(MusicbrainzQueue::Table)
+                    .value(
+                        MusicbrainzQueue::ProcessingStartedAt,
+                        Option::<String>::None,
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for column in [
+            QueueRetry::Status,
+            QueueRetry::Attempts,
+            QueueRetry::NextAttemptAt,
+        ] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(MusicbrainzQueue::Table)
+                        .drop_column(column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+pub(crate) enum QueueRetry {
+    Status,
+    Attempts,
+    NextAttemptAt,
+}
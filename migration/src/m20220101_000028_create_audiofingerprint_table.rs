@@ -0,0 +1,90 @@
+// Acoustic fingerprints of audio files, compatible with Chromaprint, for identifying recordings
+// without tags and finding duplicates that aren't byte for byte identical.
+
+use super::m20220101_000001_create_audio_table::Audio;
+
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Create the audio_fingerprint table.
+        manager
+            .create_table(
+                Table::create()
+                    .table(AudioFingerprint::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(AudioFingerprint::AudioFingerprintId)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(AudioFingerprint::Created)
+                            .date_time()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(AudioFingerprint::AudioId)
+                            .integer()
+                            .not_null(),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-audiofingerprint-audioid")
+                            .from(AudioFingerprint::Table, AudioFingerprint::AudioId)
+                            .to(Audio::Table, Audio::AudioId),
+                    )
+                    .col(
+                        ColumnDef::new(AudioFingerprint::Algorithm)
+                            .integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(AudioFingerprint::Duration)
+                            .integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(AudioFingerprint::Fingerprint)
+                            .string()
+                            .not_null(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-audiofingerprint-audioid")
+                    .table(AudioFingerprint::Table)
+                    .col(AudioFingerprint::AudioId)
+                    .unique()
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(AudioFingerprint::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+pub(crate) enum AudioFingerprint {
+    Table,
+    AudioFingerprintId,
+    Created,
+    AudioId,
+    Algorithm,
+    Duration,
+    Fingerprint,
+}
@@ -0,0 +1,77 @@
+// Recordings AcoustID matched the fingerprint of each audio file with, and how well, so files
+// without usable tags can still be identified.
+
+use super::m20220101_000001_create_audio_table::Audio;
+
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Create the audio_acoustid table.
+        manager
+            .create_table(
+                Table::create()
+                    .table(AudioAcoustid::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(AudioAcoustid::AudioAcoustidId)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(AudioAcoustid::Created)
+                            .date_time()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(AudioAcoustid::AudioId).integer().not_null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-audioacoustid-audioid")
+                            .from(AudioAcoustid::Table, AudioAcoustid::AudioId)
+                            .to(Audio::Table, Audio::AudioId),
+                    )
+                    .col(ColumnDef::new(AudioAcoustid::Acoustid).string().not_null())
+                    .col(
+                        ColumnDef::new(AudioAcoustid::MusicbrainzRecordingId)
+                            .string()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(AudioAcoustid::Score).double().not_null())
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-audioacoustid-audioid")
+                    .table(AudioAcoustid::Table)
+                    .col(AudioAcoustid::AudioId)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(AudioAcoustid::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+pub(crate) enum AudioAcoustid {
+    Table,
+    AudioAcoustidId,
+    Created,
+    AudioId,
+    Acoustid,
+    MusicbrainzRecordingId,
+    Score,
+}
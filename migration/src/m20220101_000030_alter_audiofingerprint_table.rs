@@ -0,0 +1,44 @@
+// Record when each fingerprint was looked up in AcoustID, so fingerprints that matched nothing
+// aren't looked up again on every scan. A NULL time hasn't been looked up yet.
+
+use sea_orm_migration::prelude::*;
+
+use super::m20220101_000028_create_audiofingerprint_table::AudioFingerprint;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(AudioFingerprint::Table)
+                    .add_column(
+                        ColumnDef::new(FingerprintLookup::LookedUpAt)
+                            .timestamp()
+                            .null(),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(AudioFingerprint::Table)
+                    .drop_column(FingerprintLookup::LookedUpAt)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+pub(crate) enum FingerprintLookup {
+    LookedUpAt,
+}
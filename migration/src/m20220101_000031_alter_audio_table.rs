@@ -0,0 +1,45 @@
+// Hide inferior copies of duplicate audio files from listing and playing, without removing
+// them from the library.
+
+use sea_orm_migration::prelude::*;
+
+use super::m20220101_000001_create_audio_table::Audio;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Audio::Table)
+                    .add_column(
+                        ColumnDef::new(AudioHidden::Hidden)
+                            .boolean()
+                            .not_null()
+                            .default(false),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Audio::Table)
+                    .drop_column(AudioHidden::Hidden)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+pub(crate) enum AudioHidden {
+    Hidden,
+}
@@ -0,0 +1,94 @@
+// The result of fully decoding each audio file, as scanning only probes headers, so corrupt and
+// truncated files can be found before they fail to play.
+
+use super::m20220101_000001_create_audio_table::Audio;
+
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Create the audio_verification table.
+        manager
+            .create_table(
+                Table::create()
+                    .table(AudioVerification::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(AudioVerification::AudioVerificationId)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(AudioVerification::Created)
+                            .date_time()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(AudioVerification::AudioId)
+                            .integer()
+                            .not_null(),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-audioverification-audioid")
+                            .from(AudioVerification::Table, AudioVerification::AudioId)
+                            .to(Audio::Table, Audio::AudioId),
+                    )
+                    .col(
+                        ColumnDef::new(AudioVerification::Status)
+                            .string()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(AudioVerification::DecodedFrames)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(AudioVerification::ExpectedFrames).big_integer())
+                    .col(
+                        ColumnDef::new(AudioVerification::DecodeErrors)
+                            .integer()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(AudioVerification::Error).string())
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-audioverification-audioid")
+                    .table(AudioVerification::Table)
+                    .col(AudioVerification::AudioId)
+                    .unique()
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(AudioVerification::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+pub(crate) enum AudioVerification {
+    Table,
+    AudioVerificationId,
+    Created,
+    AudioId,
+    Status,
+    DecodedFrames,
+    ExpectedFrames,
+    DecodeErrors,
+    Error,
+}
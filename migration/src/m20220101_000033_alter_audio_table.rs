@@ -0,0 +1,63 @@
+// The loudness and peak of each audio file and the album it's on, so playback can be normalised
+// with ReplayGain.
+
+use sea_orm_migration::prelude::*;
+
+use super::m20220101_000001_create_audio_table::Audio;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // SQLite only supports one alter option per statement.
+        for column in [
+            AudioLoudness::Loudness,
+            AudioLoudness::TruePeak,
+            AudioLoudness::TrackGain,
+            AudioLoudness::AlbumGain,
+            AudioLoudness::AlbumPeak,
+        ] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Audio::Table)
+                        .add_column(ColumnDef::new(column).double().null())
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for column in [
+            AudioLoudness::Loudness,
+            AudioLoudness::TruePeak,
+            AudioLoudness::TrackGain,
+            AudioLoudness::AlbumGain,
+            AudioLoudness::AlbumPeak,
+        ] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Audio::Table)
+                        .drop_column(column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+pub(crate) enum AudioLoudness {
+    Loudness,
+    TruePeak,
+    TrackGain,
+    AlbumGain,
+    AlbumPeak,
+}
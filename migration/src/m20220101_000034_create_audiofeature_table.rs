@@ -0,0 +1,88 @@
+// Tempo, key and other descriptors of each audio file computed from its decoded audio, for
+// building playlists and finding similar tracks.
+
+use super::m20220101_000001_create_audio_table::Audio;
+
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Create the audio_feature table.
+        manager
+            .create_table(
+                Table::create()
+                    .table(AudioFeature::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(AudioFeature::AudioFeatureId)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(AudioFeature::Created).date_time().not_null())
+                    .col(ColumnDef::new(AudioFeature::AudioId).integer().not_null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-audiofeature-audioid")
+                            .from(AudioFeature::Table, AudioFeature::AudioId)
+                            .to(Audio::Table, Audio::AudioId),
+                    )
+                    .col(ColumnDef::new(AudioFeature::Tempo).double().not_null())
+                    .col(
+                        ColumnDef::new(AudioFeature::Danceability)
+                            .double()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(AudioFeature::Key).string().not_null())
+                    .col(
+                        ColumnDef::new(AudioFeature::KeyConfidence)
+                            .double()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(AudioFeature::SpectralCentroid)
+                            .double()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(AudioFeature::Energy).double().not_null())
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-audiofeature-audioid")
+                    .table(AudioFeature::Table)
+                    .col(AudioFeature::AudioId)
+                    .unique()
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(AudioFeature::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+pub(crate) enum AudioFeature {
+    Table,
+    AudioFeatureId,
+    Created,
+    AudioId,
+    Tempo,
+    Danceability,
+    Key,
+    KeyConfidence,
+    SpectralCentroid,
+    Energy,
+}
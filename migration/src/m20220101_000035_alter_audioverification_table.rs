@@ -0,0 +1,64 @@
+// The highest frequency in the spectrum of lossless audio files and whether it ends in the hard
+// low-pass cutoff of a lossy encoder, so lossless files transcoded from lossy ones can be found.
+
+use sea_orm_migration::prelude::*;
+
+use super::m20220101_000032_create_audioverification_table::AudioVerification;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // SQLite only supports one alter option per statement.
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(AudioVerification::Table)
+                    .add_column(
+                        ColumnDef::new(AudioVerificationSpectrum::CutoffFrequency)
+                            .integer()
+                            .null(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(AudioVerification::Table)
+                    .add_column(
+                        ColumnDef::new(AudioVerificationSpectrum::Spectrum)
+                            .string()
+                            .null(),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for column in [
+            AudioVerificationSpectrum::CutoffFrequency,
+            AudioVerificationSpectrum::Spectrum,
+        ] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(AudioVerification::Table)
+                        .drop_column(column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+pub(crate) enum AudioVerificationSpectrum {
+    CutoffFrequency,
+    Spectrum,
+}
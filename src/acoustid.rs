@@ -0,0 +1,318 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use sea_orm::sea_query::Query;
+use sea_orm::*;
+use serde::Deserialize;
+use tracing::{event, instrument, Level};
+
+use crate::cache;
+use crate::database;
+use crate::entities::{prelude::*, *};
+use crate::media::Scope;
+use crate::musicbrainz::{self, PayloadType, QueuePayload};
+use crate::ratelimit::{self, RateLimiter};
+use crate::Config;
+
+// According to https://acoustid.org/webservice AcoustID allows 3 requests per second.
+const ACOUSTID_RATE: f64 = 3.0;
+const ACOUSTID_BURST: u32 = 3;
+
+const DEFAULT_ACOUSTID_URL: &str = "https://api.acoustid.org/v2";
+
+// The name AcoustID is rate limited and cached by.
+pub(crate) const SERVICE: &str = "AcoustID";
+
+// Audio files are only linked to a recording AcoustID matched with at least this score out of 1.
+const RECORDING_MATCH_THRESHOLD: f64 = 0.8;
+
+/// Queue fingerprinted audio files in scope without Artist or TrackTitle tags, that haven't been
+/// matched to a recording, to be looked up in AcoustID. Nothing is queued without an API key.
+#[instrument]
+pub(crate) async fn queue_lookups(config: &Config, scope: &Scope) {
+    event!(Level::TRACE, "queue_lookups");
+    if config.acoustid_api_key.is_none() {
+        return;
+    }
+
+    let untagged = |name: &str| {
+        audio::Column::AudioId.not_in_subquery(
+            Query::select()
+                .column(audio_tag::Column::AudioId)
+                .from(AudioTag)
+                .and_where(audio_tag::Column::Name.eq(name))
+                .to_owned(),
+        )
+    };
+    let db = database::connection(config).await;
+    let audio_files = match Audio::find()
+        .inner_join(AudioFingerprint)
+        .filter(audio_fingerprint::Column::LookedUpAt.is_null())
+        .filter(scope.paths(audio::Column::Path))
+        .filter(
+            Condition::any()
+                .add(audio::Column::MusicbrainzRecordingId.is_null())
+                .add(audio::Column::MusicbrainzRecordingId.eq("")),
+        )
+        .filter(
+            Condition::any()
+                .add(untagged("Artist"))
+                .add(untagged("TrackTitle")),
+        )
+        .all(db)
+        .await
+    {
+        Ok(a) => a,
+        Err(e) => {
+            event!(Level::WARN, "Audio::find() untagged failure: {}", e);
+            return;
+        }
+    };
+    for audio_file in audio_files {
+        musicbrainz::add_to_queue(
+            config,
+            QueuePayload {
+                payload_type: PayloadType::AudioFingerprint,
+                id: audio_file.audio_id,
+                value: audio_file.name,
+            },
+        )
+        .await;
+    }
+}
+
+/// Look up the fingerprint of an audio file in AcoustID, and store the recordings it matched.
+/// The best recording is looked up in MusicBrainz if it's a confident match and the audio file
+/// hasn't been matched to a recording otherwise. Returns why it failed if AcoustID couldn't be
+/// reached, so it can be retried.
+#[instrument]
+pub(crate) async fn lookup_fingerprint(config: &Config, audio_id: i32) -> Result<(), String> {
+    event!(Level::TRACE, "lookup_fingerprint");
+    let db = database::connection(config).await;
+    let fingerprint = match AudioFingerprint::find()
+        .filter(audio_fingerprint::Column::AudioId.eq(audio_id))
+        .find_also_related(Audio)
+        .one(db)
+        .await
+    {
+        Ok(Some((f, Some(a)))) => (f, a),
+        // The audio file changed or was removed since it was queued.
+        Ok(_) => return Ok(()),
+        Err(e) => {
+            event!(Level::WARN, "AudioFingerprint::find() failure: {}", e);
+            return Err(format!("AudioFingerprint::find() failure: {}", e));
+        }
+    };
+    let (fingerprint, audio_file) = fingerprint;
+
+    let results = match AcoustId::new(config)?
+        .lookup(&fingerprint.fingerprint, fingerprint.duration)
+        .await
+    {
+        Ok(r) => r,
+        Err(e) => {
+            event!(Level::WARN, "acoustid lookup failed: {}", e);
+            return Err(format!("acoustid lookup failed: {}", e));
+        }
+    };
+    let matches: Vec<(&str, &str, f64)> = results
+        .iter()
+        .flat_map(|r| {
+            r.recordings
+                .iter()
+                .map(move |recording| (r.id.as_str(), recording.id.as_str(), r.score))
+        })
+        .collect();
+    if let Err(e) = store_matches(db, &fingerprint, &matches).await {
+        event!(Level::WARN, "failed to store acoustid matches: {}", e);
+        return Err(format!("failed to store acoustid matches: {}", e));
+    }
+
+    // AcoustID tracks can be linked to several recordings, ie the same audio released as a
+    // single and on an album, which are only told apart by tags.
+    let best = results
+        .iter()
+        .filter(|r| !r.recordings.is_empty())
+        .max_by(|a, b| a.score.total_cmp(&b.score));
+    match best {
+        Some(result)
+            if result.score >= RECORDING_MATCH_THRESHOLD && result.recordings.len() == 1 =>
+        {
+            event!(
+                Level::INFO,
+                "{} matched recording {} in AcoustID ({:.2})",
+                audio_file.name,
+                result.recordings[0].id,
+                result.score
+            );
+            if audio_file
+                .musicbrainz_recording_id
+                .as_deref()
+                .is_none_or(str::is_empty)
+            {
+                musicbrainz::add_to_queue(
+                    config,
+                    QueuePayload {
+                        payload_type: PayloadType::AudioRecording,
+                        id: audio_id,
+                        value: result.recordings[0].id.clone(),
+                    },
+                )
+                .await;
+            }
+        }
+        Some(result) => event!(
+            Level::INFO,
+            "{} could not be confidently matched to a recording in AcoustID ({:.2}, {} recordings)",
+            audio_file.name,
+            result.score,
+            result.recordings.len()
+        ),
+        None => event!(Level::INFO, "{} not found in AcoustID", audio_file.name),
+    }
+    Ok(())
+}
+
+/// Replace the recordings a fingerprint matched, and record when it was looked up.
+async fn store_matches(
+    db: &DatabaseConnection,
+    fingerprint: &audio_fingerprint::Model,
+    matches: &[(&str, &str, f64)],
+) -> Result<(), DbErr> {
+    let txn = db.begin().await?;
+    AudioAcoustid::delete_many()
+        .filter(audio_acoustid::Column::AudioId.eq(fingerprint.audio_id))
+        .exec(&txn)
+        .await?;
+    let now = chrono::Utc::now().naive_utc();
+    if !matches.is_empty() {
+        AudioAcoustid::insert_many(matches.iter().map(|(acoustid, recording_id, score)| {
+            audio_acoustid::ActiveModel {
+                created: ActiveValue::Set(now),
+                audio_id: ActiveValue::Set(fingerprint.audio_id),
+                acoustid: ActiveValue::Set(acoustid.to_string()),
+                musicbrainz_recording_id: ActiveValue::Set(recording_id.to_string()),
+                score: ActiveValue::Set(*score),
+                ..Default::default()
+            }
+        }))
+        .exec(&txn)
+        .await?;
+    }
+    audio_fingerprint::ActiveModel {
+        audio_fingerprint_id: ActiveValue::Unchanged(fingerprint.audio_fingerprint_id),
+        looked_up_at: ActiveValue::Set(Some(now)),
+        ..Default::default()
+    }
+    .update(&txn)
+    .await?;
+    txn.commit().await
+}
+
+/// The rate limiter shared by all AcoustID requests.
+pub(crate) fn rate_limiter() -> Arc<RateLimiter> {
+    RateLimiter::for_service(SERVICE, ACOUSTID_RATE, ACOUSTID_BURST)
+}
+
+/// The API key of the application, which AcoustID requires with every request. It's not part
+/// of cached requests, so it isn't stored with them.
+pub(crate) fn client_query(config: &Config) -> Result<(String, String), String> {
+    match &config.acoustid_api_key {
+        Some(key) => Ok(("client".to_string(), key.clone())),
+        None => Err("no AcoustID API key is configured".to_string()),
+    }
+}
+
+/// The AcoustID API, see https://acoustid.org/webservice.
+struct AcoustId {
+    url: String,
+    client: (String, String),
+    cache: PathBuf,
+    limiter: Arc<RateLimiter>,
+}
+
+impl AcoustId {
+    fn new(config: &Config) -> Result<Self, String> {
+        Ok(AcoustId {
+            url: config
+                .acoustid_url
+                .as_deref()
+                .unwrap_or(DEFAULT_ACOUSTID_URL)
+                .trim_end_matches('/')
+                .to_string(),
+            client: client_query(config)?,
+            cache: cache::metadata_cache(config),
+            limiter: rate_limiter(),
+        })
+    }
+
+    /// Look up a compressed fingerprint and the duration of the audio it was computed from,
+    /// reusing the response to the same lookup if it's in the metadata cache.
+    async fn lookup(&self, fingerprint: &str, duration: i32) -> anyhow::Result<Vec<LookupResult>> {
+        let duration = duration.to_string();
+        let query = [
+            ("meta", "recordingids"),
+            ("duration", duration.as_str()),
+            ("fingerprint", fingerprint),
+        ];
+        let key = cache::key("lookup", &query);
+        if let Some(cached) = cache::load(&self.cache, SERVICE, &key) {
+            event!(
+                Level::DEBUG,
+                "using acoustid response cached at {}",
+                cached.fetched_at
+            );
+            return Ok(serde_json::from_value::<Lookup>(cached.response)?.results);
+        }
+
+        let url = format!("{}/lookup", self.url);
+        let query: Vec<(String, String)> = query
+            .iter()
+            .chain(&[("format", "json")])
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let request = query.iter().cloned().chain([self.client.clone()]).collect();
+        let body = ratelimit::get(&self.limiter, url.clone(), request).await?;
+        let response: serde_json::Value = serde_json::from_str(&body)?;
+        let lookup: Lookup = serde_json::from_value(response.clone())?;
+        if lookup.status != "ok" {
+            return Err(anyhow::anyhow!(
+                "AcoustID responded {}: {}",
+                lookup.status,
+                lookup.error.message
+            ));
+        }
+        cache::store(&self.cache, SERVICE, &key, &url, &query, &response);
+        Ok(lookup.results)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Lookup {
+    status: String,
+    error: LookupError,
+    results: Vec<LookupResult>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct LookupError {
+    message: String,
+}
+
+/// An AcoustID track matching a fingerprint, how well it matched out of 1, and the recordings
+/// it's linked to.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct LookupResult {
+    id: String,
+    score: f64,
+    recordings: Vec<LookupRecording>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct LookupRecording {
+    id: String,
+}
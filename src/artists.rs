@@ -0,0 +1,757 @@
+use std::path::Path;
+
+use anyhow::anyhow;
+use clap::Subcommand;
+use sea_orm::sea_query::{Expr, Query};
+use sea_orm::*;
+use tracing::{event, instrument, Level};
+
+use crate::database;
+use crate::entities::{prelude::*, *};
+use crate::media::{self, store_artist_directory, store_audio_artist};
+use crate::musicbrainz::{self, MatchStatus};
+use crate::scan::ScanFilter;
+use crate::Config;
+
+/// Commands for reviewing and correcting how artists were matched in MusicBrainz.
+#[derive(Subcommand, Debug, Clone)]
+pub(crate) enum ArtistCommand {
+    /// List artists, with their ID and how they were matched in MusicBrainz
+    List {
+        /// Only list artists with a name containing NAME
+        name: Option<String>,
+    },
+    /// List unresolved and ambiguous artists, with the MusicBrainz artists they may be
+    Review,
+    /// Link audio files with the artist NAME to the MusicBrainz artist MBID, also after rescans
+    Pin {
+        name: String,
+        mbid: String,
+        /// Only pin the artist for audio files in this directory
+        #[arg(long)]
+        directory: Option<String>,
+    },
+    /// Move the audio files of an artist in DIRECTORY to a separate artist
+    Split {
+        artist_id: i32,
+        directory: String,
+        /// MusicBrainz ID of the separate artist
+        #[arg(long)]
+        mbid: Option<String>,
+    },
+    /// Merge duplicate artists into the first
+    Merge {
+        artist_id: i32,
+        #[arg(required = true)]
+        duplicates: Vec<i32>,
+    },
+}
+
+/// Run an artist command.
+#[instrument]
+pub(crate) async fn run_command(config: &Config, command: &ArtistCommand) -> anyhow::Result<()> {
+    event!(Level::TRACE, "run_command");
+
+    match command {
+        ArtistCommand::List { name } => list_artists(config, name.as_deref()).await,
+        ArtistCommand::Review => review_artists(config).await,
+        ArtistCommand::Pin {
+            name,
+            mbid,
+            directory,
+        } => {
+            let path = match directory {
+                Some(d) => resolve_directory(config, d)?,
+                None => "".to_string(),
+            };
+            pin_musicbrainz_id(config, name, mbid, &path).await
+        }
+        ArtistCommand::Split {
+            artist_id,
+            directory,
+            mbid,
+        } => {
+            let path = resolve_directory(config, directory)?;
+            split_artist(config, *artist_id, &path, mbid.as_deref()).await
+        }
+        ArtistCommand::Merge {
+            artist_id,
+            duplicates,
+        } => merge_artists(config, *artist_id, duplicates).await,
+    }
+}
+
+/// Print all artists, or those with a name containing `name`.
+async fn list_artists(config: &Config, name: Option<&str>) -> anyhow::Result<()> {
+    let db = database::connection(config).await;
+    let mut query = Artist::find().order_by_asc(artist::Column::Name);
+    if let Some(name) = name {
+        query = query.filter(artist::Column::Name.contains(name));
+    }
+    for artist in query.all(db).await? {
+        print_artist(config, &artist).await?;
+    }
+    Ok(())
+}
+
+/// Print unresolved artists, and artists matched by name when other MusicBrainz artists were
+/// also likely, along with the candidates found for them.
+async fn review_artists(config: &Config) -> anyhow::Result<()> {
+    let db = database::connection(config).await;
+    let artists = Artist::find()
+        .filter(
+            Condition::any()
+                .add(artist::Column::MatchStatus.eq(MatchStatus::Unresolved.as_str()))
+                .add(
+                    Condition::all()
+                        .add(artist::Column::MatchStatus.eq(MatchStatus::Matched.as_str()))
+                        .add(
+                            artist::Column::ArtistId.in_subquery(
+                                Query::select()
+                                    .column(artist_candidate::Column::ArtistId)
+                                    .from(ArtistCandidate)
+                                    .to_owned(),
+                            ),
+                        ),
+                ),
+        )
+        .order_by_asc(artist::Column::Name)
+        .all(db)
+        .await?;
+    for artist in artists {
+        print_artist(config, &artist).await?;
+        let candidates = ArtistCandidate::find()
+            .filter(artist_candidate::Column::ArtistId.eq(artist.artist_id))
+            .order_by_desc(artist_candidate::Column::Confidence)
+            .all(db)
+            .await?;
+        for candidate in candidates {
+            let disambiguation = if candidate.disambiguation_comment.is_empty() {
+                "".to_string()
+            } else {
+                format!(" ({})", candidate.disambiguation_comment)
+            };
+            println!(
+                "    {:>3}% {} {}{}",
+                candidate.confidence, candidate.musicbrainz_id, candidate.name, disambiguation
+            );
+        }
+    }
+    Ok(())
+}
+
+async fn print_artist(config: &Config, artist: &artist::Model) -> anyhow::Result<()> {
+    let db = database::connection(config).await;
+    let audio_files = AudioArtist::find()
+        .filter(audio_artist::Column::ArtistId.eq(artist.artist_id))
+        .count(db)
+        .await?;
+    let musicbrainz_id = if artist.musicbrainz_id.is_empty() {
+        "-"
+    } else {
+        &artist.musicbrainz_id
+    };
+    println!(
+        "{:>6}: {} [{}, {}, {} audio files]",
+        artist.artist_id, artist.name, artist.match_status, musicbrainz_id, audio_files
+    );
+    Ok(())
+}
+
+/// Directories are compared with the paths of audio files, so they're resolved to the path the
+/// scanner stores for them, relative to the library as configured.
+fn resolve_directory(config: &Config, directory: &str) -> anyhow::Result<String> {
+    let library = Path::new(config.library.as_deref().unwrap_or("."));
+    let path = Path::new(directory);
+    let path = if path.is_relative() && !path.exists() {
+        library.join(path)
+    } else {
+        path.to_path_buf()
+    };
+    if let Err(e) = path.canonicalize() {
+        return Err(anyhow!("invalid directory {}: {}", directory, e));
+    }
+    let path = ScanFilter::new(config)?.library_path(&path);
+    if !path.starts_with(library) {
+        return Err(anyhow!("{} is not in the library", directory));
+    }
+    Ok(path.display().to_string())
+}
+
+/// Pin the MusicBrainz artist `musicbrainz_id` to the artist name, everywhere or only under
+/// `path`.
+async fn pin_musicbrainz_id(
+    config: &Config,
+    name: &str,
+    musicbrainz_id: &str,
+    path: &str,
+) -> anyhow::Result<()> {
+    if !media::is_musicbrainz_id(musicbrainz_id) {
+        return Err(anyhow!("invalid MusicBrainz ID: {}", musicbrainz_id));
+    }
+    let db = database::connection(config).await;
+
+    let artist_id = match musicbrainz::find_artist_by_id(config, musicbrainz_id).await {
+        Some(artist_id) => artist_id,
+        None => {
+            // Correct the artist already stored for the name, unless only pinning a directory.
+            let existing = if path.is_empty() {
+                let pinned = pinned_artist_ids(config).await?;
+                Artist::find()
+                    .filter(artist::Column::Name.like(name))
+                    .filter(artist::Column::ArtistId.is_not_in(pinned))
+                    .one(db)
+                    .await?
+            } else {
+                None
+            };
+            let mut artist = match musicbrainz::fetch_artist(config, musicbrainz_id).await {
+                Ok(result) => {
+                    musicbrainz::build_artist(config, name, &result, MatchStatus::Pinned, None)
+                        .await
+                        .ok_or_else(|| anyhow!("failed to store {}", musicbrainz_id))?
+                }
+                // Without MusicBrainz, fall back to what's known from searching for the name.
+                Err(_) => candidate_artist(config, name, musicbrainz_id).await?,
+            };
+            match existing {
+                Some(existing) => {
+                    artist.artist_id = ActiveValue::Unchanged(existing.artist_id);
+                    artist.update(db).await?.artist_id
+                }
+                None => Artist::insert(artist).exec(db).await?.last_insert_id,
+            }
+        }
+    };
+
+    Artist::update_many()
+        .col_expr(
+            artist::Column::MatchStatus,
+            Expr::value(MatchStatus::Pinned.as_str()),
+        )
+        .col_expr(artist::Column::MatchScore, Expr::value(Option::<i32>::None))
+        .filter(artist::Column::ArtistId.eq(artist_id))
+        .exec(db)
+        .await?;
+    ArtistCandidate::delete_many()
+        .filter(artist_candidate::Column::ArtistId.eq(artist_id))
+        .exec(db)
+        .await?;
+
+    pin_artist(config, name, path, artist_id).await?;
+    println!(
+        "Pinned {} to artist {} ({})",
+        name, artist_id, musicbrainz_id
+    );
+    Ok(())
+}
+
+/// Build a pinned artist from a candidate found when searching for the name, or with nothing
+/// but the MusicBrainz ID if it was never a candidate.
+async fn candidate_artist(
+    config: &Config,
+    name: &str,
+    musicbrainz_id: &str,
+) -> anyhow::Result<artist::ActiveModel> {
+    let db = database::connection(config).await;
+    let candidate = ArtistCandidate::find()
+        .filter(artist_candidate::Column::MusicbrainzId.eq(musicbrainz_id))
+        .one(db)
+        .await?;
+    if candidate.is_none() {
+        event!(
+            Level::WARN,
+            "{} is not a known candidate and MusicBrainz is unavailable, pinning the ID only",
+            musicbrainz_id
+        );
+    }
+    Ok(artist::ActiveModel {
+        name: ActiveValue::Set(name.to_string()),
+        musicbrainz_name: ActiveValue::Set(
+            candidate
+                .as_ref()
+                .map(|c| c.name.to_string())
+                .unwrap_or_default(),
+        ),
+        musicbrainz_id: ActiveValue::Set(musicbrainz_id.to_string()),
+        sort_name: ActiveValue::Set(
+            candidate
+                .as_ref()
+                .map(|c| c.sort_name.to_string())
+                .unwrap_or_default(),
+        ),
+        disambiguation_comment: ActiveValue::Set(
+            candidate
+                .as_ref()
+                .map(|c| c.disambiguation_comment.to_string())
+                .unwrap_or_default(),
+        ),
+        match_status: ActiveValue::Set(MatchStatus::Pinned.as_str().to_string()),
+        ..Default::default()
+    })
+}
+
+/// Move the audio files of an artist under `path` to a separate artist, which is pinned to the
+/// same name under `path` so the split survives rescans.
+async fn split_artist(
+    config: &Config,
+    artist_id: i32,
+    path: &str,
+    musicbrainz_id: Option<&str>,
+) -> anyhow::Result<()> {
+    let db = database::connection(config).await;
+    let artist = Artist::find_by_id(artist_id)
+        .one(db)
+        .await?
+        .ok_or_else(|| anyhow!("no artist with ID {}", artist_id))?;
+
+    if let Some(musicbrainz_id) = musicbrainz_id {
+        return pin_musicbrainz_id(config, &artist.name, musicbrainz_id, path).await;
+    }
+
+    // Without a MusicBrainz ID the separate artist is unresolved, with the same candidates.
+    let new_artist = Artist::insert(artist::ActiveModel {
+        name: ActiveValue::Set(artist.name.to_string()),
+        musicbrainz_name: ActiveValue::Set("".to_string()),
+        musicbrainz_id: ActiveValue::Set("".to_string()),
+        sort_name: ActiveValue::Set("".to_string()),
+        disambiguation_comment: ActiveValue::Set("".to_string()),
+        match_status: ActiveValue::Set(MatchStatus::Unresolved.as_str().to_string()),
+        ..Default::default()
+    })
+    .exec(db)
+    .await?
+    .last_insert_id;
+    let candidates: Vec<artist_candidate::ActiveModel> = ArtistCandidate::find()
+        .filter(artist_candidate::Column::ArtistId.eq(artist_id))
+        .all(db)
+        .await?
+        .into_iter()
+        .map(|c| artist_candidate::ActiveModel {
+            artist_id: ActiveValue::Set(new_artist),
+            musicbrainz_id: ActiveValue::Set(c.musicbrainz_id),
+            name: ActiveValue::Set(c.name),
+            sort_name: ActiveValue::Set(c.sort_name),
+            disambiguation_comment: ActiveValue::Set(c.disambiguation_comment),
+            search_score: ActiveValue::Set(c.search_score),
+            confidence: ActiveValue::Set(c.confidence),
+            ..Default::default()
+        })
+        .collect();
+    if !candidates.is_empty() {
+        ArtistCandidate::insert_many(candidates).exec(db).await?;
+    }
+
+    pin_artist(config, &artist.name, path, new_artist).await?;
+    println!(
+        "Split {} in {} from artist {} to artist {}",
+        artist.name, path, artist_id, new_artist
+    );
+    Ok(())
+}
+
+/// Merge duplicate artists into one, moving all their audio files, directories, albums, images
+/// and pins. The names of the duplicates are pinned to the artist, so rescans don't recreate
+/// them.
+async fn merge_artists(config: &Config, artist_id: i32, duplicates: &[i32]) -> anyhow::Result<()> {
+    let db = database::connection(config).await;
+    let artist = Artist::find_by_id(artist_id)
+        .one(db)
+        .await?
+        .ok_or_else(|| anyhow!("no artist with ID {}", artist_id))?;
+    let duplicates: Vec<i32> = duplicates
+        .iter()
+        .copied()
+        .filter(|d| *d != artist_id)
+        .collect();
+    // Names are matched without case, as artists are found by name.
+    let mut names: Vec<String> = Artist::find()
+        .filter(artist::Column::ArtistId.is_in(duplicates.clone()))
+        .all(db)
+        .await?
+        .into_iter()
+        .map(|a| a.name)
+        .filter(|n| n.to_lowercase() != artist.name.to_lowercase())
+        .collect();
+    names.sort_by_key(|n| n.to_lowercase());
+    names.dedup_by_key(|n| n.to_lowercase());
+
+    let txn = db.begin().await?;
+
+    // Links the artist already has are dropped from the duplicates, the rest are moved.
+    AudioArtist::delete_many()
+        .filter(audio_artist::Column::ArtistId.is_in(duplicates.clone()))
+        .filter(
+            audio_artist::Column::AudioId.in_subquery(
+                Query::select()
+                    .column(audio_artist::Column::AudioId)
+                    .from(AudioArtist)
+                    .and_where(audio_artist::Column::ArtistId.eq(artist_id))
+                    .to_owned(),
+            ),
+        )
+        .exec(&txn)
+        .await?;
+    AudioArtist::update_many()
+        .col_expr(audio_artist::Column::ArtistId, Expr::value(artist_id))
+        .filter(audio_artist::Column::ArtistId.is_in(duplicates.clone()))
+        .exec(&txn)
+        .await?;
+    ArtistImage::delete_many()
+        .filter(artist_image::Column::ArtistId.is_in(duplicates.clone()))
+        .filter(
+            artist_image::Column::ImageId.in_subquery(
+                Query::select()
+                    .column(artist_image::Column::ImageId)
+                    .from(ArtistImage)
+                    .and_where(artist_image::Column::ArtistId.eq(artist_id))
+                    .to_owned(),
+            ),
+        )
+        .exec(&txn)
+        .await?;
+    ArtistImage::update_many()
+        .col_expr(artist_image::Column::ArtistId, Expr::value(artist_id))
+        .filter(artist_image::Column::ArtistId.is_in(duplicates.clone()))
+        .exec(&txn)
+        .await?;
+    Album::update_many()
+        .col_expr(album::Column::ArtistId, Expr::value(artist_id))
+        .filter(album::Column::ArtistId.is_in(duplicates.clone()))
+        .exec(&txn)
+        .await?;
+    ArtistPin::update_many()
+        .col_expr(artist_pin::Column::ArtistId, Expr::value(artist_id))
+        .filter(artist_pin::Column::ArtistId.is_in(duplicates.clone()))
+        .exec(&txn)
+        .await?;
+    for name in &names {
+        ArtistPin::delete_many()
+            .filter(artist_pin::Column::Name.like(name))
+            .filter(artist_pin::Column::Path.eq(""))
+            .exec(&txn)
+            .await?;
+        ArtistPin::insert(artist_pin::ActiveModel {
+            created: ActiveValue::Set(chrono::Utc::now().naive_utc()),
+            name: ActiveValue::Set(name.to_string()),
+            path: ActiveValue::Set("".to_string()),
+            artist_id: ActiveValue::Set(artist_id),
+            ..Default::default()
+        })
+        .exec(&txn)
+        .await?;
+    }
+    // Directories are rebuilt from the audio files below.
+    ArtistDirectory::delete_many()
+        .filter(artist_directory::Column::ArtistId.is_in(duplicates.clone()))
+        .exec(&txn)
+        .await?;
+    ArtistCandidate::delete_many()
+        .filter(artist_candidate::Column::ArtistId.is_in(duplicates.clone()))
+        .exec(&txn)
+        .await?;
+    Artist::delete_many()
+        .filter(artist::Column::ArtistId.is_in(duplicates.clone()))
+        .exec(&txn)
+        .await?;
+
+    txn.commit().await?;
+
+    rebuild_artist_directories(config, &[artist_id]).await?;
+    println!(
+        "Merged {} artists into artist {}",
+        duplicates.len(),
+        artist_id
+    );
+    Ok(())
+}
+
+/// Pin an artist to a name, everywhere or only under `path`, and relink the audio files and
+/// albums it applies to.
+async fn pin_artist(config: &Config, name: &str, path: &str, artist_id: i32) -> anyhow::Result<()> {
+    let db = database::connection(config).await;
+    ArtistPin::delete_many()
+        .filter(artist_pin::Column::Name.like(name))
+        .filter(artist_pin::Column::Path.eq(path))
+        .exec(db)
+        .await?;
+    ArtistPin::insert(artist_pin::ActiveModel {
+        created: ActiveValue::Set(chrono::Utc::now().naive_utc()),
+        name: ActiveValue::Set(name.to_string()),
+        path: ActiveValue::Set(path.to_string()),
+        artist_id: ActiveValue::Set(artist_id),
+        ..Default::default()
+    })
+    .exec(db)
+    .await?;
+
+    let pins = load_pins(config, name).await;
+    let named: Vec<i32> = Artist::find()
+        .filter(artist::Column::Name.like(name))
+        .all(db)
+        .await?
+        .into_iter()
+        .map(|a| a.artist_id)
+        .collect();
+
+    // Audio files with the name as their artist, which this pin is the closest match for.
+    let audio_files = Audio::find()
+        .filter(
+            audio::Column::AudioId.in_subquery(
+                Query::select()
+                    .column(audio_tag::Column::AudioId)
+                    .from(AudioTag)
+                    .and_where(audio_tag::Column::Name.eq("Artist"))
+                    .and_where(audio_tag::Column::Value.like(name))
+                    .to_owned(),
+            ),
+        )
+        .all(db)
+        .await?;
+    for audio in audio_files {
+        if pinned_artist(&pins, &audio.path) != Some(artist_id) {
+            continue;
+        }
+        AudioArtist::delete_many()
+            .filter(audio_artist::Column::AudioId.eq(audio.audio_id))
+            .filter(audio_artist::Column::ArtistId.is_in(named.clone()))
+            .exec(db)
+            .await?;
+        store_audio_artist(config, audio.audio_id, artist_id).await?;
+    }
+
+    let albums = Album::find()
+        .filter(
+            Condition::any()
+                .add(album::Column::ArtistName.like(name))
+                .add(album::Column::ArtistId.is_in(named.clone())),
+        )
+        .all(db)
+        .await?;
+    for album in albums {
+        if pinned_artist(&pins, &album.path) != Some(artist_id) {
+            continue;
+        }
+        let mut album: album::ActiveModel = album.into();
+        album.artist_id = ActiveValue::Set(Some(artist_id));
+        album.updated = ActiveValue::Set(chrono::Utc::now().naive_utc());
+        album.update(db).await?;
+    }
+
+    let mut affected = named;
+    if !affected.contains(&artist_id) {
+        affected.push(artist_id);
+    }
+    rebuild_artist_directories(config, &affected).await
+}
+
+/// Rebuild the directories of artists from the audio files linked to them.
+async fn rebuild_artist_directories(config: &Config, artist_ids: &[i32]) -> anyhow::Result<()> {
+    let db = database::connection(config).await;
+    ArtistDirectory::delete_many()
+        .filter(artist_directory::Column::ArtistId.is_in(artist_ids.to_vec()))
+        .exec(db)
+        .await?;
+    let links = AudioArtist::find()
+        .filter(audio_artist::Column::ArtistId.is_in(artist_ids.to_vec()))
+        .all(db)
+        .await?;
+    for link in links {
+        store_artist_directory(config, link.audio_id, link.artist_id).await?;
+    }
+    Ok(())
+}
+
+/// Artists that are pinned to a name, which must not be corrected in place.
+async fn pinned_artist_ids(config: &Config) -> Result<Vec<i32>, DbErr> {
+    let db = database::connection(config).await;
+    Ok(ArtistPin::find()
+        .all(db)
+        .await?
+        .into_iter()
+        .map(|p| p.artist_id)
+        .collect())
+}
+
+/// Load all pins for an artist name.
+pub(crate) async fn load_pins(config: &Config, artist_name: &str) -> Vec<artist_pin::Model> {
+    let db = database::connection(config).await;
+    match ArtistPin::find()
+        .filter(artist_pin::Column::Name.like(artist_name))
+        .all(db)
+        .await
+    {
+        Ok(p) => p,
+        Err(e) => {
+            event!(Level::WARN, "ArtistPin::find() failure: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Find the artist pinned for a path. Pins for a directory take precedence over pins for the
+/// name everywhere, and the deepest directory wins.
+pub(crate) fn pinned_artist(pins: &[artist_pin::Model], path: &str) -> Option<i32> {
+    pins.iter()
+        .filter(|p| p.path.is_empty() || Path::new(path).starts_with(&p.path))
+        .max_by_key(|p| p.path.len())
+        .map(|p| p.artist_id)
+}
+
+/// Find the artist pinned to an artist name for an audio file.
+pub(crate) async fn find_pinned_artist(
+    config: &Config,
+    artist_name: &str,
+    audio_id: i32,
+) -> Option<i32> {
+    let pins = load_pins(config, artist_name).await;
+    if pins.is_empty() {
+        return None;
+    }
+    let db = database::connection(config).await;
+    match Audio::find_by_id(audio_id).one(db).await {
+        Ok(Some(audio)) => pinned_artist(&pins, &audio.path),
+        Ok(None) => None,
+        Err(e) => {
+            event!(Level::WARN, "Audio::find_by_id() pin failure: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scan;
+    use crate::testing::{self, TestLibrary};
+
+    const FIRST_ID: &str = "5b11f4ce-a62d-471e-81fc-a69a8278c7da";
+    const SECOND_ID: &str = "9282c8b4-ca0b-4c6b-b7e3-4f7762dfc4d6";
+
+    /// Scan audio files tagged with an artist name, all linked to an artist with that name.
+    async fn scan_tagged(test: &TestLibrary, name: &str, files: &[&str]) -> i32 {
+        let config = &test.config;
+        let db = database::connection(config).await;
+        for file in files {
+            testing::write_wav(
+                &test.path(file),
+                44100,
+                &testing::sine(44100, 440.0, -6.0, 1.0),
+            );
+        }
+        scan::scan_media_files(config).await;
+        let artist_id = insert_artist(config, name, FIRST_ID).await;
+        for audio in Audio::find().all(db).await.unwrap() {
+            AudioTag::insert(audio_tag::ActiveModel {
+                audio_id: ActiveValue::Set(audio.audio_id),
+                name: ActiveValue::Set("Artist".to_string()),
+                key: ActiveValue::Set("ARTIST".to_string()),
+                value: ActiveValue::Set(name.to_string()),
+                position: ActiveValue::Set(0),
+                ..Default::default()
+            })
+            .exec(db)
+            .await
+            .unwrap();
+            store_audio_artist(config, audio.audio_id, artist_id)
+                .await
+                .unwrap();
+        }
+        artist_id
+    }
+
+    async fn insert_artist(config: &Config, name: &str, musicbrainz_id: &str) -> i32 {
+        let db = database::connection(config).await;
+        Artist::insert(artist::ActiveModel {
+            name: ActiveValue::Set(name.to_string()),
+            musicbrainz_name: ActiveValue::Set(name.to_string()),
+            musicbrainz_id: ActiveValue::Set(musicbrainz_id.to_string()),
+            sort_name: ActiveValue::Set(name.to_string()),
+            disambiguation_comment: ActiveValue::Set("".to_string()),
+            match_status: ActiveValue::Set(MatchStatus::Matched.as_str().to_string()),
+            ..Default::default()
+        })
+        .exec(db)
+        .await
+        .unwrap()
+        .last_insert_id
+    }
+
+    /// The artists linked to the audio file with a name.
+    async fn audio_artists(config: &Config, name: &str) -> Vec<i32> {
+        let db = database::connection(config).await;
+        AudioArtist::find()
+            .inner_join(Audio)
+            .filter(audio::Column::Name.eq(name))
+            .all(db)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|a| a.artist_id)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn pinned_directory_relinks_its_audio_files() {
+        let mut test = TestLibrary::new(&[]).await;
+        // Configure the library relative to the working directory, as with the default
+        // "./music", so audio files aren't stored by their canonical path.
+        let working = std::env::current_dir().unwrap();
+        let relative = Path::new(&"../".repeat(working.components().count() - 1))
+            .join(test.library.strip_prefix("/").unwrap());
+        test.config.library = Some(relative.display().to_string());
+        let config = &test.config;
+        let first = scan_tagged(&test, "Nirvana", &["A/one.wav", "B/two.wav", "C/three.wav"]).await;
+        let second = insert_artist(config, "Nirvana", SECOND_ID).await;
+
+        // Directories are found relative to the library, and by their canonical path.
+        let pin = |directory: String| ArtistCommand::Pin {
+            name: "Nirvana".to_string(),
+            mbid: SECOND_ID.to_string(),
+            directory: Some(directory),
+        };
+        run_command(config, &pin("B".to_string())).await.unwrap();
+        let canonical = test.library.join("C").canonicalize().unwrap();
+        run_command(config, &pin(canonical.display().to_string()))
+            .await
+            .unwrap();
+
+        assert_eq!(audio_artists(config, "one.wav").await, [first]);
+        assert_eq!(audio_artists(config, "two.wav").await, [second]);
+        assert_eq!(audio_artists(config, "three.wav").await, [second]);
+        let db = database::connection(config).await;
+        let pins = ArtistPin::find().all(db).await.unwrap();
+        assert!(pins
+            .iter()
+            .all(|p| Path::new(&p.path).starts_with(&relative)));
+    }
+
+    #[tokio::test]
+    async fn merged_names_stay_merged_after_rescanning() {
+        let test = TestLibrary::new(&[]).await;
+        let config = &test.config;
+        let db = database::connection(config).await;
+        let duplicate = scan_tagged(&test, "Nirvana (US)", &["A/one.wav"]).await;
+        let artist_id = insert_artist(config, "Nirvana", SECOND_ID).await;
+
+        let merge = ArtistCommand::Merge {
+            artist_id,
+            duplicates: vec![duplicate],
+        };
+        run_command(config, &merge).await.unwrap();
+        assert_eq!(audio_artists(config, "one.wav").await, [artist_id]);
+
+        // Linking the file by its tags again, as when it's rescanned, finds the merged artist.
+        let audio = Audio::find().one(db).await.unwrap().unwrap();
+        AudioArtist::delete_many().exec(db).await.unwrap();
+        let tags = vec![media::Tag {
+            name: "Artist".to_string(),
+            key: "ARTIST".to_string(),
+            value: "Nirvana (US)".to_string(),
+            position: 0,
+        }];
+        media::link_audio_artists(config, audio.audio_id, &tags).await;
+        assert_eq!(audio_artists(config, "one.wav").await, [artist_id]);
+        assert_eq!(Artist::find().count(db).await.unwrap(), 1);
+    }
+}
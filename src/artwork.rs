@@ -0,0 +1,577 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use file_format::FileFormat;
+use sea_orm::sea_query::Query;
+use sea_orm::*;
+use symphonia::core::meta::{StandardVisualKey, Visual};
+use tracing::{event, instrument, Level};
+
+use crate::database;
+use crate::entities::{prelude::*, *};
+use crate::media::{self, MediaType, Scope};
+use crate::scan::ScanFilter;
+use crate::utils;
+use crate::Config;
+
+// Links between images and albums or artists are inserted this many at a time.
+const IMAGE_BATCH_SIZE: usize = 500;
+
+// Pictures embedded in audio files are extracted to this directory, unless `artwork_cache`
+// is set.
+const DEFAULT_ARTWORK_CACHE: &str = "./artwork";
+
+/// What an image most likely shows, guessed from its file name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ImageKind {
+    /// The front cover of an album, ie "cover.jpg" or "folder.png".
+    Front,
+    /// The back cover of an album, ie "back.jpg".
+    Back,
+    /// A photo of the artist, ie "artist.jpg".
+    Artist,
+    /// Everything else, such as scans of the booklet or disc.
+    Other,
+}
+
+impl ImageKind {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            ImageKind::Front => "front",
+            ImageKind::Back => "back",
+            ImageKind::Artist => "artist",
+            ImageKind::Other => "other",
+        }
+    }
+}
+
+/// Classify an image from its file name.
+pub(crate) fn classify_image(name: &str) -> ImageKind {
+    let stem = Path::new(name)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or(name)
+        .to_lowercase();
+    // Check for back covers first, as they're often named "back cover".
+    if ["back", "inlay", "tray"].iter().any(|b| stem.contains(b)) {
+        ImageKind::Back
+    } else if ["artist", "band", "performer"]
+        .iter()
+        .any(|a| stem.contains(a))
+    {
+        ImageKind::Artist
+    } else if ["cover", "front", "folder", "albumart", "thumb"]
+        .iter()
+        .any(|f| stem.contains(f))
+    {
+        ImageKind::Front
+    } else {
+        ImageKind::Other
+    }
+}
+
+/// Classify a picture embedded in an audio file from how the file says it's used.
+fn classify_visual(usage: Option<StandardVisualKey>) -> ImageKind {
+    match usage {
+        Some(StandardVisualKey::FrontCover) => ImageKind::Front,
+        Some(StandardVisualKey::BackCover) => ImageKind::Back,
+        Some(
+            StandardVisualKey::LeadArtistPerformerSoloist
+            | StandardVisualKey::ArtistPerformer
+            | StandardVisualKey::BandOrchestra
+            | StandardVisualKey::BandArtistLogo,
+        ) => ImageKind::Artist,
+        _ => ImageKind::Other,
+    }
+}
+
+/// Rank how likely an image is to be an album's front cover, lower is better. Back covers and
+/// artist photos are never used as the front cover. Images in the album's directory are
+/// preferred over pictures embedded in its audio files.
+fn cover_rank(image: &image::Model) -> Option<u8> {
+    if image.embedded {
+        return match image.kind.as_str() {
+            "front" => Some(5),
+            "other" => Some(7),
+            _ => None,
+        };
+    }
+    let stem = image.name.to_lowercase();
+    match classify_image(&image.name) {
+        ImageKind::Front if stem.starts_with("cover") => Some(0),
+        ImageKind::Front if stem.starts_with("front") => Some(1),
+        ImageKind::Front if stem.starts_with("folder") => Some(2),
+        // Windows Media Player also saves small copies, ie "AlbumArtSmall.jpg".
+        ImageKind::Front if !stem.contains("small") && !stem.contains("thumb") => Some(3),
+        ImageKind::Front => Some(4),
+        ImageKind::Other => Some(6),
+        ImageKind::Back | ImageKind::Artist => None,
+    }
+}
+
+/// The absolute path of the artwork cache. It's only created once a picture is extracted to it,
+/// so the path is resolved from the closest directory that exists, as it will be once created.
+pub(crate) fn artwork_cache(config: &Config) -> Option<PathBuf> {
+    let cache = PathBuf::from(
+        config
+            .artwork_cache
+            .as_deref()
+            .unwrap_or(DEFAULT_ARTWORK_CACHE),
+    );
+    let cache = match std::path::absolute(&cache) {
+        Ok(c) => c,
+        Err(e) => {
+            event!(Level::WARN, "std::path::absolute() failure: {}", e);
+            return None;
+        }
+    };
+    let existing = cache.ancestors().find(|a| a.exists())?;
+    match existing.canonicalize() {
+        Ok(c) => Some(c.join(cache.strip_prefix(existing).ok()?)),
+        Err(e) => {
+            event!(Level::WARN, "cache.canonicalize() failure: {}", e);
+            None
+        }
+    }
+}
+
+/// Extract a picture embedded in an audio file to the artwork cache. Pictures are named after
+/// a hash of their contents, so each is only stored once no matter how many audio files it's
+/// embedded in.
+pub(crate) fn extract_visual(cache: &Path, visual: &Visual) -> Option<image::ActiveModel> {
+    // Don't trust the media type set in the audio file, detect it from the picture itself.
+    let format = FileFormat::from_bytes(&visual.data);
+    if !matches!(
+        MediaType::from_str(format.media_type()),
+        Ok(MediaType::Image)
+    ) {
+        event!(
+            Level::DEBUG,
+            "skipping embedded picture ({})",
+            format.media_type()
+        );
+        return None;
+    }
+    let hash = blake3::hash(&visual.data).to_hex().to_string();
+    let name = format!("{}.{}", hash, format.extension());
+    let path = cache.join(&name);
+    if !path.exists() {
+        if let Err(e) = std::fs::create_dir_all(cache) {
+            event!(
+                Level::WARN,
+                "failed to create artwork cache {}: {}",
+                cache.display(),
+                e
+            );
+            return None;
+        }
+        if let Err(e) = std::fs::write(&path, &visual.data) {
+            event!(Level::WARN, "failed to write {}: {}", path.display(), e);
+            return None;
+        }
+    }
+    Some(image::ActiveModel {
+        uri: ActiveValue::Set(utils::file_uri(&path)?),
+        path: ActiveValue::Set(cache.display().to_string()),
+        name: ActiveValue::Set(name),
+        extension: ActiveValue::Set(format.extension().to_string()),
+        format: ActiveValue::Set(format.media_type().to_string()),
+        kind: ActiveValue::Set(classify_visual(visual.usage).as_str().to_string()),
+        size: ActiveValue::Set(visual.data.len() as i64),
+        hash: ActiveValue::Set(Some(hash)),
+        embedded: ActiveValue::Set(true),
+        ..Default::default()
+    })
+}
+
+/// Build an image found while scanning the library, ready to be written to the database.
+pub(crate) fn build_image(
+    path: &Path,
+    metadata: &Metadata,
+    uri: String,
+    format: &str,
+) -> image::ActiveModel {
+    let name = match path.file_name() {
+        Some(f) => f.to_str().unwrap_or("").to_string(),
+        None => {
+            event!(Level::WARN, "path.file_name() returned nothing");
+            "".to_string()
+        }
+    };
+    let parent = match path.parent() {
+        Some(p) => p.display().to_string(),
+        None => {
+            event!(Level::WARN, "path.parent() returned none");
+            "".to_string()
+        }
+    };
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("")
+        .to_string();
+    image::ActiveModel {
+        uri: ActiveValue::Set(uri),
+        path: ActiveValue::Set(parent),
+        kind: ActiveValue::Set(classify_image(&name).as_str().to_string()),
+        name: ActiveValue::Set(name),
+        extension: ActiveValue::Set(extension),
+        format: ActiveValue::Set(format.to_string()),
+        size: ActiveValue::Set(metadata.len() as i64),
+        hash: ActiveValue::Set(None),
+        embedded: ActiveValue::Set(false),
+        ..Default::default()
+    }
+}
+
+/// Associate images with the albums in the directory they're in, or in the directory above a
+/// disc subdirectory. Artist photos are also associated with the album's artist, as are all
+/// images in a directory that only contains albums by a single artist. Pictures embedded in
+/// audio files are associated with the albums of those audio files. Only albums in scope, and
+/// the other albums in their artist directories, are linked.
+#[instrument]
+pub(crate) async fn link_images(config: &Config, scope: &Scope) {
+    event!(Level::TRACE, "link_images");
+    let db = database::connection(config).await;
+    let Some(scope) = artist_scope(config, scope).await else {
+        return;
+    };
+    let images = match Image::find()
+        .filter(scope.paths(image::Column::Path))
+        .all(db)
+        .await
+    {
+        Ok(i) => i,
+        Err(e) => {
+            event!(Level::WARN, "Image::find() failure: {}", e);
+            return;
+        }
+    };
+    let albums = match Album::find()
+        .filter(scope.paths(album::Column::Path))
+        .all(db)
+        .await
+    {
+        Ok(a) => a,
+        Err(e) => {
+            event!(Level::WARN, "Album::find() failure: {}", e);
+            return;
+        }
+    };
+    // Only the links of the albums and images found are compared, all the images of those
+    // albums are in scope.
+    let (album_ids, image_ids): (Option<Vec<i32>>, Option<Vec<i32>>) = match scope {
+        Scope::Library => (None, None),
+        Scope::Directories(_) => (
+            Some(albums.iter().map(|a| a.album_id).collect()),
+            Some(images.iter().map(|i| i.image_id).collect()),
+        ),
+    };
+
+    // More than one album can be in the same directory.
+    let mut album_paths: HashMap<&str, Vec<&album::Model>> = HashMap::new();
+    // The artists of the albums in each directory containing albums, ie an artist directory.
+    let mut artist_paths: HashMap<String, Vec<Option<i32>>> = HashMap::new();
+    for album in &albums {
+        album_paths.entry(&album.path).or_default().push(album);
+        if let Some(parent) = Path::new(&album.path).parent() {
+            artist_paths
+                .entry(parent.display().to_string())
+                .or_default()
+                .push(album.artist_id);
+        }
+    }
+
+    let mut album_images = HashSet::new();
+    let mut artist_images = HashSet::new();
+    for image in images.iter().filter(|i| !i.embedded) {
+        let (path, _) = media::fold_disc_directory(&image.path);
+        if let Some(albums) = album_paths.get(path.as_str()) {
+            for album in albums {
+                album_images.insert((album.album_id, image.image_id));
+                if image.kind == ImageKind::Artist.as_str() {
+                    if let Some(artist_id) = album.artist_id {
+                        artist_images.insert((artist_id, image.image_id));
+                    }
+                }
+            }
+        } else if let Some(artist_ids) = artist_paths.get_mut(&image.path) {
+            artist_ids.sort_unstable();
+            artist_ids.dedup();
+            if let [Some(artist_id)] = artist_ids[..] {
+                artist_images.insert((artist_id, image.image_id));
+            }
+        }
+    }
+
+    match AudioImage::find()
+        .select_only()
+        .column(album_audio::Column::AlbumId)
+        .column(audio_image::Column::ImageId)
+        .join(JoinType::InnerJoin, audio_image::Relation::Audio.def())
+        .join(JoinType::InnerJoin, audio::Relation::AlbumAudio.def())
+        .apply_if(album_ids.clone(), |query, ids| {
+            query.filter(album_audio::Column::AlbumId.is_in(ids))
+        })
+        .distinct()
+        .into_tuple::<(i32, i32)>()
+        .all(db)
+        .await
+    {
+        Ok(embedded) => album_images.extend(embedded),
+        Err(e) => {
+            event!(Level::WARN, "AudioImage::find() failure: {}", e);
+            return;
+        }
+    }
+
+    // Remove associations that are no longer valid, for example if the album was regrouped,
+    // and add the missing ones.
+    match AlbumImage::find()
+        .apply_if(album_ids, |query, ids| {
+            query.filter(album_image::Column::AlbumId.is_in(ids))
+        })
+        .all(db)
+        .await
+    {
+        Ok(existing) => {
+            for link in existing {
+                if !album_images.remove(&(link.album_id, link.image_id)) {
+                    if let Err(e) = AlbumImage::delete_by_id(link.album_image_id).exec(db).await {
+                        event!(Level::WARN, "AlbumImage::delete_by_id() failure: {}", e);
+                    }
+                }
+            }
+        }
+        Err(e) => {
+            event!(Level::WARN, "AlbumImage::find() failure: {}", e);
+            return;
+        }
+    }
+    let album_images: Vec<album_image::ActiveModel> = album_images
+        .into_iter()
+        .map(|(album_id, image_id)| album_image::ActiveModel {
+            album_id: ActiveValue::Set(album_id),
+            image_id: ActiveValue::Set(image_id),
+            ..Default::default()
+        })
+        .collect();
+    for batch in album_images.chunks(IMAGE_BATCH_SIZE) {
+        if let Err(e) = AlbumImage::insert_many(batch.to_vec()).exec(db).await {
+            event!(Level::WARN, "AlbumImage::insert_many() failure: {}", e);
+        }
+    }
+
+    match ArtistImage::find()
+        .apply_if(image_ids, |query, ids| {
+            query.filter(artist_image::Column::ImageId.is_in(ids))
+        })
+        .all(db)
+        .await
+    {
+        Ok(existing) => {
+            for link in existing {
+                if !artist_images.remove(&(link.artist_id, link.image_id)) {
+                    if let Err(e) = ArtistImage::delete_by_id(link.artist_image_id)
+                        .exec(db)
+                        .await
+                    {
+                        event!(Level::WARN, "ArtistImage::delete_by_id() failure: {}", e);
+                    }
+                }
+            }
+        }
+        Err(e) => {
+            event!(Level::WARN, "ArtistImage::find() failure: {}", e);
+            return;
+        }
+    }
+    let artist_images: Vec<artist_image::ActiveModel> = artist_images
+        .into_iter()
+        .map(|(artist_id, image_id)| artist_image::ActiveModel {
+            artist_id: ActiveValue::Set(artist_id),
+            image_id: ActiveValue::Set(image_id),
+            ..Default::default()
+        })
+        .collect();
+    for batch in artist_images.chunks(IMAGE_BATCH_SIZE) {
+        if let Err(e) = ArtistImage::insert_many(batch.to_vec()).exec(db).await {
+            event!(Level::WARN, "ArtistImage::insert_many() failure: {}", e);
+        }
+    }
+}
+
+/// Widen a scope to the artist directories of the albums in it, as artist photos are linked by
+/// all the albums in an artist directory. Albums directly in the library aren't in an artist
+/// directory.
+async fn artist_scope(config: &Config, scope: &Scope) -> Option<Scope> {
+    if let Scope::Library = scope {
+        return Some(Scope::Library);
+    }
+    let db = database::connection(config).await;
+    let albums = match Album::find()
+        .filter(
+            Condition::any()
+                .add(scope.albums(album::Column::AlbumId))
+                .add(scope.paths(album::Column::Path)),
+        )
+        .all(db)
+        .await
+    {
+        Ok(a) => a,
+        Err(e) => {
+            event!(Level::WARN, "Album::find() in scope failure: {}", e);
+            return None;
+        }
+    };
+    let library = PathBuf::from(config.library.as_ref().expect("library must exist"));
+    let directories: Vec<PathBuf> = albums
+        .iter()
+        .map(|album| {
+            let path = Path::new(&album.path);
+            match path.parent() {
+                Some(parent) if parent != library => parent.to_path_buf(),
+                _ => path.to_path_buf(),
+            }
+        })
+        .collect();
+    Some(Scope::changed(&library, &directories))
+}
+
+/// Find the best front cover for an album. Images named like a front cover are preferred,
+/// followed by the largest image.
+#[instrument]
+pub(crate) async fn best_album_cover(config: &Config, album_id: i32) -> Option<image::Model> {
+    event!(Level::TRACE, "best_album_cover");
+    let db = database::connection(config).await;
+    let images = match Image::find()
+        .inner_join(AlbumImage)
+        .filter(album_image::Column::AlbumId.eq(album_id))
+        .all(db)
+        .await
+    {
+        Ok(i) => i,
+        Err(e) => {
+            event!(Level::WARN, "Image::find() album cover failure: {}", e);
+            return None;
+        }
+    };
+    images
+        .into_iter()
+        .filter_map(|image| cover_rank(&image).map(|rank| (rank, image)))
+        .min_by_key(|(rank, image)| (*rank, Reverse(image.size), image.name.clone()))
+        .map(|(_, image)| image)
+}
+
+/// Print the best front cover of each album with a name containing `album`.
+pub(crate) async fn print_album_covers(config: &Config, album: &str) {
+    let albums = {
+        let db = database::connection(config).await;
+        match Album::find()
+            .filter(album::Column::Name.contains(album))
+            .order_by_asc(album::Column::Name)
+            .all(db)
+            .await
+        {
+            Ok(a) => a,
+            Err(e) => {
+                event!(Level::WARN, "Album::find() failure: {}", e);
+                return;
+            }
+        }
+    };
+    for album in albums {
+        match best_album_cover(config, album.album_id).await {
+            Some(image) => println!(
+                "{}: {}",
+                album.name,
+                Path::new(&image.path).join(&image.name).display()
+            ),
+            None => println!("{}: no cover found", album.name),
+        }
+    }
+}
+
+/// Remove images in scope from the database that no longer exist on disk, or that are no longer
+/// scanned because they're excluded by the scan filter. Pictures extracted to the artwork
+/// cache are removed once they're no longer embedded in any audio file.
+pub(crate) async fn remove_missing_images(config: &Config, filter: &ScanFilter, scope: &Scope) {
+    event!(Level::TRACE, "remove_missing_images");
+    let db = database::connection(config).await;
+    let images = match Image::find()
+        .filter(
+            Condition::any().add(scope.paths(image::Column::Path)).add(
+                Condition::all().add(image::Column::Embedded.eq(true)).add(
+                    image::Column::ImageId.not_in_subquery(
+                        Query::select()
+                            .column(audio_image::Column::ImageId)
+                            .from(AudioImage)
+                            .to_owned(),
+                    ),
+                ),
+            ),
+        )
+        .all(db)
+        .await
+    {
+        Ok(i) => i,
+        Err(e) => {
+            event!(Level::WARN, "Image::find() missing failure: {}", e);
+            return;
+        }
+    };
+    for image in images {
+        let path = Path::new(&image.path).join(&image.name);
+        if image.embedded {
+            match AudioImage::find()
+                .filter(audio_image::Column::ImageId.eq(image.image_id))
+                .count(db)
+                .await
+            {
+                Ok(0) => (),
+                Ok(_) => continue,
+                Err(e) => {
+                    event!(Level::WARN, "AudioImage::find() count failure: {}", e);
+                    continue;
+                }
+            }
+            event!(Level::INFO, "Embedded image removed: {}", image.uri);
+            if let Err(e) = std::fs::remove_file(&path) {
+                event!(Level::WARN, "failed to remove {}: {}", path.display(), e);
+            }
+        } else if !path.exists() {
+            event!(Level::INFO, "Image removed: {}", image.uri);
+        } else if !filter.allows(&path, false) {
+            event!(Level::INFO, "Image excluded: {}", image.uri);
+        } else {
+            continue;
+        }
+        if let Err(e) = AlbumImage::delete_many()
+            .filter(album_image::Column::ImageId.eq(image.image_id))
+            .exec(db)
+            .await
+        {
+            event!(Level::WARN, "AlbumImage::delete_many() failure: {}", e);
+            continue;
+        }
+        if let Err(e) = ArtistImage::delete_many()
+            .filter(artist_image::Column::ImageId.eq(image.image_id))
+            .exec(db)
+            .await
+        {
+            event!(Level::WARN, "ArtistImage::delete_many() failure: {}", e);
+            continue;
+        }
+        if let Err(e) = Image::delete_by_id(image.image_id).exec(db).await {
+            event!(
+                Level::WARN,
+                "failed to delete image id {}: {}",
+                image.image_id,
+                e
+            );
+        }
+    }
+}
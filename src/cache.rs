@@ -0,0 +1,241 @@
+use std::path::{Path, PathBuf};
+
+use chrono::NaiveDateTime;
+use clap::Subcommand;
+use serde::{Deserialize, Serialize};
+use tracing::{event, instrument, Level};
+use walkdir::WalkDir;
+
+use crate::acoustid;
+use crate::musicbrainz;
+use crate::ratelimit;
+use crate::Config;
+
+// Responses from metadata services are stored in this directory, unless `metadata_cache` is
+// configured. It's kept outside the database, so it survives rebuilding the database.
+const DEFAULT_METADATA_CACHE: &str = "./metadata";
+
+// Cached responses older than this many days are fetched again by `ria cache refresh`.
+const DEFAULT_METADATA_CACHE_TTL: u64 = 30;
+
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// Commands for managing cached responses from metadata services.
+#[derive(Subcommand, Debug, Clone)]
+pub(crate) enum CacheCommand {
+    /// Fetch cached responses again once they're older than the cache TTL
+    Refresh {
+        /// Refresh responses older than this many days, defaults to metadata_cache_ttl
+        #[arg(long)]
+        older_than: Option<u64>,
+    },
+}
+
+/// A response from a metadata service, stored as JSON named after a hash of its key.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct CachedResponse {
+    pub(crate) service: String,
+    /// The request made, ie "artist/<MBID>?inc=aliases" or "artist?query=...", without
+    /// anything that doesn't change the response.
+    pub(crate) key: String,
+    pub(crate) url: String,
+    pub(crate) query: Vec<(String, String)>,
+    /// When the response was fetched, in UTC.
+    pub(crate) fetched_at: String,
+    pub(crate) response: serde_json::Value,
+}
+
+/// Run a cache command.
+#[instrument]
+pub(crate) async fn run_command(config: &Config, command: &CacheCommand) -> anyhow::Result<()> {
+    event!(Level::TRACE, "run_command");
+
+    match command {
+        CacheCommand::Refresh { older_than } => {
+            let days = older_than
+                .or(config.metadata_cache_ttl)
+                .unwrap_or(DEFAULT_METADATA_CACHE_TTL);
+            refresh_cache(config, days).await
+        }
+    }
+}
+
+/// Build the key of a request, from its path and the query parameters that change the response.
+pub(crate) fn key(path: &str, query: &[(&str, &str)]) -> String {
+    if query.is_empty() {
+        return path.to_string();
+    }
+    let query: Vec<String> = query.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+    format!("{}?{}", path, query.join("&"))
+}
+
+/// Load a cached response, no matter how old it is.
+#[instrument]
+pub(crate) fn load(cache: &Path, service: &str, key: &str) -> Option<CachedResponse> {
+    event!(Level::TRACE, "load");
+    let path = entry_path(cache, service, key);
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            event!(Level::WARN, "failed to read {}: {}", path.display(), e);
+            return None;
+        }
+    };
+    match serde_json::from_str::<CachedResponse>(&contents) {
+        // Two keys could have the same hash, however unlikely.
+        Ok(cached) if cached.key == key => Some(cached),
+        Ok(_) => None,
+        Err(e) => {
+            event!(
+                Level::WARN,
+                "invalid cached response {}: {}",
+                path.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Store a response in the cache, replacing any stored before.
+#[instrument(skip(response))]
+pub(crate) fn store(
+    cache: &Path,
+    service: &str,
+    key: &str,
+    url: &str,
+    query: &[(String, String)],
+    response: &serde_json::Value,
+) {
+    event!(Level::TRACE, "store");
+    let path = entry_path(cache, service, key);
+    let cached = CachedResponse {
+        service: service.to_string(),
+        key: key.to_string(),
+        url: url.to_string(),
+        query: query.to_vec(),
+        fetched_at: chrono::Utc::now()
+            .naive_utc()
+            .format(TIMESTAMP_FORMAT)
+            .to_string(),
+        response: response.clone(),
+    };
+    // Pretty printed, so cached responses can be read and kept as test fixtures.
+    let contents = match serde_json::to_string_pretty(&cached) {
+        Ok(c) => c,
+        Err(e) => {
+            event!(Level::WARN, "failed to serialize {}: {}", key, e);
+            return;
+        }
+    };
+    if let Some(parent) = path.parent() {
+        if let Err(e) = std::fs::create_dir_all(parent) {
+            event!(Level::WARN, "failed to create {}: {}", parent.display(), e);
+            return;
+        }
+    }
+    if let Err(e) = std::fs::write(&path, contents) {
+        event!(Level::WARN, "failed to write {}: {}", path.display(), e);
+    }
+}
+
+/// The directory responses from metadata services are cached in.
+pub(crate) fn metadata_cache(config: &Config) -> PathBuf {
+    PathBuf::from(
+        config
+            .metadata_cache
+            .as_deref()
+            .unwrap_or(DEFAULT_METADATA_CACHE),
+    )
+}
+
+/// Cached responses are grouped by service, and named after a hash of their key.
+fn entry_path(cache: &Path, service: &str, key: &str) -> PathBuf {
+    let hash = blake3::hash(key.as_bytes()).to_hex();
+    cache
+        .join(service.to_lowercase())
+        .join(format!("{}.json", &hash[..32]))
+}
+
+/// Fetch cached responses older than `days` again. Responses that can't be fetched are kept,
+/// so the cache keeps working offline.
+async fn refresh_cache(config: &Config, days: u64) -> anyhow::Result<()> {
+    let cache = metadata_cache(config);
+    let expired = chrono::Utc::now().naive_utc() - chrono::Duration::days(days as i64);
+
+    let (mut refreshed, mut failed, mut fresh) = (0, 0, 0);
+    for entry in WalkDir::new(&cache)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().extension().is_some_and(|x| x == "json"))
+    {
+        let cached: CachedResponse = match std::fs::read_to_string(entry.path())
+            .map_err(anyhow::Error::from)
+            .and_then(|c| Ok(serde_json::from_str(&c)?))
+        {
+            Ok(c) => c,
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "skipping invalid cached response {}: {}",
+                    entry.path().display(),
+                    e
+                );
+                failed += 1;
+                continue;
+            }
+        };
+        let fetched_at = NaiveDateTime::parse_from_str(&cached.fetched_at, TIMESTAMP_FORMAT).ok();
+        if fetched_at.is_some_and(|f| f > expired) {
+            fresh += 1;
+            continue;
+        }
+
+        let mut query = cached.query.clone();
+        let limiter = match cached.service.as_str() {
+            musicbrainz::SERVICE => musicbrainz::rate_limiter(config),
+            acoustid::SERVICE => match acoustid::client_query(config) {
+                Ok(client) => {
+                    query.push(client);
+                    acoustid::rate_limiter()
+                }
+                Err(e) => {
+                    event!(Level::WARN, "failed to refresh {}: {}", cached.key, e);
+                    failed += 1;
+                    continue;
+                }
+            },
+            service => {
+                event!(Level::WARN, "unknown metadata service {}", service);
+                failed += 1;
+                continue;
+            }
+        };
+        let response = ratelimit::get(&limiter, cached.url.clone(), query)
+            .await
+            .and_then(|body| Ok(serde_json::from_str::<serde_json::Value>(&body)?));
+        match response {
+            Ok(response) => {
+                store(
+                    &cache,
+                    &cached.service,
+                    &cached.key,
+                    &cached.url,
+                    &cached.query,
+                    &response,
+                );
+                refreshed += 1;
+            }
+            Err(e) => {
+                event!(Level::WARN, "failed to refresh {}: {}", cached.key, e);
+                failed += 1;
+            }
+        }
+    }
+    println!(
+        "{} responses refreshed, {} failed, {} fetched in the last {} days",
+        refreshed, failed, fresh, days
+    );
+    Ok(())
+}
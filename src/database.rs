@@ -1,9 +1,13 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
 use std::{fmt, str, str::FromStr};
 
 use anyhow::anyhow;
 use async_once_cell::OnceCell;
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use migration::{Migrator, MigratorTrait};
 use musicbrainz_rs::entity::artist::{ArtistType, Gender};
+use once_cell::sync::Lazy;
 use regex::RegexSet;
 use sea_orm::*;
 use serde::{Deserialize, Serialize};
@@ -11,7 +15,9 @@ use tracing::{event, instrument, Level};
 
 use crate::Config;
 
-static DB: OnceCell<DatabaseConnection> = OnceCell::new();
+// One connection pool per database URL. Ria only uses one database, but each test has its own.
+static DB: Lazy<Mutex<HashMap<String, &'static OnceCell<DatabaseConnection>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
 
 #[derive(Parser, Debug, Clone, Serialize, Deserialize)]
 pub(crate) enum DatabaseType {
@@ -55,7 +61,21 @@ impl fmt::Display for DatabaseType {
     }
 }
 
-// @TODO: eventually this should auto-create the database schema.
+/// Commands for managing the database schema.
+#[derive(Subcommand, Debug, Clone)]
+pub(crate) enum DbCommand {
+    /// Create the database schema if missing and apply all pending migrations
+    Migrate,
+    /// List all migrations and whether or not they have been applied
+    Status,
+    /// Roll back the most recently applied migrations
+    Rollback {
+        /// Number of migrations to roll back
+        #[arg(short, long, default_value_t = 1)]
+        steps: u32,
+    },
+}
+
 #[instrument]
 pub(crate) async fn connection(config: &Config) -> &'static DatabaseConnection {
     event!(Level::TRACE, "connection");
@@ -63,14 +83,15 @@ pub(crate) async fn connection(config: &Config) -> &'static DatabaseConnection {
     let database_base = match config.database_type {
         DatabaseType::SQLite => "sqlite://",
         DatabaseType::MySQL => "mysql://",
-        DatabaseType::PostgreSQL => "postgresq://",
+        DatabaseType::PostgreSQL => "postgres://",
     };
 
     let database_url = match config.database_type {
         DatabaseType::SQLite => {
             let default_db_name = "ria.db".to_string();
             let database_name = config.database_name.as_ref().unwrap_or(&default_db_name);
-            format!("{}{}", database_base, database_name)
+            // Create the SQLite database file if it doesn't already exist.
+            format!("{}{}?mode=rwc", database_base, database_name)
         }
         DatabaseType::MySQL | DatabaseType::PostgreSQL => {
             let default_db_name = "ria".to_string();
@@ -90,7 +111,12 @@ pub(crate) async fn connection(config: &Config) -> &'static DatabaseConnection {
 
     event!(Level::DEBUG, "database url: {}", database_url);
 
-    DB.get_or_init(async {
+    let db = *DB
+        .lock()
+        .unwrap()
+        .entry(database_url.clone())
+        .or_insert_with(|| Box::leak(Box::new(OnceCell::new())));
+    db.get_or_init(async {
         match Database::connect(database_url).await {
             Ok(d) => d,
             Err(e) => {
@@ -102,6 +128,56 @@ pub(crate) async fn connection(config: &Config) -> &'static DatabaseConnection {
     .await
 }
 
+/// Create the database schema if it doesn't exist, and apply all pending migrations.
+#[instrument]
+pub(crate) async fn migrate(config: &Config) -> Result<(), DbErr> {
+    event!(Level::TRACE, "migrate");
+
+    let db = connection(config).await;
+    let pending = Migrator::get_pending_migrations(db).await?;
+    if pending.is_empty() {
+        event!(Level::DEBUG, "database schema is up to date");
+        return Ok(());
+    }
+    event!(
+        Level::WARN,
+        "applying {} database migrations",
+        pending.len()
+    );
+    Migrator::up(db, None).await
+}
+
+/// Run a database management command.
+#[instrument]
+pub(crate) async fn run_command(config: &Config, command: &DbCommand) -> Result<(), DbErr> {
+    event!(Level::TRACE, "run_command");
+
+    let db = connection(config).await;
+    match command {
+        DbCommand::Migrate => migrate(config).await,
+        DbCommand::Status => {
+            let applied: Vec<String> = Migrator::get_migration_models(db)
+                .await?
+                .into_iter()
+                .map(|m| m.version)
+                .collect();
+            for migration in Migrator::migrations() {
+                let status = if applied.iter().any(|a| a == migration.name()) {
+                    "Applied"
+                } else {
+                    "Pending"
+                };
+                println!("{:<8} {}", status, migration.name());
+            }
+            Ok(())
+        }
+        DbCommand::Rollback { steps } => {
+            event!(Level::WARN, "rolling back {} database migrations", steps);
+            Migrator::down(db, Some(*steps)).await
+        }
+    }
+}
+
 /// The recognized `artist.artist_type` options, as defined at
 /// https://musicbrainz.org/doc/Artist. This enum is used in
 /// `src/entities/artis.rs` and makes the data in the database
@@ -0,0 +1,541 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+
+use clap::ValueEnum;
+use sea_orm::sea_query::Expr;
+use sea_orm::*;
+use serde::Serialize;
+use tracing::{event, instrument, Level};
+
+use crate::database;
+use crate::entities::{prelude::*, *};
+use crate::fingerprint;
+use crate::musicbrainz::normalise_name;
+use crate::spectrum::SpectrumVerdict;
+use crate::Config;
+
+// Fingerprints at least this similar, out of 1, are of the same recording. Unrelated audio is
+// about 0.5 similar, and the same recording in different formats usually over 0.9.
+const FINGERPRINT_MATCH_THRESHOLD: f64 = 0.85;
+
+// Fingerprints are only compared if they share at least this many sub-fingerprints, so the
+// whole library isn't compared with itself.
+const FINGERPRINT_CANDIDATE_MATCHES: usize = 3;
+
+// Sub-fingerprints found in more fingerprints than this, ie of silence or a steady tone, are too
+// common to find copies by.
+const FINGERPRINT_COMMON_VALUE: usize = 100;
+
+// Copies with the same artist and title, or similar fingerprints, are only duplicates if their
+// durations differ by at most this many seconds, ie not a live or extended version.
+const DURATION_TOLERANCE: i32 = 5;
+
+// Audio files are hidden or shown this many at a time, as there's a limit to the number of ids
+// in a query.
+const HIDE_BATCH_SIZE: usize = 500;
+
+/// How a report is printed.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ReportFormat {
+    Text,
+    Json,
+}
+
+/// Why audio files were grouped as duplicates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum DuplicateReason {
+    /// The contents of the files are identical.
+    Hash,
+    /// The fingerprints of the audio are similar.
+    Fingerprint,
+    /// The artist and title tags are the same, and the durations close.
+    Tags,
+}
+
+impl DuplicateReason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DuplicateReason::Hash => "hash",
+            DuplicateReason::Fingerprint => "fingerprint",
+            DuplicateReason::Tags => "tags",
+        }
+    }
+}
+
+/// A group of copies of the same track, best first.
+#[derive(Debug, Serialize)]
+struct DuplicateGroup {
+    title: String,
+    reasons: BTreeSet<DuplicateReason>,
+    copies: Vec<AudioCopy>,
+}
+
+/// A copy of a track, and what its quality was ranked by.
+#[derive(Debug, Serialize)]
+struct AudioCopy {
+    audio_id: i32,
+    path: String,
+    format: String,
+    /// Lossless, and not transcoded from a lossy source.
+    lossless: bool,
+    /// The spectrum of a lossless copy ends in the cutoff of a lossy encoder.
+    transcoded: bool,
+    /// The cutoff frequency of the spectrum of lossless copies in Hz, see `ria verify`.
+    cutoff_frequency: Option<i32>,
+    bits: i32,
+    hertz: i32,
+    /// Average bitrate in kbps, from the size and duration of the file.
+    bitrate: i32,
+    channels: i32,
+    duration: i32,
+    best: bool,
+    hidden: bool,
+}
+
+impl AudioCopy {
+    fn new(audio: &audio::Model, verification: Option<&audio_verification::Model>) -> Self {
+        let transcoded = verification.and_then(|v| v.spectrum.as_deref())
+            == Some(SpectrumVerdict::Transcoded.as_str());
+        AudioCopy {
+            audio_id: audio.audio_id,
+            path: std::path::Path::new(&audio.path)
+                .join(&audio.name)
+                .display()
+                .to_string(),
+            format: audio.format.clone(),
+            lossless: is_lossless(&audio.format) && !transcoded,
+            transcoded,
+            cutoff_frequency: verification.and_then(|v| v.cutoff_frequency),
+            bits: audio.bits,
+            hertz: audio.hertz,
+            bitrate: if audio.duration > 0 {
+                (audio.size * 8 / 1000 / audio.duration as i64) as i32
+            } else {
+                0
+            },
+            channels: audio.channels,
+            duration: audio.duration,
+            best: false,
+            hidden: audio.hidden,
+        }
+    }
+
+    /// Compare the quality of two copies: lossless copies are better than lossy ones and those
+    /// transcoded from lossy ones, then higher cutoff frequencies where both are known, then
+    /// higher bit depths, sample rates, bitrates and more channels are better.
+    fn quality(&self, other: &AudioCopy) -> Ordering {
+        let cutoff = match (self.cutoff_frequency, other.cutoff_frequency) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            _ => Ordering::Equal,
+        };
+        self.lossless
+            .cmp(&other.lossless)
+            .then(cutoff)
+            .then(self.bits.cmp(&other.bits))
+            .then(self.hertz.cmp(&other.hertz))
+            .then(self.bitrate.cmp(&other.bitrate))
+            .then(self.channels.cmp(&other.channels))
+    }
+}
+
+/// Find duplicate audio files, and print them grouped by track with the best copy first.
+/// Optionally hide all but the best copy of each track from listing and playing, or show all
+/// hidden audio files again.
+#[instrument]
+pub(crate) async fn run_command(
+    config: &Config,
+    format: ReportFormat,
+    hide: bool,
+    unhide: bool,
+) -> anyhow::Result<()> {
+    event!(Level::TRACE, "run_command");
+
+    let db = database::connection(config).await;
+    if unhide {
+        let result = Audio::update_many()
+            .col_expr(audio::Column::Hidden, Expr::value(false))
+            .filter(audio::Column::Hidden.eq(true))
+            .exec(db)
+            .await?;
+        println!("{} audio files shown again", result.rows_affected);
+        return Ok(());
+    }
+
+    let mut groups = find_duplicates(db).await?;
+    if hide {
+        hide_inferior_copies(db, &mut groups).await?;
+    }
+    match format {
+        ReportFormat::Text => print_groups(&groups),
+        ReportFormat::Json => println!("{}", serde_json::to_string_pretty(&groups)?),
+    }
+    Ok(())
+}
+
+/// Group audio files with the same contents, similar fingerprints, or the same artist and
+/// title and a similar duration. Groups are ranked by quality, best first.
+async fn find_duplicates(db: &DatabaseConnection) -> anyhow::Result<Vec<DuplicateGroup>> {
+    let audio_files = Audio::find()
+        .order_by_asc(audio::Column::AudioId)
+        .all(db)
+        .await?;
+    let index: HashMap<i32, usize> = audio_files
+        .iter()
+        .enumerate()
+        .map(|(i, a)| (a.audio_id, i))
+        .collect();
+    let mut groups = DisjointSet::new(audio_files.len());
+
+    // Identical contents.
+    let mut hashes: HashMap<&str, usize> = HashMap::new();
+    for (i, audio) in audio_files.iter().enumerate() {
+        if let Some(hash) = audio.hash.as_deref().filter(|h| !h.is_empty()) {
+            match hashes.get(hash) {
+                Some(first) => groups.join(*first, i, DuplicateReason::Hash),
+                None => {
+                    hashes.insert(hash, i);
+                }
+            }
+        }
+    }
+
+    // Similar fingerprints. Copies of the same recording share many sub-fingerprints, so only
+    // those are compared.
+    let fingerprints: Vec<(usize, Vec<u32>)> = AudioFingerprint::find()
+        .all(db)
+        .await?
+        .into_iter()
+        .filter_map(|f| {
+            let fingerprint = fingerprint::decompress(&f.fingerprint);
+            if fingerprint.is_none() {
+                event!(Level::WARN, "invalid fingerprint of audio {}", f.audio_id);
+            }
+            Some((*index.get(&f.audio_id)?, fingerprint?))
+        })
+        .collect();
+    let mut shared: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (f, (_, fingerprint)) in fingerprints.iter().enumerate() {
+        let values: BTreeSet<u32> = fingerprint.iter().copied().filter(|v| *v != 0).collect();
+        for value in values {
+            shared.entry(value).or_default().push(f);
+        }
+    }
+    let mut candidates: HashMap<(usize, usize), usize> = HashMap::new();
+    for fingerprints in shared.values() {
+        if fingerprints.len() > FINGERPRINT_COMMON_VALUE {
+            continue;
+        }
+        for (n, a) in fingerprints.iter().enumerate() {
+            for b in &fingerprints[n + 1..] {
+                *candidates.entry((*a, *b)).or_default() += 1;
+            }
+        }
+    }
+    for ((a, b), matches) in candidates {
+        let ((i, first), (j, second)) = (&fingerprints[a], &fingerprints[b]);
+        if matches < FINGERPRINT_CANDIDATE_MATCHES
+            || (audio_files[*i].duration - audio_files[*j].duration).abs() > DURATION_TOLERANCE
+        {
+            continue;
+        }
+        let similarity = fingerprint::similarity(first, second);
+        event!(
+            Level::DEBUG,
+            "fingerprints of audio {} and {} are {:.2} similar",
+            audio_files[*i].audio_id,
+            audio_files[*j].audio_id,
+            similarity
+        );
+        if similarity >= FINGERPRINT_MATCH_THRESHOLD {
+            groups.join(*i, *j, DuplicateReason::Fingerprint);
+        }
+    }
+
+    // The same artist and title, and a similar duration.
+    let mut tags: HashMap<i32, (Option<String>, Option<String>)> = HashMap::new();
+    for tag in AudioTag::find()
+        .filter(audio_tag::Column::Name.is_in(["Artist", "TrackTitle"]))
+        .filter(audio_tag::Column::Position.eq(0))
+        .all(db)
+        .await?
+    {
+        let entry = tags.entry(tag.audio_id).or_default();
+        if tag.name == "Artist" {
+            entry.0 = Some(tag.value);
+        } else {
+            entry.1 = Some(tag.value);
+        }
+    }
+    let mut titles: HashMap<(String, String), Vec<usize>> = HashMap::new();
+    for (audio_id, (artist, title)) in &tags {
+        if let (Some(artist), Some(title), Some(i)) = (artist, title, index.get(audio_id)) {
+            titles
+                .entry((normalise_name(artist), normalise_name(title)))
+                .or_default()
+                .push(*i);
+        }
+    }
+    for copies in titles.values() {
+        for (n, i) in copies.iter().enumerate() {
+            for j in &copies[n + 1..] {
+                if (audio_files[*i].duration - audio_files[*j].duration).abs() <= DURATION_TOLERANCE
+                {
+                    groups.join(*i, *j, DuplicateReason::Tags);
+                }
+            }
+        }
+    }
+
+    // Lossless copies found transcoded from lossy ones by `ria verify` rank below genuine ones.
+    let verifications: HashMap<i32, audio_verification::Model> = AudioVerification::find()
+        .all(db)
+        .await?
+        .into_iter()
+        .map(|v| (v.audio_id, v))
+        .collect();
+    let mut duplicates: Vec<DuplicateGroup> = groups
+        .groups()
+        .into_iter()
+        .map(|(members, reasons)| {
+            let mut copies: Vec<AudioCopy> = members
+                .iter()
+                .map(|i| {
+                    let audio = &audio_files[*i];
+                    AudioCopy::new(audio, verifications.get(&audio.audio_id))
+                })
+                .collect();
+            // The best copy first, and copies of the same quality in the order they were found.
+            copies.sort_by(|a, b| b.quality(a).then(a.audio_id.cmp(&b.audio_id)));
+            copies[0].best = true;
+            let title = match tags.get(&copies[0].audio_id) {
+                Some((Some(artist), Some(title))) => format!("{} - {}", artist, title),
+                _ => audio_files[index[&copies[0].audio_id]].name.clone(),
+            };
+            DuplicateGroup {
+                title,
+                reasons,
+                copies,
+            }
+        })
+        .collect();
+    duplicates.sort_by(|a, b| a.title.cmp(&b.title));
+    Ok(duplicates)
+}
+
+/// Hide all but the best copy of each group, and show the best copy if it was hidden before.
+async fn hide_inferior_copies(
+    db: &DatabaseConnection,
+    groups: &mut [DuplicateGroup],
+) -> anyhow::Result<()> {
+    let (best, inferior): (Vec<&mut AudioCopy>, Vec<&mut AudioCopy>) = groups
+        .iter_mut()
+        .flat_map(|g| g.copies.iter_mut())
+        .partition(|c| c.best);
+    let txn = db.begin().await?;
+    for (copies, hidden) in [(&best, false), (&inferior, true)] {
+        let audio_ids: Vec<i32> = copies.iter().map(|c| c.audio_id).collect();
+        for audio_ids in audio_ids.chunks(HIDE_BATCH_SIZE) {
+            Audio::update_many()
+                .col_expr(audio::Column::Hidden, Expr::value(hidden))
+                .filter(audio::Column::AudioId.is_in(audio_ids.to_vec()))
+                .exec(&txn)
+                .await?;
+        }
+    }
+    txn.commit().await?;
+    for copy in best {
+        copy.hidden = false;
+    }
+    for copy in inferior {
+        copy.hidden = true;
+    }
+    Ok(())
+}
+
+/// Print groups of duplicates, with the best copy of each marked.
+fn print_groups(groups: &[DuplicateGroup]) {
+    for group in groups {
+        let reasons: Vec<&str> = group.reasons.iter().map(|r| r.as_str()).collect();
+        println!("{} (same {})", group.title, reasons.join(", "));
+        for copy in &group.copies {
+            let mut quality = vec![copy.format.clone()];
+            if copy.transcoded {
+                quality.push("transcoded from a lossy source".to_string());
+            }
+            if let Some(cutoff) = copy.cutoff_frequency {
+                quality.push(format!("cut off at {:.1} kHz", cutoff as f64 / 1000.0));
+            }
+            if copy.bits > 0 {
+                quality.push(format!("{} bit", copy.bits));
+            }
+            quality.push(format!("{} Hz", copy.hertz));
+            quality.push(format!("{} kbps", copy.bitrate));
+            println!(
+                "  {} {:>6}: {} [{}]{}",
+                if copy.best { "*" } else { " " },
+                copy.audio_id,
+                copy.path,
+                quality.join(", "),
+                if copy.hidden { " (hidden)" } else { "" }
+            );
+        }
+    }
+    println!("{} tracks with duplicates", groups.len());
+}
+
+/// Whether a codec, by its long name, is lossless.
+pub(crate) fn is_lossless(format: &str) -> bool {
+    let format = format.to_lowercase();
+    format.contains("lossless") || format.starts_with("pcm")
+}
+
+/// Audio files grouped by union-find, along with why each group was joined.
+struct DisjointSet {
+    parents: Vec<usize>,
+    reasons: HashMap<usize, BTreeSet<DuplicateReason>>,
+}
+
+impl DisjointSet {
+    fn new(size: usize) -> Self {
+        DisjointSet {
+            parents: (0..size).collect(),
+            reasons: HashMap::new(),
+        }
+    }
+
+    fn root(&mut self, mut i: usize) -> usize {
+        while self.parents[i] != i {
+            self.parents[i] = self.parents[self.parents[i]];
+            i = self.parents[i];
+        }
+        i
+    }
+
+    fn join(&mut self, a: usize, b: usize, reason: DuplicateReason) {
+        let (a, b) = (self.root(a), self.root(b));
+        if a != b {
+            self.parents[b] = a;
+            let reasons = self.reasons.remove(&b).unwrap_or_default();
+            self.reasons.entry(a).or_default().extend(reasons);
+        }
+        self.reasons.entry(a).or_default().insert(reason);
+    }
+
+    /// Groups of more than one member, with why they were joined.
+    fn groups(mut self) -> Vec<(Vec<usize>, BTreeSet<DuplicateReason>)> {
+        let mut members: HashMap<usize, Vec<usize>> = HashMap::new();
+        for i in 0..self.parents.len() {
+            let root = self.root(i);
+            members.entry(root).or_default().push(i);
+        }
+        members
+            .into_iter()
+            .filter(|(_, m)| m.len() > 1)
+            .map(|(root, m)| (m, self.reasons.remove(&root).unwrap_or_default()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scan;
+    use crate::testing::{self, TestLibrary};
+
+    /// The group with a copy at a path in the library.
+    fn group_of<'a>(groups: &'a [DuplicateGroup], path: &str) -> &'a DuplicateGroup {
+        groups
+            .iter()
+            .find(|g| g.copies.iter().any(|c| c.path.ends_with(path)))
+            .unwrap_or_else(|| panic!("no group of {}", path))
+    }
+
+    fn best_path(group: &DuplicateGroup) -> &str {
+        assert!(group.copies[0].best);
+        assert!(group.copies[1..].iter().all(|c| !c.best));
+        &group.copies[0].path
+    }
+
+    #[tokio::test]
+    async fn copies_are_grouped_and_ranked() {
+        let test = TestLibrary::new(&["--hash", "--fingerprint"]).await;
+        let config = &test.config;
+        let db = database::connection(config).await;
+
+        // The same file twice, a tritone above the others so it doesn't sound like them.
+        let transposed = testing::PROGRESSION.map(|chord| chord.map(|n| n + 6));
+        let identical = testing::chords(44100, &transposed, 3);
+        testing::write_wav(&test.path("Identical/first.wav"), 44100, &identical);
+        testing::write_wav(&test.path("Identical/second.wav"), 44100, &identical);
+        // The same music at two sample rates.
+        for (name, sample_rate) in [("cd.wav", 44100), ("hires.wav", 48000)] {
+            testing::write_wav(
+                &test.path(&format!("Encoded/{}", name)),
+                sample_rate,
+                &testing::chords(sample_rate, &testing::PROGRESSION, 3),
+            );
+        }
+        // Different audio, tagged as the same track.
+        for (name, sample_rate, frequency) in [("one.wav", 44100, 440.0), ("two.wav", 22050, 660.0)]
+        {
+            testing::write_wav(
+                &test.path(&format!("Tagged/{}", name)),
+                sample_rate,
+                &testing::sine(sample_rate, frequency, -6.0, 12.0),
+            );
+        }
+        scan::scan_media_files(config).await;
+        for audio in Audio::find()
+            .filter(audio::Column::Path.ends_with("Tagged"))
+            .all(db)
+            .await
+            .unwrap()
+        {
+            for (name, value) in [("Artist", "The Tides"), ("TrackTitle", "Undertow")] {
+                AudioTag::insert(audio_tag::ActiveModel {
+                    audio_id: ActiveValue::Set(audio.audio_id),
+                    name: ActiveValue::Set(name.to_string()),
+                    key: ActiveValue::Set(name.to_uppercase()),
+                    value: ActiveValue::Set(value.to_string()),
+                    position: ActiveValue::Set(0),
+                    ..Default::default()
+                })
+                .exec(db)
+                .await
+                .unwrap();
+            }
+        }
+
+        let mut groups = find_duplicates(db).await.unwrap();
+        assert_eq!(groups.len(), 3);
+
+        let identical = group_of(&groups, "Identical/first.wav");
+        assert!(identical.reasons.contains(&DuplicateReason::Hash));
+        // Copies of the same quality are ranked in the order they were found.
+        assert!(identical.copies[0].audio_id < identical.copies[1].audio_id);
+        best_path(identical);
+
+        let encoded = group_of(&groups, "Encoded/cd.wav");
+        assert_eq!(
+            encoded.reasons,
+            BTreeSet::from([DuplicateReason::Fingerprint])
+        );
+        assert!(best_path(encoded).ends_with("Encoded/hires.wav"));
+
+        let tagged = group_of(&groups, "Tagged/one.wav");
+        assert!(tagged.reasons.contains(&DuplicateReason::Tags));
+        assert!(!tagged.reasons.contains(&DuplicateReason::Hash));
+        assert_eq!(tagged.title, "The Tides - Undertow");
+        assert!(best_path(tagged).ends_with("Tagged/one.wav"));
+
+        hide_inferior_copies(db, &mut groups).await.unwrap();
+        for audio in Audio::find().all(db).await.unwrap() {
+            let path = std::path::Path::new(&audio.path).join(&audio.name);
+            let best = groups
+                .iter()
+                .any(|g| g.copies[0].path == path.display().to_string());
+            assert_eq!(audio.hidden, !best, "{}", path.display());
+        }
+    }
+}
@@ -17,6 +17,8 @@ pub struct Model {
     pub gender: Option<RiaGender>,
     pub disambiguation_comment: String,
     pub artist_area_id: Option<i32>,
+    pub match_status: String,
+    pub match_score: Option<i32>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -29,10 +31,18 @@ pub enum Relation {
         on_delete = "NoAction"
     )]
     ArtistArea,
+    #[sea_orm(has_many = "super::album::Entity")]
+    Album,
     #[sea_orm(has_many = "super::audio_artist::Entity")]
     AudioArtist,
     #[sea_orm(has_many = "super::artist_directory::Entity")]
     ArtistDirectory,
+    #[sea_orm(has_many = "super::artist_image::Entity")]
+    ArtistImage,
+    #[sea_orm(has_many = "super::artist_candidate::Entity")]
+    ArtistCandidate,
+    #[sea_orm(has_many = "super::artist_pin::Entity")]
+    ArtistPin,
 }
 
 impl Related<super::artist_area::Entity> for Entity {
@@ -41,6 +51,12 @@ impl Related<super::artist_area::Entity> for Entity {
     }
 }
 
+impl Related<super::album::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Album.def()
+    }
+}
+
 impl Related<super::audio_artist::Entity> for Entity {
     fn to() -> RelationDef {
         Relation::AudioArtist.def()
@@ -53,4 +69,22 @@ impl Related<super::artist_directory::Entity> for Entity {
     }
 }
 
+impl Related<super::artist_image::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::ArtistImage.def()
+    }
+}
+
+impl Related<super::artist_candidate::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::ArtistCandidate.def()
+    }
+}
+
+impl Related<super::artist_pin::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::ArtistPin.def()
+    }
+}
+
 impl ActiveModelBehavior for ActiveModel {}
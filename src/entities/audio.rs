@@ -2,7 +2,7 @@
 
 use sea_orm::entity::prelude::*;
 
-#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq)]
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
 #[sea_orm(table_name = "audio")]
 pub struct Model {
     #[sea_orm(primary_key)]
@@ -17,16 +17,57 @@ pub struct Model {
     pub channels: i32,
     pub bits: i32,
     pub hertz: i32,
+    pub size: i64,
+    pub modified: Option<DateTime>,
+    pub hash: Option<String>,
+    pub musicbrainz_recording_id: Option<String>,
+    pub musicbrainz_track_id: Option<String>,
+    pub musicbrainz_title: Option<String>,
+    pub hidden: bool,
+    #[sea_orm(column_type = "Double", nullable)]
+    pub loudness: Option<f64>,
+    #[sea_orm(column_type = "Double", nullable)]
+    pub true_peak: Option<f64>,
+    #[sea_orm(column_type = "Double", nullable)]
+    pub track_gain: Option<f64>,
+    #[sea_orm(column_type = "Double", nullable)]
+    pub album_gain: Option<f64>,
+    #[sea_orm(column_type = "Double", nullable)]
+    pub album_peak: Option<f64>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
 pub enum Relation {
+    #[sea_orm(has_many = "super::album_audio::Entity")]
+    AlbumAudio,
+    #[sea_orm(has_many = "super::audio_acoustid::Entity")]
+    AudioAcoustid,
     #[sea_orm(has_many = "super::audio_artist::Entity")]
     AudioArtist,
     #[sea_orm(has_many = "super::audio_directory::Entity")]
     AudioDirectory,
+    #[sea_orm(has_one = "super::audio_feature::Entity")]
+    AudioFeature,
+    #[sea_orm(has_one = "super::audio_fingerprint::Entity")]
+    AudioFingerprint,
+    #[sea_orm(has_many = "super::audio_image::Entity")]
+    AudioImage,
     #[sea_orm(has_many = "super::audio_tag::Entity")]
     AudioTag,
+    #[sea_orm(has_one = "super::audio_verification::Entity")]
+    AudioVerification,
+}
+
+impl Related<super::album_audio::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::AlbumAudio.def()
+    }
+}
+
+impl Related<super::audio_acoustid::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::AudioAcoustid.def()
+    }
 }
 
 impl Related<super::audio_artist::Entity> for Entity {
@@ -41,10 +82,34 @@ impl Related<super::audio_directory::Entity> for Entity {
     }
 }
 
+impl Related<super::audio_feature::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::AudioFeature.def()
+    }
+}
+
+impl Related<super::audio_fingerprint::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::AudioFingerprint.def()
+    }
+}
+
+impl Related<super::audio_image::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::AudioImage.def()
+    }
+}
+
 impl Related<super::audio_tag::Entity> for Entity {
     fn to() -> RelationDef {
         Relation::AudioTag.def()
     }
 }
 
+impl Related<super::audio_verification::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::AudioVerification.def()
+    }
+}
+
 impl ActiveModelBehavior for ActiveModel {}
@@ -10,6 +10,8 @@ pub struct Model {
     pub audio_id: i32,
     pub name: String,
     pub value: String,
+    pub key: String,
+    pub position: i32,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -13,9 +13,38 @@ pub struct Model {
     pub name: String,
     pub extension: String,
     pub format: String,
+    pub kind: String,
+    pub size: i64,
+    pub hash: Option<String>,
+    pub embedded: bool,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
-pub enum Relation {}
+pub enum Relation {
+    #[sea_orm(has_many = "super::album_image::Entity")]
+    AlbumImage,
+    #[sea_orm(has_many = "super::artist_image::Entity")]
+    ArtistImage,
+    #[sea_orm(has_many = "super::audio_image::Entity")]
+    AudioImage,
+}
+
+impl Related<super::album_image::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::AlbumImage.def()
+    }
+}
+
+impl Related<super::artist_image::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::ArtistImage.def()
+    }
+}
+
+impl Related<super::audio_image::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::AudioImage.def()
+    }
+}
 
 impl ActiveModelBehavior for ActiveModel {}
@@ -2,13 +2,25 @@
 
 pub mod prelude;
 
+pub mod album;
+pub mod album_audio;
+pub mod album_image;
 pub mod artist;
 pub mod artist_area;
+pub mod artist_candidate;
 pub mod artist_directory;
+pub mod artist_image;
+pub mod artist_pin;
 pub mod audio;
+pub mod audio_acoustid;
 pub mod audio_artist;
 pub mod audio_directory;
+pub mod audio_feature;
+pub mod audio_fingerprint;
+pub mod audio_image;
 pub mod audio_tag;
+pub mod audio_verification;
 pub mod directory;
 pub mod image;
 pub mod musicbrainz_queue;
+pub mod scan_checkpoint;
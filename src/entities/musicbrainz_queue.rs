@@ -11,6 +11,9 @@ pub struct Model {
     pub processing_started_at: Option<DateTime>,
     pub errors: Option<String>,
     pub payload: Option<String>,
+    pub status: String,
+    pub attempts: i32,
+    pub next_attempt_at: Option<DateTime>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
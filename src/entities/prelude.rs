@@ -1,12 +1,24 @@
 //! `SeaORM` Entity. Generated by sea-orm-codegen 0.10.3
 
+pub use super::album::Entity as Album;
+pub use super::album_audio::Entity as AlbumAudio;
+pub use super::album_image::Entity as AlbumImage;
 pub use super::artist::Entity as Artist;
 pub use super::artist_area::Entity as ArtistArea;
+pub use super::artist_candidate::Entity as ArtistCandidate;
 pub use super::artist_directory::Entity as ArtistDirectory;
+pub use super::artist_image::Entity as ArtistImage;
+pub use super::artist_pin::Entity as ArtistPin;
 pub use super::audio::Entity as Audio;
+pub use super::audio_acoustid::Entity as AudioAcoustid;
 pub use super::audio_artist::Entity as AudioArtist;
 pub use super::audio_directory::Entity as AudioDirectory;
+pub use super::audio_feature::Entity as AudioFeature;
+pub use super::audio_fingerprint::Entity as AudioFingerprint;
+pub use super::audio_image::Entity as AudioImage;
 pub use super::audio_tag::Entity as AudioTag;
+pub use super::audio_verification::Entity as AudioVerification;
 pub use super::directory::Entity as Directory;
 pub use super::image::Entity as Image;
 pub use super::musicbrainz_queue::Entity as MusicbrainzQueue;
+pub use super::scan_checkpoint::Entity as ScanCheckpoint;
@@ -0,0 +1,471 @@
+use std::f64::consts::PI;
+use std::path::{Path, PathBuf};
+
+use sea_orm::*;
+use tokio::task::JoinSet;
+use tracing::{event, instrument, Level};
+
+use crate::database;
+use crate::entities::{prelude::*, *};
+use crate::fingerprint::{self, SAMPLE_RATE};
+use crate::media::Scope;
+use crate::scan;
+use crate::Config;
+
+// Features are computed from the start of each file, up to this many seconds.
+const ANALYSIS_LENGTH: f64 = 120.0;
+
+// The spectral centroid is the average of spectra of frames of this many samples, at the
+// sample rate of the file. Frames quieter than the threshold, in dBFS, are silence and aren't
+// counted.
+const SPECTRUM_FRAME_SIZE: usize = 2048;
+const SILENCE_THRESHOLD: f64 = -60.0;
+
+// Onsets are found in the increase of the spectrum between overlapping frames, at the rate
+// audio is resampled to for fingerprinting, relative to the average increase over this many
+// seconds around them.
+const ONSET_FRAME_SIZE: usize = 1024;
+const ONSET_FRAME_STEP: usize = 128;
+const ONSET_AVERAGE: f64 = 0.5;
+
+// Increases smaller than this are ripples in the spectrum of steady sounds, not onsets. Drum
+// hits increase it by hundreds.
+const ONSET_THRESHOLD: f64 = 1.0;
+
+// The tempo is the period onsets repeat at most strongly between these BPM, favouring periods
+// near the preferred tempo, by a spread in octaves, as people tap along at tempos near it.
+const MINIMUM_TEMPO: f64 = 60.0;
+const MAXIMUM_TEMPO: f64 = 200.0;
+const PREFERRED_TEMPO: f64 = 120.0;
+const TEMPO_SPREAD: f64 = 1.0;
+
+// The key is estimated from the energy of each note of the octave, between these frequencies,
+// in frames long enough to tell apart the notes of the lowest octave.
+const CHROMA_FRAME_SIZE: usize = 4096;
+const MINIMUM_NOTE_FREQUENCY: f64 = 65.0;
+const MAXIMUM_NOTE_FREQUENCY: f64 = 2100.0;
+
+// How typical each note of the octave is in major and minor keys, counting from the tonic, as
+// measured by Krumhansl and Kessler.
+const MAJOR_PROFILE: [f64; 12] = [
+    6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88,
+];
+const MINOR_PROFILE: [f64; 12] = [
+    6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17,
+];
+const NOTES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+/// Descriptors of the audio of a file.
+struct Features {
+    /// Beats per minute, or 0 if no beat was found.
+    tempo: f64,
+    /// How clearly the beat repeats, from 0 to 1.
+    danceability: f64,
+    /// The tonic and mode, ie "A minor", or empty if no notes were found.
+    key: String,
+    /// How well the notes matched the key, from -1 to 1.
+    key_confidence: f64,
+    /// The average frequency of the spectrum weighted by its magnitude, in Hz, higher for
+    /// brighter sounding audio.
+    spectral_centroid: f64,
+    /// The RMS level, in dBFS.
+    energy: f64,
+}
+
+/// Extract the features of audio files in scope that haven't been analysed yet, if feature
+/// extraction is enabled. Files are decoded by the same number of blocking workers as probe files when
+/// scanning.
+#[instrument]
+pub(crate) async fn extract_features(config: &Config, scope: &Scope) {
+    event!(Level::TRACE, "extract_features");
+    if !config.features {
+        return;
+    }
+
+    let db = database::connection(config).await;
+    let audio_files = match Audio::find()
+        .left_join(AudioFeature)
+        .filter(audio_feature::Column::AudioFeatureId.is_null())
+        .filter(scope.paths(audio::Column::Path))
+        .all(db)
+        .await
+    {
+        Ok(a) => a,
+        Err(e) => {
+            event!(Level::WARN, "Audio::find() unanalysed failure: {}", e);
+            return;
+        }
+    };
+
+    let workers = scan::scan_workers(config);
+    let mut analysed = 0;
+    let mut tasks = JoinSet::new();
+    let mut audio_files = audio_files.into_iter();
+    loop {
+        // Keep every worker busy, then store the features of each file as soon as it's done.
+        while tasks.len() < workers {
+            let Some(audio_file) = audio_files.next() else {
+                break;
+            };
+            let path = PathBuf::from(&audio_file.path).join(&audio_file.name);
+            tasks.spawn(tokio::task::spawn_blocking(move || {
+                let features = analyse_file(&path, &audio_file.extension);
+                (audio_file.audio_id, features)
+            }));
+        }
+        let (audio_id, features) = match tasks.join_next().await {
+            Some(Ok(Ok(r))) => r,
+            Some(Ok(Err(e)) | Err(e)) => {
+                event!(Level::WARN, "features worker failure: {}", e);
+                continue;
+            }
+            None => break,
+        };
+        let Some(features) = features else {
+            continue;
+        };
+        let audio_feature = audio_feature::ActiveModel {
+            created: ActiveValue::Set(chrono::Utc::now().naive_utc()),
+            audio_id: ActiveValue::Set(audio_id),
+            tempo: ActiveValue::Set(features.tempo),
+            danceability: ActiveValue::Set(features.danceability),
+            key: ActiveValue::Set(features.key),
+            key_confidence: ActiveValue::Set(features.key_confidence),
+            spectral_centroid: ActiveValue::Set(features.spectral_centroid),
+            energy: ActiveValue::Set(features.energy),
+            ..Default::default()
+        };
+        match audio_feature.insert(db).await {
+            Ok(_) => analysed += 1,
+            Err(e) => event!(Level::WARN, "AudioFeature::insert() failure: {}", e),
+        }
+    }
+    event!(
+        Level::INFO,
+        "extracted features of {} audio files",
+        analysed
+    );
+}
+
+/// Compute the features of an audio file. Returns None if the file can't be decoded.
+fn analyse_file(path: &Path, extension: &str) -> Option<Features> {
+    event!(Level::TRACE, "analyse_file {}", path.display());
+
+    let (samples, sample_rate) = fingerprint::decode(path, extension, ANALYSIS_LENGTH)?;
+    let energy = to_decibels(rms(&samples));
+    let spectral_centroid = spectral_centroid(&samples, sample_rate);
+    let samples = fingerprint::resample(&samples, sample_rate);
+    let (tempo, danceability) = tempo(&samples);
+    let (key, key_confidence) = key(&samples);
+    Some(Features {
+        tempo,
+        danceability,
+        key,
+        key_confidence,
+        spectral_centroid,
+        energy,
+    })
+}
+
+/// The root mean square of samples.
+fn rms(samples: &[f32]) -> f64 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    (samples.iter().map(|s| (*s as f64).powi(2)).sum::<f64>() / samples.len() as f64).sqrt()
+}
+
+/// A linear amplitude in dB, with silence at the quietest level 16 bit audio can store.
+fn to_decibels(amplitude: f64) -> f64 {
+    20.0 * amplitude.max(1.0 / 32768.0).log10()
+}
+
+/// The magnitude spectrum of a frame of samples, with a Hann window applied.
+fn spectrum(samples: &[f32], window: &[f64]) -> Vec<f64> {
+    let mut buffer: Vec<(f64, f64)> = samples
+        .iter()
+        .zip(window)
+        .map(|(s, w)| (*s as f64 * w, 0.0))
+        .collect();
+    fingerprint::fft(&mut buffer);
+    buffer[..samples.len() / 2]
+        .iter()
+        .map(|(re, im)| (re * re + im * im).sqrt())
+        .collect()
+}
+
+/// A Hann window of a length.
+fn hann(length: usize) -> Vec<f64> {
+    (0..length)
+        .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f64 / length as f64).cos())
+        .collect()
+}
+
+/// The average spectral centroid of frames that aren't silent, in Hz.
+fn spectral_centroid(samples: &[f32], sample_rate: u32) -> f64 {
+    let window = hann(SPECTRUM_FRAME_SIZE);
+    let centroids: Vec<f64> = samples
+        .chunks_exact(SPECTRUM_FRAME_SIZE)
+        .filter(|frame| to_decibels(rms(frame)) > SILENCE_THRESHOLD)
+        .filter_map(|frame| {
+            let magnitudes = spectrum(frame, &window);
+            let total: f64 = magnitudes.iter().sum();
+            let weighted: f64 = magnitudes
+                .iter()
+                .enumerate()
+                .map(|(i, m)| m * i as f64 * sample_rate as f64 / SPECTRUM_FRAME_SIZE as f64)
+                .sum();
+            (total > 0.0).then(|| weighted / total)
+        })
+        .collect();
+    if centroids.is_empty() {
+        return 0.0;
+    }
+    centroids.iter().sum::<f64>() / centroids.len() as f64
+}
+
+/// Estimate the tempo of audio resampled for analysis from how onsets repeat, along with how
+/// clearly they repeat.
+fn tempo(samples: &[f32]) -> (f64, f64) {
+    let window = hann(ONSET_FRAME_SIZE);
+    let mut flux = Vec::new();
+    let mut previous: Option<Vec<f64>> = None;
+    let mut start = 0;
+    while start + ONSET_FRAME_SIZE <= samples.len() {
+        // Magnitudes are compressed, so quiet onsets count as well as loud ones.
+        let magnitudes: Vec<f64> = spectrum(&samples[start..start + ONSET_FRAME_SIZE], &window)
+            .into_iter()
+            .map(|m| (1.0 + 100.0 * m).ln())
+            .collect();
+        if let Some(previous) = previous {
+            flux.push(
+                magnitudes
+                    .iter()
+                    .zip(&previous)
+                    .map(|(m, p)| (m - p).max(0.0))
+                    .sum::<f64>(),
+            );
+        }
+        previous = Some(magnitudes);
+        start += ONSET_FRAME_STEP;
+    }
+
+    let rate = SAMPLE_RATE as f64 / ONSET_FRAME_STEP as f64;
+    let half = (ONSET_AVERAGE * rate / 2.0) as usize;
+    let onsets: Vec<f64> = (0..flux.len())
+        .map(|i| {
+            let around = &flux[i.saturating_sub(half)..(i + half + 1).min(flux.len())];
+            let onset = flux[i] - around.iter().sum::<f64>() / around.len() as f64;
+            if onset > ONSET_THRESHOLD {
+                onset
+            } else {
+                0.0
+            }
+        })
+        .collect();
+
+    let shortest = (rate * 60.0 / MAXIMUM_TEMPO).floor() as usize;
+    let longest = (rate * 60.0 / MINIMUM_TEMPO).ceil() as usize;
+    if onsets.len() <= longest + 1 {
+        return (0.0, 0.0);
+    }
+    let autocorrelation: Vec<f64> = (0..=longest + 1)
+        .map(|lag| {
+            onsets
+                .iter()
+                .zip(&onsets[lag..])
+                .map(|(a, b)| a * b)
+                .sum::<f64>()
+                / (onsets.len() - lag) as f64
+        })
+        .collect();
+    if autocorrelation[0] <= 0.0 {
+        return (0.0, 0.0);
+    }
+    let preference = |lag: f64| {
+        let octaves = (rate * 60.0 / lag / PREFERRED_TEMPO).log2() / TEMPO_SPREAD;
+        (-0.5 * octaves * octaves).exp()
+    };
+    let Some(best) = (shortest..=longest).max_by(|a, b| {
+        (autocorrelation[*a] * preference(*a as f64))
+            .total_cmp(&(autocorrelation[*b] * preference(*b as f64)))
+    }) else {
+        return (0.0, 0.0);
+    };
+
+    // Interpolate between lags, as the tempo rarely falls on a whole number of frames.
+    let (before, peak, after) = (
+        autocorrelation[best - 1],
+        autocorrelation[best],
+        autocorrelation[best + 1],
+    );
+    let curvature = before - 2.0 * peak + after;
+    let offset = if curvature < 0.0 {
+        (0.5 * (before - after) / curvature).clamp(-0.5, 0.5)
+    } else {
+        0.0
+    };
+    let tempo = rate * 60.0 / (best as f64 + offset);
+    let danceability = (peak / autocorrelation[0]).clamp(0.0, 1.0);
+    (tempo, danceability)
+}
+
+/// Estimate the key of audio resampled for analysis, by correlating the energy of each note of
+/// the octave with the profile of each key, along with how well the best key correlated.
+fn key(samples: &[f32]) -> (String, f64) {
+    let window = hann(CHROMA_FRAME_SIZE);
+    // The note of each frequency bin, counting from C.
+    let notes: Vec<Option<usize>> = (0..CHROMA_FRAME_SIZE / 2)
+        .map(|i| {
+            let frequency = i as f64 * SAMPLE_RATE as f64 / CHROMA_FRAME_SIZE as f64;
+            if !(MINIMUM_NOTE_FREQUENCY..=MAXIMUM_NOTE_FREQUENCY).contains(&frequency) {
+                return None;
+            }
+            let midi = (12.0 * (frequency / 440.0).log2()).round() as i64 + 69;
+            Some(midi.rem_euclid(12) as usize)
+        })
+        .collect();
+    let mut chroma = [0.0; 12];
+    let mut start = 0;
+    while start + CHROMA_FRAME_SIZE <= samples.len() {
+        let magnitudes = spectrum(&samples[start..start + CHROMA_FRAME_SIZE], &window);
+        for (magnitude, note) in magnitudes.iter().zip(&notes) {
+            if let Some(note) = note {
+                chroma[*note] += magnitude;
+            }
+        }
+        start += CHROMA_FRAME_SIZE / 2;
+    }
+    if chroma.iter().all(|c| *c == 0.0) {
+        return (String::new(), 0.0);
+    }
+
+    let mut best = (String::new(), f64::MIN);
+    for tonic in 0..12 {
+        let rotated: Vec<f64> = (0..12).map(|i| chroma[(tonic + i) % 12]).collect();
+        for (mode, profile) in [("major", &MAJOR_PROFILE), ("minor", &MINOR_PROFILE)] {
+            let correlation = correlation(&rotated, profile);
+            if correlation > best.1 {
+                best = (format!("{} {}", NOTES[tonic], mode), correlation);
+            }
+        }
+    }
+    best
+}
+
+/// The Pearson correlation of two series of the same length.
+fn correlation(a: &[f64], b: &[f64]) -> f64 {
+    let mean = |x: &[f64]| x.iter().sum::<f64>() / x.len() as f64;
+    let (mean_a, mean_b) = (mean(a), mean(b));
+    let covariance: f64 = a
+        .iter()
+        .zip(b)
+        .map(|(x, y)| (x - mean_a) * (y - mean_b))
+        .sum();
+    let deviation = |x: &[f64], mean: f64| x.iter().map(|v| (v - mean).powi(2)).sum::<f64>();
+    let denominator = (deviation(a, mean_a) * deviation(b, mean_b)).sqrt();
+    if denominator > 0.0 {
+        covariance / denominator
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    /// Clicks of decaying noise on every beat at a tempo, with a quiet sine under them.
+    fn click_track(bpm: f64, seconds: f64) -> Vec<f32> {
+        let mut samples = testing::sine(44100, 220.0, -30.0, seconds);
+        let beat = 44100.0 * 60.0 / bpm;
+        let click = testing::noise(7, 441);
+        let mut position = 0.0;
+        while (position as usize) + click.len() < samples.len() {
+            for (i, noise) in click.iter().enumerate() {
+                samples[position as usize + i] += 0.5 * noise * (-(i as f32) / 80.0).exp();
+            }
+            position += beat;
+        }
+        samples
+    }
+
+    fn analyse(samples: &[f32]) -> Features {
+        let test = tempfile::tempdir().unwrap();
+        let path = test.path().join("test.wav");
+        testing::write_wav(&path, 44100, samples);
+        analyse_file(&path, "wav").unwrap()
+    }
+
+    #[test]
+    fn click_track_is_at_its_tempo() {
+        for bpm in [90.0, 128.0] {
+            let features = analyse(&click_track(bpm, 20.0));
+            assert!(
+                (features.tempo - bpm).abs() < 1.0,
+                "{} BPM found at {}",
+                bpm,
+                features.tempo
+            );
+            assert!(features.danceability > 0.5, "{}", features.danceability);
+        }
+    }
+
+    #[test]
+    fn sine_has_its_level_and_frequency() {
+        let features = analyse(&testing::sine(44100, 1000.0, -6.0, 5.0));
+        // The RMS of a sine is 3 dB below its peak.
+        assert!((features.energy + 9.03).abs() < 0.1, "{}", features.energy);
+        assert!(
+            (features.spectral_centroid - 1000.0).abs() < 50.0,
+            "{}",
+            features.spectral_centroid
+        );
+    }
+
+    #[test]
+    fn chords_are_in_their_key() {
+        // I, IV, V and I of C major, then of A minor.
+        let chord = |notes: &[f64]| -> Vec<f32> {
+            let tones: Vec<Vec<f32>> = notes
+                .iter()
+                .map(|n| testing::sine(44100, 261.63 * 2f64.powf(n / 12.0), -16.0, 1.0))
+                .collect();
+            (0..tones[0].len())
+                .map(|i| tones.iter().map(|t| t[i]).sum())
+                .collect()
+        };
+        let major: Vec<f32> = [
+            [0.0, 4.0, 7.0],
+            [5.0, 9.0, 12.0],
+            [7.0, 11.0, 14.0],
+            [0.0, 4.0, 7.0],
+        ]
+        .iter()
+        .flat_map(|c| chord(c))
+        .collect();
+        let features = analyse(&major);
+        assert_eq!(features.key, "C major");
+        let minor: Vec<f32> = [
+            [-3.0, 0.0, 4.0],
+            [2.0, 5.0, 9.0],
+            [4.0, 8.0, 11.0],
+            [-3.0, 0.0, 4.0],
+        ]
+        .iter()
+        .flat_map(|c| chord(c))
+        .collect();
+        let features = analyse(&minor);
+        assert_eq!(features.key, "A minor");
+    }
+
+    #[test]
+    fn silence_has_no_tempo_or_key() {
+        let features = analyse(&vec![0.0; 44100 * 5]);
+        assert_eq!(features.tempo, 0.0);
+        assert_eq!(features.key, "");
+    }
+}
@@ -0,0 +1,687 @@
+use std::f64::consts::PI;
+use std::path::{Path, PathBuf};
+
+use base64::Engine;
+use sea_orm::*;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::errors::Error;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use tokio::task::JoinSet;
+use tracing::{event, instrument, Level};
+
+use crate::database;
+use crate::entities::{prelude::*, *};
+use crate::media::Scope;
+use crate::scan;
+use crate::Config;
+
+// Fingerprints are computed as by Chromaprint's default algorithm, so they can be looked up in
+// AcoustID. The algorithm is stored with each fingerprint in case others are added.
+pub(crate) const ALGORITHM: i32 = 1;
+
+// Only the start of each file is fingerprinted, as by Chromaprint's fpcalc.
+const FINGERPRINT_LENGTH: f64 = 120.0;
+
+// Audio is mixed down to mono and resampled to this rate before it's analysed.
+pub(crate) const SAMPLE_RATE: u32 = 11025;
+
+// The resampling filter has this many taps, and is precomputed at this many phases between
+// input samples.
+const RESAMPLE_TAPS: usize = 16;
+const RESAMPLE_PHASES: usize = 1024;
+const RESAMPLE_CUTOFF: f64 = 0.8;
+const RESAMPLE_KAISER_BETA: f64 = 9.0;
+
+// Spectra are taken of overlapping frames, each a third of a frame after the last.
+const FRAME_SIZE: usize = 4096;
+const FRAME_STEP: usize = FRAME_SIZE / 3;
+
+// Energy between these frequencies is folded into the twelve notes of an octave.
+const MINIMUM_FREQUENCY: f64 = 28.0;
+const MAXIMUM_FREQUENCY: f64 = 3520.0;
+const BANDS: usize = 12;
+
+// Chroma features are smoothed over time by this filter, then normalised unless they're
+// quieter than the threshold.
+const CHROMA_FILTER: [f64; 5] = [0.25, 0.75, 1.0, 0.75, 0.25];
+const NORMALISE_THRESHOLD: f64 = 0.01;
+
+type Filter = (u8, usize, usize, usize);
+
+// Each classifier compares areas of the chroma image with a filter `(kind, band, height,
+// width)`, and quantizes the result with three thresholds into two bits of a sub-fingerprint.
+const CLASSIFIERS: [(Filter, [f64; 3]); 16] = [
+    ((0, 4, 3, 15), [1.98215, 2.35817, 2.63523]),
+    ((4, 4, 6, 15), [-1.03809, -0.651211, -0.282167]),
+    ((1, 0, 4, 16), [-0.298702, 0.119262, 0.558497]),
+    ((3, 8, 2, 12), [-0.105439, 0.0153946, 0.135898]),
+    ((3, 4, 4, 8), [-0.142891, 0.0258736, 0.200632]),
+    ((4, 0, 3, 5), [-0.826319, -0.590612, -0.368214]),
+    ((1, 2, 2, 9), [-0.557409, -0.233035, 0.0534525]),
+    ((2, 7, 3, 4), [-0.0646826, 0.00620476, 0.0784847]),
+    ((2, 6, 2, 16), [-0.192387, -0.029699, 0.215855]),
+    ((2, 1, 3, 2), [-0.0397818, -0.00568076, 0.0292026]),
+    ((5, 10, 1, 15), [-0.53823, -0.369934, -0.190235]),
+    ((3, 6, 2, 10), [-0.124877, 0.0296483, 0.139239]),
+    ((2, 1, 1, 14), [-0.101475, 0.0225617, 0.231971]),
+    ((3, 5, 6, 4), [-0.0799915, -0.00729616, 0.063262]),
+    ((1, 9, 2, 12), [-0.272556, 0.019424, 0.302559]),
+    ((3, 4, 2, 14), [-0.164292, -0.0321188, 0.0846339]),
+];
+const CLASSIFIER_WIDTH: usize = 16;
+const GRAY_CODE: [u32; 4] = [0, 1, 3, 2];
+
+// Compressed fingerprints store the distance between set bits in three bits, larger distances
+// are stored separately in five.
+const NORMAL_BITS: u32 = 3;
+const EXCEPTION_BITS: u32 = 5;
+const MAXIMUM_NORMAL_VALUE: u32 = 7;
+
+// Fingerprints are compared at offsets of up to this many sub-fingerprints, about 10 seconds,
+// to allow for different amounts of silence at the start of the audio.
+const MAXIMUM_OFFSET: isize = 80;
+
+// Fingerprints must overlap by at least this many sub-fingerprints, about 5 seconds, to be
+// compared at an offset.
+const MINIMUM_OVERLAP: usize = 40;
+
+/// Fingerprint audio files in scope that haven't been fingerprinted yet, if fingerprinting is
+/// enabled.
+/// Files are decoded by the same number of blocking workers as probe files when scanning.
+#[instrument]
+pub(crate) async fn fingerprint_audio(config: &Config, scope: &Scope) {
+    event!(Level::TRACE, "fingerprint_audio");
+    if !config.fingerprint {
+        return;
+    }
+
+    let db = database::connection(config).await;
+    let audio_files = match Audio::find()
+        .left_join(AudioFingerprint)
+        .filter(audio_fingerprint::Column::AudioFingerprintId.is_null())
+        .filter(scope.paths(audio::Column::Path))
+        .all(db)
+        .await
+    {
+        Ok(a) => a,
+        Err(e) => {
+            event!(Level::WARN, "Audio::find() unfingerprinted failure: {}", e);
+            return;
+        }
+    };
+
+    let workers = scan::scan_workers(config);
+    let mut fingerprinted = 0;
+    let mut tasks = JoinSet::new();
+    let mut audio_files = audio_files.into_iter();
+    loop {
+        // Keep every worker busy, then store each fingerprint as soon as it's done.
+        while tasks.len() < workers {
+            let Some(audio_file) = audio_files.next() else {
+                break;
+            };
+            let path = PathBuf::from(&audio_file.path).join(&audio_file.name);
+            tasks.spawn(tokio::task::spawn_blocking(move || {
+                let fingerprint = fingerprint_file(&path, &audio_file.extension);
+                (audio_file, fingerprint)
+            }));
+        }
+        let (audio_file, fingerprint) = match tasks.join_next().await {
+            Some(Ok(Ok(r))) => r,
+            Some(Ok(Err(e)) | Err(e)) => {
+                event!(Level::WARN, "fingerprint worker failure: {}", e);
+                continue;
+            }
+            None => break,
+        };
+        let Some((fingerprint, decoded)) = fingerprint else {
+            continue;
+        };
+        // The duration probed when scanning is of the whole file, the decoded duration is only
+        // known up to the fingerprint length.
+        let duration = if audio_file.duration > 0 {
+            audio_file.duration
+        } else {
+            decoded as i32
+        };
+        let audio_fingerprint = audio_fingerprint::ActiveModel {
+            created: ActiveValue::Set(chrono::Utc::now().naive_utc()),
+            audio_id: ActiveValue::Set(audio_file.audio_id),
+            algorithm: ActiveValue::Set(ALGORITHM),
+            duration: ActiveValue::Set(duration),
+            fingerprint: ActiveValue::Set(fingerprint),
+            ..Default::default()
+        };
+        match audio_fingerprint.insert(db).await {
+            Ok(_) => fingerprinted += 1,
+            Err(e) => event!(Level::WARN, "AudioFingerprint::insert() failure: {}", e),
+        }
+    }
+    event!(Level::INFO, "fingerprinted {} audio files", fingerprinted);
+}
+
+/// Compute the compressed fingerprint of an audio file, along with the number of seconds
+/// decoded. Returns None if the file can't be decoded, or is too short to fingerprint.
+fn fingerprint_file(path: &Path, extension: &str) -> Option<(String, f64)> {
+    event!(Level::TRACE, "fingerprint_file {}", path.display());
+
+    let (samples, sample_rate) = decode(path, extension, FINGERPRINT_LENGTH)?;
+    let decoded = samples.len() as f64 / sample_rate as f64;
+    let samples = resample(&samples, sample_rate);
+    let features = chroma(&samples);
+    let fingerprint = fingerprint(&features);
+    if fingerprint.is_empty() {
+        event!(Level::DEBUG, "too short to fingerprint: {}", path.display());
+        return None;
+    }
+    Some((compress(&fingerprint), decoded))
+}
+
+/// Decode up to `length` seconds from the start of the default track of an audio file, mixed
+/// down to mono.
+pub(crate) fn decode(path: &Path, extension: &str, length: f64) -> Option<(Vec<f32>, u32)> {
+    let src = match std::fs::File::open(path) {
+        Ok(s) => s,
+        Err(e) => {
+            event!(
+                Level::WARN,
+                "failed to open media {}: {}",
+                path.display(),
+                e
+            );
+            return None;
+        }
+    };
+    let mss = MediaSourceStream::new(Box::new(src), Default::default());
+    let mut hint = Hint::new();
+    if !extension.is_empty() {
+        hint.with_extension(extension);
+    }
+    let meta_opts: MetadataOptions = Default::default();
+    let fmt_opts: FormatOptions = Default::default();
+    let mut format = match symphonia::default::get_probe().format(&hint, mss, &fmt_opts, &meta_opts)
+    {
+        Ok(p) => p.format,
+        Err(e) => {
+            event!(Level::WARN, "Symphonia get_probe() failure: {}", e);
+            return None;
+        }
+    };
+
+    let track = format.default_track()?;
+    let track_id = track.id;
+    let mut decoder = match symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions::default())
+    {
+        Ok(d) => d,
+        Err(e) => {
+            event!(Level::WARN, "failed to decode {}: {}", path.display(), e);
+            return None;
+        }
+    };
+
+    let mut samples = Vec::new();
+    let mut sample_rate = 0;
+    let mut buffer: Option<SampleBuffer<f32>> = None;
+    loop {
+        let packet = match format.next_packet() {
+            Ok(p) => p,
+            // The end of the file is reported as an unexpected end of file.
+            Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => {
+                event!(Level::DEBUG, "stopped reading {}: {}", path.display(), e);
+                break;
+            }
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(d) => d,
+            // Corrupt packets are skipped, as a player would.
+            Err(Error::DecodeError(e)) => {
+                event!(Level::DEBUG, "skipping packet of {}: {}", path.display(), e);
+                continue;
+            }
+            Err(e) => {
+                event!(Level::WARN, "failed to decode {}: {}", path.display(), e);
+                return None;
+            }
+        };
+        let spec = *decoded.spec();
+        let channels = spec.channels.count().max(1);
+        sample_rate = spec.rate;
+        let buffer =
+            buffer.get_or_insert_with(|| SampleBuffer::new(decoded.capacity() as u64, spec));
+        if buffer.capacity() < decoded.capacity() * channels {
+            *buffer = SampleBuffer::new(decoded.capacity() as u64, spec);
+        }
+        buffer.copy_interleaved_ref(decoded);
+        samples.extend(
+            buffer
+                .samples()
+                .chunks(channels)
+                .map(|frame| frame.iter().sum::<f32>() / channels as f32),
+        );
+        if samples.len() as f64 >= length * sample_rate as f64 {
+            samples.truncate((length * sample_rate as f64) as usize);
+            break;
+        }
+    }
+    if sample_rate == 0 {
+        event!(Level::WARN, "no audio decoded from {}", path.display());
+        return None;
+    }
+    Some((samples, sample_rate))
+}
+
+/// Resample audio to the rate it's analysed at, with a windowed sinc filter.
+pub(crate) fn resample(samples: &[f32], sample_rate: u32) -> Vec<f32> {
+    if sample_rate == SAMPLE_RATE {
+        return samples.to_vec();
+    }
+    let step = sample_rate as f64 / SAMPLE_RATE as f64;
+    // Downsampling lowers the cutoff, so frequencies above the new Nyquist frequency don't
+    // alias.
+    let cutoff = RESAMPLE_CUTOFF * (1.0 / step).min(1.0);
+    let half = (RESAMPLE_TAPS / 2) as f64;
+    let filters: Vec<[f64; RESAMPLE_TAPS]> = (0..RESAMPLE_PHASES)
+        .map(|phase| {
+            let fraction = phase as f64 / RESAMPLE_PHASES as f64;
+            let mut taps = [0.0; RESAMPLE_TAPS];
+            for (i, tap) in taps.iter_mut().enumerate() {
+                let x = i as f64 - (half - 1.0) - fraction;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    (PI * cutoff * x).sin() / (PI * cutoff * x)
+                };
+                *tap = sinc * kaiser(x / half);
+            }
+            let sum: f64 = taps.iter().sum();
+            taps.iter_mut().for_each(|t| *t /= sum);
+            taps
+        })
+        .collect();
+
+    let length = (samples.len() as f64 / step) as usize;
+    (0..length)
+        .map(|n| {
+            let position = n as f64 * step;
+            let index = position.floor() as isize;
+            let phase = ((position - index as f64) * RESAMPLE_PHASES as f64) as usize;
+            let first = index - (RESAMPLE_TAPS / 2 - 1) as isize;
+            filters[phase.min(RESAMPLE_PHASES - 1)]
+                .iter()
+                .enumerate()
+                .map(|(i, tap)| {
+                    let j = first + i as isize;
+                    if j < 0 || j as usize >= samples.len() {
+                        0.0
+                    } else {
+                        samples[j as usize] as f64 * tap
+                    }
+                })
+                .sum::<f64>() as f32
+        })
+        .collect()
+}
+
+/// The Kaiser window, for `x` from -1 to 1.
+fn kaiser(x: f64) -> f64 {
+    if x.abs() > 1.0 {
+        return 0.0;
+    }
+    bessel_i0(RESAMPLE_KAISER_BETA * (1.0 - x * x).sqrt()) / bessel_i0(RESAMPLE_KAISER_BETA)
+}
+
+/// The zeroth order modified Bessel function of the first kind.
+fn bessel_i0(x: f64) -> f64 {
+    let (mut sum, mut term, mut k) = (1.0, 1.0, 1.0);
+    while term > sum * 1e-12 {
+        term *= (x / (2.0 * k)) * (x / (2.0 * k));
+        sum += term;
+        k += 1.0;
+    }
+    sum
+}
+
+/// Compute the chroma features of audio, the energy of each note of the octave in each frame,
+/// smoothed over time and normalised.
+fn chroma(samples: &[f32]) -> Vec<[f64; BANDS]> {
+    let window: Vec<f64> = (0..FRAME_SIZE)
+        .map(|i| 0.54 - 0.46 * (2.0 * PI * i as f64 / (FRAME_SIZE - 1) as f64).cos())
+        .collect();
+    let index = |frequency: f64| (FRAME_SIZE as f64 * frequency / SAMPLE_RATE as f64).round();
+    let first = (index(MINIMUM_FREQUENCY) as usize).max(1);
+    let last = (index(MAXIMUM_FREQUENCY) as usize).min(FRAME_SIZE / 2);
+    // The note of each frequency bin, counting from A0.
+    let notes: Vec<usize> = (first..last)
+        .map(|i| {
+            let frequency = i as f64 * SAMPLE_RATE as f64 / FRAME_SIZE as f64;
+            let octave = (frequency / 27.5).log2();
+            ((BANDS as f64 * (octave - octave.floor())) as usize).min(BANDS - 1)
+        })
+        .collect();
+
+    let mut frames = Vec::new();
+    let mut start = 0;
+    let mut buffer = vec![(0.0, 0.0); FRAME_SIZE];
+    while start + FRAME_SIZE <= samples.len() {
+        for (i, value) in buffer.iter_mut().enumerate() {
+            *value = (samples[start + i] as f64 * window[i], 0.0);
+        }
+        fft(&mut buffer);
+        let mut features = [0.0; BANDS];
+        for i in first..last {
+            let (re, im) = buffer[i];
+            features[notes[i - first]] += re * re + im * im;
+        }
+        frames.push(features);
+        start += FRAME_STEP;
+    }
+
+    frames
+        .windows(CHROMA_FILTER.len())
+        .map(|window| {
+            let mut features = [0.0; BANDS];
+            for (frame, coefficient) in window.iter().zip(CHROMA_FILTER) {
+                for (feature, value) in features.iter_mut().zip(frame) {
+                    *feature += value * coefficient;
+                }
+            }
+            let norm = features.iter().map(|f| f * f).sum::<f64>().sqrt();
+            if norm < NORMALISE_THRESHOLD {
+                features = [0.0; BANDS];
+            } else {
+                features.iter_mut().for_each(|f| *f /= norm);
+            }
+            features
+        })
+        .collect()
+}
+
+/// An in place radix-2 fast Fourier transform, of a buffer whose length is a power of two.
+pub(crate) fn fft(buffer: &mut [(f64, f64)]) {
+    let n = buffer.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            buffer.swap(i, j);
+        }
+    }
+    let mut length = 2;
+    while length <= n {
+        let angle = -2.0 * PI / length as f64;
+        for start in (0..n).step_by(length) {
+            for k in 0..length / 2 {
+                let (sin, cos) = (angle * k as f64).sin_cos();
+                let (re, im) = buffer[start + k + length / 2];
+                let odd = (re * cos - im * sin, re * sin + im * cos);
+                let even = buffer[start + k];
+                buffer[start + k] = (even.0 + odd.0, even.1 + odd.1);
+                buffer[start + k + length / 2] = (even.0 - odd.0, even.1 - odd.1);
+            }
+        }
+        length <<= 1;
+    }
+}
+
+/// Compute the fingerprint of chroma features, one 32 bit sub-fingerprint for each position of
+/// the classifiers along the features.
+fn fingerprint(features: &[[f64; BANDS]]) -> Vec<u32> {
+    // An integral image of the features, so the area of any rectangle takes four lookups.
+    let mut image = vec![[0.0; BANDS]; features.len()];
+    for (x, frame) in features.iter().enumerate() {
+        let mut row = 0.0;
+        for y in 0..BANDS {
+            row += frame[y];
+            image[x][y] = row + if x > 0 { image[x - 1][y] } else { 0.0 };
+        }
+    }
+    let area = |x1: usize, y1: usize, x2: usize, y2: usize| -> f64 {
+        if x2 <= x1 || y2 <= y1 {
+            return 0.0;
+        }
+        let mut area = image[x2 - 1][y2 - 1];
+        if x1 > 0 {
+            area -= image[x1 - 1][y2 - 1];
+        }
+        if y1 > 0 {
+            area -= image[x2 - 1][y1 - 1];
+        }
+        if x1 > 0 && y1 > 0 {
+            area += image[x1 - 1][y1 - 1];
+        }
+        area
+    };
+
+    let positions = (features.len() + 1).saturating_sub(CLASSIFIER_WIDTH);
+    (0..positions)
+        .map(|x| {
+            CLASSIFIERS
+                .iter()
+                .fold(0, |bits, ((kind, y, h, w), thresholds)| {
+                    let (y, h, w) = (*y, *h, *w);
+                    let (a, b) = match kind {
+                        0 => (area(x, y, x + w, y + h), 0.0),
+                        1 => (
+                            area(x, y + h / 2, x + w, y + h),
+                            area(x, y, x + w, y + h / 2),
+                        ),
+                        2 => (
+                            area(x + w / 2, y, x + w, y + h),
+                            area(x, y, x + w / 2, y + h),
+                        ),
+                        3 => (
+                            area(x, y + h / 2, x + w / 2, y + h)
+                                + area(x + w / 2, y, x + w, y + h / 2),
+                            area(x, y, x + w / 2, y + h / 2)
+                                + area(x + w / 2, y + h / 2, x + w, y + h),
+                        ),
+                        4 => (
+                            area(x, y + h / 3, x + w, y + 2 * h / 3),
+                            area(x, y, x + w, y + h / 3) + area(x, y + 2 * h / 3, x + w, y + h),
+                        ),
+                        _ => (
+                            area(x + w / 3, y, x + 2 * w / 3, y + h),
+                            area(x, y, x + w / 3, y + h) + area(x + 2 * w / 3, y, x + w, y + h),
+                        ),
+                    };
+                    let value = ((1.0 + a) / (1.0 + b)).ln();
+                    let code = thresholds.iter().filter(|t| value >= **t).count();
+                    (bits << 2) | GRAY_CODE[code]
+                })
+        })
+        .collect()
+}
+
+/// Compress a fingerprint as Chromaprint does, so it can be submitted to AcoustID. Each
+/// sub-fingerprint is XORed with the one before, and the distances between its set bits are
+/// packed into as few bits as possible.
+fn compress(fingerprint: &[u32]) -> String {
+    let mut bits = Vec::new();
+    let mut previous = 0;
+    for sub in fingerprint {
+        let (mut x, mut bit, mut last) = (sub ^ previous, 1, 0);
+        while x != 0 {
+            if x & 1 != 0 {
+                bits.push(bit - last);
+                last = bit;
+            }
+            x >>= 1;
+            bit += 1;
+        }
+        bits.push(0);
+        previous = *sub;
+    }
+
+    let size = fingerprint.len();
+    let mut output = vec![
+        ALGORITHM as u8,
+        (size >> 16) as u8,
+        (size >> 8) as u8,
+        size as u8,
+    ];
+    pack(
+        &mut output,
+        bits.iter().map(|b| (*b).min(MAXIMUM_NORMAL_VALUE)),
+        NORMAL_BITS,
+    );
+    pack(
+        &mut output,
+        bits.iter()
+            .filter(|b| **b >= MAXIMUM_NORMAL_VALUE)
+            .map(|b| b - MAXIMUM_NORMAL_VALUE),
+        EXCEPTION_BITS,
+    );
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(output)
+}
+
+/// Decompress a fingerprint compressed by `compress()`, or by Chromaprint. Returns None if it
+/// isn't a valid compressed fingerprint.
+pub(crate) fn decompress(compressed: &str) -> Option<Vec<u32>> {
+    let data = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(compressed)
+        .ok()?;
+    if data.len() < 4 {
+        return None;
+    }
+    let size = u32::from_be_bytes([0, data[1], data[2], data[3]]) as usize;
+
+    // Each sub-fingerprint ends with a zero, so the normal values end at the last one.
+    let mut bits = Vec::new();
+    let mut ends = 0;
+    for value in unpack(&data[4..], NORMAL_BITS) {
+        if ends == size {
+            break;
+        }
+        if value == 0 {
+            ends += 1;
+        }
+        bits.push(value);
+    }
+    if ends < size {
+        return None;
+    }
+    let offset = 4 + (bits.len() * NORMAL_BITS as usize).div_ceil(8);
+    let mut exceptions = unpack(data.get(offset..)?, EXCEPTION_BITS);
+    for bit in bits.iter_mut().filter(|b| **b == MAXIMUM_NORMAL_VALUE) {
+        *bit += exceptions.next()?;
+    }
+
+    let mut fingerprint = Vec::with_capacity(size);
+    let (mut value, mut last, mut previous) = (0u32, 0, 0);
+    for bit in bits {
+        if bit == 0 {
+            previous ^= value;
+            fingerprint.push(previous);
+            (value, last) = (0, 0);
+        } else {
+            last += bit;
+            value |= 1u32.checked_shl(last - 1)?;
+        }
+    }
+    Some(fingerprint)
+}
+
+/// How similar two fingerprints are, from 0 to 1, as the fraction of bits that match where they
+/// line up best. Fingerprints of unrelated audio are about 0.5 similar.
+pub(crate) fn similarity(a: &[u32], b: &[u32]) -> f64 {
+    let minimum_overlap = MINIMUM_OVERLAP.min(a.len()).min(b.len()).max(1);
+    let mut best = 0.0;
+    for offset in -MAXIMUM_OFFSET..=MAXIMUM_OFFSET {
+        let (a, b) = if offset < 0 {
+            (a, b.get(offset.unsigned_abs()..).unwrap_or_default())
+        } else {
+            (a.get(offset as usize..).unwrap_or_default(), b)
+        };
+        let overlap = a.len().min(b.len());
+        if overlap < minimum_overlap {
+            continue;
+        }
+        let errors: u32 = a.iter().zip(b).map(|(a, b)| (a ^ b).count_ones()).sum();
+        let similarity = 1.0 - errors as f64 / (overlap * 32) as f64;
+        if similarity > best {
+            best = similarity;
+        }
+    }
+    best
+}
+
+/// Unpack values of `width` bits from bytes, least significant bits first.
+fn unpack(data: &[u8], width: u32) -> impl Iterator<Item = u32> + '_ {
+    let count = data.len() * 8 / width as usize;
+    (0..count).map(move |i| {
+        let start = i * width as usize;
+        (0..width as usize).fold(0, |value, bit| {
+            let position = start + bit;
+            let set = (data[position / 8] >> (position % 8)) & 1;
+            value | (set as u32) << bit
+        })
+    })
+}
+
+/// Pack values of `width` bits into bytes, least significant bits first.
+fn pack(output: &mut Vec<u8>, values: impl Iterator<Item = u32>, width: u32) {
+    let (mut buffer, mut length) = (0u32, 0);
+    for value in values {
+        buffer |= value << length;
+        length += width;
+        while length >= 8 {
+            output.push(buffer as u8);
+            buffer >>= 8;
+            length -= 8;
+        }
+    }
+    if length > 0 {
+        output.push(buffer as u8);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn fingerprint_of(samples: &[f32]) -> Vec<u32> {
+        fingerprint(&chroma(&resample(samples, 44100)))
+    }
+
+    #[test]
+    fn identical_audio_has_identical_fingerprints() {
+        let first = fingerprint_of(&testing::chords(44100, &testing::PROGRESSION, 3));
+        let second = fingerprint_of(&testing::chords(44100, &testing::PROGRESSION, 3));
+        assert!(!first.is_empty());
+        assert_eq!(first, second);
+        assert_eq!(decompress(&compress(&first)), Some(first.clone()));
+        assert_eq!(similarity(&first, &second), 1.0);
+    }
+
+    #[test]
+    fn fingerprints_are_stable_under_gain_changes() {
+        let samples = testing::chords(44100, &testing::PROGRESSION, 3);
+        let original = fingerprint_of(&samples);
+        let quieter: Vec<f32> = samples.iter().map(|s| s * 0.7).collect();
+        let similar = similarity(&original, &fingerprint_of(&quieter));
+
+        let mut reversed = testing::PROGRESSION;
+        reversed.reverse();
+        let different = similarity(
+            &original,
+            &fingerprint_of(&testing::chords(44100, &reversed[1..], 3)),
+        );
+        assert!(similar > 0.95, "similarity {}", similar);
+        assert!(different < 0.8, "similarity {}", different);
+    }
+}
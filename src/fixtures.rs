@@ -0,0 +1,499 @@
+use std::convert::Infallible;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use clap::Subcommand;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use percent_encoding::percent_decode_str;
+use tracing::{event, instrument, Level};
+
+use crate::acoustid;
+use crate::cache;
+use crate::musicbrainz;
+use crate::Config;
+
+// Requests larger than this aren't made by Ria, and are refused.
+const MAXIMUM_REQUEST_SIZE: usize = 64 * 1024;
+
+/// Commands for testing against recorded responses instead of MusicBrainz and AcoustID.
+#[derive(Subcommand, Debug, Clone)]
+pub(crate) enum FixturesCommand {
+    /// Serve recorded responses as MusicBrainz and AcoustID would, point `--musicbrainz-url` at
+    /// ADDRESS/ws/2 and `--acoustid-url` at ADDRESS/v2
+    Serve {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:8099")]
+        address: String,
+        /// Directory of recorded responses, defaults to the metadata cache
+        #[arg(long)]
+        directory: Option<String>,
+    },
+}
+
+/// Run a fixtures command.
+#[instrument]
+pub(crate) async fn run_command(config: &Config, command: &FixturesCommand) -> anyhow::Result<()> {
+    event!(Level::TRACE, "run_command");
+
+    match command {
+        FixturesCommand::Serve { address, directory } => {
+            let directory = match directory {
+                Some(d) => PathBuf::from(d),
+                None => cache::metadata_cache(config),
+            };
+            serve_fixtures(address, directory).await
+        }
+    }
+}
+
+/// Serve responses recorded in the metadata cache, until interrupted. Responses are recorded by
+/// running Ria against MusicBrainz with `--metadata-cache` set to the fixtures directory.
+async fn serve_fixtures(address: &str, directory: PathBuf) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    println!(
+        "serving responses recorded in {} at http://{}/ws/2 and http://{1}/v2",
+        directory.display(),
+        listener.local_addr()?
+    );
+    serve(listener, directory).await
+}
+
+/// Answer requests to a listener with recorded responses.
+async fn serve(listener: TcpListener, directory: PathBuf) -> anyhow::Result<()> {
+    listener.set_nonblocking(true)?;
+    let directory = Arc::new(directory);
+    let service = make_service_fn(move |_| {
+        let directory = directory.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                respond(request, directory.clone())
+            }))
+        }
+    });
+    Server::from_tcp(listener)?
+        .http1_max_buf_size(MAXIMUM_REQUEST_SIZE)
+        // Every request gets a connection of its own, so no client holds on to one.
+        .http1_keepalive(false)
+        .serve(service)
+        .await?;
+    Ok(())
+}
+
+/// Answer a request with the recorded response to the same query, or 404 Not Found.
+async fn respond(
+    request: Request<Body>,
+    directory: Arc<PathBuf>,
+) -> Result<Response<Body>, Infallible> {
+    if request.method() != Method::GET {
+        event!(
+            Level::WARN,
+            "refusing {} {}",
+            request.method(),
+            request.uri()
+        );
+        return Ok(json_response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            r#"{"error":"Method Not Allowed"}"#.to_string(),
+        ));
+    }
+    let target = request
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+    let (service, key) = fixture_key(target);
+
+    Ok(match cache::load(&directory, service, &key) {
+        Some(cached) => {
+            event!(Level::INFO, "serving fixture for {}", key);
+            json_response(StatusCode::OK, cached.response.to_string())
+        }
+        None => {
+            event!(Level::WARN, "no fixture for {}", key);
+            json_response(
+                StatusCode::NOT_FOUND,
+                r#"{"error":"Not Found"}"#.to_string(),
+            )
+        }
+    })
+}
+
+fn json_response(status: StatusCode, body: String) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
+
+/// Rebuild the service and cache key of a request from its target, as `MusicBrainz` or
+/// `AcoustId` built them before sending.
+fn fixture_key(target: &str) -> (&'static str, String) {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    // The format is always JSON and the AcoustID API key is the same for every request, neither
+    // are part of the key.
+    let (service, path, ignored) = match path.strip_prefix("/v2/") {
+        Some(path) => (acoustid::SERVICE, path, &["format", "client"][..]),
+        None => (
+            musicbrainz::SERVICE,
+            path.strip_prefix("/ws/2/")
+                .unwrap_or(path.trim_start_matches('/')),
+            &["fmt"][..],
+        ),
+    };
+    let query: Vec<(String, String)> = query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(k, v)| (decode(k), decode(v)))
+        .filter(|(k, _)| !ignored.contains(&k.as_str()))
+        .collect();
+    let query: Vec<(&str, &str)> = query
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect();
+    (service, cache::key(&decode(path), &query))
+}
+
+/// Decode a component of a URL, where spaces in the query are sent as "+".
+fn decode(value: &str) -> String {
+    percent_decode_str(&value.replace('+', " "))
+        .decode_utf8_lossy()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use sea_orm::*;
+    use tokio::sync::oneshot;
+
+    use super::*;
+    use crate::database;
+    use crate::entities::{prelude::*, *};
+    use crate::media::{self, Scope};
+    use crate::musicbrainz::{PayloadType, QueuePayload};
+    use crate::releases;
+    use crate::scan;
+    use crate::testing::{self, TestLibrary};
+
+    // The artist credited with "Low Water", of the two named "The Tides" in the fixtures.
+    const ARTIST_ID: &str = "43b16b01-9a4e-4ea9-9998-e530a4c92b21";
+    const RELEASE_ID: &str = "a4011a37-8d27-41fe-9ea4-593d44583d58";
+    // The recording of "Undertow", which AcoustID matched the fingerprint of the chords to.
+    const RECORDING_ID: &str = "9709039c-d66a-4e61-aa1a-af892734142f";
+
+    /// Serve the fixtures committed with Ria on a free port, returning its address.
+    fn serve_committed_fixtures() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+        tokio::spawn(serve(listener, directory));
+        format!("http://{}", address)
+    }
+
+    /// A library querying the fixtures instead of MusicBrainz and AcoustID, with an empty
+    /// metadata cache.
+    async fn fixtures_library(flags: &[&str]) -> TestLibrary {
+        let address = serve_committed_fixtures();
+        let musicbrainz_url = format!("{}/ws/2", address);
+        let acoustid_url = format!("{}/v2", address);
+        let mut args = vec![
+            "--musicbrainz-url",
+            &musicbrainz_url,
+            "--musicbrainz-rate",
+            "100",
+            "--musicbrainz-burst",
+            "100",
+            "--acoustid-url",
+            &acoustid_url,
+        ];
+        args.extend(flags);
+        let mut test = TestLibrary::new(&args).await;
+        let cache = test.library.with_file_name("metadata");
+        test.config.metadata_cache = Some(cache.display().to_string());
+        test
+    }
+
+    /// Scan an album by The Tides of audio files with tags, ie ("Undertow", "1") for each title
+    /// and track.
+    async fn scan_album(test: &TestLibrary, album: &str, tracks: &[(&str, &str)]) -> Vec<i32> {
+        let config = &test.config;
+        let db = database::connection(config).await;
+        for (title, _) in tracks {
+            testing::write_wav(
+                &test.path(&format!("The Tides/{}/{}.wav", album, title)),
+                44100,
+                &testing::sine(44100, 440.0, -6.0, 1.0),
+            );
+        }
+        scan::scan_media_files(config).await;
+        let mut audio_ids = Vec::new();
+        for (title, track_number) in tracks {
+            let audio = Audio::find()
+                .filter(audio::Column::Name.eq(format!("{}.wav", title)))
+                .one(db)
+                .await
+                .unwrap()
+                .unwrap();
+            let tags = [
+                ("Artist", "The Tides"),
+                ("AlbumArtist", "The Tides"),
+                ("Album", album),
+                ("TrackTitle", title),
+                ("TrackNumber", track_number),
+            ];
+            for (name, value) in tags {
+                AudioTag::insert(audio_tag::ActiveModel {
+                    audio_id: ActiveValue::Set(audio.audio_id),
+                    name: ActiveValue::Set(name.to_string()),
+                    key: ActiveValue::Set(name.to_uppercase()),
+                    value: ActiveValue::Set(value.to_string()),
+                    position: ActiveValue::Set(0),
+                    ..Default::default()
+                })
+                .exec(db)
+                .await
+                .unwrap();
+            }
+            audio_ids.push(audio.audio_id);
+        }
+        media::group_albums(config, &Scope::Library).await;
+        audio_ids
+    }
+
+    #[test]
+    fn fixture_key_ignores_the_format_and_api_key() {
+        assert_eq!(
+            fixture_key("/ws/2/artist?query=artist%3A%22The+Tides%22&limit=10&fmt=json"),
+            (
+                musicbrainz::SERVICE,
+                "artist?query=artist:\"The Tides\"&limit=10".to_string()
+            )
+        );
+        assert_eq!(
+            fixture_key("/ws/2/release/a4011a37?inc=recordings%2Brelease-groups&fmt=json"),
+            (
+                musicbrainz::SERVICE,
+                "release/a4011a37?inc=recordings+release-groups".to_string()
+            )
+        );
+        assert_eq!(
+            fixture_key("/v2/lookup?client=KEY&meta=recordings&duration=10&format=json"),
+            (
+                acoustid::SERVICE,
+                "lookup?meta=recordings&duration=10".to_string()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn recorded_responses_are_served() {
+        let url = serve_committed_fixtures();
+        let client = reqwest::Client::new();
+        let get = |target: &str| client.get(format!("{}{}", url, target)).send();
+
+        let response = get(&format!(
+            "/ws/2/release/{}?inc=recordings%2Brelease-groups&fmt=json",
+            RELEASE_ID
+        ))
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let release: serde_json::Value =
+            serde_json::from_str(&response.text().await.unwrap()).unwrap();
+        assert_eq!(release["id"], RELEASE_ID);
+
+        let response = get("/ws/2/artist?query=nothing&fmt=json").await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = client
+            .post(format!("{}/ws/2/artist", url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+        // Requests larger than any Ria makes are refused, rather than read without limit.
+        let response = client
+            .get(format!("{}/ws/2/artist", url))
+            .header("X-Padding", "a".repeat(4 * MAXIMUM_REQUEST_SIZE))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            response.status(),
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+        );
+    }
+
+    #[tokio::test]
+    async fn queue_matches_artists_and_releases() {
+        let test = fixtures_library(&[]).await;
+        let config = &test.config;
+        let db = database::connection(config).await;
+        let audio_ids = scan_album(&test, "Low Water", &[("Undertow", "1"), ("Salt", "2")]).await;
+        let album = Album::find().one(db).await.unwrap().unwrap();
+
+        musicbrainz::add_to_queue(
+            config,
+            QueuePayload {
+                payload_type: PayloadType::AudioArtist,
+                id: audio_ids[0],
+                value: "The Tides".to_string(),
+            },
+        )
+        .await;
+        musicbrainz::add_to_queue(
+            config,
+            QueuePayload {
+                payload_type: PayloadType::AlbumRelease,
+                id: album.album_id,
+                value: album.name,
+            },
+        )
+        .await;
+        // Exits once the queue is empty.
+        let (tx, rx) = oneshot::channel();
+        tx.send(true).unwrap();
+        musicbrainz::process_queue(config, rx).await;
+        assert!(MusicbrainzQueue::find().all(db).await.unwrap().is_empty());
+
+        // Two artists are called "The Tides", the one credited with the album and track wins.
+        let artist = Artist::find().one(db).await.unwrap().unwrap();
+        assert_eq!(artist.musicbrainz_id, ARTIST_ID);
+        assert_eq!(artist.match_status, "matched");
+        let candidates = ArtistCandidate::find()
+            .filter(artist_candidate::Column::ArtistId.eq(artist.artist_id))
+            .all(db)
+            .await
+            .unwrap();
+        assert_eq!(candidates.len(), 2);
+        let linked = AudioArtist::find()
+            .filter(audio_artist::Column::AudioId.eq(audio_ids[0]))
+            .one(db)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(linked.artist_id, artist.artist_id);
+
+        let album = Album::find().one(db).await.unwrap().unwrap();
+        assert_eq!(album.musicbrainz_release_id.as_deref(), Some(RELEASE_ID));
+        for (audio_id, title) in audio_ids.into_iter().zip(["Undertow", "Salt"]) {
+            let audio = Audio::find_by_id(audio_id).one(db).await.unwrap().unwrap();
+            assert_eq!(audio.musicbrainz_title.as_deref(), Some(title));
+        }
+    }
+
+    #[tokio::test]
+    async fn queue_links_only_the_albums_of_the_audio() {
+        let test = fixtures_library(&[]).await;
+        let config = &test.config;
+        let db = database::connection(config).await;
+        let audio_ids = scan_album(&test, "Low Water", &[("Undertow", "1")]).await;
+        let other_ids = scan_album(&test, "High Water", &[("Flood", "1")]).await;
+        AudioTag::insert(audio_tag::ActiveModel {
+            audio_id: ActiveValue::Set(other_ids[0]),
+            name: ActiveValue::Set("MusicBrainzAlbumArtistId".to_string()),
+            key: ActiveValue::Set("MUSICBRAINZ_ALBUMARTISTID".to_string()),
+            value: ActiveValue::Set(ARTIST_ID.to_string()),
+            position: ActiveValue::Set(0),
+            ..Default::default()
+        })
+        .exec(db)
+        .await
+        .unwrap();
+
+        musicbrainz::add_to_queue(
+            config,
+            QueuePayload {
+                payload_type: PayloadType::AudioArtist,
+                id: audio_ids[0],
+                value: "The Tides".to_string(),
+            },
+        )
+        .await;
+        let (tx, rx) = oneshot::channel();
+        tx.send(true).unwrap();
+        musicbrainz::process_queue(config, rx).await;
+
+        // The other album is tagged with the same artist, but it's linked once its own audio
+        // files' artists have been looked up.
+        let artist = Artist::find().one(db).await.unwrap().unwrap();
+        for (name, artist_id) in [("Low Water", Some(artist.artist_id)), ("High Water", None)] {
+            let album = Album::find()
+                .filter(album::Column::Name.eq(name))
+                .one(db)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(album.artist_id, artist_id, "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn queue_links_untagged_audio_to_its_acoustid_recording() {
+        let test = fixtures_library(&["--fingerprint", "--acoustid-api-key", "KEY"]).await;
+        let config = &test.config;
+        let db = database::connection(config).await;
+        testing::write_wav(
+            &test.path("Unknown/track01.wav"),
+            44100,
+            &testing::chords(44100, &testing::PROGRESSION, 3),
+        );
+        // Fingerprints the audio file and queues its lookup, as it has no tags.
+        scan::scan_media_files(config).await;
+
+        let (tx, rx) = oneshot::channel();
+        tx.send(true).unwrap();
+        musicbrainz::process_queue(config, rx).await;
+
+        let audio = Audio::find().one(db).await.unwrap().unwrap();
+        assert_eq!(
+            audio.musicbrainz_recording_id.as_deref(),
+            Some(RECORDING_ID)
+        );
+        assert_eq!(audio.musicbrainz_title.as_deref(), Some("Undertow"));
+        let matches = AudioAcoustid::find().all(db).await.unwrap();
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].audio_id, audio.audio_id);
+        assert_eq!(matches[0].musicbrainz_recording_id, RECORDING_ID);
+        let fingerprint = AudioFingerprint::find().one(db).await.unwrap().unwrap();
+        assert!(fingerprint.looked_up_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn unconfident_release_matches_are_not_stored() {
+        let test = fixtures_library(&[]).await;
+        let config = &test.config;
+        let db = database::connection(config).await;
+        // The search finds "Low Water", but with too many tracks and none of its titles.
+        let tracks = [
+            ("Storm", "1"),
+            ("Gale", "2"),
+            ("Squall", "3"),
+            ("Calm", "4"),
+        ];
+        let audio_ids = scan_album(&test, "Low Water", &tracks).await;
+        let album = Album::find().one(db).await.unwrap().unwrap();
+
+        releases::match_album_release(config, album.album_id)
+            .await
+            .unwrap();
+
+        let album = Album::find().one(db).await.unwrap().unwrap();
+        assert_eq!(album.musicbrainz_release_id.as_deref(), Some(""));
+        assert_eq!(album.musicbrainz_release_group_id, None);
+        let score = album.musicbrainz_score.unwrap();
+        assert!(score > 0 && score < 70, "{}", score);
+        for audio_id in audio_ids {
+            let audio = Audio::find_by_id(audio_id).one(db).await.unwrap().unwrap();
+            assert_eq!(audio.musicbrainz_recording_id.as_deref(), Some(""));
+            assert_eq!(audio.musicbrainz_title, None);
+        }
+    }
+}
@@ -0,0 +1,801 @@
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use sea_orm::*;
+use serde::{Deserialize, Serialize};
+use symphonia::core::audio::{Channels, SampleBuffer};
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::errors::Error;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use tokio::task::JoinSet;
+use tracing::{event, instrument, Level};
+
+use crate::database;
+use crate::entities::{prelude::*, *};
+use crate::media::{MediaList, Scope, ALBUM_BATCH_SIZE};
+use crate::scan;
+use crate::Config;
+
+// ReplayGain 2.0 normalises audio to this integrated loudness, in LUFS.
+const REFERENCE_LOUDNESS: f64 = -18.0;
+
+// Loudness is measured over blocks of 400ms, overlapping by 75%, as by EBU R128. Blocks are
+// summed from sub-blocks of 100ms as they're decoded.
+const BLOCK_DURATION: f64 = 0.4;
+const SUB_BLOCKS: usize = 4;
+
+// Blocks quieter than this, in LUFS, are silence and don't count towards the loudness.
+const ABSOLUTE_GATE: f64 = -70.0;
+
+// Blocks more than this many LU quieter than the loudness of the remaining blocks don't count
+// either, so quiet passages don't lower the loudness of a loud track.
+const RELATIVE_GATE: f64 = -10.0;
+
+// Surround channels are weighted louder than front channels, and LFE channels aren't counted.
+const SURROUND_WEIGHT: f64 = 1.41;
+
+// The K-weighting filter of ITU-R BS.1770, as a high shelf modelling the acoustic effect of the
+// head followed by a high pass, designed for any sample rate as by libebur128.
+const SHELF_FREQUENCY: f64 = 1681.974450955533;
+const SHELF_GAIN: f64 = 3.999843853973347;
+const SHELF_Q: f64 = 0.7071752369554196;
+const HIGH_PASS_FREQUENCY: f64 = 38.13547087602444;
+const HIGH_PASS_Q: f64 = 0.5003270373238773;
+
+// True peaks are found by oversampling 4 times with this polyphase filter from ITU-R BS.1770,
+// as peaks between samples can clip once converted to analogue or lossy formats. Audio at
+// this sample rate or above is already oversampled enough.
+const TRUE_PEAK_FILTER: [[f64; 12]; 4] = [
+    [
+        0.0017089843750,
+        0.0109863281250,
+        -0.0196533203125,
+        0.0332031250000,
+        -0.0594482421875,
+        0.1373291015625,
+        0.9721679687500,
+        -0.1022949218750,
+        0.0476074218750,
+        -0.0266113281250,
+        0.0148925781250,
+        -0.0083007812500,
+    ],
+    [
+        -0.0291748046875,
+        0.0292968750000,
+        -0.0517578125000,
+        0.0891113281250,
+        -0.1665039062500,
+        0.4650878906250,
+        0.7797851562500,
+        -0.2003173828125,
+        0.1015625000000,
+        -0.0582275390625,
+        0.0330810546875,
+        -0.0189208984375,
+    ],
+    [
+        -0.0189208984375,
+        0.0330810546875,
+        -0.0582275390625,
+        0.1015625000000,
+        -0.2003173828125,
+        0.7797851562500,
+        0.4650878906250,
+        -0.1665039062500,
+        0.0891113281250,
+        -0.0517578125000,
+        0.0292968750000,
+        -0.0291748046875,
+    ],
+    [
+        -0.0083007812500,
+        0.0148925781250,
+        -0.0266113281250,
+        0.0476074218750,
+        -0.1022949218750,
+        0.9721679687500,
+        0.1373291015625,
+        -0.0594482421875,
+        0.0332031250000,
+        -0.0196533203125,
+        0.0109863281250,
+        0.0017089843750,
+    ],
+];
+const TRUE_PEAK_MAX_RATE: u32 = 96000;
+
+// The names of ReplayGain tags, by their standard name or their key in the audio file.
+const TRACK_GAIN_TAGS: [&str; 2] = ["ReplayGainTrackGain", "REPLAYGAIN_TRACK_GAIN"];
+const TRACK_PEAK_TAGS: [&str; 2] = ["ReplayGainTrackPeak", "REPLAYGAIN_TRACK_PEAK"];
+const ALBUM_GAIN_TAGS: [&str; 2] = ["ReplayGainAlbumGain", "REPLAYGAIN_ALBUM_GAIN"];
+const ALBUM_PEAK_TAGS: [&str; 2] = ["ReplayGainAlbumPeak", "REPLAYGAIN_ALBUM_PEAK"];
+
+/// Which ReplayGain is applied when playing.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum GainMode {
+    /// Play every track at the same loudness.
+    Track,
+    /// Play every album at the same loudness, keeping the differences between its tracks.
+    Album,
+    /// Play audio files as they are.
+    Off,
+}
+
+/// The loudness and true peak of an audio file, and the blocks its loudness was measured
+/// over, so it can be combined with the other audio files on its album.
+struct Measurement {
+    /// The mean square of each block, weighted by channel.
+    blocks: Vec<f64>,
+    /// The true peak, as a linear amplitude.
+    peak: f64,
+}
+
+/// The loudness of an audio file, and the gain applied to play it at the reference loudness.
+#[derive(Debug, Default)]
+struct Gain {
+    loudness: Option<f64>,
+    true_peak: Option<f64>,
+    track_gain: Option<f64>,
+    album_gain: Option<f64>,
+    album_peak: Option<f64>,
+}
+
+/// Measure the loudness and true peak of audio files in scope that haven't been measured, as by EBU
+/// R128, along with the loudness of the albums they're on. Albums are measured as a whole,
+/// so all audio files of an album with a new or changed audio file are measured again. With
+/// `replaygain_tags`, ReplayGain tags are used instead for albums that are fully tagged.
+#[instrument]
+pub(crate) async fn analyse_audio(config: &Config, scope: &Scope) {
+    event!(Level::TRACE, "analyse_audio");
+    if !config.loudness {
+        return;
+    }
+
+    let db = database::connection(config).await;
+    let unmeasured: Vec<i32> = match Audio::find()
+        .filter(audio::Column::Loudness.is_null())
+        .filter(scope.paths(audio::Column::Path))
+        .all(db)
+        .await
+    {
+        Ok(a) => a.into_iter().map(|a| a.audio_id).collect(),
+        Err(e) => {
+            event!(Level::WARN, "Audio::find() unmeasured failure: {}", e);
+            return;
+        }
+    };
+    if unmeasured.is_empty() {
+        return;
+    }
+
+    // Audio files are measured with the other audio files on their album, or alone if they
+    // aren't on one.
+    let albums: HashMap<i32, i32> = match AlbumAudio::find()
+        .filter(scope.albums(album_audio::Column::AlbumId))
+        .all(db)
+        .await
+    {
+        Ok(a) => a.into_iter().map(|a| (a.audio_id, a.album_id)).collect(),
+        Err(e) => {
+            event!(Level::WARN, "AlbumAudio::find() failure: {}", e);
+            return;
+        }
+    };
+    let mut album_audio: HashMap<i32, Vec<i32>> = HashMap::new();
+    for (audio_id, album_id) in &albums {
+        album_audio.entry(*album_id).or_default().push(*audio_id);
+    }
+    let mut groups: Vec<Vec<i32>> = Vec::new();
+    for audio_id in unmeasured {
+        match albums.get(&audio_id) {
+            // Each album is measured once, however many of its audio files are unmeasured.
+            Some(album_id) => groups.extend(album_audio.remove(album_id)),
+            None => groups.push(vec![audio_id]),
+        }
+    }
+
+    // Audio files are loaded in batches, as there's a limit to the number of ids in a query.
+    let audio_ids: Vec<i32> = groups.iter().flatten().copied().collect();
+    let mut audio_files: HashMap<i32, audio::Model> = HashMap::new();
+    for audio_ids in audio_ids.chunks(ALBUM_BATCH_SIZE) {
+        match Audio::find()
+            .filter(audio::Column::AudioId.is_in(audio_ids.to_vec()))
+            .all(db)
+            .await
+        {
+            Ok(a) => audio_files.extend(a.into_iter().map(|a| (a.audio_id, a))),
+            Err(e) => {
+                event!(Level::WARN, "Audio::find() album audio failure: {}", e);
+                return;
+            }
+        }
+    }
+    let mut groups: Vec<Vec<audio::Model>> = groups
+        .into_iter()
+        .map(|group| {
+            group
+                .into_iter()
+                .filter_map(|audio_id| audio_files.remove(&audio_id))
+                .collect()
+        })
+        .collect();
+
+    if config.replaygain_tags {
+        let mut untagged = Vec::new();
+        for group in groups {
+            match tagged_gains(db, &group).await {
+                Some(gains) => store_gains(db, gains).await,
+                None => untagged.push(group),
+            }
+        }
+        groups = untagged;
+    }
+
+    let workers = scan::scan_workers(config);
+    let mut measured = 0;
+    let mut tasks = JoinSet::new();
+    let mut pending: HashMap<usize, (usize, Vec<(i32, Measurement)>)> = HashMap::new();
+    let mut audio_files = groups
+        .into_iter()
+        .enumerate()
+        .flat_map(|(group, audio_files)| {
+            let size = audio_files.len();
+            audio_files
+                .into_iter()
+                .map(move |audio_file| (group, size, audio_file))
+        })
+        .collect::<Vec<_>>()
+        .into_iter();
+    loop {
+        // Keep every worker busy, then store the gains of each album as soon as all its audio
+        // files have been measured.
+        while tasks.len() < workers {
+            let Some((group, size, audio_file)) = audio_files.next() else {
+                break;
+            };
+            pending.entry(group).or_insert_with(|| (size, Vec::new()));
+            let audio_id = audio_file.audio_id;
+            let path = PathBuf::from(&audio_file.path).join(&audio_file.name);
+            let extension = audio_file.extension.clone();
+            tasks.spawn(tokio::task::spawn_blocking(move || {
+                (group, audio_id, measure_file(&path, &extension))
+            }));
+        }
+        let (group, audio_id, measurement) = match tasks.join_next().await {
+            Some(Ok(Ok(r))) => r,
+            Some(Ok(Err(e)) | Err(e)) => {
+                event!(Level::WARN, "loudness worker failure: {}", e);
+                continue;
+            }
+            None => break,
+        };
+        let Some((remaining, measurements)) = pending.get_mut(&group) else {
+            continue;
+        };
+        *remaining -= 1;
+        // Audio files that can't be decoded are left unmeasured, and the album is measured
+        // without them.
+        if let Some(measurement) = measurement {
+            measurements.push((audio_id, measurement));
+        }
+        if *remaining == 0 {
+            if let Some((_, measurements)) = pending.remove(&group) {
+                measured += measurements.len();
+                store_gains(db, album_gains(measurements)).await;
+            }
+        }
+    }
+    event!(Level::INFO, "measured loudness of {} audio files", measured);
+}
+
+/// The gain of each audio file on an album, from the blocks it was measured over.
+fn album_gains(measurements: Vec<(i32, Measurement)>) -> Vec<(i32, Gain)> {
+    let album_blocks: Vec<f64> = measurements
+        .iter()
+        .flat_map(|(_, m)| m.blocks.iter().copied())
+        .collect();
+    let album_loudness = gated_loudness(&album_blocks);
+    let album_peak = measurements.iter().map(|(_, m)| m.peak).fold(0.0, f64::max);
+    measurements
+        .into_iter()
+        .map(|(audio_id, measurement)| {
+            let loudness = gated_loudness(&measurement.blocks);
+            (
+                audio_id,
+                Gain {
+                    // Silence is stored at the absolute gate, so it isn't measured again, but
+                    // it isn't amplified.
+                    loudness: Some(loudness.unwrap_or(ABSOLUTE_GATE)),
+                    true_peak: to_decibels(measurement.peak),
+                    track_gain: Some(loudness.map_or(0.0, |l| REFERENCE_LOUDNESS - l)),
+                    album_gain: Some(album_loudness.map_or(0.0, |l| REFERENCE_LOUDNESS - l)),
+                    album_peak: to_decibels(album_peak),
+                },
+            )
+        })
+        .collect()
+}
+
+/// The gains of an album from the ReplayGain tags of its audio files, if all of them have a
+/// track gain. Album gains are only used if all of them have one too.
+async fn tagged_gains(
+    db: &DatabaseConnection,
+    audio_files: &[audio::Model],
+) -> Option<Vec<(i32, Gain)>> {
+    let tags = match AudioTag::find()
+        .filter(audio_tag::Column::AudioId.is_in(audio_files.iter().map(|a| a.audio_id)))
+        .filter(
+            audio_tag::Column::Name.is_in(
+                TRACK_GAIN_TAGS
+                    .iter()
+                    .chain(&TRACK_PEAK_TAGS)
+                    .chain(&ALBUM_GAIN_TAGS)
+                    .chain(&ALBUM_PEAK_TAGS)
+                    .copied(),
+            ),
+        )
+        .all(db)
+        .await
+    {
+        Ok(t) => t,
+        Err(e) => {
+            event!(Level::WARN, "AudioTag::find() replaygain failure: {}", e);
+            return None;
+        }
+    };
+    let tag = |audio_id: i32, names: &[&str]| {
+        tags.iter()
+            .find(|t| t.audio_id == audio_id && names.contains(&t.name.as_str()))
+            .and_then(|t| parse_tag(&t.value))
+    };
+    let mut gains: Vec<(i32, Gain)> = audio_files
+        .iter()
+        .map(|a| {
+            let track_gain = tag(a.audio_id, &TRACK_GAIN_TAGS);
+            let gain = Gain {
+                loudness: track_gain.map(|g| REFERENCE_LOUDNESS - g),
+                true_peak: tag(a.audio_id, &TRACK_PEAK_TAGS).and_then(to_decibels),
+                track_gain,
+                album_gain: tag(a.audio_id, &ALBUM_GAIN_TAGS),
+                album_peak: tag(a.audio_id, &ALBUM_PEAK_TAGS).and_then(to_decibels),
+            };
+            (a.audio_id, gain)
+        })
+        .collect();
+    if gains.iter().any(|(_, g)| g.track_gain.is_none()) {
+        return None;
+    }
+    if gains.iter().any(|(_, g)| g.album_gain.is_none()) {
+        for (_, gain) in &mut gains {
+            gain.album_gain = None;
+            gain.album_peak = None;
+        }
+    }
+    Some(gains)
+}
+
+/// The number in a ReplayGain tag, ie "-6.50 dB" or "0.988547".
+fn parse_tag(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let value = value
+        .strip_suffix("dB")
+        .or_else(|| value.strip_suffix("DB"))
+        .unwrap_or(value);
+    value.trim().parse().ok().filter(|v: &f64| v.is_finite())
+}
+
+/// Store the gains of the audio files on an album.
+async fn store_gains(db: &DatabaseConnection, gains: Vec<(i32, Gain)>) {
+    let txn = match db.begin().await {
+        Ok(t) => t,
+        Err(e) => {
+            event!(Level::WARN, "failed to begin transaction: {}", e);
+            return;
+        }
+    };
+    for (audio_id, gain) in gains {
+        let audio = audio::ActiveModel {
+            audio_id: ActiveValue::Unchanged(audio_id),
+            loudness: ActiveValue::Set(gain.loudness),
+            true_peak: ActiveValue::Set(gain.true_peak),
+            track_gain: ActiveValue::Set(gain.track_gain),
+            album_gain: ActiveValue::Set(gain.album_gain),
+            album_peak: ActiveValue::Set(gain.album_peak),
+            ..Default::default()
+        };
+        if let Err(e) = audio.update(&txn).await {
+            event!(Level::WARN, "Audio::update() loudness failure: {}", e);
+            return;
+        }
+    }
+    if let Err(e) = txn.commit().await {
+        event!(Level::WARN, "failed to store loudness: {}", e);
+    }
+}
+
+/// The gain in dB to play an audio file with, lowered if needed so its peak doesn't clip.
+pub(crate) fn playback_gain(config: &Config, audio: &MediaList) -> f64 {
+    let (gain, peak) = match config.replaygain.unwrap_or(GainMode::Track) {
+        GainMode::Off => return 0.0,
+        GainMode::Track => (audio.track_gain, audio.true_peak),
+        GainMode::Album => match audio.album_gain {
+            Some(gain) => (Some(gain), audio.album_peak),
+            // Audio files not on an album, or on a partly tagged one, are played at the
+            // loudness of the track.
+            None => (audio.track_gain, audio.true_peak),
+        },
+    };
+    match (gain, peak) {
+        (Some(gain), Some(peak)) => gain.min(-peak),
+        (Some(gain), None) => gain,
+        _ => 0.0,
+    }
+}
+
+/// A linear amplitude in dB, if it isn't silent.
+fn to_decibels(amplitude: f64) -> Option<f64> {
+    (amplitude > 0.0).then(|| 20.0 * amplitude.log10())
+}
+
+/// The loudness of blocks in LUFS, gated as by EBU R128. Returns None if they're all silent.
+fn gated_loudness(blocks: &[f64]) -> Option<f64> {
+    let loudness = |mean_square: f64| -0.691 + 10.0 * mean_square.log10();
+    let mean = |blocks: &[f64]| blocks.iter().sum::<f64>() / blocks.len() as f64;
+    let audible: Vec<f64> = blocks
+        .iter()
+        .copied()
+        .filter(|b| loudness(*b) > ABSOLUTE_GATE)
+        .collect();
+    if audible.is_empty() {
+        return None;
+    }
+    let gate = loudness(mean(&audible)) + RELATIVE_GATE;
+    let gated: Vec<f64> = audible
+        .into_iter()
+        .filter(|b| loudness(*b) > gate)
+        .collect();
+    Some(loudness(mean(&gated)))
+}
+
+/// Decode the default track of an audio file, and measure its loudness and true peak.
+/// Returns None if the file can't be decoded.
+fn measure_file(path: &Path, extension: &str) -> Option<Measurement> {
+    event!(Level::TRACE, "measure_file {}", path.display());
+
+    let src = match std::fs::File::open(path) {
+        Ok(s) => s,
+        Err(e) => {
+            event!(
+                Level::WARN,
+                "failed to open media {}: {}",
+                path.display(),
+                e
+            );
+            return None;
+        }
+    };
+    let mss = MediaSourceStream::new(Box::new(src), Default::default());
+    let mut hint = Hint::new();
+    if !extension.is_empty() {
+        hint.with_extension(extension);
+    }
+    let meta_opts: MetadataOptions = Default::default();
+    let fmt_opts: FormatOptions = Default::default();
+    let mut format = match symphonia::default::get_probe().format(&hint, mss, &fmt_opts, &meta_opts)
+    {
+        Ok(p) => p.format,
+        Err(e) => {
+            event!(Level::WARN, "Symphonia get_probe() failure: {}", e);
+            return None;
+        }
+    };
+
+    let track = format.default_track()?;
+    let track_id = track.id;
+    let mut decoder = match symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions::default())
+    {
+        Ok(d) => d,
+        Err(e) => {
+            event!(Level::WARN, "failed to decode {}: {}", path.display(), e);
+            return None;
+        }
+    };
+
+    let mut meter: Option<Meter> = None;
+    let mut buffer: Option<SampleBuffer<f32>> = None;
+    loop {
+        let packet = match format.next_packet() {
+            Ok(p) => p,
+            // The end of the file is reported as an unexpected end of file.
+            Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => {
+                event!(Level::DEBUG, "stopped reading {}: {}", path.display(), e);
+                break;
+            }
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(d) => d,
+            // Corrupt packets are skipped, as a player would.
+            Err(Error::DecodeError(e)) => {
+                event!(Level::DEBUG, "skipping packet of {}: {}", path.display(), e);
+                continue;
+            }
+            Err(e) => {
+                event!(Level::WARN, "failed to decode {}: {}", path.display(), e);
+                return None;
+            }
+        };
+        let spec = *decoded.spec();
+        let channels = spec.channels.count().max(1);
+        let meter = meter.get_or_insert_with(|| Meter::new(spec.rate, spec.channels));
+        let buffer =
+            buffer.get_or_insert_with(|| SampleBuffer::new(decoded.capacity() as u64, spec));
+        if buffer.capacity() < decoded.capacity() * channels {
+            *buffer = SampleBuffer::new(decoded.capacity() as u64, spec);
+        }
+        buffer.copy_interleaved_ref(decoded);
+        meter.add(buffer.samples());
+    }
+    meter.map(|m| Measurement {
+        blocks: m.blocks,
+        peak: m.peak,
+    })
+}
+
+/// A biquad filter, in transposed direct form II.
+#[derive(Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    state: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.state[0];
+        self.state[0] = self.b[1] * x - self.a[0] * y + self.state[1];
+        self.state[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+/// The K-weighting filters for a sample rate.
+fn k_weighting(rate: u32) -> [Biquad; 2] {
+    let k = (PI * SHELF_FREQUENCY / rate as f64).tan();
+    let vh = 10f64.powf(SHELF_GAIN / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / SHELF_Q + k * k;
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / SHELF_Q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / SHELF_Q + k * k) / a0,
+        ],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / SHELF_Q + k * k) / a0],
+        state: [0.0; 2],
+    };
+    let k = (PI * HIGH_PASS_FREQUENCY / rate as f64).tan();
+    let a0 = 1.0 + k / HIGH_PASS_Q + k * k;
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [
+            2.0 * (k * k - 1.0) / a0,
+            (1.0 - k / HIGH_PASS_Q + k * k) / a0,
+        ],
+        state: [0.0; 2],
+    };
+    [shelf, high_pass]
+}
+
+/// Measures the loudness and true peak of interleaved samples as they're decoded.
+struct Meter {
+    weights: Vec<f64>,
+    filters: Vec<[Biquad; 2]>,
+    /// The most recent samples of each channel, for oversampling, newest first.
+    history: Vec<[f64; 12]>,
+    oversample: bool,
+    sub_block_length: usize,
+    sub_block_position: usize,
+    sub_block_energy: f64,
+    sub_blocks: Vec<f64>,
+    blocks: Vec<f64>,
+    peak: f64,
+}
+
+impl Meter {
+    fn new(rate: u32, channels: Channels) -> Self {
+        let weights: Vec<f64> = channels
+            .iter()
+            .map(|channel| {
+                if channel == Channels::LFE1 || channel == Channels::LFE2 {
+                    0.0
+                } else if channel == Channels::REAR_LEFT
+                    || channel == Channels::REAR_RIGHT
+                    || channel == Channels::SIDE_LEFT
+                    || channel == Channels::SIDE_RIGHT
+                {
+                    SURROUND_WEIGHT
+                } else {
+                    1.0
+                }
+            })
+            .collect();
+        let weights = if weights.is_empty() {
+            vec![1.0]
+        } else {
+            weights
+        };
+        Meter {
+            filters: vec![k_weighting(rate); weights.len()],
+            history: vec![[0.0; 12]; weights.len()],
+            weights,
+            oversample: rate < TRUE_PEAK_MAX_RATE,
+            sub_block_length: ((rate as f64 * BLOCK_DURATION / SUB_BLOCKS as f64) as usize).max(1),
+            sub_block_position: 0,
+            sub_block_energy: 0.0,
+            sub_blocks: Vec::with_capacity(SUB_BLOCKS),
+            blocks: Vec::new(),
+            peak: 0.0,
+        }
+    }
+
+    fn add(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(self.weights.len()) {
+            for (channel, sample) in frame.iter().enumerate() {
+                let sample = *sample as f64;
+                let [shelf, high_pass] = &mut self.filters[channel];
+                let weighted = high_pass.process(shelf.process(sample));
+                self.sub_block_energy += self.weights[channel] * weighted * weighted;
+
+                self.peak = self.peak.max(sample.abs());
+                if self.oversample {
+                    let history = &mut self.history[channel];
+                    history.copy_within(0..11, 1);
+                    history[0] = sample;
+                    for phase in &TRUE_PEAK_FILTER {
+                        let interpolated: f64 =
+                            phase.iter().zip(history.iter()).map(|(h, x)| h * x).sum();
+                        self.peak = self.peak.max(interpolated.abs());
+                    }
+                }
+            }
+
+            self.sub_block_position += 1;
+            if self.sub_block_position == self.sub_block_length {
+                if self.sub_blocks.len() == SUB_BLOCKS {
+                    self.sub_blocks.remove(0);
+                }
+                self.sub_blocks.push(self.sub_block_energy);
+                if self.sub_blocks.len() == SUB_BLOCKS {
+                    let energy: f64 = self.sub_blocks.iter().sum();
+                    self.blocks
+                        .push(energy / (self.sub_block_length * SUB_BLOCKS) as f64);
+                }
+                self.sub_block_position = 0;
+                self.sub_block_energy = 0.0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestLibrary};
+
+    fn assert_near(actual: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{} isn't within {} of {}",
+            actual,
+            tolerance,
+            expected
+        );
+    }
+
+    #[test]
+    fn sine_is_measured_at_its_known_loudness_and_peak() {
+        // A 1 kHz sine peaking at -20 dBFS in one channel is -23 LUFS, the EBU R128 target.
+        let test = tempfile::tempdir().unwrap();
+        let path = test.path().join("sine.wav");
+        testing::write_wav(&path, 48000, &testing::sine(48000, 1000.0, -20.0, 10.0));
+        let measurement = measure_file(&path, "wav").unwrap();
+        assert_near(gated_loudness(&measurement.blocks).unwrap(), -23.0, 0.1);
+        assert_near(to_decibels(measurement.peak).unwrap(), -20.0, 0.1);
+
+        let gains = album_gains(vec![(1, measurement)]);
+        assert_near(
+            gains[0].1.track_gain.unwrap(),
+            REFERENCE_LOUDNESS + 23.0,
+            0.1,
+        );
+        assert_near(
+            gains[0].1.album_gain.unwrap(),
+            REFERENCE_LOUDNESS + 23.0,
+            0.1,
+        );
+    }
+
+    #[test]
+    fn channels_are_summed() {
+        // The same sine in both channels is 3 dB louder than in one.
+        let sine = testing::sine(48000, 1000.0, -20.0, 10.0);
+        let stereo: Vec<f32> = sine.iter().flat_map(|s| [*s, *s]).collect();
+        let mut meter = Meter::new(48000, Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
+        meter.add(&stereo);
+        assert_near(gated_loudness(&meter.blocks).unwrap(), -20.0, 0.1);
+    }
+
+    #[test]
+    fn true_peak_is_found_between_samples() {
+        // A quarter of the sample rate, sampled 45 degrees off its peaks, never reaches them.
+        let samples: Vec<f32> = (0..48000)
+            .map(|i| (0.5 * (PI / 2.0 * i as f64 + PI / 4.0).sin()) as f32)
+            .collect();
+        let sample_peak = samples.iter().fold(0.0f32, |p, s| p.max(s.abs())) as f64;
+        assert_near(to_decibels(sample_peak).unwrap(), -9.0, 0.1);
+        let mut meter = Meter::new(48000, Channels::FRONT_LEFT);
+        meter.add(&samples);
+        assert_near(to_decibels(meter.peak).unwrap(), -6.0, 0.5);
+    }
+
+    #[test]
+    fn silence_has_no_loudness() {
+        let mut meter = Meter::new(48000, Channels::FRONT_LEFT);
+        meter.add(&vec![0.0; 48000]);
+        assert_eq!(gated_loudness(&meter.blocks), None);
+    }
+
+    #[tokio::test]
+    async fn albums_are_measured_again_with_an_unmeasured_audio_file() {
+        let test = TestLibrary::new(&["--loudness"]).await;
+        let config = &test.config;
+        let db = database::connection(config).await;
+        for (name, dbfs) in [("one", -20.0), ("two", -30.0)] {
+            testing::write_wav(
+                &test.path(&format!("Album/{}.wav", name)),
+                48000,
+                &testing::sine(48000, 1000.0, dbfs, 3.0),
+            );
+        }
+        scan::scan_media_files(config).await;
+        let audio_file = |name: &'static str| async move {
+            Audio::find()
+                .filter(audio::Column::Name.eq(format!("{}.wav", name)))
+                .one(db)
+                .await
+                .unwrap()
+                .unwrap()
+        };
+        let (one, two) = (audio_file("one").await, audio_file("two").await);
+        assert_near(one.track_gain.unwrap(), two.track_gain.unwrap() - 10.0, 0.1);
+        assert_near(one.album_gain.unwrap(), two.album_gain.unwrap(), 0.01);
+
+        // Measuring one audio file again measures the rest of its album with it.
+        let album_gain = one.album_gain;
+        let mut one: audio::ActiveModel = one.into();
+        one.loudness = ActiveValue::Set(None);
+        one.update(db).await.unwrap();
+        let mut two: audio::ActiveModel = two.into();
+        two.album_gain = ActiveValue::Set(Some(0.0));
+        two.update(db).await.unwrap();
+        analyse_audio(config, &Scope::Library).await;
+        assert!(audio_file("one").await.loudness.is_some());
+        assert_near(
+            audio_file("two").await.album_gain.unwrap(),
+            album_gain.unwrap(),
+            0.01,
+        );
+    }
+}
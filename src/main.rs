@@ -1,27 +1,59 @@
+mod acoustid;
+mod artists;
+mod artwork;
+mod cache;
 mod database;
+mod duplicates;
+mod features;
+mod fingerprint;
+mod fixtures;
+mod loudness;
+// Generated by sea-orm-codegen, not all entities are used yet.
+#[allow(unused_imports, clippy::enum_variant_names)]
 mod entities;
 mod media;
 mod musicbrainz;
 mod player;
+mod provider;
+mod queue;
+mod ratelimit;
+mod releases;
+mod scan;
+mod spectrum;
+#[cfg(test)]
+mod testing;
 mod utils;
+mod verify;
+mod watch;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use figment::{
     providers::{Env, Format, Serialized, Toml},
     Figment,
 };
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
 use tracing::{event, Level};
 use tracing_appender::rolling::{RollingFileAppender, Rotation};
 use tracing_subscriber::fmt::writer::MakeWriterExt;
 
-use crate::database::DatabaseType;
+use crate::artists::ArtistCommand;
+use crate::cache::CacheCommand;
+use crate::database::{DatabaseType, DbCommand};
+use crate::duplicates::ReportFormat;
+use crate::fixtures::FixturesCommand;
+use crate::loudness::GainMode;
+use crate::queue::QueueCommand;
 
 static USER_AGENT: Lazy<String> = Lazy::new(utils::build_user_agent);
 
 #[derive(Clone, Debug, Parser, Serialize, Deserialize)]
 struct Config {
+    #[command(subcommand)]
+    #[serde(skip)]
+    command: Option<Command>,
+
     /// Set path to the music library
     #[arg(short, long)]
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -29,17 +61,126 @@ struct Config {
     /// Scan the music library
     #[arg(short, long)]
     scan: bool,
+    /// Compare content hashes to detect changed and moved files when scanning
+    #[arg(long)]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    hash: bool,
+    /// Compute acoustic fingerprints of audio files when scanning, to identify untagged files
+    /// and find duplicates
+    #[arg(long)]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    fingerprint: bool,
+    /// Measure the loudness of audio files and albums when scanning, to normalise playback with
+    /// ReplayGain
+    #[arg(long)]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    loudness: bool,
+    /// Use the ReplayGain tags of albums that have them, instead of measuring their loudness
+    #[arg(long)]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    replaygain_tags: bool,
+    /// Extract the tempo, key and other features of audio files when scanning, for building
+    /// playlists
+    #[arg(long)]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    features: bool,
+    /// Number of files to probe at the same time when scanning, defaults to the number of CPUs
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scan_workers: Option<usize>,
+    /// Stop scanning after this many files, the next scan continues where it stopped
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scan_max_files: Option<usize>,
+    /// Stop scanning after this many seconds, the next scan continues where it stopped
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scan_max_duration: Option<u64>,
+    /// Only scan files matching this glob, relative to the library (can be repeated)
+    #[arg(long)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    scan_include: Vec<String>,
+    /// Don't scan files or directories matching this glob, relative to the library (can be
+    /// repeated)
+    #[arg(long)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    scan_exclude: Vec<String>,
+    /// Directory to extract pictures embedded in audio files to, defaults to ./artwork
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    artwork_cache: Option<String>,
+    /// Minimum confidence (0-100) to link an artist found by name in MusicBrainz, defaults to 80
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    artist_match_threshold: Option<i32>,
+    /// Times to try a MusicBrainz lookup before giving up on it, defaults to 5
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    queue_max_attempts: Option<i32>,
+    /// Seconds to wait before retrying a failed MusicBrainz lookup, doubled after each attempt,
+    /// defaults to 60
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    queue_retry_delay: Option<u64>,
+    /// Seconds after which a MusicBrainz lookup that never finished is retried, defaults to 600
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    queue_lease_timeout: Option<u64>,
+    /// URL of the MusicBrainz API, defaults to https://musicbrainz.org/ws/2
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    musicbrainz_url: Option<String>,
+    /// Requests per second to make to MusicBrainz at most, defaults to 0.5
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    musicbrainz_rate: Option<f64>,
+    /// Requests that can be made to MusicBrainz at once after a pause, defaults to 1
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    musicbrainz_burst: Option<u32>,
+    /// URL of the AcoustID API, defaults to https://api.acoustid.org/v2
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    acoustid_url: Option<String>,
+    /// AcoustID application API key, fingerprints are only looked up in AcoustID with one
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    acoustid_api_key: Option<String>,
+    /// Directory to cache responses from MusicBrainz in, defaults to ./metadata
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata_cache: Option<String>,
+    /// Days after which cached responses are fetched again by `cache refresh`, defaults to 30
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata_cache_ttl: Option<u64>,
+    /// Watch the music library for changes, indexing them as they happen
+    #[arg(short, long)]
+    watch: bool,
+    /// Seconds a file must stop changing before it's indexed in watch mode
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    watch_delay: Option<u64>,
     /// Print contents of music library
     #[arg(long)]
     print: bool,
     /// Play selected music from library
     #[arg(short, long)]
     play: bool,
+    /// Apply track or album ReplayGain when playing, or play audio files as they are, defaults
+    /// to track
+    #[arg(long, value_enum)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    replaygain: Option<GainMode>,
 
     /// Filter by artist
     #[arg(long)]
     #[serde(skip_serializing_if = "Option::is_none")]
     artist: Option<String>,
+    /// Filter by album
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    album: Option<String>,
     /// Filter by track
     #[arg(long)]
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -48,6 +189,23 @@ struct Config {
     #[arg(long)]
     #[serde(skip_serializing_if = "Option::is_none")]
     directory: Option<String>,
+    /// Filter by tag, as NAME=VALUE or just NAME (can be repeated)
+    #[arg(long)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tag: Vec<String>,
+    /// Filter by tempo, as a range of BPM like 120-130, or at least or at most a BPM like 120-
+    /// or -90, see `--features`
+    #[arg(long, allow_hyphen_values = true)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bpm: Option<String>,
+    /// Filter by key, as a tonic and mode like "A minor", or just a tonic, see `--features`
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    /// Skip audio files that failed verification, see `ria verify`
+    #[arg(long)]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    skip_broken: bool,
 
     /// Specify database type
     #[arg(short, long, default_value_t = DatabaseType::SQLite)]
@@ -70,19 +228,81 @@ struct Config {
     //random: bool,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), ()> {
+#[derive(Clone, Debug, Subcommand)]
+enum Command {
+    /// Manage the database schema
+    #[command(subcommand)]
+    Db(DbCommand),
+    /// Print the best cover image of albums with a name containing ALBUM
+    Cover { album: String },
+    /// Find duplicate audio files by content hash, fingerprint, or artist, title and duration,
+    /// and rank the copies of each by quality
+    Duplicates {
+        /// Print the report as text or JSON
+        #[arg(long, value_enum, default_value_t = ReportFormat::Text)]
+        format: ReportFormat,
+        /// Hide all but the best copy of each duplicate from listing and playing
+        #[arg(long)]
+        hide: bool,
+        /// Show all hidden audio files again
+        #[arg(long, conflicts_with = "hide")]
+        unhide: bool,
+    },
+    /// Fully decode audio files to find corrupt and truncated ones, and report the broken files
+    Verify {
+        /// Print the report as text or JSON
+        #[arg(long, value_enum, default_value_t = ReportFormat::Text)]
+        format: ReportFormat,
+        /// Verify audio files again that were already verified
+        #[arg(long)]
+        recheck: bool,
+        /// Report all verified audio files, not only the broken ones
+        #[arg(long)]
+        all: bool,
+    },
+    /// Review and correct how artists were matched in MusicBrainz
+    #[command(subcommand)]
+    Artist(ArtistCommand),
+    /// Inspect, retry and purge queued MusicBrainz lookups
+    #[command(subcommand)]
+    Queue(QueueCommand),
+    /// Refresh cached responses from MusicBrainz
+    #[command(subcommand)]
+    Cache(CacheCommand),
+    /// Serve recorded responses in place of MusicBrainz, for testing without network access
+    #[command(subcommand)]
+    Fixtures(FixturesCommand),
+}
+
+// Database queries build deeply nested futures, which overflow tokio's default 2 MiB thread
+// stack in debug builds.
+const THREAD_STACK_SIZE: usize = 8 * 1024 * 1024;
+
+fn main() -> Result<(), ()> {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .thread_stack_size(THREAD_STACK_SIZE)
+        .build()
+        .expect("failed to build tokio runtime")
+        .block_on(run())
+}
+
+async fn run() -> Result<(), ()> {
+    // Subcommands are only set via the command line.
+    let flags = Config::parse();
+    let command = flags.command.clone();
+
     // Start with toml configuration file.
     let config: Config = Figment::from(Toml::file("ria.toml"))
         // Override with anything set in environment variables.
         .merge(Env::prefixed("RIA_"))
         // Override with anything set via flags.
-        .merge(Serialized::defaults(Config::parse()))
+        .merge(Serialized::defaults(flags))
         .extract()
         .unwrap();
 
     // Library must be configurex (typically in `ria.toml` or RIA_LIBRARY.)
-    if config.library.is_none() {
+    if config.library.is_none() && command.is_none() {
         println!("\nUsage: ria --library <LIBRARY>\n");
         std::process::exit(0);
     }
@@ -95,18 +315,122 @@ async fn main() -> Result<(), ()> {
         .with_writer(stdout.and(logfile))
         .init();
 
-    // Dynamically build a user agent from package name and version, set as MusicBrainz user agent.
-    musicbrainz_rs::config::set_user_agent(&USER_AGENT);
+    if let Some(Command::Db(db_command)) = &command {
+        if let Err(e) = database::run_command(&config, db_command).await {
+            event!(Level::ERROR, "database command failed: {}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if let Some(Command::Cache(cache_command)) = &command {
+        if let Err(e) = cache::run_command(&config, cache_command).await {
+            event!(Level::ERROR, "cache command failed: {}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if let Some(Command::Fixtures(fixtures_command)) = &command {
+        if let Err(e) = fixtures::run_command(&config, fixtures_command).await {
+            event!(Level::ERROR, "fixtures command failed: {}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    // Create the database schema if missing, and apply any pending migrations.
+    if let Err(e) = database::migrate(&config).await {
+        event!(Level::ERROR, "database migration failed: {}", e);
+        std::process::exit(1);
+    }
+
+    if let Some(Command::Cover { album }) = &command {
+        artwork::print_album_covers(&config, album).await;
+        return Ok(());
+    }
+
+    if let Some(Command::Duplicates {
+        format,
+        hide,
+        unhide,
+    }) = &command
+    {
+        if let Err(e) = duplicates::run_command(&config, *format, *hide, *unhide).await {
+            event!(Level::ERROR, "duplicates command failed: {}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if let Some(Command::Verify {
+        format,
+        recheck,
+        all,
+    }) = &command
+    {
+        if let Err(e) = verify::run_command(&config, *format, *recheck, *all).await {
+            event!(Level::ERROR, "verify command failed: {}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if let Some(Command::Artist(artist_command)) = &command {
+        if let Err(e) = artists::run_command(&config, artist_command).await {
+            event!(Level::ERROR, "artist command failed: {}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if let Some(Command::Queue(queue_command)) = &command {
+        if let Err(e) = queue::run_command(&config, queue_command).await {
+            event!(Level::ERROR, "queue command failed: {}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    // Some items require an API lookup. We have to throttle our API requests, so some items will
+    // have to wait in a queue until we are able to process them.
+    let queue = if config.scan || config.watch {
+        let queue_config = config.clone();
+        let (queue_tx, queue_rx) = oneshot::channel::<bool>();
+        let queue_handle =
+            tokio::spawn(async move { musicbrainz::process_queue(&queue_config, queue_rx).await });
+        Some((queue_tx, queue_handle))
+    } else {
+        None
+    };
 
     if config.scan {
         // Spawn thread for scanning for media.
         let spawn_config = config.clone();
         let handle =
-            tokio::spawn(async move { media::scan_media_files(&spawn_config.clone()).await });
+            tokio::spawn(async move { scan::scan_media_files(&spawn_config.clone()).await });
         // @TODO: allow scan to happen in the background while other tasks happen.
         let _ = handle.await;
     }
 
+    if config.watch {
+        // Keep indexing changes until interrupted.
+        watch::watch_media_files(&config).await;
+    }
+
+    if let Some((queue_tx, queue_handle)) = queue {
+        if config.watch {
+            // Stop processing the queue when no longer watching, anything left in the queue
+            // will be processed the next time Ria scans or watches the library.
+            queue_handle.abort();
+        } else {
+            // Tell thread processing queue that no new messages are coming.
+            let _ = queue_tx.send(true);
+            // Wait for the queue processing thread to finish.
+            let _ = queue_handle.await;
+        }
+    }
+
     if config.print {
         media::print_media(&config).await;
     }
@@ -121,7 +445,10 @@ async fn main() -> Result<(), ()> {
                 audio_file.audio_id,
                 audio_file_name
             );
-            player::play_audio(&audio_file_name);
+            player::play_audio(
+                &audio_file_name,
+                loudness::playback_gain(&config, &audio_file),
+            );
         }
     }
 
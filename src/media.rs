@@ -1,21 +1,23 @@
-use std::path::Path;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
-use file_format::FileFormat;
-use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use sea_orm::sea_query::{Alias, Expr, Func, Query, SimpleExpr};
 use sea_orm::*;
 use symphonia::core::formats::FormatOptions;
 use symphonia::core::io::MediaSourceStream;
-use symphonia::core::meta::MetadataOptions;
+use symphonia::core::meta::{MetadataOptions, Value, Visual};
 use symphonia::core::probe::Hint;
-use tokio::sync::oneshot;
 use tracing::{event, instrument, Level};
-use walkdir::WalkDir;
 
+use crate::artists;
 use crate::database;
 use crate::entities::{prelude::*, *};
 use crate::musicbrainz;
-use crate::utils;
+use crate::scan::ScanFilter;
+use crate::verify::VerifyStatus;
 use crate::Config;
 
 #[derive(Clone, Debug, FromQueryResult)]
@@ -23,10 +25,69 @@ pub(crate) struct MediaList {
     pub(crate) audio_name: String,
     pub(crate) audio_path: String,
     pub(crate) audio_id: u32,
-    pub(crate) directory_name: String,
+    pub(crate) album_id: Option<i32>,
+    pub(crate) album_name: Option<String>,
+    pub(crate) total_discs: Option<i32>,
+    pub(crate) disc_number: Option<i32>,
+    pub(crate) track_number: Option<i32>,
+    pub(crate) directory_name: Option<String>,
     pub(crate) artist_name: Option<String>,
+    pub(crate) true_peak: Option<f64>,
+    pub(crate) track_gain: Option<f64>,
+    pub(crate) album_gain: Option<f64>,
+    pub(crate) album_peak: Option<f64>,
 }
 
+/// A tag found in an audio file.
+#[derive(Clone, Debug)]
+pub(crate) struct Tag {
+    /// The standard name of the tag, ie "TrackNumber", or for tags without a standard name the
+    /// normalised key, ie "MUSICBRAINZ_ALBUMTYPE" for "TXXX:MusicBrainz Album Type".
+    pub(crate) name: String,
+    /// The key exactly as it's stored in the audio file, ie "TRCK" or "TRACKNUMBER".
+    pub(crate) key: String,
+    pub(crate) value: String,
+    /// Tags with more than one value are stored once per value, numbered in order.
+    pub(crate) position: i32,
+}
+
+/// Tags found in an audio file.
+pub(crate) type Tags = Vec<Tag>;
+
+// Tags used to group audio files into albums.
+const ALBUM_TAGS: [&str; 6] = [
+    "Album",
+    "AlbumArtist",
+    "DiscNumber",
+    "DiscTotal",
+    "TrackNumber",
+    "Date",
+];
+
+// Disc subdirectories of multi-disc albums, ie "CD1", "Disc 2" or "disk 3 - Bonus".
+static DISC_DIRECTORY: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)^(?:cd|dis[ck])[\s._-]*(\d+)\b").expect("failed to compile DISC_DIRECTORY")
+});
+
+// MusicBrainz identifiers are UUIDs, ie "a74b1b7f-71a5-4011-9441-d0b5e4122711".
+static MUSICBRAINZ_ID: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"[0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12}")
+        .expect("failed to compile MUSICBRAINZ_ID")
+});
+
+// Audio files are grouped into directories and albums this many at a time.
+const DIRECTORY_BATCH_SIZE: usize = 500;
+pub(crate) const ALBUM_BATCH_SIZE: usize = 500;
+
+// Audio files are checked for removal this many at a time, and those removed are deleted in
+// batches of this many.
+const AUDIO_PAGE_SIZE: u64 = 1000;
+const DELETE_BATCH_SIZE: usize = 500;
+
+// Batches of filesystem events that change more directories than this are processed like a
+// full scan, rather than adding a condition for each directory to every query.
+const SCOPE_DIRECTORY_LIMIT: usize = 100;
+
 /// The general media types Ria works with.
 pub(crate) enum MediaType {
     /// Audio files, such as FLAC or MP3.
@@ -56,7 +117,130 @@ impl FromStr for MediaType {
     }
 }
 
-pub(crate) async fn store_audio_artist(config: &Config, audio_id: i32, artist_id: i32) {
+/// The part of the library that audio files are grouped, linked and analysed in after indexing.
+#[derive(Clone, Debug)]
+pub(crate) enum Scope {
+    /// The whole library, as after a full scan.
+    Library,
+    /// Directories changed while watching the library, including everything in them. Disc
+    /// subdirectories are folded into the directory above them.
+    Directories(Vec<String>),
+}
+
+impl Scope {
+    /// The scope of changes to directories, stored as the scanner stores them. Changes to the
+    /// library itself, or to too many directories, are the whole library.
+    pub(crate) fn changed(library: &Path, directories: &[PathBuf]) -> Self {
+        let mut folded: Vec<String> = directories
+            .iter()
+            .map(|d| fold_disc_directory(&d.display().to_string()).0)
+            .collect();
+        if folded.iter().any(|d| Path::new(d) == library) {
+            return Scope::Library;
+        }
+        folded.sort();
+        // Directories in a directory already in scope don't need conditions of their own.
+        let mut directories: Vec<String> = Vec::new();
+        for directory in folded {
+            if !directories
+                .iter()
+                .any(|d| *d == directory || directory.starts_with(&format!("{}/", d)))
+            {
+                directories.push(directory);
+            }
+        }
+        if directories.len() > SCOPE_DIRECTORY_LIMIT {
+            return Scope::Library;
+        }
+        Scope::Directories(directories)
+    }
+
+    /// The scope of an audio file and the albums it's in, ie to relink them once its artist has
+    /// been looked up.
+    pub(crate) async fn of_audio(config: &Config, audio_id: i32) -> Result<Self, String> {
+        let db = database::connection(config).await;
+        let mut directories: Vec<PathBuf> = match Album::find()
+            .inner_join(AlbumAudio)
+            .filter(album_audio::Column::AudioId.eq(audio_id))
+            .all(db)
+            .await
+        {
+            Ok(albums) => albums.iter().map(|a| PathBuf::from(&a.path)).collect(),
+            Err(e) => return Err(format!("Album::find() by audio failure: {}", e)),
+        };
+        match Audio::find_by_id(audio_id).one(db).await {
+            Ok(Some(audio)) => directories.push(PathBuf::from(audio.path)),
+            Ok(None) => {}
+            Err(e) => return Err(format!("Audio::find_by_id() failure: {}", e)),
+        }
+        let library = PathBuf::from(config.library.as_ref().expect("library must exist"));
+        Ok(Scope::changed(&library, &directories))
+    }
+
+    /// Limit a query to paths in scope, ie of Audio, Directory or Image.
+    pub(crate) fn paths(&self, column: impl ColumnTrait) -> Condition {
+        match self {
+            Scope::Library => Condition::all(),
+            Scope::Directories(directories) => {
+                directories.iter().fold(Condition::any(), |condition, d| {
+                    condition
+                        .add(column.eq(d.as_str()))
+                        .add(in_directory(column, d))
+                })
+            }
+        }
+    }
+
+    /// Limit a query to audio files in scope, by a column of audio ids.
+    pub(crate) fn audio(&self, column: impl ColumnTrait) -> Condition {
+        match self {
+            Scope::Library => Condition::all(),
+            Scope::Directories(_) => Condition::all().add(
+                column.in_subquery(
+                    Query::select()
+                        .column(audio::Column::AudioId)
+                        .from(Audio)
+                        .cond_where(self.paths(audio::Column::Path))
+                        .to_owned(),
+                ),
+            ),
+        }
+    }
+
+    /// Limit a query to albums of audio files in scope, by a column of album ids.
+    pub(crate) fn albums(&self, column: impl ColumnTrait) -> Condition {
+        match self {
+            Scope::Library => Condition::all(),
+            Scope::Directories(_) => Condition::all().add(
+                column.in_subquery(
+                    Query::select()
+                        .column(album_audio::Column::AlbumId)
+                        .from(AlbumAudio)
+                        .cond_where(self.audio(album_audio::Column::AudioId))
+                        .to_owned(),
+                ),
+            ),
+        }
+    }
+}
+
+/// Paths below a directory. They're compared by prefix rather than with LIKE, as "_" and "%" are
+/// common in the names of directories of music.
+fn in_directory(column: impl ColumnTrait, directory: &str) -> SimpleExpr {
+    let prefix = format!("{}/", directory);
+    Expr::expr(Func::cust(Alias::new("substr")).args([
+        Expr::col(column.as_column_ref()).into(),
+        1.into(),
+        (prefix.chars().count() as i32).into(),
+    ]))
+    .eq(prefix)
+}
+
+pub(crate) async fn store_audio_artist(
+    config: &Config,
+    audio_id: i32,
+    artist_id: i32,
+) -> Result<(), DbErr> {
     let audio_artist = audio_artist::ActiveModel {
         audio_id: ActiveValue::Set(audio_id),
         artist_id: ActiveValue::Set(artist_id),
@@ -65,14 +249,16 @@ pub(crate) async fn store_audio_artist(config: &Config, audio_id: i32, artist_id
 
     event!(Level::DEBUG, "Insert AudioArtist: {:?}", audio_artist);
     let db = database::connection(config).await;
-    AudioArtist::insert(audio_artist)
-        .exec(db)
-        .await
-        .expect("failed to write audio_artist to database");
+    AudioArtist::insert(audio_artist).exec(db).await?;
+    Ok(())
 }
 
 // Map directory to all artists found in contained audio files.
-pub(crate) async fn store_artist_directory(config: &Config, audio_id: i32, artist_id: i32) {
+pub(crate) async fn store_artist_directory(
+    config: &Config,
+    audio_id: i32,
+    artist_id: i32,
+) -> Result<(), DbErr> {
     event!(
         Level::TRACE,
         "store_artist_directory audio_id({}) artist_id({})",
@@ -88,36 +274,19 @@ pub(crate) async fn store_artist_directory(config: &Config, audio_id: i32, artis
     enum QueryAs {
         DirectoryId,
     }
-    let results: Vec<i32> = match audio_directory::Entity::find()
+    let results: Vec<i32> = audio_directory::Entity::find()
         .filter(audio_directory::Column::AudioId.eq(audio_id))
         .select_only()
         .column_as(audio_directory::Column::DirectoryId, QueryAs::DirectoryId)
         .into_values::<_, QueryAs>()
         .all(db)
-        .await
-    {
-        Ok(r) => r,
-        Err(e) => {
-            event!(Level::WARN, "Audio::find() directory_id failure: {}", e);
-            // @TODO: What to do?
-            return;
-        }
-    };
+        .await?;
     for directory_id in results {
-        let existing = {
-            match ArtistDirectory::find()
-                .filter(artist_directory::Column::ArtistId.eq(artist_id))
-                .filter(artist_directory::Column::DirectoryId.eq(directory_id))
-                .one(db)
-                .await
-            {
-                Ok(e) => e,
-                Err(e) => {
-                    event!(Level::WARN, "Audio::find() failure: {}", e);
-                    break;
-                }
-            }
-        };
+        let existing = ArtistDirectory::find()
+            .filter(artist_directory::Column::ArtistId.eq(artist_id))
+            .filter(artist_directory::Column::DirectoryId.eq(directory_id))
+            .one(db)
+            .await?;
 
         // Only add if not already existing.
         if existing.is_none() {
@@ -134,79 +303,222 @@ pub(crate) async fn store_artist_directory(config: &Config, audio_id: i32, artis
                 "Insert ArtistDirectory: {:?}",
                 artist_directory
             );
-            ArtistDirectory::insert(artist_directory)
-                .exec(db)
-                .await
-                .expect("failed to write artist_directory to database");
+            ArtistDirectory::insert(artist_directory).exec(db).await?;
         }
     }
+    Ok(())
 }
 
 // @TODO: Optional filters (ie, artist, etc)
 pub(crate) async fn print_media(config: &Config) {
     let media = get_media(config).await;
     let mut last_artist = None;
-    let mut last_album = String::new();
+    let mut last_album = None;
+    let mut last_disc = None;
     for audio in media {
         if !audio.artist_name.eq(&last_artist) {
             last_artist = audio.artist_name;
+            last_album = None;
             if let Some(artist) = last_artist.as_ref() {
                 println!("\n{}:", artist)
             } else {
                 println!("\nUnidentified artist:");
             }
         }
-        if last_album != audio.directory_name {
-            last_album = audio.directory_name;
-            println!("  {}", last_album)
+        if last_album.is_none() || last_album != audio.album_id {
+            last_album = audio.album_id;
+            last_disc = None;
+            // Audio files that haven't been grouped yet are listed by directory.
+            let album = audio
+                .album_name
+                .or(audio.directory_name)
+                .unwrap_or_default();
+            println!("  {}", album)
+        }
+        // Only multi-disc albums are listed by disc.
+        let disc = audio.disc_number.unwrap_or(1);
+        if audio.album_id.is_some()
+            && (audio.total_discs.unwrap_or(1) > 1 || disc > 1)
+            && last_disc != Some(disc)
+        {
+            last_disc = Some(disc);
+            println!("   Disc {}:", disc);
+        }
+        match audio.track_number {
+            Some(track) => println!("    {:>2}. {}", track, audio.audio_name),
+            None => println!("     - {}", audio.audio_name),
         }
-        println!("     - {}", audio.audio_name);
     }
 }
 
 // @TODO: Optional filters (ie, artist, etc)
 pub(crate) async fn get_media(config: &Config) -> Vec<MediaList> {
     let db = database::connection(config).await;
-    // SELECT ar.name, d.name, a.name FROM audio_directory AS ad
-    //   LEFT JOIN audio AS a ON ad.audio_id = a.audio_id
+    // SELECT ar.name, al.name, al.total_discs, aa.disc_number, aa.track_number, d.name, a.name
+    //   FROM audio AS a
+    //   LEFT JOIN album_audio AS aa ON aa.audio_id = a.audio_id
+    //   LEFT JOIN album AS al ON aa.album_id = al.album_id
+    //   LEFT JOIN artist AS ar ON al.artist_id = ar.artist_id
+    //   LEFT JOIN audio_directory AS ad ON ad.audio_id = a.audio_id
     //   LEFT JOIN directory AS d ON ad.directory_id = d.directory_id
-    //   LEFT JOIN artist_directory AS ard ON ard.directory_id = d.directory_id
-    //   LEFT JOIN artist AS ar ON ard.artist_id = ar.artist_id
-    // ORDER BY a.name;
-
-    //match audio_directory::Entity::find()
-    let mut select_query = audio_directory::Entity::find()
-        .left_join(Audio)
-        .left_join(Directory)
+    // ORDER BY ar.sort_name, al.name, al.album_id, aa.disc_number, aa.track_number, a.name;
+    let mut select_query = Audio::find()
+        .join(JoinType::LeftJoin, audio::Relation::AlbumAudio.def())
+        .join(JoinType::LeftJoin, album_audio::Relation::Album.def())
+        .join(JoinType::LeftJoin, album::Relation::Artist.def())
+        .join(JoinType::LeftJoin, audio::Relation::AudioDirectory.def())
         .join(
             JoinType::LeftJoin,
-            directory::Relation::ArtistDirectory.def(),
+            audio_directory::Relation::Directory.def(),
         )
-        .join(JoinType::LeftJoin, artist_directory::Relation::Artist.def())
         .select_only()
         .column_as(audio::Column::AudioId, "audio_id")
         .column_as(audio::Column::Path, "audio_path")
         .column_as(audio::Column::Name, "audio_name")
+        .column_as(album::Column::AlbumId, "album_id")
+        .column_as(album::Column::Name, "album_name")
+        .column_as(album::Column::TotalDiscs, "total_discs")
+        .column_as(album_audio::Column::DiscNumber, "disc_number")
+        .column_as(album_audio::Column::TrackNumber, "track_number")
         .column_as(directory::Column::Name, "directory_name")
-        .column_as(artist::Column::Name, "artist_name");
+        .column_as(artist::Column::Name, "artist_name")
+        .column_as(audio::Column::TruePeak, "true_peak")
+        .column_as(audio::Column::TrackGain, "track_gain")
+        .column_as(audio::Column::AlbumGain, "album_gain")
+        .column_as(audio::Column::AlbumPeak, "album_peak")
+        // Inferior copies of duplicates, see `ria duplicates --hide`.
+        .filter(audio::Column::Hidden.eq(false));
+
+    // Match the album artist, or the artist of the track itself.
+    if let Some(artist) = config.artist.as_ref() {
+        select_query = select_query.filter(
+            Condition::any()
+                .add(artist::Column::Name.contains(artist))
+                .add(
+                    audio::Column::AudioId.in_subquery(
+                        Query::select()
+                            .column(audio_artist::Column::AudioId)
+                            .from(AudioArtist)
+                            .inner_join(
+                                Artist,
+                                Expr::col((Artist, artist::Column::ArtistId))
+                                    .equals((AudioArtist, audio_artist::Column::ArtistId)),
+                            )
+                            .and_where(
+                                Expr::col((Artist, artist::Column::Name))
+                                    .like(format!("%{}%", artist).as_str()),
+                            )
+                            .to_owned(),
+                    ),
+                ),
+        );
+    }
 
-    if config.artist.is_some() {
-        select_query =
-            select_query.filter(artist::Column::Name.contains(&config.artist.as_ref().unwrap()));
+    if let Some(album) = config.album.as_ref() {
+        select_query = select_query.filter(album::Column::Name.contains(album));
     }
 
-    if config.directory.is_some() {
-        select_query = select_query
-            .filter(directory::Column::Name.contains(&config.directory.as_ref().unwrap()));
+    if let Some(directory) = config.directory.as_ref() {
+        select_query = select_query.filter(directory::Column::Name.contains(directory));
     }
 
-    if config.track.is_some() {
-        select_query =
-            select_query.filter(audio::Column::Name.contains(&config.track.as_ref().unwrap()));
+    if let Some(track) = config.track.as_ref() {
+        select_query = select_query.filter(audio::Column::Name.contains(track));
+    }
+
+    // Match audio files with a tag, by its standard name or its key in the audio file, and
+    // optionally its value.
+    for tag in &config.tag {
+        let (name, value) = match tag.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value)),
+            None => (tag.trim(), None),
+        };
+        let mut tag_query = Query::select()
+            .column(audio_tag::Column::AudioId)
+            .from(AudioTag)
+            .cond_where(
+                Condition::any()
+                    .add(
+                        Expr::expr(Func::lower(Expr::col(audio_tag::Column::Name)))
+                            .eq(name.to_lowercase()),
+                    )
+                    .add(
+                        Expr::expr(Func::lower(Expr::col(audio_tag::Column::Key)))
+                            .eq(name.to_lowercase()),
+                    ),
+            )
+            .to_owned();
+        if let Some(value) = value {
+            tag_query.and_where(audio_tag::Column::Value.contains(value));
+        }
+        select_query = select_query.filter(audio::Column::AudioId.in_subquery(tag_query));
+    }
+
+    // Match audio files with a tempo in a range of BPM.
+    if let Some(bpm) = config.bpm.as_ref() {
+        let Some((minimum, maximum)) = parse_bpm_range(bpm) else {
+            event!(Level::WARN, "invalid BPM range: {}", bpm);
+            return Vec::new();
+        };
+        let mut feature_query = Query::select()
+            .column(audio_feature::Column::AudioId)
+            .from(AudioFeature)
+            .to_owned();
+        if let Some(minimum) = minimum {
+            feature_query.and_where(audio_feature::Column::Tempo.gte(minimum));
+        }
+        if let Some(maximum) = maximum {
+            feature_query.and_where(audio_feature::Column::Tempo.lt(maximum));
+        }
+        select_query = select_query.filter(audio::Column::AudioId.in_subquery(feature_query));
+    }
+
+    // Match audio files in a key, or with a tonic in either mode.
+    if let Some(key) = config.key.as_ref() {
+        let key = key.trim().to_lowercase();
+        select_query = select_query.filter(
+            audio::Column::AudioId.in_subquery(
+                Query::select()
+                    .column(audio_feature::Column::AudioId)
+                    .from(AudioFeature)
+                    .cond_where(
+                        Condition::any()
+                            .add(
+                                Expr::expr(Func::lower(Expr::col(audio_feature::Column::Key)))
+                                    .eq(key.as_str()),
+                            )
+                            .add(
+                                Expr::expr(Func::lower(Expr::col(audio_feature::Column::Key)))
+                                    .like(format!("{} %", key).as_str()),
+                            ),
+                    )
+                    .to_owned(),
+            ),
+        );
+    }
+
+    if config.skip_broken {
+        select_query = select_query.filter(
+            audio::Column::AudioId.not_in_subquery(
+                Query::select()
+                    .column(audio_verification::Column::AudioId)
+                    .from(AudioVerification)
+                    .and_where(audio_verification::Column::Status.ne(VerifyStatus::Ok.as_str()))
+                    .to_owned(),
+            ),
+        );
     }
 
     match select_query
         .order_by_asc(artist::Column::SortName)
+        .order_by_asc(album::Column::Name)
+        .order_by_asc(album::Column::AlbumId)
+        // Tracks without a disc number are on the first disc.
+        .order_by_asc(SimpleExpr::from(Func::coalesce([
+            Expr::col((AlbumAudio, album_audio::Column::DiscNumber)).into(),
+            Expr::val(1).into(),
+        ])))
+        .order_by_asc(album_audio::Column::TrackNumber)
         .order_by_asc(directory::Column::Name)
         .order_by_asc(audio::Column::Name)
         .into_model::<MediaList>()
@@ -216,434 +528,981 @@ pub(crate) async fn get_media(config: &Config) -> Vec<MediaList> {
         Ok(r) => r,
         Err(e) => {
             // No results to return.
+            event!(Level::WARN, "Audio::find() list_media failure: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Parse a range of BPM, like "120-130", "120-" or "-90", into an inclusive minimum and an
+/// exclusive maximum. A single BPM, like "128", matches tempos that round to it.
+fn parse_bpm_range(range: &str) -> Option<(Option<f64>, Option<f64>)> {
+    let bpm = |value: &str| -> Option<Option<f64>> {
+        let value = value.trim();
+        if value.is_empty() {
+            return Some(None);
+        }
+        value.parse().ok().filter(|v: &f64| v.is_finite()).map(Some)
+    };
+    match range.split_once('-') {
+        Some((minimum, maximum)) => Some((bpm(minimum)?, bpm(maximum)?)),
+        None => {
+            let tempo = bpm(range)??;
+            Some((Some(tempo - 0.5), Some(tempo + 0.5)))
+        }
+    }
+}
+
+/// Group audio files into directories, giving an initial view of what are most likely
+/// albums. Disc subdirectories are grouped with the directory above them. Only audio files
+/// in scope that aren't already in a directory are grouped.
+pub(crate) async fn group_directories(config: &Config, scope: &Scope) {
+    event!(Level::TRACE, "group_directories");
+    let db = database::connection(config).await;
+
+    let audio_files = match Audio::find()
+        .left_join(AudioDirectory)
+        .filter(audio_directory::Column::AudioDirectoryId.is_null())
+        .filter(scope.paths(audio::Column::Path))
+        .all(db)
+        .await
+    {
+        Ok(a) => a,
+        Err(e) => {
             event!(
                 Level::WARN,
-                "AudioDirectory::find() list_media failure: {}",
+                "Audio::find() without directory failure: {}",
+                e
+            );
+            return;
+        }
+    };
+    if audio_files.is_empty() {
+        return;
+    }
+
+    let mut directories: HashMap<String, i32> = match Directory::find().all(db).await {
+        Ok(d) => d
+            .into_iter()
+            .map(|directory| (directory.path, directory.directory_id))
+            .collect(),
+        Err(e) => {
+            event!(Level::WARN, "Directory::find() failure: {}", e);
+            return;
+        }
+    };
+
+    for audio_files in audio_files.chunks(DIRECTORY_BATCH_SIZE) {
+        let now = chrono::Utc::now().naive_utc();
+        let mut audio_directories = Vec::new();
+        for audio_file in audio_files {
+            // Disc subdirectories are grouped together with the rest of their album.
+            let (path, _) = fold_disc_directory(&audio_file.path);
+            let directory_id = if let Some(directory_id) = directories.get(&path) {
+                *directory_id
+            } else {
+                let name = match Path::new(&path).file_name() {
+                    Some(n) => n.to_string_lossy().to_string(),
+                    None => path.clone(),
+                };
+                let new_directory = directory::ActiveModel {
+                    created: ActiveValue::Set(now.to_owned()),
+                    updated: ActiveValue::Set(now.to_owned()),
+                    name: ActiveValue::Set(name),
+                    path: ActiveValue::Set(path.clone()),
+                    ..Default::default()
+                };
+                let directory_id = match Directory::insert(new_directory).exec(db).await {
+                    Ok(d) => d.last_insert_id,
+                    Err(e) => {
+                        event!(Level::WARN, "failed to write directory to database: {}", e);
+                        continue;
+                    }
+                };
+                directories.insert(path, directory_id);
+                directory_id
+            };
+            audio_directories.push(audio_directory::ActiveModel {
+                created: ActiveValue::Set(now.to_owned()),
+                updated: ActiveValue::Set(now.to_owned()),
+                directory_id: ActiveValue::Set(directory_id),
+                audio_id: ActiveValue::Set(audio_file.audio_id),
+                ..Default::default()
+            });
+        }
+        if audio_directories.is_empty() {
+            continue;
+        }
+        if let Err(e) = AudioDirectory::insert_many(audio_directories)
+            .exec(db)
+            .await
+        {
+            event!(
+                Level::WARN,
+                "failed to write audio_directory to database: {}",
                 e
             );
-            Vec::new()
         }
     }
 }
 
-// Scan for media files.
-pub(crate) async fn scan_media_files(config: &Config) {
-    // Percent-encode all characters except alpha-numerics and "/" to build proper
-    // paths. @TODO: remove characters necessary to navigate Windows paths.
-    const FRAGMENT: &AsciiSet = &NON_ALPHANUMERIC.remove(b'/');
+/// Group audio files into albums using their Album, AlbumArtist, DiscNumber, TrackNumber and
+/// Date tags. Albums are identified by their name and album artist. Audio files without an
+/// AlbumArtist tag fall back to being grouped with the other files in the same directory
+/// that share their Album tag, or their directory name if they have no Album tag. Only
+/// audio files in scope that aren't already in an album are grouped.
+pub(crate) async fn group_albums(config: &Config, scope: &Scope) {
+    event!(Level::TRACE, "group_albums");
+    let db = database::connection(config).await;
 
-    let path = config
-        .library
-        .as_ref()
-        .expect("library must exist")
-        .to_string();
+    let audio_files = match Audio::find()
+        .left_join(AlbumAudio)
+        .filter(album_audio::Column::AlbumAudioId.is_null())
+        .filter(scope.paths(audio::Column::Path))
+        .all(db)
+        .await
+    {
+        Ok(a) => a,
+        Err(e) => {
+            event!(Level::WARN, "Audio::find() without album failure: {}", e);
+            return;
+        }
+    };
+    if audio_files.is_empty() {
+        return;
+    }
 
-    let queue_config = config.clone();
-    let (queue_tx, queue_rx) = oneshot::channel::<bool>();
-    // Some items require an API lookup. We have to throttle our API requests, so some items will
-    // have to wait in a queue until we are able to process them.
-    let queue_handle =
-        tokio::spawn(async move { musicbrainz::process_queue(&queue_config, queue_rx).await });
+    let known_albums = match Album::find().all(db).await {
+        Ok(a) => a,
+        Err(e) => {
+            event!(Level::WARN, "Album::find() failure: {}", e);
+            return;
+        }
+    };
+    let mut total_discs: HashMap<i32, Option<i32>> = known_albums
+        .iter()
+        .map(|album| (album.album_id, album.total_discs))
+        .collect();
+    let mut albums: HashMap<AlbumKey, i32> = known_albums
+        .into_iter()
+        .map(|album| {
+            (
+                AlbumKey::new(album.name, album.artist_name, &album.path),
+                album.album_id,
+            )
+        })
+        .collect();
+    let mut updated_discs = Vec::new();
 
-    let walker = WalkDir::new(path).follow_links(true).into_iter();
-    for (counter, entry) in walker.filter_entry(|e| !utils::is_hidden(e)).enumerate() {
-        let metadata = match entry.as_ref() {
-            Ok(i) => match i.metadata() {
-                Ok(m) => m,
-                Err(e) => {
-                    event!(Level::WARN, "metadata() failure: {}", e);
-                    continue;
-                }
-            },
+    for audio_files in audio_files.chunks(ALBUM_BATCH_SIZE) {
+        let audio_ids: Vec<i32> = audio_files.iter().map(|a| a.audio_id).collect();
+        let audio_tags = match AudioTag::find()
+            .filter(audio_tag::Column::AudioId.is_in(audio_ids))
+            .filter(audio_tag::Column::Name.is_in(ALBUM_TAGS))
+            .order_by_asc(audio_tag::Column::Position)
+            .order_by_asc(audio_tag::Column::AudioTagId)
+            .all(db)
+            .await
+        {
+            Ok(t) => t,
             Err(e) => {
-                event!(Level::WARN, "WalkDir entry.as_ref() failure: {}", e);
-                continue;
+                event!(Level::WARN, "AudioTag::find() album tags failure: {}", e);
+                return;
             }
         };
+        // Only the first value of each tag is used.
+        let mut tags: HashMap<i32, HashMap<String, String>> = HashMap::new();
+        for tag in audio_tags {
+            let value = tag.value.trim().to_string();
+            if !value.is_empty() {
+                tags.entry(tag.audio_id)
+                    .or_default()
+                    .entry(tag.name)
+                    .or_insert(value);
+            }
+        }
 
-        // Files may be tracks, images, playlists, and more.
-        if metadata.is_file() {
-            let format = match FileFormat::from_file(match entry.as_ref() {
-                Ok(i) => i.path(),
-                Err(e) => {
-                    event!(Level::WARN, "WalkDir entry.as_ref() failure: {}", e);
-                    continue;
-                }
-            }) {
-                Ok(f) => f,
-                Err(e) => {
-                    event!(Level::WARN, "FileFormat::from_file() failure: {}", e);
-                    continue;
-                }
+        let now = chrono::Utc::now().naive_utc();
+        let mut album_audio = Vec::new();
+        for audio_file in audio_files {
+            let tags = tags.remove(&audio_file.audio_id).unwrap_or_default();
+            // Disc subdirectories are part of the album in the directory above them.
+            let (path, directory_disc) = fold_disc_directory(&audio_file.path);
+            let name = match tags.get("Album") {
+                Some(album) => album.to_string(),
+                None => match Path::new(&path).file_name() {
+                    Some(directory) => directory.to_string_lossy().to_string(),
+                    None => "Unknown album".to_string(),
+                },
             };
+            let artist_name = tags.get("AlbumArtist").cloned();
+            let key = AlbumKey::new(name.clone(), artist_name.clone(), &path);
 
-            let media_type = MediaType::from_str(format.media_type()).unwrap_or(MediaType::Unknown);
-            match media_type {
-                MediaType::Image => {
-                    // @TODO: Associate the image with the album or artist depending on where it is.
-                    event!(
-                        Level::DEBUG,
-                        "Image detected ({}): {}",
-                        format.media_type(),
-                        match entry.as_ref() {
-                            Ok(d) => d.path().display(),
-                            Err(e) => {
-                                event!(Level::WARN, "WalkDir entry.as_ref() failure: {}", e);
-                                continue;
-                            }
-                        }
-                    );
-                }
-                MediaType::_Text => {
-                    // @TODO: How to properly detect text?
-                }
-                MediaType::Audio => {
-                    // Build an absolute URI to uniquely identify files.
-                    let path = Path::new(match &std::env::current_dir() {
-                        Ok(c) => c,
-                        Err(e) => {
-                            event!(Level::WARN, "std::env::current_dir() failure: {}", e);
-                            continue;
-                        }
-                    })
-                    .join(match entry.as_ref() {
-                        Ok(d) => d.path(),
-                        Err(e) => {
-                            event!(Level::WARN, "WalkDir entry.as_ref() failure: {}", e);
-                            continue;
-                        }
-                    });
-
-                    let uri = format!(
-                        "file://{}",
-                        utf8_percent_encode(
-                            match path.to_str() {
-                                Some(p) => p,
-                                None => {
-                                    event!(Level::WARN, "path.to_str() failure: NONE");
-                                    continue;
-                                }
-                            },
-                            FRAGMENT
-                        )
-                        .collect::<String>(),
-                    );
-
-                    event!(
-                        Level::DEBUG,
-                        "Audio file detected at path: {} ({})",
-                        path.display(),
-                        uri
-                    );
-
-                    // Check if this audio file is already in the database.
-                    let existing = {
-                        let db = database::connection(config).await;
-                        match Audio::find()
-                            .filter(audio::Column::Uri.like(&uri))
-                            .one(db)
-                            .await
-                        {
-                            Ok(e) => e,
-                            Err(e) => {
-                                event!(Level::WARN, "Audio::find() failure: {}", e);
-                                break;
-                            }
-                        }
-                    };
-
-                    let extension = match path.extension() {
-                        Some(e) => e.to_str().unwrap_or(""),
-                        None => {
-                            event!(Level::WARN, "path.extension() returned nothing");
-                            ""
-                        }
-                    };
-
-                    // For now we only scan a file one time -- if it's already been seen and
-                    // changes this logic will not update it.
-                    // @TODO: Detect changes to the files, and update as needed.
-                    // @TODO: Error handling.
-                    if existing.is_none() {
-                        // @TODO: compare to database.
-                        let mut audio = audio::ActiveModel {
-                            uri: ActiveValue::Set(uri.clone()),
-                            path: ActiveValue::Set(match entry.as_ref().unwrap().path().parent() {
-                                Some(p) => p.display().to_string(),
-                                None => {
-                                    event!(Level::WARN, "path.parent() returned none");
-                                    "".to_string()
-                                }
-                            }),
-                            name: ActiveValue::Set(match path.file_name() {
-                                Some(f) => f.to_str().unwrap_or("").to_string(),
-                                None => {
-                                    event!(Level::WARN, "path.file_name() returned nothing");
-                                    "".to_string()
-                                }
-                            }),
-                            extension: ActiveValue::Set(extension.to_string()),
-                            // The following values will be replaced later if Symphonia is able to
-                            // identify the contents of this audio file.
-                            format: ActiveValue::Set("UNKNOWN".to_string()),
-                            duration: ActiveValue::Set(0),
-                            channels: ActiveValue::Set(0),
-                            bits: ActiveValue::Set(0),
-                            hertz: ActiveValue::Set(0),
-                            ..Default::default()
-                        };
-
-                        let src = std::fs::File::open(&path).expect("failed to open media");
-                        let mss = MediaSourceStream::new(Box::new(src), Default::default());
-
-                        // Add file suffix hint to speed of probe.
-                        let mut hint = Hint::new();
-                        if !extension.is_empty() {
-                            hint.with_extension(extension);
-                        }
-
-                        // Use the default options for metadata and format readers.
-                        let meta_opts: MetadataOptions = Default::default();
-                        let fmt_opts: FormatOptions = Default::default();
-
-                        // Probe the media source.
-                        let mut probed = match symphonia::default::get_probe()
-                            .format(&hint, mss, &fmt_opts, &meta_opts)
-                        {
-                            Ok(p) => p,
-                            Err(e) => {
-                                event!(Level::WARN, "Symphonia get_probe() failure: {}", e);
-                                continue;
-                            }
-                        };
-
-                        let tracks = probed.format.tracks();
-                        for (idx, track) in tracks.iter().enumerate() {
-                            assert!(idx == 0);
-                            let params = &track.codec_params;
-
-                            if let Some(codec) =
-                                symphonia::default::get_codecs().get_codec(params.codec)
-                            {
-                                audio.format =
-                                    sea_orm::ActiveValue::Set(codec.long_name.to_string());
-                            }
-
-                            // Get duration.
-                            if let Some(n_frames) = params.n_frames {
-                                if let Some(tb) = params.time_base {
-                                    audio.duration = sea_orm::ActiveValue::Set(
-                                        tb.calc_time(n_frames).seconds as i32,
-                                    );
-                                }
-                            }
-                            // Get channels.
-                            audio.channels =
-                                sea_orm::ActiveValue::Set(params.channels.unwrap().count() as i32);
-                            audio.bits = sea_orm::ActiveValue::Set(match params.bits_per_sample {
-                                Some(b) => b as i32,
-                                None => 0,
-                            });
-                            audio.hertz =
-                                sea_orm::ActiveValue::Set(params.sample_rate.unwrap() as i32);
-                        }
-
-                        event!(Level::DEBUG, "Insert Audio File: {:?}", audio);
-                        let new_audio = {
-                            let db = database::connection(config).await;
-                            Audio::insert(audio)
-                                .exec(db)
-                                .await
-                                .expect("failed to write audio details to database")
-                        };
-
-                        if let Some(metadata_rev) = probed.format.metadata().current() {
-                            // Step through only known tags.
-                            for tag in metadata_rev.tags().iter().filter(|tag| tag.is_known()) {
-                                if let Some(std_key) = tag.std_key {
-                                    event!(Level::DEBUG, "tag {:?}: {}", std_key, tag.value);
-                                    let name = format!("{:?}", std_key);
-                                    let new_tag = audio_tag::ActiveModel {
-                                        audio_id: ActiveValue::Set(new_audio.last_insert_id),
-                                        name: ActiveValue::Set(name.to_string()),
-                                        value: ActiveValue::Set(tag.value.to_string()),
-                                        ..Default::default()
-                                    };
-                                    {
-                                        event!(Level::DEBUG, "Insert AudioTag: {:?}", new_tag);
-                                        let db = database::connection(config).await;
-                                        AudioTag::insert(new_tag)
-                                            .exec(db)
-                                            .await
-                                            .expect("failed to write tag to database");
-                                    }
-                                    if name == "Artist" {
-                                        let existing_artist = {
-                                            let db = database::connection(config).await;
-                                            match Artist::find()
-                                                .filter(
-                                                    artist::Column::Name
-                                                        .like(&tag.value.to_string()),
-                                                )
-                                                .one(db)
-                                                .await
-                                            {
-                                                Ok(e) => e,
-                                                Err(e) => {
-                                                    event!(
-                                                        Level::WARN,
-                                                        "Artist::find() failure: {}",
-                                                        e
-                                                    );
-                                                    break;
-                                                }
-                                            }
-                                        };
-
-                                        if let Some(artist) = existing_artist {
-                                            store_audio_artist(
-                                                config,
-                                                new_audio.last_insert_id,
-                                                artist.artist_id,
-                                            )
-                                            .await;
-                                        } else {
-                                            // Artist doesn't exist in our database, add to MusicBrainz queue
-                                            // to download details.
-                                            musicbrainz::add_to_queue(
-                                                config,
-                                                musicbrainz::QueuePayload {
-                                                    payload_type:
-                                                        musicbrainz::PayloadType::AudioArtist,
-                                                    id: new_audio.last_insert_id,
-                                                    value: tag.value.to_string(),
-                                                },
-                                            )
-                                            .await;
-                                        }
-                                    }
-                                }
-                            }
-                        }
+            let album_id = if let Some(album_id) = albums.get(&key) {
+                *album_id
+            } else {
+                let new_album = album::ActiveModel {
+                    created: ActiveValue::Set(now.to_owned()),
+                    updated: ActiveValue::Set(now.to_owned()),
+                    name: ActiveValue::Set(name),
+                    artist_name: ActiveValue::Set(artist_name),
+                    date: ActiveValue::Set(tags.get("Date").cloned()),
+                    path: ActiveValue::Set(path),
+                    ..Default::default()
+                };
+                event!(Level::DEBUG, "Insert Album: {:?}", new_album);
+                let album_id = match Album::insert(new_album).exec(db).await {
+                    Ok(a) => a.last_insert_id,
+                    Err(e) => {
+                        event!(Level::WARN, "failed to write album to database: {}", e);
+                        continue;
                     }
-                }
-                MediaType::Unknown => {
-                    // @TODO: Deal with audio files that we didn't properly detect.
-                    // @TODO: Perhaps detect text files in a second pass here, on the file extension?
-                    event!(
-                        Level::WARN,
-                        "UNKNOWN ({}): {}",
-                        format.media_type(),
-                        match entry.as_ref() {
-                            Ok(d) => d.path().display(),
-                            Err(e) => {
-                                event!(Level::WARN, "WalkDir entry.as_ref() failure: {}", e);
-                                continue;
-                            }
-                        }
-                    );
-                }
+                };
+                albums.insert(key, album_id);
+                total_discs.insert(album_id, None);
+                album_id
+            };
+
+            // Prefer the DiscNumber tag, falling back to the disc subdirectory.
+            let disc_number = match tags.get("DiscNumber") {
+                Some(disc) => tag_number(disc),
+                None => directory_disc,
+            };
+            let track_number = tags.get("TrackNumber").and_then(|n| tag_number(n));
+
+            // The number of discs is the most found in the DiscTotal tag, the total in the
+            // DiscNumber tag (ie "1/2"), or the highest disc number.
+            let discs = [
+                tags.get("DiscTotal").and_then(|n| tag_number(n)),
+                tags.get("DiscNumber").and_then(|n| tag_total(n)),
+                disc_number,
+            ]
+            .into_iter()
+            .flatten()
+            .max();
+            let known_discs = total_discs.entry(album_id).or_default();
+            if discs > *known_discs {
+                *known_discs = discs;
+                updated_discs.push(album_id);
             }
-        // Albums are collected together in directories.
-        } else if metadata.is_dir() {
-            // @TODO: Track directories for visualization, organization, and to assist in
-            // auto-identifying albums.
+
+            album_audio.push(album_audio::ActiveModel {
+                created: ActiveValue::Set(now.to_owned()),
+                updated: ActiveValue::Set(now.to_owned()),
+                album_id: ActiveValue::Set(album_id),
+                audio_id: ActiveValue::Set(audio_file.audio_id),
+                disc_number: ActiveValue::Set(disc_number),
+                track_number: ActiveValue::Set(track_number),
+                ..Default::default()
+            });
+        }
+        if album_audio.is_empty() {
+            continue;
         }
+        if let Err(e) = AlbumAudio::insert_many(album_audio).exec(db).await {
+            event!(
+                Level::WARN,
+                "failed to write album_audio to database: {}",
+                e
+            );
+        }
+    }
 
-        // @TODO: Make this configurable and optional.
-        if counter > 25_000 {
-            break;
+    updated_discs.sort_unstable();
+    updated_discs.dedup();
+    for album_id in updated_discs {
+        let album = album::ActiveModel {
+            album_id: ActiveValue::Unchanged(album_id),
+            total_discs: ActiveValue::Set(total_discs.get(&album_id).copied().flatten()),
+            updated: ActiveValue::Set(chrono::Utc::now().naive_utc()),
+            ..Default::default()
+        };
+        if let Err(e) = album.update(db).await {
+            event!(Level::WARN, "failed to update album discs: {}", e);
         }
     }
 
-    // Next, group audio files into directories (giving an initial view of what are most likely albums).
+    link_album_artists(config, scope).await;
+}
+
+/// Albums are identified by name and album artist, or by name and directory when there's no
+/// album artist.
+#[derive(PartialEq, Eq, Hash)]
+struct AlbumKey(String, Option<String>, Option<String>);
+
+impl AlbumKey {
+    fn new(name: String, artist_name: Option<String>, path: &str) -> Self {
+        let path = match artist_name {
+            Some(_) => None,
+            None => Some(path.to_string()),
+        };
+        AlbumKey(name, artist_name, path)
+    }
+}
+
+/// Parse a track or disc number tag, which may also include the total, ie "3/12".
+fn tag_number(value: &str) -> Option<i32> {
+    value.split('/').next()?.trim().parse().ok()
+}
+
+/// Parse the total from a track or disc number tag, ie "3/12".
+fn tag_total(value: &str) -> Option<i32> {
+    value.split('/').nth(1)?.trim().parse().ok()
+}
+
+/// Multi-disc albums are often split into a subdirectory per disc. Returns the album's
+/// directory, which is the directory above a disc subdirectory, along with the disc number
+/// if the given directory is a disc subdirectory.
+pub(crate) fn fold_disc_directory(path: &str) -> (String, Option<i32>) {
+    let directory = Path::new(path);
+    let name = directory.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    let disc = DISC_DIRECTORY
+        .captures(name)
+        .and_then(|c| c.get(1))
+        .and_then(|d| d.as_str().parse().ok());
+    match (disc, directory.parent()) {
+        (Some(disc), Some(parent)) if !parent.as_os_str().is_empty() => {
+            (parent.display().to_string(), Some(disc))
+        }
+        _ => (path.to_string(), None),
+    }
+}
+
+/// Link albums to their artist: the artist named by the AlbumArtist tag, or otherwise the
+/// only artist found on the album's tracks. Albums whose artists haven't been identified yet
+/// are linked once they are.
+pub(crate) async fn link_album_artists(config: &Config, scope: &Scope) {
+    event!(Level::TRACE, "link_album_artists");
+    let db = database::connection(config).await;
+
+    let albums = match Album::find()
+        .filter(album::Column::ArtistId.is_null())
+        .filter(scope.albums(album::Column::AlbumId))
+        .all(db)
+        .await
     {
-        let db = database::connection(config).await;
-        // Build enum to select column into a Vec<String>.
-        #[derive(Copy, Clone, Debug, EnumIter, DeriveColumn)]
-        enum QueryAs {
-            GroupByPath,
+        Ok(a) => a,
+        Err(e) => {
+            event!(Level::WARN, "Album::find() without artist failure: {}", e);
+            return;
         }
-        let results: Vec<String> = match audio::Entity::find()
+    };
+
+    // Build enum to select artist_id column into a Vec<i32>.
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveColumn)]
+    enum QueryAs {
+        ArtistId,
+    }
+    for album in albums {
+        // Prefer the MusicBrainz album artist IDs tagged in the album's audio files.
+        let artist_ids: Vec<i32> = match Artist::find()
+            .filter(
+                artist::Column::MusicbrainzId.in_subquery(
+                    Query::select()
+                        .column(audio_tag::Column::Value)
+                        .from(AudioTag)
+                        .and_where(audio_tag::Column::Name.eq("MusicBrainzAlbumArtistId"))
+                        .and_where(
+                            audio_tag::Column::AudioId.in_subquery(
+                                Query::select()
+                                    .column(album_audio::Column::AudioId)
+                                    .from(AlbumAudio)
+                                    .and_where(album_audio::Column::AlbumId.eq(album.album_id))
+                                    .to_owned(),
+                            ),
+                        )
+                        .to_owned(),
+                ),
+            )
             .select_only()
-            .column_as(audio::Column::Path, QueryAs::GroupByPath)
-            .group_by(audio::Column::Path)
+            .column_as(artist::Column::ArtistId, QueryAs::ArtistId)
             .into_values::<_, QueryAs>()
             .all(db)
             .await
         {
-            Ok(q) => q,
+            Ok(a) => a,
             Err(e) => {
-                event!(Level::WARN, "Audio::find() directories failure: {}", e);
-                // @TODO: What to do?
-                return;
+                event!(Level::WARN, "Artist::find() album artist id failure: {}", e);
+                continue;
             }
         };
-        for result in results {
-            let components = Path::new(&result).components();
-            // @TODO: Error handling.
-            let name = components.last().unwrap().as_os_str().to_str().unwrap();
-
-            let existing = {
-                match Directory::find()
-                    .filter(directory::Column::Name.eq(name))
-                    .one(db)
-                    .await
-                {
-                    Ok(e) => e,
-                    Err(e) => {
-                        event!(Level::WARN, "Audio::find() failure: {}", e);
-                        break;
-                    }
+        let pinned = match album.artist_name.as_ref() {
+            Some(artist_name) => {
+                artists::pinned_artist(&artists::load_pins(config, artist_name).await, &album.path)
+            }
+            None => None,
+        };
+        let artist_ids: Vec<i32> = if !artist_ids.is_empty() {
+            artist_ids
+        } else if let Some(artist_id) = pinned {
+            vec![artist_id]
+        } else if let Some(artist_name) = album.artist_name.as_ref() {
+            match Artist::find()
+                .filter(artist::Column::Name.like(artist_name))
+                .select_only()
+                .column_as(artist::Column::ArtistId, QueryAs::ArtistId)
+                .into_values::<_, QueryAs>()
+                .all(db)
+                .await
+            {
+                Ok(a) => a,
+                Err(e) => {
+                    event!(Level::WARN, "Artist::find() album artist failure: {}", e);
+                    continue;
                 }
-            };
-            if existing.is_none() {
-                let now = chrono::Utc::now().naive_utc();
-                let new_directory = directory::ActiveModel {
-                    created: ActiveValue::Set(now.to_owned()),
-                    updated: ActiveValue::Set(now.to_owned()),
-                    // @TODO: Error handling.
-                    name: ActiveValue::Set(name.to_owned()),
-                    path: ActiveValue::Set(result.to_owned()),
-                    ..Default::default()
-                };
-                let created_directory = Directory::insert(new_directory)
-                    .exec(db)
-                    .await
-                    .expect("failed to write directory to database");
-
-                // Find all audio files contained in the directory.
-                let audio_files = match Audio::find()
-                    .filter(audio::Column::Path.like(&result))
-                    .all(db)
-                    .await
-                {
-                    Ok(e) => e,
-                    Err(e) => {
-                        event!(Level::WARN, "Artist::find() by path failure: {}", e);
-                        // @TODO: what to do?
-                        return;
-                    }
-                };
-                for audio_file in audio_files {
-                    let audio = audio_directory::ActiveModel {
-                        created: ActiveValue::Set(now.to_owned()),
-                        updated: ActiveValue::Set(now),
-                        directory_id: ActiveValue::Set(created_directory.last_insert_id),
-                        audio_id: ActiveValue::Set(audio_file.audio_id),
-                        ..Default::default()
-                    };
-                    AudioDirectory::insert(audio)
-                        .exec(db)
-                        .await
-                        .expect("failed to write audio_directory to database");
+            }
+        } else {
+            match AudioArtist::find()
+                .filter(
+                    audio_artist::Column::AudioId.in_subquery(
+                        Query::select()
+                            .column(album_audio::Column::AudioId)
+                            .from(AlbumAudio)
+                            .and_where(album_audio::Column::AlbumId.eq(album.album_id))
+                            .to_owned(),
+                    ),
+                )
+                .select_only()
+                .column_as(audio_artist::Column::ArtistId, QueryAs::ArtistId)
+                .distinct()
+                .into_values::<_, QueryAs>()
+                .all(db)
+                .await
+            {
+                Ok(a) => a,
+                Err(e) => {
+                    event!(Level::WARN, "AudioArtist::find() album failure: {}", e);
+                    continue;
                 }
             }
+        };
+
+        // Compilations have more than one artist.
+        if let [artist_id] = artist_ids[..] {
+            let mut album: album::ActiveModel = album.into();
+            album.artist_id = ActiveValue::Set(Some(artist_id));
+            album.updated = ActiveValue::Set(chrono::Utc::now().naive_utc());
+            if let Err(e) = album.update(db).await {
+                event!(Level::WARN, "failed to update album artist: {}", e);
+            }
+        }
+    }
+}
+
+/// Remove albums that no longer contain any audio files.
+pub(crate) async fn remove_empty_albums(config: &Config) {
+    event!(Level::TRACE, "remove_empty_albums");
+    let db = database::connection(config).await;
+    let albums = match Album::find()
+        .left_join(AlbumAudio)
+        .filter(album_audio::Column::AlbumAudioId.is_null())
+        .all(db)
+        .await
+    {
+        Ok(a) => a,
+        Err(e) => {
+            event!(Level::WARN, "Album::find() empty failure: {}", e);
+            return;
+        }
+    };
+    for album in albums {
+        event!(Level::INFO, "Album removed: {}", album.name);
+        if let Err(e) = AlbumImage::delete_many()
+            .filter(album_image::Column::AlbumId.eq(album.album_id))
+            .exec(db)
+            .await
+        {
+            event!(Level::WARN, "AlbumImage::delete_many() failure: {}", e);
+            continue;
+        }
+        if let Err(e) = Album::delete_by_id(album.album_id).exec(db).await {
+            event!(
+                Level::WARN,
+                "failed to delete album id {}: {}",
+                album.album_id,
+                e
+            );
+        }
+    }
+}
+
+/// Determine if an audio file has changed since it was last scanned.
+pub(crate) fn audio_changed(
+    audio: &audio::Model,
+    size: i64,
+    modified: Option<chrono::NaiveDateTime>,
+    hash: &Option<String>,
+) -> bool {
+    if audio.size != size || audio.modified != modified {
+        return true;
+    }
+    // Hashes are only compared if the file has been hashed before.
+    matches!((&audio.hash, hash), (Some(a), Some(b)) if a != b)
+}
+
+/// Probe an audio file with Symphonia, updating the ActiveModel with the detected format
+/// details. Returns all known tags as (name, value) pairs, or None if the file can't be
+/// probed.
+pub(crate) fn probe_audio(
+    path: &Path,
+    extension: &str,
+    audio: &mut audio::ActiveModel,
+) -> Option<(Tags, Vec<Visual>)> {
+    event!(Level::TRACE, "probe_audio {}", path.display());
+
+    // The following values will be replaced if Symphonia is able to identify the contents
+    // of this audio file.
+    audio.format = ActiveValue::Set("UNKNOWN".to_string());
+    audio.duration = ActiveValue::Set(0);
+    audio.channels = ActiveValue::Set(0);
+    audio.bits = ActiveValue::Set(0);
+    audio.hertz = ActiveValue::Set(0);
+
+    let src = match std::fs::File::open(path) {
+        Ok(s) => s,
+        Err(e) => {
+            event!(
+                Level::WARN,
+                "failed to open media {}: {}",
+                path.display(),
+                e
+            );
+            return None;
+        }
+    };
+    let mss = MediaSourceStream::new(Box::new(src), Default::default());
+
+    // Add file suffix hint to speed of probe.
+    let mut hint = Hint::new();
+    if !extension.is_empty() {
+        hint.with_extension(extension);
+    }
+
+    // Use the default options for metadata and format readers.
+    let meta_opts: MetadataOptions = Default::default();
+    let fmt_opts: FormatOptions = Default::default();
+
+    // Probe the media source.
+    let mut probed = match symphonia::default::get_probe().format(&hint, mss, &fmt_opts, &meta_opts)
+    {
+        Ok(p) => p,
+        Err(e) => {
+            event!(Level::WARN, "Symphonia get_probe() failure: {}", e);
+            return None;
+        }
+    };
+
+    // Details are taken from the default track. Probes run in worker threads, so missing
+    // details are recorded as unknown rather than panicking.
+    if let Some(track) = probed.format.default_track() {
+        let params = &track.codec_params;
+
+        if let Some(codec) = symphonia::default::get_codecs().get_codec(params.codec) {
+            audio.format = sea_orm::ActiveValue::Set(codec.long_name.to_string());
+        }
+
+        // Get duration.
+        if let Some(n_frames) = params.n_frames {
+            if let Some(tb) = params.time_base {
+                audio.duration = sea_orm::ActiveValue::Set(tb.calc_time(n_frames).seconds as i32);
+            }
         }
+        // Get channels.
+        audio.channels = sea_orm::ActiveValue::Set(match params.channels {
+            Some(c) => c.count() as i32,
+            None => 0,
+        });
+        audio.bits = sea_orm::ActiveValue::Set(match params.bits_per_sample {
+            Some(b) => b as i32,
+            None => 0,
+        });
+        audio.hertz = sea_orm::ActiveValue::Set(match params.sample_rate {
+            Some(r) => r as i32,
+            None => 0,
+        });
+    }
+
+    let mut tags = Vec::new();
+    let mut visuals = Vec::new();
+    // Metadata found while probing, ie ID3v2 tags at the start of the file, isn't part of the
+    // container's metadata.
+    if let Some(metadata_rev) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
+        add_tags(&mut tags, metadata_rev.tags());
+        visuals.extend(metadata_rev.visuals().iter().cloned());
     }
+    if let Some(metadata_rev) = probed.format.metadata().current() {
+        add_tags(&mut tags, metadata_rev.tags());
+        visuals.extend(metadata_rev.visuals().iter().cloned());
+    }
+    Some((tags, visuals))
+}
+
+/// Add all tags with a text or numeric value, numbering tags that have more than one value.
+fn add_tags(tags: &mut Tags, found: &[symphonia::core::meta::Tag]) {
+    for tag in found {
+        let value = match &tag.value {
+            Value::Binary(_) => continue,
+            v => v.to_string(),
+        };
+        let name = match tag.std_key {
+            Some(std_key) => format!("{:?}", std_key),
+            None => normalise_tag_key(&tag.key),
+        };
+        // Some formats store multiple values in one tag, separated by nulls.
+        for value in value.split('\0').map(str::trim).filter(|v| !v.is_empty()) {
+            event!(Level::DEBUG, "tag {} ({}): {}", name, tag.key, value);
+            let position = tags.iter().filter(|t| t.name == name).count() as i32;
+            tags.push(Tag {
+                name: name.clone(),
+                key: tag.key.clone(),
+                value: value.to_string(),
+                position,
+            });
+        }
+    }
+}
+
+/// Normalise the key of a tag without a standard name, so the same tag can be found in
+/// different formats, ie "TXXX:MusicBrainz Album Type" in ID3v2 and "MUSICBRAINZ_ALBUMTYPE"
+/// in Vorbis comments are both "MUSICBRAINZ_ALBUMTYPE".
+fn normalise_tag_key(key: &str) -> String {
+    let key = key.strip_prefix("TXXX:").unwrap_or(key);
+    let key = key.trim().to_uppercase();
+    // Multi-word MusicBrainz keys are written without separators in Vorbis comments.
+    match key.strip_prefix("MUSICBRAINZ ") {
+        Some(rest) => format!("MUSICBRAINZ_{}", rest.replace([' ', '-'], "")),
+        None => key.replace([' ', '-'], "_"),
+    }
+}
+
+/// Associate an audio file with the artists found in its tags. Artists that aren't in the
+/// database yet are added to the MusicBrainz queue.
+pub(crate) async fn link_audio_artists(config: &Config, audio_id: i32, tags: &[Tag]) {
+    event!(Level::TRACE, "link_audio_artists audio_id({})", audio_id);
 
-    // Tell thread processing queue that no new messages are coming.
-    let _ = queue_tx.send(true);
-    // Wait for the queue processing thread to finish.
-    let _ = queue_handle.await;
+    // Album artists are looked up so albums can be linked to them, see link_album_artists().
+    for musicbrainz_id in musicbrainz_ids(tags, "MusicBrainzAlbumArtistId") {
+        if musicbrainz::find_artist_by_id(config, &musicbrainz_id)
+            .await
+            .is_none()
+        {
+            musicbrainz::add_to_queue(
+                config,
+                musicbrainz::QueuePayload {
+                    payload_type: musicbrainz::PayloadType::ArtistId,
+                    id: audio_id,
+                    value: musicbrainz_id,
+                },
+            )
+            .await;
+        }
+    }
+
+    // MusicBrainz IDs identify artists exactly, only search by name if the tags have none.
+    let musicbrainz_ids = musicbrainz_ids(tags, "MusicBrainzArtistId");
+    if !musicbrainz_ids.is_empty() {
+        for musicbrainz_id in musicbrainz_ids {
+            if let Some(artist_id) = musicbrainz::find_artist_by_id(config, &musicbrainz_id).await {
+                if let Err(e) = store_audio_artist(config, audio_id, artist_id).await {
+                    event!(Level::WARN, "failed to store audio artist: {}", e);
+                }
+            } else {
+                musicbrainz::add_to_queue(
+                    config,
+                    musicbrainz::QueuePayload {
+                        payload_type: musicbrainz::PayloadType::AudioArtistId,
+                        id: audio_id,
+                        value: musicbrainz_id,
+                    },
+                )
+                .await;
+            }
+        }
+        return;
+    }
+
+    for value in tags.iter().filter(|t| t.name == "Artist").map(|t| &t.value) {
+        // Artists pinned by hand take precedence over matching by name.
+        if let Some(artist_id) = artists::find_pinned_artist(config, value, audio_id).await {
+            if let Err(e) = store_audio_artist(config, audio_id, artist_id).await {
+                event!(Level::WARN, "failed to store audio artist: {}", e);
+            }
+            continue;
+        }
+
+        let existing_artist = {
+            let db = database::connection(config).await;
+            match Artist::find()
+                .filter(artist::Column::Name.like(value))
+                .one(db)
+                .await
+            {
+                Ok(e) => e,
+                Err(e) => {
+                    event!(Level::WARN, "Artist::find() failure: {}", e);
+                    continue;
+                }
+            }
+        };
+
+        if let Some(artist) = existing_artist {
+            if let Err(e) = store_audio_artist(config, audio_id, artist.artist_id).await {
+                event!(Level::WARN, "failed to store audio artist: {}", e);
+            }
+        } else {
+            // Artist doesn't exist in our database, add to MusicBrainz queue
+            // to download details.
+            musicbrainz::add_to_queue(
+                config,
+                musicbrainz::QueuePayload {
+                    payload_type: musicbrainz::PayloadType::AudioArtist,
+                    id: audio_id,
+                    value: value.clone(),
+                },
+            )
+            .await;
+        }
+    }
+}
+
+/// Check if a value is exactly one MusicBrainz ID.
+pub(crate) fn is_musicbrainz_id(value: &str) -> bool {
+    MUSICBRAINZ_ID
+        .find(value)
+        .is_some_and(|m| m.as_str() == value)
+}
+
+/// Extract the MusicBrainz IDs from all tags with the given name. Some taggers store several
+/// IDs in a single value, separated by slashes or semicolons.
+pub(crate) fn musicbrainz_ids(tags: &[Tag], name: &str) -> Vec<String> {
+    let mut ids: Vec<String> = Vec::new();
+    for tag in tags.iter().filter(|t| t.name == name) {
+        for id in MUSICBRAINZ_ID.find_iter(&tag.value.to_lowercase()) {
+            if !ids.iter().any(|i| i == id.as_str()) {
+                ids.push(id.as_str().to_string());
+            }
+        }
+    }
+    ids
+}
+
+/// Look for an audio file in the database that no longer exists where expected, but
+/// matches the size, modification time, and (optionally) content hash of a newly
+/// discovered file. This is most likely the same file, renamed or moved.
+pub(crate) async fn find_moved_audio(
+    config: &Config,
+    size: i64,
+    modified: Option<chrono::NaiveDateTime>,
+    hash: &Option<String>,
+) -> Option<audio::Model> {
+    event!(Level::TRACE, "find_moved_audio");
+    if size == 0 {
+        return None;
+    }
+    if hash.is_none() && modified.is_none() {
+        return None;
+    }
+    // A matching content hash is conclusive, otherwise rely on the modification time
+    // which is preserved when moving files.
+    let mut same_file = Condition::any();
+    if let Some(hash) = hash {
+        same_file = same_file.add(audio::Column::Hash.eq(hash.as_str()));
+    }
+    if let Some(modified) = modified {
+        let mut same_modified = Condition::all().add(audio::Column::Modified.eq(modified));
+        // Only files that were never hashed are matched on modification time.
+        if hash.is_some() {
+            same_modified = same_modified.add(audio::Column::Hash.is_null());
+        }
+        same_file = same_file.add(same_modified);
+    }
+    let select_query = Audio::find()
+        .filter(audio::Column::Size.eq(size))
+        .filter(same_file);
+
+    let db = database::connection(config).await;
+    let candidates = match select_query.all(db).await {
+        Ok(c) => c,
+        Err(e) => {
+            event!(Level::WARN, "Audio::find() moved failure: {}", e);
+            return None;
+        }
+    };
+    candidates
+        .into_iter()
+        .find(|candidate| !Path::new(&candidate.path).join(&candidate.name).exists())
+}
+
+/// Remove audio files from the database that were at the given path, which may be a single
+/// file or an entire directory, if they no longer exist on disk.
+pub(crate) async fn remove_audio_at(config: &Config, path: &Path) {
+    event!(Level::TRACE, "remove_audio_at {}", path.display());
+    let directory = path.display().to_string();
+    let parent = match path.parent() {
+        Some(p) => p.display().to_string(),
+        None => "".to_string(),
+    };
+    let name = match path.file_name() {
+        Some(f) => f.to_str().unwrap_or("").to_string(),
+        None => "".to_string(),
+    };
+
+    let db = database::connection(config).await;
+    let audio_files = match Audio::find()
+        .filter(
+            Condition::any()
+                .add(
+                    Condition::all()
+                        .add(audio::Column::Path.eq(parent))
+                        .add(audio::Column::Name.eq(name)),
+                )
+                .add(audio::Column::Path.eq(directory.as_str()))
+                .add(audio::Column::Path.starts_with(&format!("{}/", directory))),
+        )
+        .all(db)
+        .await
+    {
+        Ok(a) => a,
+        Err(e) => {
+            event!(Level::WARN, "Audio::find() by path failure: {}", e);
+            return;
+        }
+    };
+    let mut removed = Vec::new();
+    for audio_file in audio_files {
+        if Path::new(&audio_file.path).join(&audio_file.name).exists() {
+            continue;
+        }
+        event!(Level::INFO, "Audio file removed: {}", audio_file.uri);
+        removed.push(audio_file.audio_id);
+    }
+    delete_audio(config, &removed).await;
+}
+
+/// Remove audio files from the database that no longer exist on disk, or that are no longer
+/// scanned because they're excluded by the scan filter.
+pub(crate) async fn remove_missing_audio(config: &Config, filter: &ScanFilter) {
+    event!(Level::TRACE, "remove_missing_audio");
+    let db = database::connection(config).await;
+    let mut pages = Audio::find()
+        .order_by_asc(audio::Column::AudioId)
+        .paginate(db, AUDIO_PAGE_SIZE);
+    let mut removed = Vec::new();
+    loop {
+        let audio_files = match pages.fetch_and_next().await {
+            Ok(Some(a)) => a,
+            Ok(None) => break,
+            Err(e) => {
+                event!(Level::WARN, "Audio::find() missing failure: {}", e);
+                return;
+            }
+        };
+        for audio_file in audio_files {
+            let path = Path::new(&audio_file.path).join(&audio_file.name);
+            if !path.exists() {
+                event!(Level::INFO, "Audio file removed: {}", audio_file.uri);
+            } else if !filter.allows(&path, false) {
+                event!(Level::INFO, "Audio file excluded: {}", audio_file.uri);
+            } else {
+                continue;
+            }
+            removed.push(audio_file.audio_id);
+        }
+    }
+    delete_audio(config, &removed).await;
+}
+
+/// Delete audio files from the database, along with their tags, fingerprints and AcoustID
+/// matches, verifications and features, and their artist, album, directory and embedded image
+/// associations. Each batch of audio files is deleted in a transaction of its own.
+async fn delete_audio(config: &Config, audio_ids: &[i32]) {
+    let db = database::connection(config).await;
+    for audio_ids in audio_ids.chunks(DELETE_BATCH_SIZE) {
+        event!(Level::DEBUG, "delete_audio {} audio files", audio_ids.len());
+        if let Err(e) = delete_audio_batch(db, audio_ids.to_vec()).await {
+            event!(Level::WARN, "failed to delete audio files: {}", e);
+        }
+    }
+}
+
+async fn delete_audio_batch(db: &DatabaseConnection, audio_ids: Vec<i32>) -> Result<(), DbErr> {
+    let txn = db.begin().await?;
+    AudioTag::delete_many()
+        .filter(audio_tag::Column::AudioId.is_in(audio_ids.clone()))
+        .exec(&txn)
+        .await?;
+    AudioArtist::delete_many()
+        .filter(audio_artist::Column::AudioId.is_in(audio_ids.clone()))
+        .exec(&txn)
+        .await?;
+    AlbumAudio::delete_many()
+        .filter(album_audio::Column::AudioId.is_in(audio_ids.clone()))
+        .exec(&txn)
+        .await?;
+    AudioImage::delete_many()
+        .filter(audio_image::Column::AudioId.is_in(audio_ids.clone()))
+        .exec(&txn)
+        .await?;
+    AudioDirectory::delete_many()
+        .filter(audio_directory::Column::AudioId.is_in(audio_ids.clone()))
+        .exec(&txn)
+        .await?;
+    AudioAcoustid::delete_many()
+        .filter(audio_acoustid::Column::AudioId.is_in(audio_ids.clone()))
+        .exec(&txn)
+        .await?;
+    AudioFingerprint::delete_many()
+        .filter(audio_fingerprint::Column::AudioId.is_in(audio_ids.clone()))
+        .exec(&txn)
+        .await?;
+    AudioVerification::delete_many()
+        .filter(audio_verification::Column::AudioId.is_in(audio_ids.clone()))
+        .exec(&txn)
+        .await?;
+    AudioFeature::delete_many()
+        .filter(audio_feature::Column::AudioId.is_in(audio_ids.clone()))
+        .exec(&txn)
+        .await?;
+    Audio::delete_many()
+        .filter(audio::Column::AudioId.is_in(audio_ids))
+        .exec(&txn)
+        .await?;
+    txn.commit().await
+}
+
+/// Remove directories in scope that no longer contain any audio files, along with their
+/// artist associations.
+pub(crate) async fn remove_empty_directories(config: &Config, scope: &Scope) {
+    event!(Level::TRACE, "remove_empty_directories");
+    let db = database::connection(config).await;
+    let directories = match Directory::find()
+        .left_join(AudioDirectory)
+        .filter(audio_directory::Column::AudioDirectoryId.is_null())
+        .filter(scope.paths(directory::Column::Path))
+        .all(db)
+        .await
+    {
+        Ok(d) => d,
+        Err(e) => {
+            event!(Level::WARN, "Directory::find() empty failure: {}", e);
+            return;
+        }
+    };
+    for directory in directories {
+        event!(Level::INFO, "Directory removed: {}", directory.path);
+        if let Err(e) = ArtistDirectory::delete_many()
+            .filter(artist_directory::Column::DirectoryId.eq(directory.directory_id))
+            .exec(db)
+            .await
+        {
+            event!(Level::WARN, "ArtistDirectory::delete_many() failure: {}", e);
+            continue;
+        }
+        if let Err(e) = Directory::delete_by_id(directory.directory_id)
+            .exec(db)
+            .await
+        {
+            event!(Level::WARN, "Directory::delete_by_id() failure: {}", e);
+        }
+    }
 }
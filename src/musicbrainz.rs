@@ -1,25 +1,71 @@
-use std::sync::{Arc, RwLock};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use async_trait::async_trait;
 
-use musicbrainz_rs::Search;
-use once_cell::sync::Lazy;
 use sea_orm::*;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use tokio::sync::oneshot::Receiver;
 use tracing::{event, instrument, Level};
 
+use crate::acoustid;
+use crate::artists;
+use crate::artwork::link_images;
+use crate::cache;
 use crate::database::{self, RiaArtistType, RiaGender};
 use crate::entities::{prelude::*, *};
-use crate::media::{store_artist_directory, store_audio_artist};
+use crate::media::{link_album_artists, store_artist_directory, store_audio_artist, Scope};
+use crate::provider::{
+    provider, MetadataProvider, Recording, Release, ReleaseResult, ScoredArtist,
+};
+use crate::ratelimit::{self, RateLimiter};
+use crate::releases;
 use crate::Config;
 
-static MUSICBRAINZ_LAST_REQUEST: Lazy<Arc<RwLock<u64>>> = Lazy::new(|| Arc::new(RwLock::new(0)));
-
 // According to https://musicbrainz.org/doc/MusicBrainz_API/Rate_Limiting the MusicBrainz API
-// allows ~50 requests per second. We make a maximum of 1 request every 2 seconds, which is only
-// 30 requests per second, well below this upper limit. It would take ~33 minutes to process
-// 1,000 artists.
-const MINIMUM_DELAY: u64 = 2;
+// allows an average of 1 request per second. By default we make a maximum of 1 request every 2
+// seconds, well below this upper limit. It would take ~33 minutes to process 1,000 artists.
+const DEFAULT_MUSICBRAINZ_RATE: f64 = 0.5;
+
+// By default requests aren't bunched up, even after a while without any.
+const DEFAULT_MUSICBRAINZ_BURST: u32 = 1;
+
+// Seconds to wait before checking an empty queue again.
+const QUEUE_POLL_INTERVAL: u64 = 2;
+
+const DEFAULT_MUSICBRAINZ_URL: &str = "https://musicbrainz.org/ws/2";
+
+// The name MusicBrainz is rate limited and cached by.
+pub(crate) const SERVICE: &str = "MusicBrainz";
+
+// How many search results are considered when matching an artist by name.
+const SEARCH_LIMIT: usize = 10;
+
+// Artists found by name are only linked if matched with at least this confidence out of 100,
+// otherwise they're stored as unresolved for later review.
+const DEFAULT_MATCH_THRESHOLD: i32 = 80;
+
+// The best match must also be this much more confident than the next best.
+const MATCH_MARGIN: i32 = 10;
+
+// How much the MusicBrainz search score counts towards confidence, the rest is name similarity.
+const SEARCH_SCORE_WEIGHT: f64 = 0.4;
+
+// Confidence gained for each of the album and track being credited to an artist in MusicBrainz.
+const CORROBORATION_BONUS: i32 = 15;
+
+// Failed queue items are tried this many times before being set aside as dead letters.
+const DEFAULT_QUEUE_MAX_ATTEMPTS: i32 = 5;
+
+// Seconds to wait before retrying a failed queue item, doubled after each attempt.
+const DEFAULT_QUEUE_RETRY_DELAY: u64 = 60;
+
+// Retries are never delayed by more than a day.
+const MAXIMUM_RETRY_DELAY: u64 = 24 * 60 * 60;
+
+// Queue items still being processed after this many seconds were abandoned, ie by a crash.
+const DEFAULT_QUEUE_LEASE_TIMEOUT: u64 = 600;
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub(crate) struct QueuePayload {
@@ -30,67 +76,161 @@ pub(crate) struct QueuePayload {
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub(crate) enum PayloadType {
+    /// Search for an artist by name, and link it to an audio file.
     AudioArtist,
+    /// Look up an artist by its MusicBrainz ID, and link it to an audio file.
+    AudioArtistId,
+    /// Look up an artist by its MusicBrainz ID, ie the album artist of an audio file.
+    ArtistId,
+    /// Match an album to a MusicBrainz release, and its audio files to recordings.
+    AlbumRelease,
+    /// Look up a recording by its MusicBrainz ID, for an audio file not matched with a release.
+    AudioRecording,
+    /// Look up the fingerprint of an audio file in AcoustID, for an audio file without tags.
+    AudioFingerprint,
+}
+
+/// How an artist was matched in MusicBrainz.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum MatchStatus {
+    /// Found by its MusicBrainz ID, or confidently matched by name.
+    Matched,
+    /// Matched by name, but not confidently, see the artist's candidates.
+    Unresolved,
+    /// Not found in MusicBrainz at all.
+    NotFound,
+    /// Chosen by hand, see `ria artist pin`.
+    Pinned,
+}
+
+/// Whether a queue item is still to be processed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum QueueStatus {
+    /// Waiting to be processed, or retried.
+    Pending,
+    /// Failed too often to be retried, see `ria queue retry`.
+    Dead,
+}
+
+impl QueueStatus {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            QueueStatus::Pending => "pending",
+            QueueStatus::Dead => "dead",
+        }
+    }
+}
+
+impl MatchStatus {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            MatchStatus::Matched => "matched",
+            MatchStatus::Unresolved => "unresolved",
+            MatchStatus::NotFound => "not_found",
+            MatchStatus::Pinned => "pinned",
+        }
+    }
 }
 
 #[instrument]
 pub(crate) async fn process_queue(config: &Config, mut rx: Receiver<bool>) {
     event!(Level::TRACE, "process_queue");
 
-    // Ensure that we query the Musicbrainz API no more than once every 2 seconds.
+    // Requests made while processing items are rate limited, see `rate_limiter`.
     loop {
-        let time_since_last_request = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
-            - *MUSICBRAINZ_LAST_REQUEST.read().unwrap();
-
-        if time_since_last_request >= MINIMUM_DELAY {
-            event!(Level::TRACE, "process next queue item");
-            if let Some(item) = load_from_queue(config).await {
-                // @TODO: Error handling.
-                let payload: QueuePayload = serde_json::from_str(&item.payload.unwrap()).unwrap();
-                match payload.payload_type {
-                    PayloadType::AudioArtist => {
-                        if let Some(artist_id) = load_artist_by_name(config, &payload.value).await {
-                            store_audio_artist(config, payload.id, artist_id).await;
-                            store_artist_directory(config, payload.id, artist_id).await;
-                            remove_from_queue(config, item.musicbrainz_queue_id).await;
-                        }
-                    }
+        event!(Level::TRACE, "process next queue item");
+        if let Some(item) = load_from_queue(config).await {
+            let payload = match item.payload.as_deref().map(serde_json::from_str) {
+                Some(Ok(payload)) => payload,
+                Some(Err(e)) => {
+                    // Retrying won't help, give up on the item straight away.
+                    let error = format!("invalid payload: {}", e);
+                    fail_queue_item(config, item, &error, true).await;
+                    continue;
                 }
-            } else {
-                event!(Level::TRACE, "the queue is empty");
-                if rx.try_recv().is_ok() {
-                    event!(
-                        Level::INFO,
-                        "process_queue received shutdown message, exiting"
-                    );
-                    break;
+                None => {
+                    fail_queue_item(config, item, "missing payload", true).await;
+                    continue;
+                }
+            };
+            match process_payload(config, &payload).await {
+                Ok(()) => remove_from_queue(config, item.musicbrainz_queue_id).await,
+                Err(error) => {
+                    fail_queue_item(config, item, &error, false).await;
                 }
-                tokio::time::sleep(tokio::time::Duration::from_secs(MINIMUM_DELAY)).await;
             }
         } else {
-            // Only sleep if the musicbrainz api was invoked.
-            let sleep_for = MINIMUM_DELAY - time_since_last_request;
-            event!(Level::TRACE, "sleep {} seconds", sleep_for);
-            tokio::time::sleep(tokio::time::Duration::from_secs(sleep_for)).await;
+            event!(Level::TRACE, "the queue is empty");
+            if rx.try_recv().is_ok() {
+                event!(
+                    Level::INFO,
+                    "process_queue received shutdown message, exiting"
+                );
+                break;
+            }
+            tokio::time::sleep(tokio::time::Duration::from_secs(QUEUE_POLL_INTERVAL)).await;
         }
     }
 }
 
+/// Process a queue item, returning why it failed if it should be retried.
+#[instrument]
+async fn process_payload(config: &Config, payload: &QueuePayload) -> Result<(), String> {
+    event!(Level::TRACE, "process_payload");
+
+    match payload.payload_type {
+        PayloadType::AudioArtist | PayloadType::AudioArtistId => {
+            let artist_id = if payload.payload_type == PayloadType::AudioArtist {
+                load_artist_by_name(config, &payload.value, payload.id).await?
+            } else {
+                load_artist_by_id(config, &payload.value).await?
+            };
+            store_audio_artist(config, payload.id, artist_id)
+                .await
+                .map_err(|e| format!("failed to store audio artist: {}", e))?;
+            store_artist_directory(config, payload.id, artist_id)
+                .await
+                .map_err(|e| format!("failed to store artist directory: {}", e))?;
+            let scope = Scope::of_audio(config, payload.id).await?;
+            link_album_artists(config, &scope).await;
+            link_images(config, &scope).await;
+        }
+        PayloadType::ArtistId => {
+            load_artist_by_id(config, &payload.value).await?;
+            let scope = Scope::of_audio(config, payload.id).await?;
+            link_album_artists(config, &scope).await;
+            link_images(config, &scope).await;
+        }
+        PayloadType::AlbumRelease => {
+            releases::match_album_release(config, payload.id).await?;
+        }
+        PayloadType::AudioRecording => {
+            releases::load_recording(config, payload.id, &payload.value).await?;
+        }
+        PayloadType::AudioFingerprint => {
+            acoustid::lookup_fingerprint(config, payload.id).await?;
+        }
+    }
+    Ok(())
+}
+
 #[instrument]
 pub(crate) async fn add_to_queue(config: &Config, payload: QueuePayload) {
     event!(Level::TRACE, "add_to_queue");
 
-    // Convert payload to JSON String. @TODO: error handling.
-    let payload_json = serde_json::to_string(&payload).unwrap();
+    let payload_json = match serde_json::to_string(&payload) {
+        Ok(p) => p,
+        Err(e) => {
+            event!(Level::WARN, "failed to serialize {:?}: {}", payload, e);
+            return;
+        }
+    };
 
     // Be sure the payload isn't already in the queue.
     let queue_id = {
         let db = database::connection(config).await;
         match MusicbrainzQueue::find()
-            .filter(musicbrainz_queue::Column::Payload.like(&payload_json))
+            .filter(musicbrainz_queue::Column::Payload.like(payload_json.as_str()))
             .one(db)
             .await
         {
@@ -131,35 +271,70 @@ pub(crate) async fn add_to_queue(config: &Config, payload: QueuePayload) {
     }
 }
 
+/// Claim the next queue item that's due to be processed.
 #[instrument]
 pub(crate) async fn load_from_queue(config: &Config) -> Option<musicbrainz_queue::Model> {
     event!(Level::TRACE, "load_from_queue");
 
-    let queue_item = {
-        let db = database::connection(config).await;
-        // @TODO: wrap in a lock, or turn this into a subquery as described at
-        // https://blabosoft.com/implementing-queue-in-postgresql. Lock is probably
-        // better for keeping this database-agnostic.
-        match MusicbrainzQueue::find()
-            .filter(musicbrainz_queue::Column::ProcessingStartedAt.is_null())
-            .order_by_asc(musicbrainz_queue::Column::CreatedAt)
-            .one(db)
-            .await
-        {
-            Ok(e) => e,
-            Err(e) => {
-                event!(Level::WARN, "MusicbrainzQueue::find() failure: {}", e);
+    let lease_timeout = config
+        .queue_lease_timeout
+        .unwrap_or(DEFAULT_QUEUE_LEASE_TIMEOUT);
+    loop {
+        let now = chrono::Utc::now().naive_utc();
+        let lease_expired = now - chrono::Duration::seconds(lease_timeout as i64);
+        let queue_item = {
+            let db = database::connection(config).await;
+            // @TODO: wrap in a lock, or turn this into a subquery as described at
+            // https://blabosoft.com/implementing-queue-in-postgresql. Lock is probably
+            // better for keeping this database-agnostic.
+            match MusicbrainzQueue::find()
+                .filter(musicbrainz_queue::Column::Status.eq(QueueStatus::Pending.as_str()))
+                .filter(
+                    Condition::any()
+                        .add(musicbrainz_queue::Column::ProcessingStartedAt.is_null())
+                        .add(musicbrainz_queue::Column::ProcessingStartedAt.lt(lease_expired)),
+                )
+                .filter(
+                    Condition::any()
+                        .add(musicbrainz_queue::Column::NextAttemptAt.is_null())
+                        .add(musicbrainz_queue::Column::NextAttemptAt.lte(now)),
+                )
+                .order_by_asc(musicbrainz_queue::Column::CreatedAt)
+                .one(db)
+                .await
+            {
+                Ok(e) => e,
+                Err(e) => {
+                    event!(Level::WARN, "MusicbrainzQueue::find() failure: {}", e);
+                    return None;
+                }
+            }
+        }?;
+        let queue_id = queue_item.musicbrainz_queue_id;
+
+        // The lease on an item still being processed expired, it was abandoned by a crash or
+        // by Ria being stopped. Count it as a failed attempt, in case the item caused it.
+        if let Some(started) = queue_item.processing_started_at {
+            event!(
+                Level::WARN,
+                "reclaiming queue_id {}, abandoned while processing since {}",
+                queue_id,
+                started
+            );
+            let error = format!("abandoned while processing since {}", started);
+            if !fail_queue_item(config, queue_item, &error, false).await {
                 return None;
             }
+            continue;
         }
-    };
-    if let Some(item) = queue_item {
-        let queue_id = item.musicbrainz_queue_id;
+
         event!(Level::TRACE, "next queue_id: {}", queue_id);
-        let mut item: musicbrainz_queue::ActiveModel = item.into();
-        item.processing_started_at = Set(Some(chrono::Utc::now().naive_utc().to_owned()));
+        let attempts = queue_item.attempts + 1;
+        let mut item: musicbrainz_queue::ActiveModel = queue_item.into();
+        item.processing_started_at = Set(Some(now));
+        item.attempts = Set(attempts);
         let db = database::connection(config).await;
-        match item.update(db).await {
+        return match item.update(db).await {
             Ok(i) => Some(i),
             Err(e) => {
                 event!(
@@ -170,10 +345,87 @@ pub(crate) async fn load_from_queue(config: &Config) -> Option<musicbrainz_queue
                 );
                 None
             }
-        }
+        };
+    }
+}
+
+/// Record why processing a queue item failed, and either schedule it to be retried after a
+/// delay that doubles with each attempt, or set it aside as a dead letter if it failed too often
+/// or `permanent` is set. Returns false if the item couldn't be updated.
+#[instrument]
+pub(crate) async fn fail_queue_item(
+    config: &Config,
+    item: musicbrainz_queue::Model,
+    error: &str,
+    permanent: bool,
+) -> bool {
+    event!(Level::TRACE, "fail_queue_item");
+
+    let now = chrono::Utc::now().naive_utc();
+    let queue_id = item.musicbrainz_queue_id;
+    let attempts = item.attempts.max(1);
+    let max_attempts = config
+        .queue_max_attempts
+        .unwrap_or(DEFAULT_QUEUE_MAX_ATTEMPTS);
+
+    // Errors are kept one per line, with when they happened and on which attempt.
+    let line = format!(
+        "{} attempt {}: {}",
+        now.format("%Y-%m-%d %H:%M:%S"),
+        attempts,
+        error
+    );
+    let errors = match &item.errors {
+        Some(errors) if !errors.is_empty() => format!("{}\n{}", errors, line),
+        _ => line,
+    };
+
+    let mut item: musicbrainz_queue::ActiveModel = item.into();
+    item.errors = Set(Some(errors));
+    item.processing_started_at = Set(None);
+    if permanent || attempts >= max_attempts {
+        event!(
+            Level::WARN,
+            "giving up on queue_id {} after {} attempts: {}",
+            queue_id,
+            attempts,
+            error
+        );
+        item.status = Set(QueueStatus::Dead.as_str().to_string());
+        item.next_attempt_at = Set(None);
     } else {
-        None
+        let delay = retry_delay(config, attempts);
+        event!(
+            Level::WARN,
+            "queue_id {} failed, retrying in {} seconds: {}",
+            queue_id,
+            delay,
+            error
+        );
+        item.next_attempt_at = Set(Some(now + chrono::Duration::seconds(delay as i64)));
+    }
+    let db = database::connection(config).await;
+    if let Err(e) = item.update(db).await {
+        event!(
+            Level::WARN,
+            "failed to update id {} in musicbrainz_queue: {}",
+            queue_id,
+            e
+        );
+        return false;
     }
+    true
+}
+
+/// Seconds to wait before the next attempt, after `attempts` failed attempts.
+fn retry_delay(config: &Config, attempts: i32) -> u64 {
+    let delay = config
+        .queue_retry_delay
+        .unwrap_or(DEFAULT_QUEUE_RETRY_DELAY);
+    let exponent = (attempts - 1).clamp(0, 32) as u32;
+    delay
+        .saturating_mul(2u64.saturating_pow(exponent))
+        .min(MAXIMUM_RETRY_DELAY)
 }
 
 #[instrument]
@@ -192,56 +444,574 @@ pub(crate) async fn remove_from_queue(config: &Config, id: i32) {
 }
 
 #[instrument]
-pub(crate) async fn load_artist_by_name(config: &Config, artist_name: &str) -> Option<i32> {
+pub(crate) async fn load_artist_by_name(
+    config: &Config,
+    artist_name: &str,
+    audio_id: i32,
+) -> Result<i32, String> {
     // Check if the artist is already in the database.
     let existing = {
         let db = database::connection(config).await;
         match Artist::find()
-            .filter(artist::Column::Name.like(&artist_name))
+            .filter(artist::Column::Name.like(artist_name))
             .one(db)
             .await
         {
             Ok(e) => e,
             Err(e) => {
                 event!(Level::WARN, "Artist::find() failure: {}", e);
-                return None;
+                return Err(format!("Artist::find() failure: {}", e));
             }
         }
     };
 
     event!(Level::DEBUG, "{:#?}", existing);
 
+    // Artists pinned by hand take precedence, they may have been pinned since this was queued.
+    if let Some(artist_id) = artists::find_pinned_artist(config, artist_name, audio_id).await {
+        return Ok(artist_id);
+    }
+
     if let Some(artist) = existing {
         event!(Level::TRACE, "artist exists in database: {:#?}", artist);
-        return Some(artist.artist_id);
+        return Ok(artist.artist_id);
+    }
+
+    let artists = match provider(config).search_artists(artist_name).await {
+        Ok(a) => a,
+        Err(e) => {
+            event!(Level::WARN, "musicbrainz query failed: {}", e);
+            return Err(format!("musicbrainz query failed: {}", e));
+        }
+    };
+
+    if artists.is_empty() {
+        event!(Level::WARN, "{} not found in MusicBrainz", artist_name);
+        return insert_unmatched_artist(config, artist_name, MatchStatus::NotFound, None)
+            .await
+            .ok_or_else(|| format!("failed to store artist {}", artist_name));
+    }
+
+    let mut candidates: Vec<Candidate> = artists
+        .into_iter()
+        .map(|a| Candidate {
+            confidence: base_confidence(artist_name, &a),
+            search_score: a.score,
+            artist: a.artist,
+        })
+        .collect();
+    candidates.sort_by_key(|c| std::cmp::Reverse(c.confidence));
+
+    // Common names match more than one artist, look for the album and track in MusicBrainz to
+    // tell them apart.
+    let threshold = config
+        .artist_match_threshold
+        .unwrap_or(DEFAULT_MATCH_THRESHOLD);
+    if !is_confident(&candidates, threshold) {
+        corroborate(config, artist_name, audio_id, &mut candidates).await;
+        candidates.sort_by_key(|c| std::cmp::Reverse(c.confidence));
+    }
+
+    event!(
+        Level::DEBUG,
+        "{} candidates for {}: {:?}",
+        candidates.len(),
+        artist_name,
+        candidates
+            .iter()
+            .map(|c| (&c.artist.name, &c.artist.id, c.confidence))
+            .collect::<Vec<_>>()
+    );
+
+    if is_confident(&candidates, threshold) {
+        let best = &candidates[0];
+        let artist_id = store_artist(config, artist_name, &best.artist, best.confidence)
+            .await
+            .ok_or_else(|| format!("failed to store artist {}", artist_name))?;
+        // Keep the runners up, so the match can be reviewed.
+        if candidates.len() > 1 {
+            store_candidates(config, artist_id, &candidates).await;
+        }
+        Ok(artist_id)
+    } else {
+        event!(
+            Level::WARN,
+            "{} could not be confidently matched in MusicBrainz, storing as unresolved",
+            artist_name
+        );
+        let artist_id = insert_unmatched_artist(
+            config,
+            artist_name,
+            MatchStatus::Unresolved,
+            Some(candidates[0].confidence),
+        )
+        .await
+        .ok_or_else(|| format!("failed to store artist {}", artist_name))?;
+        store_candidates(config, artist_id, &candidates).await;
+        Ok(artist_id)
+    }
+}
+
+/// An artist returned by a MusicBrainz search, and how confident we are that it's a match.
+struct Candidate {
+    artist: musicbrainz_rs::entity::artist::Artist,
+    search_score: i32,
+    confidence: i32,
+}
+
+/// The best candidate is only trusted if it's above the threshold, and clearly ahead of the next.
+fn is_confident(candidates: &[Candidate], threshold: i32) -> bool {
+    match candidates {
+        [] => false,
+        [best] => best.confidence >= threshold,
+        [best, next, ..] => {
+            best.confidence >= threshold && best.confidence - next.confidence >= MATCH_MARGIN
+        }
+    }
+}
+
+/// Combine the MusicBrainz search score with how closely the name, sort name or any alias of the
+/// candidate matches the name in the audio file, into a confidence out of 100.
+fn base_confidence(artist_name: &str, result: &ScoredArtist) -> i32 {
+    let name = normalise_name(artist_name);
+    let artist = &result.artist;
+    let similarity = [&artist.name, &artist.sort_name]
+        .into_iter()
+        .chain(artist.aliases.iter().flatten().map(|a| &a.name))
+        .map(|n| strsim::normalized_levenshtein(&name, &normalise_name(n)))
+        .fold(0.0, f64::max);
+    (result.score.clamp(0, 100) as f64 * SEARCH_SCORE_WEIGHT
+        + similarity * 100.0 * (1.0 - SEARCH_SCORE_WEIGHT))
+        .round() as i32
+}
+
+/// Normalise an artist name for comparison, ie "The Beatles" and "beatles" are the same.
+pub(crate) fn normalise_name(name: &str) -> String {
+    let name: String = name
+        .to_lowercase()
+        .replace('&', " and ")
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect();
+    let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+    match name.strip_prefix("the ") {
+        Some(n) => n.to_string(),
+        None => name,
+    }
+}
+
+/// Raise the confidence of candidates credited with the album or track of the audio file in
+/// MusicBrainz, and lower the confidence of those that aren't if any are.
+async fn corroborate(
+    config: &Config,
+    artist_name: &str,
+    audio_id: i32,
+    candidates: &mut [Candidate],
+) {
+    let tags = {
+        let db = database::connection(config).await;
+        match AudioTag::find()
+            .filter(audio_tag::Column::AudioId.eq(audio_id))
+            .filter(audio_tag::Column::Name.is_in(["Album", "TrackTitle"]))
+            .order_by_asc(audio_tag::Column::Position)
+            .all(db)
+            .await
+        {
+            Ok(t) => t,
+            Err(e) => {
+                event!(Level::WARN, "AudioTag::find() corroborate failure: {}", e);
+                return;
+            }
+        }
+    };
+
+    let provider = provider(config);
+    let mut credited: Vec<String> = Vec::new();
+    for tag in ["Album", "TrackTitle"] {
+        let Some(tag) = tags.iter().find(|t| t.name == tag) else {
+            continue;
+        };
+        let credits = if tag.name == "Album" {
+            provider
+                .release_group_credits(&tag.value, artist_name)
+                .await
+        } else {
+            provider.recording_credits(&tag.value, artist_name).await
+        };
+        match credits {
+            Ok(c) => credited.extend(c),
+            Err(e) => event!(Level::WARN, "musicbrainz {} query failed: {}", tag.name, e),
+        }
+    }
+
+    if !candidates.iter().any(|c| credited.contains(&c.artist.id)) {
+        return;
+    }
+    for candidate in candidates.iter_mut() {
+        let evidence = credited
+            .iter()
+            .filter(|id| **id == candidate.artist.id)
+            .count() as i32;
+        candidate.confidence = if evidence > 0 {
+            (candidate.confidence + CORROBORATION_BONUS * evidence.min(2)).min(100)
+        } else {
+            (candidate.confidence - CORROBORATION_BONUS).max(0)
+        };
+    }
+}
+
+/// Quote a value as a Lucene phrase for a MusicBrainz search query.
+pub(crate) fn lucene_phrase(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// The rate limiter shared by all MusicBrainz requests.
+pub(crate) fn rate_limiter(config: &Config) -> Arc<RateLimiter> {
+    RateLimiter::for_service(
+        SERVICE,
+        config.musicbrainz_rate.unwrap_or(DEFAULT_MUSICBRAINZ_RATE),
+        config
+            .musicbrainz_burst
+            .unwrap_or(DEFAULT_MUSICBRAINZ_BURST),
+    )
+}
+
+/// The MusicBrainz API, see https://musicbrainz.org/doc/MusicBrainz_API.
+pub(crate) struct MusicBrainz {
+    url: String,
+    cache: PathBuf,
+    limiter: Arc<RateLimiter>,
+}
+
+impl MusicBrainz {
+    pub(crate) fn new(config: &Config) -> Self {
+        MusicBrainz {
+            url: config
+                .musicbrainz_url
+                .as_deref()
+                .unwrap_or(DEFAULT_MUSICBRAINZ_URL)
+                .trim_end_matches('/')
+                .to_string(),
+            cache: cache::metadata_cache(config),
+            limiter: rate_limiter(config),
+        }
+    }
+
+    /// Search MusicBrainz. Unlike musicbrainz_rs, this keeps the score of each result.
+    async fn search<T: DeserializeOwned>(&self, entity: &str, query: &str) -> anyhow::Result<T> {
+        self.request(
+            entity,
+            &[("query", query), ("limit", &SEARCH_LIMIT.to_string())],
+        )
+        .await
+    }
+
+    /// Look up an entity by its MusicBrainz ID, including related entities, ie "recordings" for
+    /// the tracks of a release.
+    async fn lookup<T: DeserializeOwned>(
+        &self,
+        entity: &str,
+        musicbrainz_id: &str,
+        include: &str,
+    ) -> anyhow::Result<T> {
+        let query: &[(&str, &str)] = if include.is_empty() {
+            &[]
+        } else {
+            &[("inc", include)]
+        };
+        self.request(&format!("{}/{}", entity, musicbrainz_id), query)
+            .await
+    }
+
+    /// Query MusicBrainz, reusing the response to the same query if it's in the metadata cache.
+    async fn request<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &[(&str, &str)],
+    ) -> anyhow::Result<T> {
+        let key = cache::key(path, query);
+        if let Some(cached) = cache::load(&self.cache, SERVICE, &key) {
+            event!(
+                Level::DEBUG,
+                "using response to {} cached at {}",
+                key,
+                cached.fetched_at
+            );
+            return Ok(serde_json::from_value(cached.response)?);
+        }
+
+        let url = format!("{}/{}", self.url, path);
+        let query: Vec<(String, String)> = query
+            .iter()
+            .chain(&[("fmt", "json")])
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let body = ratelimit::get(&self.limiter, url.clone(), query.clone()).await?;
+        let response: serde_json::Value = serde_json::from_str(&body)?;
+        cache::store(&self.cache, SERVICE, &key, &url, &query, &response);
+        Ok(serde_json::from_value(response)?)
+    }
+
+    /// IDs of the artists credited with release groups or recordings matching a search.
+    async fn credits(
+        &self,
+        entity: &str,
+        field: &str,
+        title: &str,
+        artist_name: &str,
+    ) -> anyhow::Result<Vec<String>> {
+        let query = format!(
+            "{}:{} AND artist:{}",
+            field,
+            lucene_phrase(title),
+            lucene_phrase(artist_name)
+        );
+        let search: CreditSearch = self.search(entity, &query).await?;
+        Ok(search
+            .release_groups
+            .into_iter()
+            .chain(search.recordings)
+            .flat_map(|c| c.artist_credit)
+            .map(|c| c.artist.id)
+            .collect())
+    }
+}
+
+#[async_trait]
+impl MetadataProvider for MusicBrainz {
+    async fn search_artists(&self, name: &str) -> anyhow::Result<Vec<ScoredArtist>> {
+        let query = format!("artist:{0} OR alias:{0}", lucene_phrase(name));
+        let search: ArtistSearch = self.search("artist", &query).await?;
+        Ok(search.artists)
+    }
+
+    async fn lookup_artist(
+        &self,
+        artist_id: &str,
+    ) -> anyhow::Result<musicbrainz_rs::entity::artist::Artist> {
+        self.lookup("artist", artist_id, "").await
+    }
+
+    async fn release_group_credits(
+        &self,
+        title: &str,
+        artist_name: &str,
+    ) -> anyhow::Result<Vec<String>> {
+        self.credits("release-group", "releasegroup", title, artist_name)
+            .await
+    }
+
+    async fn recording_credits(
+        &self,
+        title: &str,
+        artist_name: &str,
+    ) -> anyhow::Result<Vec<String>> {
+        self.credits("recording", "recording", title, artist_name)
+            .await
+    }
+
+    async fn search_releases(
+        &self,
+        title: &str,
+        artist_name: Option<&str>,
+        release_group_id: Option<&str>,
+    ) -> anyhow::Result<Vec<ReleaseResult>> {
+        // The release group narrows a search down to its editions.
+        let query = match (release_group_id, artist_name) {
+            (Some(release_group_id), _) => format!("rgid:{}", release_group_id),
+            (None, Some(artist_name)) => format!(
+                "release:{} AND artist:{}",
+                lucene_phrase(title),
+                lucene_phrase(artist_name)
+            ),
+            (None, None) => format!("release:{}", lucene_phrase(title)),
+        };
+        let search: ReleaseSearch = self.search("release", &query).await?;
+        Ok(search.releases)
     }
 
-    let query = musicbrainz_rs::entity::artist::Artist::query_builder()
-        .name(&artist_name)
-        .build();
+    async fn lookup_release(&self, release_id: &str) -> anyhow::Result<Release> {
+        self.lookup("release", release_id, "recordings+release-groups")
+            .await
+    }
 
-    // Update global tracking last request to MusicBrainz API to allow throttling requests.
+    async fn lookup_recording(&self, recording_id: &str) -> anyhow::Result<Recording> {
+        self.lookup("recording", recording_id, "").await
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ArtistSearch {
+    artists: Vec<ScoredArtist>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ReleaseSearch {
+    releases: Vec<ReleaseResult>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+struct CreditSearch {
+    release_groups: Vec<Credited>,
+    recordings: Vec<Credited>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+struct Credited {
+    artist_credit: Vec<ArtistCredit>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ArtistCredit {
+    artist: CreditedArtist,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct CreditedArtist {
+    id: String,
+}
+
+/// Store the MusicBrainz artists an artist may be, for later review, replacing any found before.
+async fn store_candidates(config: &Config, artist_id: i32, candidates: &[Candidate]) {
+    let candidates: Vec<artist_candidate::ActiveModel> = candidates
+        .iter()
+        .map(|c| artist_candidate::ActiveModel {
+            artist_id: ActiveValue::Set(artist_id),
+            musicbrainz_id: ActiveValue::Set(c.artist.id.to_string()),
+            name: ActiveValue::Set(c.artist.name.to_string()),
+            sort_name: ActiveValue::Set(c.artist.sort_name.to_string()),
+            disambiguation_comment: ActiveValue::Set(c.artist.disambiguation.to_string()),
+            search_score: ActiveValue::Set(c.search_score),
+            confidence: ActiveValue::Set(c.confidence),
+            ..Default::default()
+        })
+        .collect();
+    let db = database::connection(config).await;
+    if let Err(e) = ArtistCandidate::delete_many()
+        .filter(artist_candidate::Column::ArtistId.eq(artist_id))
+        .exec(db)
+        .await
     {
-        let mut last_request = MUSICBRAINZ_LAST_REQUEST.write().unwrap();
-        *last_request = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+        event!(Level::WARN, "ArtistCandidate::delete_many() failure: {}", e);
+        return;
+    }
+    if let Err(e) = ArtistCandidate::insert_many(candidates).exec(db).await {
+        event!(Level::WARN, "ArtistCandidate::insert_many() failure: {}", e);
+    }
+}
+
+/// Store an artist that wasn't matched in MusicBrainz, using the name from the audio file.
+async fn insert_unmatched_artist(
+    config: &Config,
+    artist_name: &str,
+    match_status: MatchStatus,
+    match_score: Option<i32>,
+) -> Option<i32> {
+    insert_artist(
+        config,
+        artist::ActiveModel {
+            name: ActiveValue::Set(artist_name.to_string()),
+            musicbrainz_name: ActiveValue::Set("".to_string()),
+            musicbrainz_id: ActiveValue::Set("".to_string()),
+            sort_name: ActiveValue::Set("".to_string()),
+            disambiguation_comment: ActiveValue::Set("".to_string()),
+            match_status: ActiveValue::Set(match_status.as_str().to_string()),
+            match_score: ActiveValue::Set(match_score),
+            ..Default::default()
+        },
+    )
+    .await
+}
+
+/// Load an artist by its MusicBrainz ID, as found in the tags of audio files, looking it up in
+/// MusicBrainz if it's not already in the database.
+#[instrument]
+pub(crate) async fn load_artist_by_id(
+    config: &Config,
+    musicbrainz_id: &str,
+) -> Result<i32, String> {
+    if let Some(artist_id) = find_artist_by_id(config, musicbrainz_id).await {
+        return Ok(artist_id);
     }
-    let query_result = match musicbrainz_rs::entity::artist::Artist::search(query).execute() {
-        Ok(q) => q,
+
+    let result = fetch_artist(config, musicbrainz_id).await?;
+    // Without a name from the audio file, the artist is named as it is in MusicBrainz.
+    store_artist(config, &result.name, &result, 100)
+        .await
+        .ok_or_else(|| format!("failed to store artist {}", result.name))
+}
+
+/// Look up an artist in MusicBrainz by its MusicBrainz ID.
+pub(crate) async fn fetch_artist(
+    config: &Config,
+    musicbrainz_id: &str,
+) -> Result<musicbrainz_rs::entity::artist::Artist, String> {
+    match provider(config).lookup_artist(musicbrainz_id).await {
+        Ok(r) => Ok(r),
         Err(e) => {
-            event!(Level::WARN, "musicbrainz query failed: {}", e);
-            return None;
+            event!(Level::WARN, "musicbrainz lookup failed: {}", e);
+            Err(format!("musicbrainz lookup failed: {}", e))
         }
-    };
+    }
+}
 
-    let artist = if let Some(result) =
-        // For now assume the first matching artist.
-        query_result.entities.get(0)
+/// Find an artist in the database by its MusicBrainz ID.
+pub(crate) async fn find_artist_by_id(config: &Config, musicbrainz_id: &str) -> Option<i32> {
+    let db = database::connection(config).await;
+    match Artist::find()
+        .filter(artist::Column::MusicbrainzId.eq(musicbrainz_id))
+        .one(db)
+        .await
     {
-        event!(Level::INFO, "MusicBrainz response: {:#?}", result);
+        Ok(a) => a.map(|a| a.artist_id),
+        Err(e) => {
+            event!(Level::WARN, "Artist::find() by id failure: {}", e);
+            None
+        }
+    }
+}
+
+/// Store an artist found in MusicBrainz, unless an artist with the same MusicBrainz ID is
+/// already in the database.
+async fn store_artist(
+    config: &Config,
+    artist_name: &str,
+    result: &musicbrainz_rs::entity::artist::Artist,
+    match_score: i32,
+) -> Option<i32> {
+    event!(Level::INFO, "MusicBrainz response: {:#?}", result);
 
+    // The same artist may be named differently in different audio files.
+    if let Some(artist_id) = find_artist_by_id(config, &result.id).await {
+        return Some(artist_id);
+    }
+
+    let artist = build_artist(
+        config,
+        artist_name,
+        result,
+        MatchStatus::Matched,
+        Some(match_score),
+    )
+    .await?;
+    insert_artist(config, artist).await
+}
+
+/// Build an artist from one found in MusicBrainz, storing its area if it's new.
+pub(crate) async fn build_artist(
+    config: &Config,
+    artist_name: &str,
+    result: &musicbrainz_rs::entity::artist::Artist,
+    match_status: MatchStatus,
+    match_score: Option<i32>,
+) -> Option<artist::ActiveModel> {
+    let artist = {
         let mut area_id = 0;
 
         // If area is defined, track it in the database.
@@ -272,14 +1042,18 @@ pub(crate) async fn load_artist_by_name(config: &Config, artist_name: &str) -> O
                     ..Default::default()
                 };
                 event!(Level::DEBUG, "Insert ArtistArea: {:?}", new_area);
-                let new_artist_area = {
-                    let db = database::connection(config).await;
-                    ArtistArea::insert(new_area)
-                        .exec(db)
-                        .await
-                        .expect("failed to write artist to database")
-                };
-                new_artist_area.last_insert_id
+                let db = database::connection(config).await;
+                match ArtistArea::insert(new_area).exec(db).await {
+                    Ok(a) => a.last_insert_id,
+                    Err(e) => {
+                        event!(
+                            Level::WARN,
+                            "failed to write artist area to database: {}",
+                            e
+                        );
+                        return None;
+                    }
+                }
             };
         }
 
@@ -288,17 +1062,11 @@ pub(crate) async fn load_artist_by_name(config: &Config, artist_name: &str) -> O
 
         // ArtistType is optional, convert to RiaArtistType to add
         // SeaOrm mapping.
-        let artist_type: Option<RiaArtistType> = result
-            .artist_type
-            .as_ref()
-            .map(|a| a.try_into().expect("ArtistType conversion can't fail"));
+        let artist_type: Option<RiaArtistType> = result.artist_type.as_ref().map(|a| a.into());
 
         // Gender is optional, convert to RiaGender to add
         // SeaOrm mapping.
-        let gender: Option<RiaGender> = result
-            .gender
-            .as_ref()
-            .map(|g| g.try_into().expect("Gender conversion can't fail"));
+        let gender: Option<RiaGender> = result.gender.as_ref().map(|g| g.into());
 
         artist::ActiveModel {
             name: ActiveValue::Set(artist_name.to_string()),
@@ -309,23 +1077,125 @@ pub(crate) async fn load_artist_by_name(config: &Config, artist_name: &str) -> O
             artist_area_id: ActiveValue::Set(artist_area_id),
             artist_type: ActiveValue::Set(artist_type),
             gender: ActiveValue::Set(gender),
-            ..Default::default()
-        }
-    } else {
-        event!(Level::WARN, "{} not found in MusicBrainz", artist_name);
-        artist::ActiveModel {
-            name: ActiveValue::Set(artist_name.to_string()),
+            match_status: ActiveValue::Set(match_status.as_str().to_string()),
+            match_score: ActiveValue::Set(match_score),
             ..Default::default()
         }
     };
+    Some(artist)
+}
 
+/// Store an artist, returning None if it couldn't be written so the queue item is retried.
+async fn insert_artist(config: &Config, artist: artist::ActiveModel) -> Option<i32> {
     event!(Level::DEBUG, "Insert Artist: {:?}", artist);
-    let new_artist = {
+    let db = database::connection(config).await;
+    match Artist::insert(artist).exec(db).await {
+        Ok(a) => Some(a.last_insert_id),
+        Err(e) => {
+            event!(Level::WARN, "failed to write artist to database: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::testing::TestLibrary;
+
+    /// Queue an artist lookup, returning the queue item.
+    async fn queue_lookup(config: &Config, value: &str) -> musicbrainz_queue::Model {
+        add_to_queue(
+            config,
+            QueuePayload {
+                payload_type: PayloadType::ArtistId,
+                id: 1,
+                value: value.to_string(),
+            },
+        )
+        .await;
         let db = database::connection(config).await;
-        Artist::insert(artist)
-            .exec(db)
+        MusicbrainzQueue::find()
+            .order_by_desc(musicbrainz_queue::Column::MusicbrainzQueueId)
+            .one(db)
             .await
-            .expect("failed to write artist to database")
-    };
-    Some(new_artist.last_insert_id)
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn failing_items_are_dead_after_the_maximum_attempts() {
+        let test =
+            TestLibrary::new(&["--queue-max-attempts", "3", "--queue-retry-delay", "0"]).await;
+        let config = &test.config;
+        let db = database::connection(config).await;
+        let queued = queue_lookup(config, "failing").await;
+
+        for attempt in 1..=3 {
+            let item = load_from_queue(config).await.unwrap();
+            assert_eq!(item.musicbrainz_queue_id, queued.musicbrainz_queue_id);
+            assert_eq!(item.attempts, attempt);
+            assert!(fail_queue_item(config, item, "lookup failed", false).await);
+        }
+        assert!(load_from_queue(config).await.is_none());
+
+        let item = MusicbrainzQueue::find_by_id(queued.musicbrainz_queue_id)
+            .one(db)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(item.status, QueueStatus::Dead.as_str());
+        assert_eq!(item.attempts, 3);
+        assert_eq!(item.next_attempt_at, None);
+        assert_eq!(item.errors.unwrap().lines().count(), 3);
+    }
+
+    #[tokio::test]
+    async fn abandoned_items_are_reclaimed_once_their_lease_expires() {
+        let test = TestLibrary::new(&[]).await;
+        let config = &test.config;
+        let db = database::connection(config).await;
+        let queued = queue_lookup(config, "abandoned").await;
+        let item = load_from_queue(config).await.unwrap();
+        assert!(item.processing_started_at.is_some());
+
+        // Still leased, so it isn't claimed again.
+        assert!(load_from_queue(config).await.is_none());
+
+        let started = chrono::Utc::now().naive_utc()
+            - chrono::Duration::seconds(DEFAULT_QUEUE_LEASE_TIMEOUT as i64 + 60);
+        let mut item: musicbrainz_queue::ActiveModel = item.into();
+        item.processing_started_at = Set(Some(started));
+        item.update(db).await.unwrap();
+
+        // The abandoned attempt counts as a failure, and is retried after the retry delay.
+        assert!(load_from_queue(config).await.is_none());
+        let item = MusicbrainzQueue::find_by_id(queued.musicbrainz_queue_id)
+            .one(db)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(item.status, QueueStatus::Pending.as_str());
+        assert_eq!(item.attempts, 1);
+        assert_eq!(item.processing_started_at, None);
+        assert!(item.next_attempt_at.is_some());
+        assert!(item.errors.unwrap().contains("abandoned while processing"));
+    }
+
+    #[test]
+    fn retry_delay_doubles_up_to_the_maximum() {
+        let config = Config::parse_from(["ria", "--queue-retry-delay", "60"]);
+        assert_eq!(retry_delay(&config, 0), 60);
+        assert_eq!(retry_delay(&config, 1), 60);
+        assert_eq!(retry_delay(&config, 2), 120);
+        assert_eq!(retry_delay(&config, 5), 960);
+        assert_eq!(retry_delay(&config, 12), MAXIMUM_RETRY_DELAY);
+        assert_eq!(retry_delay(&config, i32::MAX), MAXIMUM_RETRY_DELAY);
+
+        let config = Config::parse_from(["ria", "--queue-retry-delay", &u64::MAX.to_string()]);
+        assert_eq!(retry_delay(&config, 1), MAXIMUM_RETRY_DELAY);
+        assert_eq!(retry_delay(&config, i32::MAX), MAXIMUM_RETRY_DELAY);
+    }
 }
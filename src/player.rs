@@ -1,18 +1,39 @@
 use std::io::BufReader;
+
+use rodio::Source;
 use tracing::{event, instrument, Level};
 
+/// Play an audio file, amplified by a gain in dB.
 #[instrument]
-pub(crate) fn play_audio(path_str: &str) {
+pub(crate) fn play_audio(path_str: &str, gain: f64) {
     event!(Level::TRACE, "play_audio {}", path_str);
 
     // Create a rodio sink representing the audio track.
-    let (_stream, handle) = rodio::OutputStream::try_default().unwrap();
-    let sink = rodio::Sink::try_new(&handle).unwrap();
+    let (_stream, handle) = match rodio::OutputStream::try_default() {
+        Ok(s) => s,
+        Err(e) => {
+            event!(Level::ERROR, "play_audio no output device: {}", e);
+            return;
+        }
+    };
+    let sink = match rodio::Sink::try_new(&handle) {
+        Ok(s) => s,
+        Err(e) => {
+            event!(Level::ERROR, "play_audio sink error: {}", e);
+            return;
+        }
+    };
 
     // Load file into the sink.
-    let file = std::fs::File::open(path_str).unwrap();
+    let file = match std::fs::File::open(path_str) {
+        Ok(f) => f,
+        Err(e) => {
+            event!(Level::ERROR, "play_audio {} error: {}", path_str, e);
+            return;
+        }
+    };
     sink.append(match rodio::Decoder::new(BufReader::new(file)) {
-        Ok(d) => d,
+        Ok(d) => d.amplify(10f64.powf(gain / 20.0) as f32),
         Err(e) => {
             event!(Level::ERROR, "play_audio {} error: {}", path_str, e);
             return;
@@ -0,0 +1,113 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use crate::musicbrainz::MusicBrainz;
+use crate::Config;
+
+/// A source of information about artists, releases and recordings. Identifiers and entities are
+/// those of MusicBrainz, which other providers are expected to map theirs to.
+#[async_trait]
+pub(crate) trait MetadataProvider: Send + Sync {
+    /// Search for artists by name, or by one of their aliases.
+    async fn search_artists(&self, name: &str) -> anyhow::Result<Vec<ScoredArtist>>;
+
+    /// Look up an artist by its ID.
+    async fn lookup_artist(
+        &self,
+        artist_id: &str,
+    ) -> anyhow::Result<musicbrainz_rs::entity::artist::Artist>;
+
+    /// Search for release groups with this title by an artist, returning the IDs of the artists
+    /// credited with them.
+    async fn release_group_credits(
+        &self,
+        title: &str,
+        artist_name: &str,
+    ) -> anyhow::Result<Vec<String>>;
+
+    /// Search for recordings with this title by an artist, returning the IDs of the artists
+    /// credited with them.
+    async fn recording_credits(
+        &self,
+        title: &str,
+        artist_name: &str,
+    ) -> anyhow::Result<Vec<String>>;
+
+    /// Search for the releases of a release group, if known, or else by title and artist.
+    async fn search_releases(
+        &self,
+        title: &str,
+        artist_name: Option<&str>,
+        release_group_id: Option<&str>,
+    ) -> anyhow::Result<Vec<ReleaseResult>>;
+
+    /// Look up a release by its ID, with its release group and the recordings of its tracks.
+    async fn lookup_release(&self, release_id: &str) -> anyhow::Result<Release>;
+
+    /// Look up a recording by its ID.
+    async fn lookup_recording(&self, recording_id: &str) -> anyhow::Result<Recording>;
+}
+
+/// The metadata provider to use.
+pub(crate) fn provider(config: &Config) -> Box<dyn MetadataProvider> {
+    Box::new(MusicBrainz::new(config))
+}
+
+/// An artist found by searching, and how well it matched the search out of 100.
+#[derive(Debug, Deserialize)]
+pub(crate) struct ScoredArtist {
+    #[serde(default)]
+    pub(crate) score: i32,
+    #[serde(flatten)]
+    pub(crate) artist: musicbrainz_rs::entity::artist::Artist,
+}
+
+/// A release found by searching, and how well it matched the search out of 100.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub(crate) struct ReleaseResult {
+    pub(crate) id: String,
+    pub(crate) score: i32,
+    pub(crate) title: String,
+    pub(crate) track_count: i32,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub(crate) struct Release {
+    pub(crate) id: String,
+    pub(crate) title: String,
+    pub(crate) release_group: ReleaseGroup,
+    pub(crate) media: Vec<Medium>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub(crate) struct ReleaseGroup {
+    pub(crate) id: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub(crate) struct Medium {
+    pub(crate) position: i32,
+    pub(crate) tracks: Vec<Track>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub(crate) struct Track {
+    pub(crate) id: String,
+    pub(crate) position: i32,
+    pub(crate) title: String,
+    pub(crate) length: Option<i64>,
+    pub(crate) recording: Recording,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub(crate) struct Recording {
+    pub(crate) id: String,
+    pub(crate) title: String,
+    pub(crate) length: Option<i64>,
+}
@@ -0,0 +1,194 @@
+use clap::Subcommand;
+use sea_orm::sea_query::Expr;
+use sea_orm::*;
+use tracing::{event, instrument, Level};
+
+use crate::database;
+use crate::entities::{prelude::*, *};
+use crate::musicbrainz::{QueuePayload, QueueStatus};
+use crate::Config;
+
+/// Commands for inspecting the queue of MusicBrainz lookups.
+#[derive(Subcommand, Debug, Clone)]
+pub(crate) enum QueueCommand {
+    /// List queued lookups, with how often they failed and why
+    List {
+        /// Only list dead letters, lookups that failed too often to be retried
+        #[arg(long)]
+        dead: bool,
+    },
+    /// Retry the given lookups now, or all dead letters if no ID is given
+    Retry { ids: Vec<i32> },
+    /// Delete the given lookups, or all dead letters if no ID is given
+    Purge {
+        ids: Vec<i32>,
+        /// Delete every queued lookup, not only dead letters
+        #[arg(long, conflicts_with = "ids")]
+        all: bool,
+    },
+}
+
+/// Run a queue command.
+#[instrument]
+pub(crate) async fn run_command(config: &Config, command: &QueueCommand) -> anyhow::Result<()> {
+    event!(Level::TRACE, "run_command");
+
+    match command {
+        QueueCommand::List { dead } => list_queue(config, *dead).await,
+        QueueCommand::Retry { ids } => retry_queue(config, ids).await,
+        QueueCommand::Purge { ids, all } => purge_queue(config, ids, *all).await,
+    }
+}
+
+/// Print queued lookups, followed by the errors of any failed attempts.
+async fn list_queue(config: &Config, dead: bool) -> anyhow::Result<()> {
+    let db = database::connection(config).await;
+    let mut query = MusicbrainzQueue::find().order_by_asc(musicbrainz_queue::Column::CreatedAt);
+    if dead {
+        query = query.filter(musicbrainz_queue::Column::Status.eq(QueueStatus::Dead.as_str()));
+    }
+    for item in query.all(db).await? {
+        let payload = item.payload.as_deref().unwrap_or_default();
+        let description = match serde_json::from_str::<QueuePayload>(payload) {
+            Ok(p) => format!("{:?} {} {}", p.payload_type, p.id, p.value),
+            Err(_) => payload.to_string(),
+        };
+        let state = if item.status != QueueStatus::Pending.as_str() {
+            item.status.clone()
+        } else if item.processing_started_at.is_some() {
+            "processing".to_string()
+        } else if let Some(next) = item.next_attempt_at {
+            format!("retry at {}", next.format("%Y-%m-%d %H:%M:%S"))
+        } else {
+            item.status.clone()
+        };
+        println!(
+            "{:>6}: {} [{}, {} attempts]",
+            item.musicbrainz_queue_id, description, state, item.attempts
+        );
+        for error in item.errors.as_deref().unwrap_or_default().lines() {
+            println!("        {}", error);
+        }
+    }
+    Ok(())
+}
+
+/// Make lookups due to be processed again, with a fresh set of attempts. Earlier errors are
+/// kept.
+async fn retry_queue(config: &Config, ids: &[i32]) -> anyhow::Result<()> {
+    let db = database::connection(config).await;
+    let filter = if ids.is_empty() {
+        musicbrainz_queue::Column::Status.eq(QueueStatus::Dead.as_str())
+    } else {
+        musicbrainz_queue::Column::MusicbrainzQueueId.is_in(ids.iter().copied())
+    };
+    let result = MusicbrainzQueue::update_many()
+        .col_expr(
+            musicbrainz_queue::Column::Status,
+            Expr::value(QueueStatus::Pending.as_str()),
+        )
+        .col_expr(musicbrainz_queue::Column::Attempts, Expr::value(0))
+        .col_expr(
+            musicbrainz_queue::Column::NextAttemptAt,
+            Expr::value(Option::<chrono::NaiveDateTime>::None),
+        )
+        .col_expr(
+            musicbrainz_queue::Column::ProcessingStartedAt,
+            Expr::value(Option::<chrono::NaiveDateTime>::None),
+        )
+        .filter(filter)
+        .exec(db)
+        .await?;
+    println!(
+        "{} lookups will be retried the next time the library is scanned or watched",
+        result.rows_affected
+    );
+    Ok(())
+}
+
+/// Delete lookups from the queue.
+async fn purge_queue(config: &Config, ids: &[i32], all: bool) -> anyhow::Result<()> {
+    let db = database::connection(config).await;
+    let mut query = MusicbrainzQueue::delete_many();
+    if !ids.is_empty() {
+        query =
+            query.filter(musicbrainz_queue::Column::MusicbrainzQueueId.is_in(ids.iter().copied()));
+    } else if !all {
+        query = query.filter(musicbrainz_queue::Column::Status.eq(QueueStatus::Dead.as_str()));
+    }
+    let result = query.exec(db).await?;
+    println!("{} lookups deleted", result.rows_affected);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestLibrary;
+
+    /// Store a queue item with a status, returning its id.
+    async fn insert_item(config: &Config, status: QueueStatus, attempts: i32) -> i32 {
+        let db = database::connection(config).await;
+        let item = musicbrainz_queue::ActiveModel {
+            created_at: ActiveValue::Set(chrono::Utc::now().naive_utc()),
+            status: ActiveValue::Set(status.as_str().to_string()),
+            attempts: ActiveValue::Set(attempts),
+            errors: ActiveValue::Set(Some("lookup failed".to_string())),
+            next_attempt_at: ActiveValue::Set(Some(chrono::Utc::now().naive_utc())),
+            ..Default::default()
+        };
+        item.insert(db).await.unwrap().musicbrainz_queue_id
+    }
+
+    async fn remaining(config: &Config) -> Vec<musicbrainz_queue::Model> {
+        let db = database::connection(config).await;
+        MusicbrainzQueue::find()
+            .order_by_asc(musicbrainz_queue::Column::MusicbrainzQueueId)
+            .all(db)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn retry_resets_dead_or_given_items() {
+        let test = TestLibrary::new(&[]).await;
+        let config = &test.config;
+        let dead = insert_item(config, QueueStatus::Dead, 5).await;
+        let pending = insert_item(config, QueueStatus::Pending, 2).await;
+
+        // Without ids only dead letters are retried, keeping their errors.
+        retry_queue(config, &[]).await.unwrap();
+        let items = remaining(config).await;
+        assert_eq!(items[0].musicbrainz_queue_id, dead);
+        assert_eq!(items[0].status, QueueStatus::Pending.as_str());
+        assert_eq!(items[0].attempts, 0);
+        assert_eq!(items[0].next_attempt_at, None);
+        assert_eq!(items[0].errors.as_deref(), Some("lookup failed"));
+        assert_eq!(items[1].attempts, 2);
+        assert!(items[1].next_attempt_at.is_some());
+
+        retry_queue(config, &[pending]).await.unwrap();
+        let items = remaining(config).await;
+        assert_eq!(items[1].attempts, 0);
+        assert_eq!(items[1].next_attempt_at, None);
+    }
+
+    #[tokio::test]
+    async fn purge_deletes_dead_given_or_all_items() {
+        let test = TestLibrary::new(&[]).await;
+        let config = &test.config;
+        insert_item(config, QueueStatus::Dead, 5).await;
+        let first = insert_item(config, QueueStatus::Pending, 1).await;
+        let second = insert_item(config, QueueStatus::Pending, 2).await;
+        insert_item(config, QueueStatus::Pending, 3).await;
+
+        purge_queue(config, &[], false).await.unwrap();
+        assert_eq!(remaining(config).await.len(), 3);
+
+        purge_queue(config, &[first, second], false).await.unwrap();
+        assert_eq!(remaining(config).await.len(), 1);
+
+        purge_queue(config, &[], true).await.unwrap();
+        assert!(remaining(config).await.is_empty());
+    }
+}
@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use once_cell::sync::Lazy;
+use tracing::{event, instrument, Level};
+
+use crate::USER_AGENT;
+
+static RATE_LIMITERS: Lazy<Mutex<HashMap<String, Arc<RateLimiter>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
+
+// How many times a request is retried after the service asks us to slow down.
+const MAXIMUM_RETRIES: u32 = 3;
+
+// Seconds to back off when a service is unavailable without saying for how long.
+const DEFAULT_RETRY_AFTER: u64 = 5;
+
+// Services asking us to back off for longer than this are treated as down.
+const MAXIMUM_RETRY_AFTER: u64 = 5 * 60;
+
+/// A token bucket limiting how often a service is queried. Tokens are added at `rate` per
+/// second, up to `burst`, and each request takes one, so up to `burst` requests can be made at
+/// once before slowing down to `rate`.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    name: String,
+    rate: f64,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    // Set when the service asked us to back off, no requests are made until then.
+    blocked_until: Option<Instant>,
+}
+
+impl RateLimiter {
+    fn new(name: &str, rate: f64, burst: u32) -> Self {
+        let burst = burst.max(1) as f64;
+        RateLimiter {
+            name: name.to_string(),
+            rate: rate.max(f64::MIN_POSITIVE),
+            burst,
+            bucket: Mutex::new(Bucket {
+                tokens: burst,
+                updated: Instant::now(),
+                blocked_until: None,
+            }),
+        }
+    }
+
+    /// The rate limiter of a service, shared by everything querying it. The rate and burst are
+    /// set by whatever first queries the service.
+    pub(crate) fn for_service(name: &str, rate: f64, burst: u32) -> Arc<RateLimiter> {
+        let mut limiters = RATE_LIMITERS.lock().unwrap();
+        limiters
+            .entry(name.to_string())
+            .or_insert_with(|| Arc::new(RateLimiter::new(name, rate, burst)))
+            .clone()
+    }
+
+    /// Wait until a request can be made, and take a token for it.
+    pub(crate) async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+                let now = Instant::now();
+                let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+                bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+                bucket.updated = now;
+                match bucket.blocked_until {
+                    Some(until) if until > now => until - now,
+                    _ if bucket.tokens >= 1.0 => {
+                        bucket.tokens -= 1.0;
+                        return;
+                    }
+                    _ => Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate),
+                }
+            };
+            event!(
+                Level::TRACE,
+                "{} rate limited, waiting {:?}",
+                self.name,
+                wait
+            );
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Stop making requests for a while, ie when the service asked us to slow down.
+    pub(crate) fn back_off(&self, delay: Duration) {
+        let mut bucket = self.bucket.lock().unwrap();
+        let until = Instant::now() + delay;
+        if bucket.blocked_until.is_none_or(|u| u < until) {
+            bucket.blocked_until = Some(until);
+        }
+        bucket.tokens = 0.0;
+    }
+}
+
+/// Make a rate limited GET request to a service, returning the body of the response. When the
+/// service is unavailable or asks us to slow down, all its requests back off for as long as it
+/// asks before retrying.
+#[instrument(skip(limiter))]
+pub(crate) async fn get(
+    limiter: &RateLimiter,
+    url: String,
+    query: Vec<(String, String)>,
+) -> anyhow::Result<String> {
+    event!(Level::TRACE, "get");
+
+    let mut retries = 0;
+    loop {
+        limiter.acquire().await;
+        let response = CLIENT
+            .get(&url)
+            .query(&query)
+            .header(reqwest::header::USER_AGENT, USER_AGENT.as_str())
+            .send()
+            .await?;
+
+        let status = response.status();
+        if status == reqwest::StatusCode::SERVICE_UNAVAILABLE
+            || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        {
+            // Only the number of seconds form of Retry-After is supported.
+            let retry_after = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .unwrap_or(DEFAULT_RETRY_AFTER);
+            if retries >= MAXIMUM_RETRIES || retry_after > MAXIMUM_RETRY_AFTER {
+                return Err(anyhow!(
+                    "{} responded {} to {}, retry after {} seconds",
+                    limiter.name,
+                    status,
+                    url,
+                    retry_after
+                ));
+            }
+            event!(
+                Level::WARN,
+                "{} responded {}, backing off for {} seconds",
+                limiter.name,
+                status,
+                retry_after
+            );
+            limiter.back_off(Duration::from_secs(retry_after));
+            retries += 1;
+            continue;
+        }
+
+        return Ok(response.error_for_status()?.text().await?);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::convert::Infallible;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use hyper::header::RETRY_AFTER;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Response, Server};
+
+    use super::*;
+
+    /// Serve a script of responses, ie (429, Some("1")) for Too Many Requests with a Retry-After
+    /// of a second, then "ok" once it runs out. Returns the URL, and how many requests were made.
+    fn serve_script(script: Vec<(u16, Option<String>)>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let script = Arc::new(Mutex::new(VecDeque::from(script)));
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let service = make_service_fn(move |_| {
+            let script = script.clone();
+            let counter = counter.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |_| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    let response = match script.lock().unwrap().pop_front() {
+                        Some((status, retry_after)) => {
+                            let mut response = Response::builder().status(status);
+                            if let Some(retry_after) = retry_after {
+                                response = response.header(RETRY_AFTER, retry_after);
+                            }
+                            response.body(Body::empty()).unwrap()
+                        }
+                        None => Response::new(Body::from("ok")),
+                    };
+                    async move { Ok::<_, Infallible>(response) }
+                }))
+            }
+        });
+        tokio::spawn(Server::from_tcp(listener).unwrap().serve(service));
+        (url, requests)
+    }
+
+    #[tokio::test]
+    async fn acquire_bursts_then_slows_to_the_rate() {
+        let limiter = RateLimiter::new("test", 20.0, 3);
+        let started = Instant::now();
+        for _ in 0..3 {
+            limiter.acquire().await;
+        }
+        assert!(started.elapsed() < Duration::from_millis(20));
+
+        // The bucket is empty, the next token takes 1/rate seconds.
+        let started = Instant::now();
+        limiter.acquire().await;
+        let waited = started.elapsed();
+        assert!(waited >= Duration::from_millis(45), "{:?}", waited);
+        assert!(waited < Duration::from_millis(150), "{:?}", waited);
+    }
+
+    #[tokio::test]
+    async fn back_off_blocks_acquire() {
+        let limiter = RateLimiter::new("test", 1000.0, 10);
+        let started = Instant::now();
+        limiter.back_off(Duration::from_millis(100));
+        // A shorter back off doesn't cut a longer one short.
+        limiter.back_off(Duration::from_millis(10));
+        limiter.acquire().await;
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn get_backs_off_for_as_long_as_asked() {
+        let limiter = RateLimiter::new("test", 1000.0, 10);
+        let (url, requests) = serve_script(vec![(429, Some("1".to_string()))]);
+        let started = Instant::now();
+        assert_eq!(get(&limiter, url, Vec::new()).await.unwrap(), "ok");
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn get_backs_off_by_default_without_retry_after() {
+        let limiter = RateLimiter::new("test", 1000.0, 10);
+        let (url, requests) = serve_script(vec![(503, None)]);
+        let started = Instant::now();
+        assert_eq!(get(&limiter, url, Vec::new()).await.unwrap(), "ok");
+        assert!(started.elapsed() >= Duration::from_secs(DEFAULT_RETRY_AFTER));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn get_gives_up_after_the_maximum_retries() {
+        let limiter = RateLimiter::new("test", 1000.0, 10);
+        let script = vec![(429, Some("0".to_string())); MAXIMUM_RETRIES as usize + 1];
+        let (url, requests) = serve_script(script);
+        assert!(get(&limiter, url, Vec::new()).await.is_err());
+        assert_eq!(
+            requests.load(Ordering::SeqCst),
+            MAXIMUM_RETRIES as usize + 1
+        );
+    }
+
+    #[tokio::test]
+    async fn get_gives_up_when_asked_to_wait_too_long() {
+        let limiter = RateLimiter::new("test", 1000.0, 10);
+        let retry_after = (MAXIMUM_RETRY_AFTER + 1).to_string();
+        let (url, requests) = serve_script(vec![(503, Some(retry_after))]);
+        let started = Instant::now();
+        assert!(get(&limiter, url, Vec::new()).await.is_err());
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn get_does_not_retry_other_errors() {
+        let limiter = RateLimiter::new("test", 1000.0, 10);
+        let (url, requests) = serve_script(vec![(404, None)]);
+        assert!(get(&limiter, url, Vec::new()).await.is_err());
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,748 @@
+use std::collections::HashMap;
+
+use sea_orm::sea_query::{Expr, Query};
+use sea_orm::*;
+use tracing::{event, instrument, Level};
+
+use crate::database;
+use crate::entities::{prelude::*, *};
+use crate::media::{self, Scope};
+use crate::musicbrainz::{self, normalise_name};
+use crate::provider::{provider, Release, ReleaseResult, Track};
+use crate::Config;
+
+// Albums are only matched to a release with at least this confidence out of 100.
+const RELEASE_MATCH_THRESHOLD: i32 = 70;
+
+// How many of the best releases found by searching are looked up to compare their tracks.
+const RELEASE_CANDIDATES: usize = 3;
+
+// Audio files are only matched to a track on the release with at least this score out of 1.
+const TRACK_MATCH_THRESHOLD: f64 = 0.5;
+
+// Durations that differ by this many seconds or more don't count towards a match at all.
+const DURATION_TOLERANCE: f64 = 30.0;
+
+/// Queue albums in scope that haven't been matched to a MusicBrainz release, and albums matched
+/// to a release that contain audio files not yet matched to its tracks.
+#[instrument]
+pub(crate) async fn queue_releases(config: &Config, scope: &Scope) {
+    event!(Level::TRACE, "queue_releases");
+    let db = database::connection(config).await;
+    let albums = match Album::find()
+        .filter(scope.albums(album::Column::AlbumId))
+        .filter(
+            Condition::any()
+                .add(album::Column::MusicbrainzReleaseId.is_null())
+                .add(
+                    Condition::all()
+                        .add(album::Column::MusicbrainzReleaseId.ne(""))
+                        .add(
+                            album::Column::AlbumId.in_subquery(
+                                Query::select()
+                                    .column(album_audio::Column::AlbumId)
+                                    .from(AlbumAudio)
+                                    .inner_join(
+                                        Audio,
+                                        Expr::col((AlbumAudio, album_audio::Column::AudioId))
+                                            .equals((Audio, audio::Column::AudioId)),
+                                    )
+                                    .and_where(
+                                        Expr::col((Audio, audio::Column::MusicbrainzRecordingId))
+                                            .is_null(),
+                                    )
+                                    .to_owned(),
+                            ),
+                        ),
+                ),
+        )
+        .all(db)
+        .await
+    {
+        Ok(a) => a,
+        Err(e) => {
+            event!(Level::WARN, "Album::find() without release failure: {}", e);
+            return;
+        }
+    };
+    for album in albums {
+        musicbrainz::add_to_queue(
+            config,
+            musicbrainz::QueuePayload {
+                payload_type: musicbrainz::PayloadType::AlbumRelease,
+                id: album.album_id,
+                value: album.name,
+            },
+        )
+        .await;
+    }
+}
+
+/// Match an album to a MusicBrainz release, by the release or release group ID tagged in its
+/// audio files, or else by searching for its name and comparing the number, durations and
+/// titles of tracks. Returns why it failed if MusicBrainz couldn't be reached, so it can be
+/// retried.
+#[instrument]
+pub(crate) async fn match_album_release(config: &Config, album_id: i32) -> Result<(), String> {
+    event!(Level::TRACE, "match_album_release");
+    let db = database::connection(config).await;
+    let album = match Album::find_by_id(album_id).one(db).await {
+        Ok(Some(a)) => a,
+        // The album was regrouped or removed since it was queued.
+        Ok(None) => return Ok(()),
+        Err(e) => {
+            event!(Level::WARN, "Album::find_by_id() failure: {}", e);
+            return Err(format!("Album::find_by_id() failure: {}", e));
+        }
+    };
+    let Some(tracks) = load_album_tracks(config, album_id).await else {
+        return Err(format!(
+            "failed to load the audio files of album {}",
+            album_id
+        ));
+    };
+    if tracks.is_empty() {
+        return Ok(());
+    }
+
+    // An album already matched to a release only needs new audio files matched to its tracks.
+    let tagged = match album.musicbrainz_release_id.as_deref() {
+        Some(release_id) if !release_id.is_empty() => Some(release_id.to_string()),
+        _ => most_common(tracks.iter().filter_map(|t| t.release_id.as_deref())),
+    };
+    let (release, confidence) = if let Some(release_id) = tagged {
+        match provider(config).lookup_release(&release_id).await {
+            Ok(r) => (r, 100),
+            Err(e) => {
+                event!(Level::WARN, "musicbrainz release lookup failed: {}", e);
+                return Err(format!("musicbrainz release lookup failed: {}", e));
+            }
+        }
+    } else {
+        match search_release(config, &album, &tracks).await? {
+            Some(r) => r,
+            None => {
+                event!(Level::INFO, "{} not found in MusicBrainz", album.name);
+                store_release(config, album_id, None, None).await;
+                queue_recordings(config, &tracks).await;
+                return Ok(());
+            }
+        }
+    };
+
+    if confidence < RELEASE_MATCH_THRESHOLD {
+        event!(
+            Level::WARN,
+            "{} could not be confidently matched to a MusicBrainz release ({}%)",
+            album.name,
+            confidence
+        );
+        store_release(config, album_id, None, Some(confidence)).await;
+        queue_recordings(config, &tracks).await;
+        return Ok(());
+    }
+
+    event!(
+        Level::INFO,
+        "{} matched to MusicBrainz release {} ({}%)",
+        album.name,
+        release.id,
+        confidence
+    );
+    store_release(config, album_id, Some(&release), Some(confidence)).await;
+    let matched = align_tracks(&tracks, &release);
+    for (index, track) in tracks.iter().enumerate() {
+        let recording = matched.get(&index).map(|(_, t)| {
+            (
+                t.recording.id.as_str(),
+                t.id.as_str(),
+                t.recording.title.as_str(),
+            )
+        });
+        store_recording(config, track.audio_id, recording).await;
+    }
+    Ok(())
+}
+
+/// Look up a recording by its MusicBrainz ID, for an audio file that wasn't matched to a track
+/// of a release. Returns why it failed if MusicBrainz couldn't be reached, so it can be retried.
+#[instrument]
+pub(crate) async fn load_recording(
+    config: &Config,
+    audio_id: i32,
+    musicbrainz_id: &str,
+) -> Result<(), String> {
+    event!(Level::TRACE, "load_recording");
+    match provider(config).lookup_recording(musicbrainz_id).await {
+        Ok(recording) => {
+            store_recording(
+                config,
+                audio_id,
+                Some((&recording.id, "", &recording.title)),
+            )
+            .await;
+            Ok(())
+        }
+        Err(e) => {
+            event!(Level::WARN, "musicbrainz recording lookup failed: {}", e);
+            Err(format!("musicbrainz recording lookup failed: {}", e))
+        }
+    }
+}
+
+/// An audio file in an album, with what's known about it to match it to a track.
+#[derive(Debug)]
+struct AlbumTrack {
+    audio_id: i32,
+    disc_number: i32,
+    track_number: Option<i32>,
+    duration: i32,
+    title: Option<String>,
+    release_id: Option<String>,
+    release_group_id: Option<String>,
+    track_id: Option<String>,
+    recording_id: Option<String>,
+}
+
+/// Load the audio files of an album, in order, along with their titles and any MusicBrainz IDs
+/// they're tagged with.
+async fn load_album_tracks(config: &Config, album_id: i32) -> Option<Vec<AlbumTrack>> {
+    let db = database::connection(config).await;
+    let album_audio = match AlbumAudio::find()
+        .filter(album_audio::Column::AlbumId.eq(album_id))
+        .find_also_related(Audio)
+        .order_by_asc(album_audio::Column::DiscNumber)
+        .order_by_asc(album_audio::Column::TrackNumber)
+        .all(db)
+        .await
+    {
+        Ok(a) => a,
+        Err(e) => {
+            event!(Level::WARN, "AlbumAudio::find() tracks failure: {}", e);
+            return None;
+        }
+    };
+    let audio_ids: Vec<i32> = album_audio.iter().map(|(a, _)| a.audio_id).collect();
+    let tags = match AudioTag::find()
+        .filter(audio_tag::Column::AudioId.is_in(audio_ids))
+        .filter(audio_tag::Column::Name.is_in([
+            "TrackTitle",
+            "MusicBrainzAlbumId",
+            "MusicBrainzReleaseGroupId",
+            "MusicBrainzReleaseTrackId",
+            "MusicBrainzRecordingId",
+            "MusicBrainzTrackId",
+        ]))
+        .order_by_asc(audio_tag::Column::Position)
+        .all(db)
+        .await
+    {
+        Ok(t) => t,
+        Err(e) => {
+            event!(Level::WARN, "AudioTag::find() track tags failure: {}", e);
+            return None;
+        }
+    };
+    // Only the first value of each tag is used.
+    let mut values: HashMap<(i32, String), String> = HashMap::new();
+    for tag in tags {
+        values
+            .entry((tag.audio_id, tag.name))
+            .or_insert(tag.value.trim().to_string());
+    }
+    let value = |audio_id: i32, name: &str| values.get(&(audio_id, name.to_string())).cloned();
+    let id =
+        |audio_id: i32, name: &str| value(audio_id, name).filter(|v| media::is_musicbrainz_id(v));
+
+    Some(
+        album_audio
+            .into_iter()
+            .filter_map(|(album_audio, audio)| {
+                let audio = audio?;
+                Some(AlbumTrack {
+                    audio_id: audio.audio_id,
+                    disc_number: album_audio.disc_number.unwrap_or(1),
+                    track_number: album_audio.track_number,
+                    duration: audio.duration,
+                    title: value(audio.audio_id, "TrackTitle"),
+                    release_id: id(audio.audio_id, "MusicBrainzAlbumId"),
+                    release_group_id: id(audio.audio_id, "MusicBrainzReleaseGroupId"),
+                    track_id: id(audio.audio_id, "MusicBrainzReleaseTrackId"),
+                    // Picard stores recording IDs as MUSICBRAINZ_TRACKID in Vorbis comments.
+                    recording_id: id(audio.audio_id, "MusicBrainzRecordingId")
+                        .or_else(|| id(audio.audio_id, "MusicBrainzTrackId")),
+                })
+            })
+            .collect(),
+    )
+}
+
+/// The value found most often, ie the release ID tagged in most audio files of an album.
+fn most_common<'a>(values: impl Iterator<Item = &'a str>) -> Option<String> {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for value in values {
+        *counts.entry(value).or_default() += 1;
+    }
+    counts
+        .into_iter()
+        .max_by_key(|(value, count)| (*count, *value))
+        .map(|(value, _)| value.to_string())
+}
+
+/// Search MusicBrainz for the release an album is most likely to be, and how confident we are
+/// that it's a match. Returns None if nothing was found, or why it failed if MusicBrainz couldn't
+/// be reached.
+async fn search_release(
+    config: &Config,
+    album: &album::Model,
+    tracks: &[AlbumTrack],
+) -> Result<Option<(Release, i32)>, String> {
+    // Prefer the release group tagged in the audio files, which narrows it down to its editions.
+    let release_group_id = most_common(tracks.iter().filter_map(|t| t.release_group_id.as_deref()));
+    let artist_name = match (&release_group_id, album.artist_name.clone()) {
+        (Some(_), _) => None,
+        (None, Some(a)) => Some(a),
+        (None, None) => album_artist_name(config, tracks).await,
+    };
+    let provider = provider(config);
+    let releases = match provider
+        .search_releases(
+            &album.name,
+            artist_name.as_deref(),
+            release_group_id.as_deref(),
+        )
+        .await
+    {
+        Ok(r) => r,
+        Err(e) => {
+            event!(Level::WARN, "musicbrainz release query failed: {}", e);
+            return Err(format!("musicbrainz release query failed: {}", e));
+        }
+    };
+
+    // Rank by what's known without looking up each release, then compare the tracks of the best.
+    let mut results: Vec<(f64, ReleaseResult)> = releases
+        .into_iter()
+        .map(|r| (summary_score(album, tracks.len(), &r), r))
+        .collect();
+    results.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    let mut best: Option<(Release, i32)> = None;
+    for (summary, result) in results.into_iter().take(RELEASE_CANDIDATES) {
+        let release = match provider.lookup_release(&result.id).await {
+            Ok(r) => r,
+            Err(e) => {
+                event!(Level::WARN, "musicbrainz release lookup failed: {}", e);
+                return Err(format!("musicbrainz release lookup failed: {}", e));
+            }
+        };
+        let confidence = ((summary * (1.0 - TRACKS_WEIGHT)
+            + alignment_score(tracks, &release) * TRACKS_WEIGHT)
+            * 100.0)
+            .round() as i32;
+        event!(
+            Level::DEBUG,
+            "release {} {} confidence {}",
+            release.id,
+            release.title,
+            confidence
+        );
+        if best.as_ref().is_none_or(|(_, c)| confidence > *c) {
+            best = Some((release, confidence));
+        }
+    }
+    Ok(best)
+}
+
+// How much comparing the tracks of a release counts towards confidence, the rest is the search
+// score, title and number of tracks.
+const TRACKS_WEIGHT: f64 = 0.5;
+
+/// Score a release found by searching out of 1, from the search score, the similarity of the
+/// title, and the number of tracks.
+fn summary_score(album: &album::Model, track_count: usize, result: &ReleaseResult) -> f64 {
+    let search = result.score.clamp(0, 100) as f64 / 100.0;
+    let title = strsim::normalized_levenshtein(
+        &normalise_name(&album.name),
+        &normalise_name(&result.title),
+    );
+    let count = if result.track_count > 0 {
+        let difference = (result.track_count - track_count as i32).abs() as f64;
+        (1.0 - difference / result.track_count.max(track_count as i32) as f64).max(0.0)
+    } else {
+        0.0
+    };
+    search * 0.4 + title * 0.3 + count * 0.3
+}
+
+/// Score how well the audio files of an album match the tracks of a release out of 1, ie 1 if
+/// every audio file matches a track with the same position, title and duration. Tracks of the
+/// release with no audio file also lower the score.
+fn alignment_score(tracks: &[AlbumTrack], release: &Release) -> f64 {
+    let release_tracks = release.media.iter().map(|m| m.tracks.len()).sum::<usize>();
+    if tracks.is_empty() || release_tracks == 0 {
+        return 0.0;
+    }
+    let matched = align_tracks(tracks, release);
+    let total: f64 = tracks
+        .iter()
+        .enumerate()
+        .filter_map(|(index, track)| {
+            matched
+                .get(&index)
+                .map(|(disc, release_track)| track_score(track, *disc, release_track))
+        })
+        .sum();
+    total / tracks.len().max(release_tracks) as f64
+}
+
+/// Match the audio files of an album to the tracks of a release, best matches first so each
+/// track is only used once. Returns the matching track, and the position of the medium it's on,
+/// for the index of each audio file.
+fn align_tracks<'a>(
+    tracks: &[AlbumTrack],
+    release: &'a Release,
+) -> HashMap<usize, (i32, &'a Track)> {
+    let release_tracks: Vec<(i32, &Track)> = release
+        .media
+        .iter()
+        .flat_map(|m| m.tracks.iter().map(move |t| (m.position, t)))
+        .collect();
+    let mut pairs: Vec<(f64, usize, usize)> = Vec::new();
+    for (index, track) in tracks.iter().enumerate() {
+        for (release_index, (disc, release_track)) in release_tracks.iter().enumerate() {
+            let score = track_score(track, *disc, release_track);
+            if score >= TRACK_MATCH_THRESHOLD {
+                pairs.push((score, index, release_index));
+            }
+        }
+    }
+    pairs.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    let mut matched: HashMap<usize, (i32, &Track)> = HashMap::new();
+    let mut used = vec![false; release_tracks.len()];
+    for (_, index, release_index) in pairs {
+        if matched.contains_key(&index) || used[release_index] {
+            continue;
+        }
+        used[release_index] = true;
+        matched.insert(index, release_tracks[release_index]);
+    }
+    matched
+}
+
+/// Score how well an audio file matches a track out of 1. MusicBrainz IDs tagged in the audio
+/// file are certain, otherwise the position, title and duration are compared.
+fn track_score(track: &AlbumTrack, disc: i32, release_track: &Track) -> f64 {
+    if track.track_id.as_deref() == Some(release_track.id.as_str())
+        || track.recording_id.as_deref() == Some(release_track.recording.id.as_str())
+    {
+        return 1.0;
+    }
+    let position =
+        if track.disc_number == disc && track.track_number == Some(release_track.position) {
+            1.0
+        } else {
+            0.0
+        };
+    let title = match &track.title {
+        Some(title) => strsim::normalized_levenshtein(
+            &normalise_name(title),
+            &normalise_name(&release_track.title),
+        ),
+        None => 0.0,
+    };
+    let duration = match release_track.length.or(release_track.recording.length) {
+        Some(length) if track.duration > 0 => {
+            let difference = (length as f64 / 1000.0 - track.duration as f64).abs();
+            (1.0 - difference / DURATION_TOLERANCE).max(0.0)
+        }
+        _ => 0.0,
+    };
+    position * 0.4 + title * 0.35 + duration * 0.25
+}
+
+/// The artist of the first audio file with one, for albums without an album artist.
+async fn album_artist_name(config: &Config, tracks: &[AlbumTrack]) -> Option<String> {
+    let db = database::connection(config).await;
+    match AudioTag::find()
+        .filter(audio_tag::Column::AudioId.is_in(tracks.iter().map(|t| t.audio_id)))
+        .filter(audio_tag::Column::Name.eq("Artist"))
+        .order_by_asc(audio_tag::Column::AudioId)
+        .order_by_asc(audio_tag::Column::Position)
+        .one(db)
+        .await
+    {
+        Ok(t) => t.map(|t| t.value),
+        Err(e) => {
+            event!(Level::WARN, "AudioTag::find() artist failure: {}", e);
+            None
+        }
+    }
+}
+
+/// Store the release an album matched, or an empty release ID if it wasn't matched so it's not
+/// looked up again.
+async fn store_release(
+    config: &Config,
+    album_id: i32,
+    release: Option<&Release>,
+    confidence: Option<i32>,
+) {
+    let album = album::ActiveModel {
+        album_id: ActiveValue::Unchanged(album_id),
+        musicbrainz_release_id: ActiveValue::Set(Some(
+            release.map(|r| r.id.to_string()).unwrap_or_default(),
+        )),
+        musicbrainz_release_group_id: ActiveValue::Set(
+            release.map(|r| r.release_group.id.to_string()),
+        ),
+        musicbrainz_title: ActiveValue::Set(release.map(|r| r.title.to_string())),
+        musicbrainz_score: ActiveValue::Set(confidence),
+        updated: ActiveValue::Set(chrono::Utc::now().naive_utc()),
+        ..Default::default()
+    };
+    let db = database::connection(config).await;
+    if let Err(e) = album.update(db).await {
+        event!(Level::WARN, "failed to update album release: {}", e);
+    }
+}
+
+/// Store the recording, track and canonical title an audio file matched, or an empty recording
+/// ID if it wasn't matched so it's not looked up again.
+async fn store_recording(config: &Config, audio_id: i32, recording: Option<(&str, &str, &str)>) {
+    let (recording_id, track_id, title) = match recording {
+        Some((recording_id, track_id, title)) => (
+            recording_id.to_string(),
+            Some(track_id.to_string()).filter(|t| !t.is_empty()),
+            Some(title.to_string()),
+        ),
+        None => ("".to_string(), None, None),
+    };
+    let audio = audio::ActiveModel {
+        audio_id: ActiveValue::Unchanged(audio_id),
+        musicbrainz_recording_id: ActiveValue::Set(Some(recording_id)),
+        musicbrainz_track_id: ActiveValue::Set(track_id),
+        musicbrainz_title: ActiveValue::Set(title),
+        ..Default::default()
+    };
+    let db = database::connection(config).await;
+    if let Err(e) = audio.update(db).await {
+        event!(Level::WARN, "failed to update audio recording: {}", e);
+    }
+}
+
+/// Queue the recordings tagged in the audio files of an album that wasn't matched to a release,
+/// and mark the rest as not found.
+async fn queue_recordings(config: &Config, tracks: &[AlbumTrack]) {
+    for track in tracks {
+        match &track.recording_id {
+            Some(recording_id) => {
+                musicbrainz::add_to_queue(
+                    config,
+                    musicbrainz::QueuePayload {
+                        payload_type: musicbrainz::PayloadType::AudioRecording,
+                        id: track.audio_id,
+                        value: recording_id.to_string(),
+                    },
+                )
+                .await
+            }
+            None => store_recording(config, track.audio_id, None).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::{Medium, Recording, ReleaseGroup};
+
+    fn assert_near(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{} isn't {}",
+            actual,
+            expected
+        );
+    }
+
+    /// An untagged audio file on the first disc of an album.
+    fn album_track(track_number: Option<i32>, title: &str, duration: i32) -> AlbumTrack {
+        AlbumTrack {
+            audio_id: track_number.unwrap_or_default(),
+            disc_number: 1,
+            track_number,
+            duration,
+            title: Some(title.to_string()),
+            release_id: None,
+            release_group_id: None,
+            track_id: None,
+            recording_id: None,
+        }
+    }
+
+    /// A release of one medium, with the title and length in seconds of each track.
+    fn release_of(tracks: &[(&str, i64)]) -> Release {
+        Release {
+            id: "release".to_string(),
+            title: "Low Water".to_string(),
+            release_group: ReleaseGroup::default(),
+            media: vec![Medium {
+                position: 1,
+                tracks: tracks
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (title, seconds))| Track {
+                        id: format!("track-{}", i + 1),
+                        position: i as i32 + 1,
+                        title: title.to_string(),
+                        length: Some(seconds * 1000),
+                        recording: Recording {
+                            id: format!("recording-{}", i + 1),
+                            title: title.to_string(),
+                            length: Some(seconds * 1000),
+                        },
+                    })
+                    .collect(),
+            }],
+        }
+    }
+
+    fn album(name: &str) -> album::Model {
+        let now = chrono::Utc::now().naive_utc();
+        album::Model {
+            album_id: 1,
+            created: now,
+            updated: now,
+            name: name.to_string(),
+            artist_name: None,
+            artist_id: None,
+            date: None,
+            path: "/library/Low Water".to_string(),
+            total_discs: None,
+            musicbrainz_release_id: None,
+            musicbrainz_release_group_id: None,
+            musicbrainz_title: None,
+            musicbrainz_score: None,
+        }
+    }
+
+    #[test]
+    fn track_score_weighs_position_title_and_duration() {
+        let release = release_of(&[("Undertow", 200)]);
+        let track = &release.media[0].tracks[0];
+        assert_near(
+            track_score(&album_track(Some(1), "Undertow", 200), 1, track),
+            1.0,
+        );
+        // Half the tolerance off loses half the weight of the duration.
+        assert_near(
+            track_score(&album_track(Some(1), "Undertow", 215), 1, track),
+            0.875,
+        );
+        // Durations off by more than the tolerance don't count at all.
+        assert_near(
+            track_score(&album_track(Some(1), "Undertow", 200 + 31), 1, track),
+            0.75,
+        );
+        assert_near(
+            track_score(&album_track(Some(1), "Undertow", 200), 2, track),
+            0.6,
+        );
+
+        // Tagged IDs are certain, whatever else differs.
+        let mut tagged = album_track(Some(5), "Something else", 10);
+        tagged.recording_id = Some("recording-1".to_string());
+        assert_near(track_score(&tagged, 2, track), 1.0);
+    }
+
+    #[test]
+    fn tracks_are_aligned_by_title_and_duration_without_positions() {
+        let release = release_of(&[("Undertow", 200), ("Salt", 180), ("Tideline", 240)]);
+        let tracks = [
+            album_track(None, "Tideline", 240),
+            album_track(None, "Undertow", 201),
+            album_track(None, "Salt", 180),
+        ];
+        let matched = align_tracks(&tracks, &release);
+        let ids: Vec<&str> = (0..tracks.len())
+            .map(|i| matched[&i].1.id.as_str())
+            .collect();
+        assert_eq!(ids, ["track-3", "track-1", "track-2"]);
+    }
+
+    #[test]
+    fn each_track_is_only_aligned_once() {
+        let release = release_of(&[("Undertow", 200), ("Salt", 180)]);
+        // More audio files than tracks, two of them copies of the same track.
+        let tracks = [
+            album_track(Some(1), "Undertow", 200),
+            album_track(Some(2), "Salt", 180),
+            album_track(None, "Undertow", 200),
+        ];
+        let matched = align_tracks(&tracks, &release);
+        assert_eq!(matched.len(), 2);
+        assert_eq!(matched[&0].1.id, "track-1");
+        assert_eq!(matched[&1].1.id, "track-2");
+        assert!(!matched.contains_key(&2));
+
+        // The unmatched audio file lowers the score.
+        assert_near(alignment_score(&tracks, &release), 2.0 / 3.0);
+        assert_near(alignment_score(&tracks[..2], &release), 1.0);
+    }
+
+    #[test]
+    fn alignment_score_counts_tracks_without_audio_files() {
+        let release = release_of(&[
+            ("Undertow", 200),
+            ("Salt", 180),
+            ("Tideline", 240),
+            ("Ebb", 90),
+        ]);
+        let tracks = [
+            album_track(Some(1), "Undertow", 200),
+            album_track(Some(2), "Salt", 180),
+        ];
+        assert_near(alignment_score(&tracks, &release), 0.5);
+        assert_near(alignment_score(&[], &release), 0.0);
+        assert_near(alignment_score(&tracks, &release_of(&[])), 0.0);
+    }
+
+    #[test]
+    fn tracks_too_far_off_are_not_aligned() {
+        let release = release_of(&[("Undertow", 200)]);
+        // Neither the position, the title nor the duration match.
+        let tracks = [album_track(Some(2), "Storm", 200 + 31)];
+        assert!(align_tracks(&tracks, &release).is_empty());
+        assert_near(alignment_score(&tracks, &release), 0.0);
+    }
+
+    #[test]
+    fn summary_score_weighs_search_title_and_track_count() {
+        let result = |score: i32, title: &str, track_count: i32| ReleaseResult {
+            id: "release".to_string(),
+            score,
+            title: title.to_string(),
+            track_count,
+        };
+        let album = album("Low Water");
+        assert_near(
+            summary_score(&album, 10, &result(100, "Low Water", 10)),
+            1.0,
+        );
+        assert_near(summary_score(&album, 10, &result(50, "Low Water", 10)), 0.8);
+        // Half the tracks are missing.
+        assert_near(
+            summary_score(&album, 5, &result(100, "Low Water", 10)),
+            0.85,
+        );
+        assert_near(
+            summary_score(&album, 20, &result(100, "Low Water", 10)),
+            0.85,
+        );
+        // An unknown number of tracks doesn't count.
+        assert_near(summary_score(&album, 10, &result(100, "Low Water", 0)), 0.7);
+        assert!(summary_score(&album, 10, &result(100, "High Tide", 10)) < 0.85);
+    }
+}
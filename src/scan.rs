@@ -0,0 +1,1329 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use file_format::FileFormat;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use sea_orm::sea_query::OnConflict;
+use sea_orm::*;
+use symphonia::core::meta::Visual;
+use tokio::sync::{mpsc, Semaphore};
+use tracing::{event, instrument, Level};
+use walkdir::WalkDir;
+
+use crate::acoustid;
+use crate::artwork;
+use crate::database;
+use crate::entities::{prelude::*, *};
+use crate::features;
+use crate::fingerprint;
+use crate::loudness;
+use crate::media::{self, MediaType, Scope, Tags};
+use crate::releases;
+use crate::utils;
+use crate::Config;
+
+// Audio files are written to the database in transactions of this many files.
+const AUDIO_BATCH_SIZE: usize = 50;
+
+// Tags are inserted this many at a time, staying well within the bound parameter limits
+// of all supported databases.
+const TAG_BATCH_SIZE: usize = 250;
+
+// Files found while walking the library, and probed files waiting to be written to the
+// database, are queued up to this many at a time.
+const SCAN_QUEUE_SIZE: usize = 256;
+
+// Scan progress is saved at least this often, in files, so an interrupted scan can resume.
+const CHECKPOINT_INTERVAL: usize = 500;
+
+/// The outcome of examining a single file, ready to be written to the database.
+enum ScannedFile {
+    /// A file that couldn't be examined, because its path isn't valid UTF-8 or probing it
+    /// failed.
+    Skipped,
+    /// Not an audio file.
+    Other,
+    /// An audio file that couldn't be probed.
+    Unreadable,
+    /// An image, such as an album cover or artist photo.
+    Image { image: image::ActiveModel },
+    /// An audio file that hasn't changed since it was last scanned, along with its hash if
+    /// it was hashed for the first time.
+    Unchanged { audio_id: i32, hash: Option<String> },
+    /// An audio file that has changed since it was last scanned, and was probed again.
+    Changed {
+        audio_id: i32,
+        audio: audio::ActiveModel,
+        tags: Tags,
+        images: Vec<image::ActiveModel>,
+    },
+    /// An audio file that isn't in the database. It may be new, or it may have been moved.
+    New {
+        audio: audio::ActiveModel,
+        tags: Tags,
+        images: Vec<image::ActiveModel>,
+        size: i64,
+        modified: Option<chrono::NaiveDateTime>,
+        hash: Option<String>,
+    },
+}
+
+impl ScannedFile {
+    fn is_audio(&self) -> bool {
+        !matches!(
+            self,
+            ScannedFile::Skipped | ScannedFile::Other | ScannedFile::Image { .. }
+        )
+    }
+}
+
+/// A file found while walking the library, numbered in the order it was found.
+struct WalkedFile {
+    sequence: usize,
+    path: PathBuf,
+    metadata: Metadata,
+}
+
+/// Decides which files and directories in the library are scanned, from the `scan_include`
+/// and `scan_exclude` globs. Globs are matched against paths relative to the library.
+pub(crate) struct ScanFilter {
+    library: PathBuf,
+    // Filesystem watchers report absolute paths.
+    canonical_library: Option<PathBuf>,
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+}
+
+impl ScanFilter {
+    pub(crate) fn new(config: &Config) -> Result<Self, globset::Error> {
+        let library = PathBuf::from(config.library.as_ref().expect("library must exist"));
+        Ok(ScanFilter {
+            canonical_library: library.canonicalize().ok(),
+            library,
+            include: build_glob_set(&config.scan_include)?,
+            exclude: build_glob_set(&config.scan_exclude)?,
+        })
+    }
+
+    /// Strip the library from a path.
+    pub(crate) fn relative<'a>(&self, path: &'a Path) -> &'a Path {
+        if let Ok(relative) = path.strip_prefix(&self.library) {
+            return relative;
+        }
+        if let Some(Ok(relative)) = self
+            .canonical_library
+            .as_ref()
+            .map(|l| path.strip_prefix(l))
+        {
+            return relative;
+        }
+        path
+    }
+
+    /// Convert a path in the library to the form scanned paths are stored in, the library as
+    /// configured joined with the path relative to it. Filesystem watchers report canonical
+    /// paths, and paths given on the command line may be relative to the current directory.
+    pub(crate) fn library_path(&self, path: &Path) -> PathBuf {
+        if let Ok(relative) = path.strip_prefix(&self.library) {
+            return self.library.join(relative);
+        }
+        let Some(canonical_library) = self.canonical_library.as_ref() else {
+            return path.to_path_buf();
+        };
+        // Deleted files can't be canonicalized, but the directory they were in may still exist.
+        let canonical = path.canonicalize().ok().or_else(|| {
+            let parent = path.parent()?.canonicalize().ok()?;
+            Some(parent.join(path.file_name()?))
+        });
+        for path in [Some(path), canonical.as_deref()].into_iter().flatten() {
+            if let Ok(relative) = path.strip_prefix(canonical_library) {
+                return self.library.join(relative);
+            }
+        }
+        path.to_path_buf()
+    }
+
+    /// Determine if a file or directory should be scanned. Directories are only excluded,
+    /// never filtered by the include globs, so the files within them can still be included.
+    pub(crate) fn allows(&self, path: &Path, is_dir: bool) -> bool {
+        let relative = self.relative(path);
+        if relative.as_os_str().is_empty() {
+            return true;
+        }
+        // Files in excluded directories are also excluded.
+        if let Some(exclude) = &self.exclude {
+            if relative
+                .ancestors()
+                .any(|a| !a.as_os_str().is_empty() && exclude.is_match(a))
+            {
+                return false;
+            }
+        }
+        match &self.include {
+            Some(include) if !is_dir => include.is_match(relative),
+            _ => true,
+        }
+    }
+}
+
+/// Build a set of globs where `*` matches within a single directory, and `**` matches
+/// across directories.
+fn build_glob_set(globs: &[String]) -> Result<Option<GlobSet>, globset::Error> {
+    if globs.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(GlobBuilder::new(glob).literal_separator(true).build()?);
+    }
+    Ok(Some(builder.build()?))
+}
+
+/// Tracks how far a scan has progressed. Files are probed in parallel and finish out of
+/// order, so the checkpoint is the last file for which every file walked before it has
+/// been scanned.
+struct ScanProgress {
+    library: String,
+    next: usize,
+    finished: BTreeMap<usize, PathBuf>,
+    path: Option<PathBuf>,
+    files: i64,
+    unsaved: usize,
+}
+
+impl ScanProgress {
+    fn new(library: String, files: i64) -> Self {
+        ScanProgress {
+            library,
+            next: 0,
+            finished: BTreeMap::new(),
+            path: None,
+            files,
+            unsaved: 0,
+        }
+    }
+
+    /// Record that a file has been scanned.
+    fn finish(&mut self, sequence: usize, path: PathBuf) {
+        self.finished.insert(sequence, path);
+        while let Some(path) = self.finished.remove(&self.next) {
+            self.path = Some(path);
+            self.next += 1;
+            self.files += 1;
+            self.unsaved += 1;
+        }
+    }
+
+    /// Build a checkpoint if the scan has progressed since it was last saved.
+    fn checkpoint(&self) -> Option<scan_checkpoint::ActiveModel> {
+        if self.unsaved == 0 {
+            return None;
+        }
+        let path = self.path.as_ref()?;
+        let relative = path.strip_prefix(&self.library).unwrap_or(path);
+        let now = chrono::Utc::now().naive_utc();
+        Some(scan_checkpoint::ActiveModel {
+            created: ActiveValue::Set(now),
+            updated: ActiveValue::Set(now),
+            library: ActiveValue::Set(self.library.clone()),
+            path: ActiveValue::Set(relative.display().to_string()),
+            files: ActiveValue::Set(self.files),
+            ..Default::default()
+        })
+    }
+}
+
+/// Scan the music library. Files are found by walking the library in one thread, probed by a
+/// bounded pool of blocking workers, and written to the database in batched transactions.
+#[instrument]
+pub(crate) async fn scan_media_files(config: &Config) {
+    event!(Level::TRACE, "scan_media_files");
+
+    let library = config.library.as_ref().expect("library must exist").clone();
+    let filter = match ScanFilter::new(config) {
+        Ok(f) => Arc::new(f),
+        Err(e) => {
+            event!(
+                Level::ERROR,
+                "invalid scan_include or scan_exclude glob: {}",
+                e
+            );
+            return;
+        }
+    };
+
+    // Resume an interrupted scan where it stopped.
+    let checkpoint = {
+        let db = database::connection(config).await;
+        match ScanCheckpoint::find()
+            .filter(scan_checkpoint::Column::Library.eq(library.as_str()))
+            .one(db)
+            .await
+        {
+            Ok(c) => c,
+            Err(e) => {
+                event!(Level::WARN, "ScanCheckpoint::find() failure: {}", e);
+                None
+            }
+        }
+    };
+    let (resume_after, files_scanned) = match checkpoint {
+        Some(c) => {
+            event!(
+                Level::WARN,
+                "resuming scan of {} after {} files, at {}",
+                library,
+                c.files,
+                c.path
+            );
+            (Some(PathBuf::from(c.path)), c.files)
+        }
+        None => (None, 0),
+    };
+
+    // Load all known audio files up front, so unchanged files are skipped without a
+    // database query for each one.
+    let known = {
+        let db = database::connection(config).await;
+        match Audio::find().all(db).await {
+            Ok(a) => a,
+            Err(e) => {
+                event!(Level::WARN, "Audio::find() known failure: {}", e);
+                return;
+            }
+        }
+    };
+    let mut writer = ScanWriter::new(config, Some(&known));
+    writer.progress = Some(ScanProgress::new(library.clone(), files_scanned));
+    let mut known: HashMap<String, audio::Model> =
+        known.into_iter().map(|a| (a.uri.clone(), a)).collect();
+
+    let (files_tx, mut files_rx) = mpsc::channel(SCAN_QUEUE_SIZE);
+    let limits = (
+        config.scan_max_files,
+        config.scan_max_duration.map(Duration::from_secs),
+    );
+    let walk_filter = filter.clone();
+    let walker = tokio::task::spawn_blocking(move || {
+        walk_library(&walk_filter, resume_after.as_deref(), limits, files_tx)
+    });
+
+    // Hand each file to a probe worker as soon as one is free.
+    let workers = scan_workers(config);
+    event!(Level::DEBUG, "scanning with {} probe workers", workers);
+    let hash = config.hash;
+    let cache = artwork::artwork_cache(config);
+    let (results_tx, mut results_rx) = mpsc::channel(SCAN_QUEUE_SIZE);
+    // Every file walked gets a result, even if it couldn't be examined, so scan progress can
+    // move past it.
+    let dispatcher = tokio::spawn(async move {
+        let semaphore = Arc::new(Semaphore::new(workers));
+        while let Some(WalkedFile {
+            sequence,
+            path,
+            metadata,
+        }) = files_rx.recv().await
+        {
+            let uri = match utils::file_uri(&path) {
+                Some(u) => u,
+                None => {
+                    let _ = results_tx
+                        .send((sequence, path, ScannedFile::Skipped))
+                        .await;
+                    continue;
+                }
+            };
+            let existing = known.remove(&uri);
+            let permit = match semaphore.clone().acquire_owned().await {
+                Ok(p) => p,
+                Err(e) => {
+                    event!(Level::WARN, "semaphore.acquire_owned() failure: {}", e);
+                    break;
+                }
+            };
+            let results_tx = results_tx.clone();
+            let cache = cache.clone();
+            tokio::spawn(async move {
+                let probed = path.clone();
+                let scanned = match tokio::task::spawn_blocking(move || {
+                    let scanned =
+                        examine_file(&probed, &metadata, uri, existing, hash, cache.as_deref());
+                    drop(permit);
+                    scanned
+                })
+                .await
+                {
+                    Ok(s) => s,
+                    Err(e) => {
+                        event!(
+                            Level::WARN,
+                            "probe worker failure for {}: {}",
+                            path.display(),
+                            e
+                        );
+                        ScannedFile::Skipped
+                    }
+                };
+                let _ = results_tx.send((sequence, path, scanned)).await;
+            });
+        }
+    });
+
+    // Track how many audio files are found, as a sanity check before removing missing files.
+    let mut audio_files_found: usize = 0;
+
+    // Results stop arriving once the walker is done and every worker has finished.
+    while let Some((sequence, path, scanned)) = results_rx.recv().await {
+        if scanned.is_audio() {
+            audio_files_found += 1;
+        }
+        if let Some(progress) = writer.progress.as_mut() {
+            progress.finish(sequence, path);
+        }
+        writer.add(scanned).await;
+    }
+    writer.flush().await;
+    let complete = walker.await.unwrap_or(false);
+    let _ = dispatcher.await;
+
+    // The next scan starts from the beginning once the entire library has been scanned.
+    if complete {
+        let db = database::connection(config).await;
+        if let Err(e) = ScanCheckpoint::delete_many()
+            .filter(scan_checkpoint::Column::Library.eq(library.as_str()))
+            .exec(db)
+            .await
+        {
+            event!(Level::WARN, "ScanCheckpoint::delete_many() failure: {}", e);
+        }
+    }
+
+    // Remove audio files that have been deleted since the last scan. If no audio files were
+    // found at all the library is most likely missing or unmounted, so nothing is removed.
+    if audio_files_found > 0 {
+        media::remove_missing_audio(config, &filter).await;
+        artwork::remove_missing_images(config, &filter, &Scope::Library).await;
+    } else {
+        event!(
+            Level::WARN,
+            "no audio files found in library, not removing missing audio files"
+        );
+    }
+
+    // Next, group audio files into directories and albums.
+    media::group_directories(config, &Scope::Library).await;
+    media::group_albums(config, &Scope::Library).await;
+
+    // Finally, remove directories and albums that no longer contain any audio files.
+    media::remove_empty_directories(config, &Scope::Library).await;
+    media::remove_empty_albums(config).await;
+
+    // Associate images with the albums and artists that remain.
+    artwork::link_images(config, &Scope::Library).await;
+
+    // Fingerprint audio files that are new or have changed, identify untagged ones, measure
+    // their loudness and extract their features.
+    fingerprint::fingerprint_audio(config, &Scope::Library).await;
+    acoustid::queue_lookups(config, &Scope::Library).await;
+    loudness::analyse_audio(config, &Scope::Library).await;
+    features::extract_features(config, &Scope::Library).await;
+
+    // Look up albums that are new, or have new audio files, in MusicBrainz.
+    releases::queue_releases(config, &Scope::Library).await;
+}
+
+/// The number of blocking workers probing files at the same time.
+pub(crate) fn scan_workers(config: &Config) -> usize {
+    config
+        .scan_workers
+        .unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1)
+        })
+        .max(1)
+}
+
+/// Scan a single file, adding it to the database or updating it as needed.
+pub(crate) async fn scan_file(config: &Config, path: &Path, metadata: &Metadata) {
+    event!(Level::TRACE, "scan_file {}", path.display());
+
+    let uri = match utils::file_uri(path) {
+        Some(u) => u,
+        None => return,
+    };
+    let existing = {
+        let db = database::connection(config).await;
+        match Audio::find()
+            .filter(audio::Column::Uri.eq(uri.as_str()))
+            .one(db)
+            .await
+        {
+            Ok(e) => e,
+            Err(e) => {
+                event!(Level::WARN, "Audio::find() failure: {}", e);
+                return;
+            }
+        }
+    };
+
+    let (path, metadata, hash) = (path.to_path_buf(), metadata.clone(), config.hash);
+    let cache = artwork::artwork_cache(config);
+    let scanned = match tokio::task::spawn_blocking(move || {
+        examine_file(&path, &metadata, uri, existing, hash, cache.as_deref())
+    })
+    .await
+    {
+        Ok(s) => s,
+        Err(e) => {
+            event!(Level::WARN, "probe worker failure: {}", e);
+            return;
+        }
+    };
+
+    let mut writer = ScanWriter::new(config, None);
+    writer.add(scanned).await;
+    writer.flush().await;
+}
+
+/// Walk the music library, queueing up every file found to be probed. Files are walked in
+/// a consistent order, skipping any scanned before `resume_after`. Returns true if the entire
+/// library was walked, or false if the walk stopped early because of `limits`, the maximum
+/// number of files and maximum duration.
+fn walk_library(
+    filter: &ScanFilter,
+    resume_after: Option<&Path>,
+    limits: (Option<usize>, Option<Duration>),
+    files_tx: mpsc::Sender<WalkedFile>,
+) -> bool {
+    event!(Level::TRACE, "walk_library");
+
+    let (max_files, max_duration) = limits;
+    let started = Instant::now();
+    let mut sequence = 0;
+
+    let walker = WalkDir::new(&filter.library)
+        .follow_links(true)
+        .sort_by_file_name()
+        .into_iter();
+    for entry in walker.filter_entry(|e| {
+        let is_dir = e.file_type().is_dir();
+        !utils::is_hidden(e)
+            && filter.allows(e.path(), is_dir)
+            && !already_scanned(filter.relative(e.path()), is_dir, resume_after)
+    }) {
+        let entry = match entry {
+            Ok(e) => e,
+            Err(e) => {
+                event!(Level::WARN, "WalkDir entry failure: {}", e);
+                continue;
+            }
+        };
+        let metadata = match entry.metadata() {
+            Ok(m) => m,
+            Err(e) => {
+                event!(Level::WARN, "metadata() failure: {}", e);
+                continue;
+            }
+        };
+
+        // Files may be tracks, images, playlists, and more.
+        if metadata.is_file() {
+            if max_files.map(|m| sequence >= m).unwrap_or(false) {
+                event!(
+                    Level::WARN,
+                    "stopped scanning after {} files, scan again to continue",
+                    sequence
+                );
+                return false;
+            }
+            if max_duration
+                .map(|m| started.elapsed() >= m)
+                .unwrap_or(false)
+            {
+                event!(
+                    Level::WARN,
+                    "stopped scanning after {} seconds, scan again to continue",
+                    started.elapsed().as_secs()
+                );
+                return false;
+            }
+            let file = WalkedFile {
+                sequence,
+                path: entry.into_path(),
+                metadata,
+            };
+            if files_tx.blocking_send(file).is_err() {
+                // Nothing is receiving files anymore.
+                return false;
+            }
+            sequence += 1;
+        // Albums are collected together in directories.
+        } else if metadata.is_dir() {
+            // @TODO: Track directories for visualization, organization, and to assist in
+            // auto-identifying albums.
+        }
+    }
+    true
+}
+
+/// Determine if a file or directory, relative to the library, was already scanned before
+/// the checkpoint being resumed from. Paths compare in the same order they're walked.
+fn already_scanned(relative: &Path, is_dir: bool, resume_after: Option<&Path>) -> bool {
+    match resume_after {
+        // Skip directories that were completely scanned, and enter the one that wasn't.
+        Some(checkpoint) if is_dir => relative < checkpoint && !checkpoint.starts_with(relative),
+        Some(checkpoint) => relative <= checkpoint,
+        None => false,
+    }
+}
+
+/// Examine a single file, detecting its media type and probing it if it's a new or changed
+/// audio file. Pictures embedded in audio files are extracted to the artwork `cache`. This
+/// does blocking IO, and is run by the probe workers.
+fn examine_file(
+    path: &Path,
+    metadata: &Metadata,
+    uri: String,
+    existing: Option<audio::Model>,
+    hash: bool,
+    cache: Option<&Path>,
+) -> ScannedFile {
+    event!(Level::TRACE, "examine_file {}", path.display());
+
+    let format = match FileFormat::from_file(path) {
+        Ok(f) => f,
+        Err(e) => {
+            event!(Level::WARN, "FileFormat::from_file() failure: {}", e);
+            return ScannedFile::Other;
+        }
+    };
+
+    match MediaType::from_str(format.media_type()).unwrap_or(MediaType::Unknown) {
+        MediaType::Image => {
+            // The artwork cache may be inside the library, skip pictures extracted to it.
+            let parent = path.parent().and_then(|p| p.canonicalize().ok());
+            if cache.is_some() && parent.as_deref() == cache {
+                return ScannedFile::Other;
+            }
+            // Images are associated with albums and artists after scanning.
+            event!(
+                Level::DEBUG,
+                "Image detected ({}): {}",
+                format.media_type(),
+                path.display()
+            );
+            let image = artwork::build_image(path, metadata, uri, format.media_type());
+            return ScannedFile::Image { image };
+        }
+        MediaType::_Text => {
+            // @TODO: How to properly detect text?
+            return ScannedFile::Other;
+        }
+        MediaType::Audio => (),
+        MediaType::Unknown => {
+            // @TODO: Deal with audio files that we didn't properly detect.
+            // @TODO: Perhaps detect text files in a second pass here, on the file extension?
+            event!(
+                Level::WARN,
+                "UNKNOWN ({}): {}",
+                format.media_type(),
+                path.display()
+            );
+            return ScannedFile::Other;
+        }
+    }
+
+    event!(
+        Level::DEBUG,
+        "Audio file detected at path: {} ({})",
+        path.display(),
+        uri
+    );
+
+    let extension = match path.extension() {
+        Some(e) => e.to_str().unwrap_or(""),
+        None => {
+            event!(Level::WARN, "path.extension() returned nothing");
+            ""
+        }
+    };
+    let parent = match path.parent() {
+        Some(p) => p.display().to_string(),
+        None => {
+            event!(Level::WARN, "path.parent() returned none");
+            "".to_string()
+        }
+    };
+    let name = match path.file_name() {
+        Some(f) => f.to_str().unwrap_or("").to_string(),
+        None => {
+            event!(Level::WARN, "path.file_name() returned nothing");
+            "".to_string()
+        }
+    };
+
+    // Size, modification time, and optionally a hash of the contents are
+    // compared to what's in the database to detect changed and moved files.
+    let size = metadata.len() as i64;
+    let modified = utils::modified(metadata);
+    let hash = if hash { utils::hash_file(path) } else { None };
+
+    if let Some(existing) = existing {
+        let audio_id = existing.audio_id;
+        if !media::audio_changed(&existing, size, modified, &hash) {
+            event!(Level::TRACE, "Audio file unchanged: {}", uri);
+            // Store the hash the first time the file is scanned with hashing enabled.
+            let hash = if existing.hash.is_none() { hash } else { None };
+            return ScannedFile::Unchanged { audio_id, hash };
+        }
+
+        // The file has changed: probe it again and rebuild its tags.
+        event!(Level::INFO, "Audio file changed: {}", uri);
+        let mut audio: audio::ActiveModel = existing.into();
+        audio.extension = ActiveValue::Set(extension.to_string());
+        audio.size = ActiveValue::Set(size);
+        audio.modified = ActiveValue::Set(modified);
+        audio.hash = ActiveValue::Set(hash);
+        match media::probe_audio(path, extension, &mut audio) {
+            Some((tags, visuals)) => ScannedFile::Changed {
+                audio_id,
+                audio,
+                tags,
+                images: extract_visuals(cache, &visuals),
+            },
+            None => ScannedFile::Unreadable,
+        }
+    } else {
+        let mut audio = audio::ActiveModel {
+            uri: ActiveValue::Set(uri),
+            path: ActiveValue::Set(parent),
+            name: ActiveValue::Set(name),
+            extension: ActiveValue::Set(extension.to_string()),
+            size: ActiveValue::Set(size),
+            modified: ActiveValue::Set(modified),
+            hash: ActiveValue::Set(hash.clone()),
+            ..Default::default()
+        };
+        match media::probe_audio(path, extension, &mut audio) {
+            Some((tags, visuals)) => ScannedFile::New {
+                audio,
+                tags,
+                images: extract_visuals(cache, &visuals),
+                size,
+                modified,
+                hash,
+            },
+            None => ScannedFile::Unreadable,
+        }
+    }
+}
+
+/// Extract the pictures embedded in an audio file, if there's an artwork cache to put them in.
+fn extract_visuals(cache: Option<&Path>, visuals: &[Visual]) -> Vec<image::ActiveModel> {
+    match cache {
+        Some(cache) => visuals
+            .iter()
+            .filter_map(|v| artwork::extract_visual(cache, v))
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Scanned audio files and images waiting to be written to the database.
+#[derive(Clone, Default)]
+struct ScanBatch {
+    updated: Vec<audio::ActiveModel>,
+    moved: Vec<i32>,
+    changed: Vec<(i32, audio::ActiveModel, Tags)>,
+    inserted: Vec<(audio::ActiveModel, Tags)>,
+    images: Vec<image::ActiveModel>,
+    // Pictures embedded in new and changed audio files, by the URI of the audio file.
+    embedded: Vec<(String, image::ActiveModel)>,
+}
+
+impl ScanBatch {
+    fn len(&self) -> usize {
+        self.updated.len() + self.changed.len() + self.inserted.len() + self.images.len()
+    }
+}
+
+/// Collects scanned audio files, writing them to the database in batched transactions.
+struct ScanWriter<'a> {
+    config: &'a Config,
+    // Known audio files indexed by size, any of which may have been moved to where a new
+    // file was found. If not set, the database is queried instead.
+    moved_candidates: Option<HashMap<i64, Vec<audio::Model>>>,
+    // Progress of a full library scan, saved along with each batch.
+    progress: Option<ScanProgress>,
+    batch: ScanBatch,
+    // The size of the batch and of the unsaved progress when writing them last failed. The
+    // batch is kept and written again once either has grown as much again.
+    failed: (usize, usize),
+}
+
+impl<'a> ScanWriter<'a> {
+    fn new(config: &'a Config, known: Option<&[audio::Model]>) -> Self {
+        let moved_candidates = known.map(|known| {
+            let mut candidates: HashMap<i64, Vec<audio::Model>> = HashMap::new();
+            for audio in known.iter().filter(|a| a.size > 0) {
+                candidates
+                    .entry(audio.size)
+                    .or_default()
+                    .push(audio.clone());
+            }
+            candidates
+        });
+        ScanWriter {
+            config,
+            moved_candidates,
+            progress: None,
+            batch: ScanBatch::default(),
+            failed: (0, 0),
+        }
+    }
+
+    /// Add a scanned file, writing the batch to the database once it's full.
+    async fn add(&mut self, scanned: ScannedFile) {
+        match scanned {
+            ScannedFile::Skipped | ScannedFile::Other | ScannedFile::Unreadable => (),
+            ScannedFile::Image { image } => self.batch.images.push(image),
+            ScannedFile::Unchanged { hash: None, .. } => (),
+            ScannedFile::Unchanged {
+                audio_id,
+                hash: Some(hash),
+            } => {
+                self.batch.updated.push(audio::ActiveModel {
+                    audio_id: ActiveValue::Unchanged(audio_id),
+                    hash: ActiveValue::Set(Some(hash)),
+                    ..Default::default()
+                });
+            }
+            ScannedFile::Changed {
+                audio_id,
+                audio,
+                tags,
+                images,
+            } => {
+                let uri = audio.uri.as_ref();
+                for image in images {
+                    self.batch.embedded.push((uri.clone(), image));
+                }
+                self.batch.changed.push((audio_id, audio, tags));
+            }
+            ScannedFile::New {
+                audio,
+                tags,
+                images,
+                size,
+                modified,
+                hash,
+            } => {
+                if let Some(moved) = self.find_moved(size, modified, &hash).await {
+                    // The file was renamed or moved, it's otherwise the same track.
+                    event!(
+                        Level::INFO,
+                        "Audio file moved: {} -> {}",
+                        moved.uri,
+                        audio.uri.as_ref()
+                    );
+                    self.batch.moved.push(moved.audio_id);
+                    let mut update: audio::ActiveModel = moved.into();
+                    update.uri = audio.uri;
+                    update.path = audio.path;
+                    update.name = audio.name;
+                    update.extension = audio.extension;
+                    if hash.is_some() {
+                        update.hash = ActiveValue::Set(hash);
+                    }
+                    self.batch.updated.push(update);
+                } else {
+                    let uri = audio.uri.as_ref();
+                    for image in images {
+                        self.batch.embedded.push((uri.clone(), image));
+                    }
+                    self.batch.inserted.push((audio, tags));
+                }
+            }
+        }
+        let unsaved = self.progress.as_ref().map(|p| p.unsaved).unwrap_or(0);
+        if self.batch.len() >= self.failed.0 + AUDIO_BATCH_SIZE
+            || unsaved >= self.failed.1 + CHECKPOINT_INTERVAL
+        {
+            self.flush().await;
+        }
+    }
+
+    /// Look for a known audio file that no longer exists where expected, but matches a newly
+    /// discovered file.
+    async fn find_moved(
+        &mut self,
+        size: i64,
+        modified: Option<chrono::NaiveDateTime>,
+        hash: &Option<String>,
+    ) -> Option<audio::Model> {
+        let candidates = match self.moved_candidates.as_mut() {
+            Some(c) => c.get_mut(&size)?,
+            None => return media::find_moved_audio(self.config, size, modified, hash).await,
+        };
+        // Matches the database query in `media::find_moved_audio()`.
+        let index = candidates.iter().position(|candidate| {
+            let matches = match (hash, &candidate.hash) {
+                (Some(a), Some(b)) => a == b,
+                _ => modified.is_some() && candidate.modified == modified,
+            };
+            matches && !Path::new(&candidate.path).join(&candidate.name).exists()
+        })?;
+        // Each known file can only be moved once.
+        Some(candidates.swap_remove(index))
+    }
+
+    /// Write all collected audio files and their tags to the database in one transaction,
+    /// then associate them with their artists. If writing fails the batch is kept, and the
+    /// checkpoint isn't saved until it's written.
+    async fn flush(&mut self) {
+        let checkpoint = self.progress.as_ref().and_then(|p| p.checkpoint());
+        if self.batch.len() == 0 && checkpoint.is_none() {
+            return;
+        }
+        event!(Level::DEBUG, "writing {} scanned files", self.batch.len());
+
+        let stored = {
+            let db = database::connection(self.config).await;
+            match write_batch(db, self.batch.clone(), checkpoint).await {
+                Ok(s) => s,
+                Err(e) => {
+                    event!(Level::WARN, "failed to write scanned audio files: {}", e);
+                    let unsaved = self.progress.as_ref().map(|p| p.unsaved).unwrap_or(0);
+                    self.failed = (self.batch.len(), unsaved);
+                    return;
+                }
+            }
+        };
+
+        self.batch = ScanBatch::default();
+        self.failed = (0, 0);
+        if let Some(progress) = self.progress.as_mut() {
+            progress.unsaved = 0;
+        }
+
+        for (audio_id, tags) in stored {
+            media::link_audio_artists(self.config, audio_id, &tags).await;
+        }
+    }
+}
+
+/// Write a batch of scanned audio files and images in a single transaction, along with the
+/// scan checkpoint if any. Returns the tags stored for each new or changed audio file.
+async fn write_batch(
+    db: &DatabaseConnection,
+    batch: ScanBatch,
+    checkpoint: Option<scan_checkpoint::ActiveModel>,
+) -> Result<Vec<(i32, Tags)>, DbErr> {
+    let ScanBatch {
+        updated,
+        moved,
+        changed,
+        inserted,
+        mut images,
+        embedded,
+    } = batch;
+    let txn = db.begin().await?;
+
+    for audio in updated {
+        audio.update(&txn).await?;
+    }
+
+    // The directory and album of moved files may have changed, they're regrouped after
+    // scanning.
+    if !moved.is_empty() {
+        AudioDirectory::delete_many()
+            .filter(audio_directory::Column::AudioId.is_in(moved.clone()))
+            .exec(&txn)
+            .await?;
+        AlbumAudio::delete_many()
+            .filter(album_audio::Column::AudioId.is_in(moved))
+            .exec(&txn)
+            .await?;
+    }
+
+    let mut stored = Vec::new();
+
+    // Tags, artists, albums, fingerprints, verifications and features of changed files are
+    // rebuilt.
+    if !changed.is_empty() {
+        let audio_ids: Vec<i32> = changed.iter().map(|(audio_id, _, _)| *audio_id).collect();
+        AudioTag::delete_many()
+            .filter(audio_tag::Column::AudioId.is_in(audio_ids.clone()))
+            .exec(&txn)
+            .await?;
+        AudioArtist::delete_many()
+            .filter(audio_artist::Column::AudioId.is_in(audio_ids.clone()))
+            .exec(&txn)
+            .await?;
+        AudioImage::delete_many()
+            .filter(audio_image::Column::AudioId.is_in(audio_ids.clone()))
+            .exec(&txn)
+            .await?;
+        AlbumAudio::delete_many()
+            .filter(album_audio::Column::AudioId.is_in(audio_ids.clone()))
+            .exec(&txn)
+            .await?;
+        AudioFingerprint::delete_many()
+            .filter(audio_fingerprint::Column::AudioId.is_in(audio_ids.clone()))
+            .exec(&txn)
+            .await?;
+        AudioAcoustid::delete_many()
+            .filter(audio_acoustid::Column::AudioId.is_in(audio_ids.clone()))
+            .exec(&txn)
+            .await?;
+        AudioVerification::delete_many()
+            .filter(audio_verification::Column::AudioId.is_in(audio_ids.clone()))
+            .exec(&txn)
+            .await?;
+        AudioFeature::delete_many()
+            .filter(audio_feature::Column::AudioId.is_in(audio_ids))
+            .exec(&txn)
+            .await?;
+        for (audio_id, mut audio, tags) in changed {
+            // The loudness of changed files, and of the albums they're on, is measured again.
+            audio.loudness = ActiveValue::Set(None);
+            audio.update(&txn).await?;
+            stored.push((audio_id, tags));
+        }
+    }
+
+    if !inserted.is_empty() {
+        let (audio, tags): (Vec<_>, Vec<_>) = inserted.into_iter().unzip();
+        let uris: Vec<String> = audio.iter().map(|a| a.uri.as_ref().clone()).collect();
+        Audio::insert_many(audio).exec(&txn).await?;
+
+        // Bulk inserts only return the last id, look up the rest by their unique URI.
+        let audio_ids: HashMap<String, i32> = Audio::find()
+            .filter(audio::Column::Uri.is_in(uris.clone()))
+            .all(&txn)
+            .await?
+            .into_iter()
+            .map(|a| (a.uri, a.audio_id))
+            .collect();
+        for (uri, tags) in uris.into_iter().zip(tags) {
+            if let Some(audio_id) = audio_ids.get(&uri) {
+                stored.push((*audio_id, tags));
+            }
+        }
+    }
+
+    let audio_tags: Vec<audio_tag::ActiveModel> = stored
+        .iter()
+        .flat_map(|(audio_id, tags)| {
+            tags.iter().map(|tag| audio_tag::ActiveModel {
+                audio_id: ActiveValue::Set(*audio_id),
+                name: ActiveValue::Set(tag.name.clone()),
+                key: ActiveValue::Set(tag.key.clone()),
+                value: ActiveValue::Set(tag.value.clone()),
+                position: ActiveValue::Set(tag.position),
+                ..Default::default()
+            })
+        })
+        .collect();
+    for batch in audio_tags.chunks(TAG_BATCH_SIZE) {
+        AudioTag::insert_many(batch.to_vec()).exec(&txn).await?;
+    }
+
+    // Images are stored again each time they're scanned, in case they've changed. The same
+    // picture is often embedded in many audio files, but can only be stored once per statement.
+    let embedded_uris: Vec<String> = embedded.iter().map(|(uri, _)| uri.clone()).collect();
+    let mut embedded_images = HashSet::new();
+    let mut image_uris = HashSet::new();
+    for (audio_uri, image) in embedded {
+        embedded_images.insert((audio_uri, image.uri.as_ref().clone()));
+        images.push(image);
+    }
+    images.retain(|i| image_uris.insert(i.uri.as_ref().clone()));
+    if !images.is_empty() {
+        Image::insert_many(images)
+            .on_conflict(
+                OnConflict::column(image::Column::Uri)
+                    .update_columns([
+                        image::Column::Path,
+                        image::Column::Name,
+                        image::Column::Extension,
+                        image::Column::Format,
+                        image::Column::Kind,
+                        image::Column::Size,
+                        image::Column::Hash,
+                        image::Column::Embedded,
+                    ])
+                    .to_owned(),
+            )
+            .exec(&txn)
+            .await?;
+    }
+
+    // Link embedded pictures to the audio files they were found in.
+    if !embedded_images.is_empty() {
+        let audio_ids: HashMap<String, i32> = Audio::find()
+            .filter(audio::Column::Uri.is_in(embedded_uris))
+            .all(&txn)
+            .await?
+            .into_iter()
+            .map(|a| (a.uri, a.audio_id))
+            .collect();
+        let image_ids: HashMap<String, i32> = Image::find()
+            .filter(image::Column::Uri.is_in(image_uris))
+            .all(&txn)
+            .await?
+            .into_iter()
+            .map(|i| (i.uri, i.image_id))
+            .collect();
+        let audio_images: Vec<audio_image::ActiveModel> = embedded_images
+            .iter()
+            .filter_map(|(audio_uri, image_uri)| {
+                Some(audio_image::ActiveModel {
+                    audio_id: ActiveValue::Set(*audio_ids.get(audio_uri)?),
+                    image_id: ActiveValue::Set(*image_ids.get(image_uri)?),
+                    ..Default::default()
+                })
+            })
+            .collect();
+        for batch in audio_images.chunks(TAG_BATCH_SIZE) {
+            AudioImage::insert_many(batch.to_vec()).exec(&txn).await?;
+        }
+    }
+
+    if let Some(checkpoint) = checkpoint {
+        ScanCheckpoint::insert(checkpoint)
+            .on_conflict(
+                OnConflict::column(scan_checkpoint::Column::Library)
+                    .update_columns([
+                        scan_checkpoint::Column::Updated,
+                        scan_checkpoint::Column::Path,
+                        scan_checkpoint::Column::Files,
+                    ])
+                    .to_owned(),
+            )
+            .exec(&txn)
+            .await?;
+    }
+
+    txn.commit().await?;
+    Ok(stored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestLibrary};
+
+    async fn audio_named(config: &Config, name: &str) -> Option<audio::Model> {
+        let db = database::connection(config).await;
+        Audio::find()
+            .filter(audio::Column::Name.eq(name))
+            .one(db)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn moved_file_keeps_its_audio_id() {
+        let test = TestLibrary::new(&["--hash"]).await;
+        let track = test.path("A/track.wav");
+        testing::write_wav(&track, 44100, &testing::sine(44100, 440.0, -6.0, 1.0));
+        testing::write_wav(
+            &test.path("A/other.wav"),
+            44100,
+            &testing::sine(44100, 880.0, -6.0, 1.0),
+        );
+        scan_media_files(&test.config).await;
+        let before = audio_named(&test.config, "track.wav").await.unwrap();
+
+        let moved = test.path("B/moved.wav");
+        std::fs::rename(&track, &moved).unwrap();
+        scan_media_files(&test.config).await;
+
+        let db = database::connection(&test.config).await;
+        assert!(audio_named(&test.config, "track.wav").await.is_none());
+        let after = audio_named(&test.config, "moved.wav").await.unwrap();
+        assert_eq!(after.audio_id, before.audio_id);
+        assert_eq!(Path::new(&after.path), moved.parent().unwrap());
+        assert_eq!(Audio::find().count(db).await.unwrap(), 2);
+
+        // The moved file is regrouped into its new directory.
+        let (_, directory) = AudioDirectory::find()
+            .filter(audio_directory::Column::AudioId.eq(after.audio_id))
+            .find_also_related(Directory)
+            .one(db)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(directory.unwrap().name, "B");
+    }
+
+    #[tokio::test]
+    async fn vanished_file_is_removed_with_its_links() {
+        let test = TestLibrary::new(&[]).await;
+        testing::write_wav(
+            &test.path("A/kept.wav"),
+            44100,
+            &testing::sine(44100, 440.0, -6.0, 1.0),
+        );
+        let vanished = test.path("B/vanished.wav");
+        testing::write_wav(&vanished, 44100, &testing::sine(44100, 880.0, -6.0, 1.0));
+        scan_media_files(&test.config).await;
+        let audio_id = audio_named(&test.config, "vanished.wav")
+            .await
+            .unwrap()
+            .audio_id;
+
+        let db = database::connection(&test.config).await;
+        let links = || async {
+            (
+                AudioDirectory::find()
+                    .filter(audio_directory::Column::AudioId.eq(audio_id))
+                    .count(db)
+                    .await
+                    .unwrap(),
+                AlbumAudio::find()
+                    .filter(album_audio::Column::AudioId.eq(audio_id))
+                    .count(db)
+                    .await
+                    .unwrap(),
+            )
+        };
+        assert_eq!(links().await, (1, 1));
+
+        std::fs::remove_file(&vanished).unwrap();
+        scan_media_files(&test.config).await;
+
+        assert!(audio_named(&test.config, "vanished.wav").await.is_none());
+        assert!(audio_named(&test.config, "kept.wav").await.is_some());
+        assert_eq!(links().await, (0, 0));
+        // The directory and album it was the only file in are removed too.
+        let directories: Vec<String> = Directory::find()
+            .all(db)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|d| d.name)
+            .collect();
+        assert_eq!(directories, ["A"]);
+        let albums: Vec<String> = Album::find()
+            .all(db)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|a| a.name)
+            .collect();
+        assert_eq!(albums, ["A"]);
+    }
+
+    #[tokio::test]
+    async fn failed_batch_is_kept_without_saving_the_checkpoint() {
+        let test = TestLibrary::new(&[]).await;
+        let config = &test.config;
+        let db = database::connection(config).await;
+        let first = test.path("A/first.wav");
+        let second = test.path("A/second.wav");
+        for path in [&first, &second] {
+            testing::write_wav(path, 44100, &testing::sine(44100, 440.0, -6.0, 1.0));
+        }
+        scan_file(config, &first, &first.metadata().unwrap()).await;
+        let uri = audio_named(config, "first.wav").await.unwrap().uri;
+
+        // The second file is stored under the URI of the first, so writing it fails.
+        let scanned = examine_file(
+            &second,
+            &second.metadata().unwrap(),
+            uri.clone(),
+            None,
+            false,
+            None,
+        );
+        let mut writer = ScanWriter::new(config, Some(&[]));
+        let library = test.library.display().to_string();
+        writer.progress = Some(ScanProgress::new(library, 0));
+        writer.progress.as_mut().unwrap().finish(0, second.clone());
+        writer.add(scanned).await;
+        writer.flush().await;
+        assert_eq!(writer.batch.len(), 1);
+        assert_eq!(writer.progress.as_ref().unwrap().unsaved, 1);
+        assert_eq!(ScanCheckpoint::find().count(db).await.unwrap(), 0);
+
+        // Once the conflict is gone the kept batch is written along with the checkpoint.
+        Audio::update_many()
+            .col_expr(
+                audio::Column::Uri,
+                sea_query::Expr::value("file:///elsewhere"),
+            )
+            .filter(audio::Column::Uri.eq(uri))
+            .exec(db)
+            .await
+            .unwrap();
+        writer.flush().await;
+        assert_eq!(writer.batch.len(), 0);
+        assert_eq!(writer.progress.as_ref().unwrap().unsaved, 0);
+        assert_eq!(ScanCheckpoint::find().count(db).await.unwrap(), 1);
+        assert!(audio_named(config, "second.wav").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn skipped_file_does_not_stall_scan_progress() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let test = TestLibrary::new(&["--scan-max-files", "3"]).await;
+        let config = &test.config;
+        let db = database::connection(config).await;
+        let sine = testing::sine(44100, 440.0, -6.0, 1.0);
+        testing::write_wav(&test.path("A/a.wav"), 44100, &sine);
+        // File names that aren't valid UTF-8 can't be made into a URI.
+        let invalid = std::ffi::OsStr::from_bytes(b"b\xff.wav");
+        testing::write_wav(&test.path("A").join(invalid), 44100, &sine);
+        testing::write_wav(&test.path("A/c.wav"), 44100, &sine);
+        testing::write_wav(&test.path("A/d.wav"), 44100, &sine);
+
+        scan_media_files(config).await;
+        let checkpoint = ScanCheckpoint::find().one(db).await.unwrap().unwrap();
+        assert_eq!(checkpoint.files, 3);
+        assert_eq!(Path::new(&checkpoint.path), Path::new("A/c.wav"));
+    }
+
+    #[tokio::test]
+    async fn disc_directories_are_folded_into_one_album() {
+        let test = TestLibrary::new(&[]).await;
+        let config = &test.config;
+        let db = database::connection(config).await;
+        for (path, frequency) in [
+            ("Album/CD1/one.wav", 440.0),
+            ("Album/CD1/two.wav", 550.0),
+            ("Album/Disc 2/three.wav", 660.0),
+        ] {
+            testing::write_wav(
+                &test.path(path),
+                44100,
+                &testing::sine(44100, frequency, -6.0, 1.0),
+            );
+        }
+        scan_media_files(config).await;
+
+        let albums = Album::find().all(db).await.unwrap();
+        assert_eq!(albums.len(), 1);
+        assert_eq!(albums[0].name, "Album");
+        assert_eq!(Path::new(&albums[0].path), test.library.join("Album"));
+        assert_eq!(albums[0].total_discs, Some(2));
+        for (name, disc_number) in [("one.wav", 1), ("two.wav", 1), ("three.wav", 2)] {
+            let audio = audio_named(config, name).await.unwrap();
+            let album_audio = AlbumAudio::find()
+                .filter(album_audio::Column::AudioId.eq(audio.audio_id))
+                .one(db)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(album_audio.album_id, albums[0].album_id);
+            assert_eq!(album_audio.disc_number, Some(disc_number), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use crate::fingerprint;
+
+// The number of samples in each frame of the spectrum, about 90 ms at 44.1 kHz, and 11 Hz per
+// bin.
+const FRAME_SIZE: usize = 4096;
+
+// The spectrum is smoothed over this many Hz, so single bins of noise or tones don't move the
+// cutoff.
+const SMOOTHING_BANDWIDTH: f64 = 100.0;
+
+// A cutoff is hard if the average level of the band of this many Hz below it is at least
+// HARD_CUTOFF_DROP dB above the band above it, skipping TRANSITION_BANDWIDTH Hz either side.
+// Lossy encoders low-pass with a transition band of a few hundred Hz, where the high
+// frequencies of music roll off over octaves.
+const HARD_CUTOFF_BANDWIDTH: f64 = 500.0;
+const TRANSITION_BANDWIDTH: f64 = 250.0;
+const HARD_CUTOFF_DROP: f64 = 30.0;
+
+// Lossy encoders low-pass between about 11 kHz at low bitrates and 20.5 kHz at the highest.
+// Cutoffs outside this, such as of audio upsampled from 44.1 kHz or above the highest
+// harmonic of a tone, aren't from a lossy encoder.
+const LOSSY_CUTOFF_RANGE: (f64, f64) = (10000.0, 20500.0);
+
+// The anti-aliasing filters of converters cut off hard between about 92% of the Nyquist frequency
+// and the Nyquist frequency, so cutoffs there are of genuine lossless audio.
+const ANTI_ALIASING_CUTOFF: f64 = 0.92;
+
+/// Whether the spectrum of a lossless audio file shows it was transcoded from a lossy one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum SpectrumVerdict {
+    /// The spectrum extends to near the Nyquist frequency, or rolls off gradually.
+    Genuine,
+    /// The spectrum ends in the hard low-pass cutoff of a lossy encoder.
+    Transcoded,
+    /// The audio is silent, or too short to tell.
+    Inconclusive,
+}
+
+impl SpectrumVerdict {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            SpectrumVerdict::Genuine => "genuine",
+            SpectrumVerdict::Transcoded => "transcoded",
+            SpectrumVerdict::Inconclusive => "inconclusive",
+        }
+    }
+}
+
+/// The average power spectrum of audio, added to as it's decoded.
+pub(crate) struct Spectrum {
+    sample_rate: u32,
+    window: Vec<f64>,
+    /// Samples, mixed to mono, not yet in a whole frame.
+    frame: Vec<f32>,
+    /// The sum of the power of each bin over all frames.
+    power: Vec<f64>,
+    frames: usize,
+}
+
+impl Spectrum {
+    pub(crate) fn new(sample_rate: u32) -> Self {
+        Spectrum {
+            sample_rate,
+            window: (0..FRAME_SIZE)
+                .map(|i| {
+                    0.5 - 0.5 * (2.0 * std::f64::consts::PI * i as f64 / FRAME_SIZE as f64).cos()
+                })
+                .collect(),
+            frame: Vec::with_capacity(FRAME_SIZE),
+            power: vec![0.0; FRAME_SIZE / 2],
+            frames: 0,
+        }
+    }
+
+    /// Add interleaved samples of a number of channels.
+    pub(crate) fn push(&mut self, samples: &[f32], channels: usize) {
+        let channels = channels.max(1);
+        for frame in samples.chunks(channels) {
+            self.frame.push(frame.iter().sum::<f32>() / channels as f32);
+            if self.frame.len() == FRAME_SIZE {
+                self.add_frame();
+            }
+        }
+    }
+
+    fn add_frame(&mut self) {
+        let mut buffer: Vec<(f64, f64)> = self
+            .frame
+            .iter()
+            .zip(&self.window)
+            .map(|(s, w)| (*s as f64 * w, 0.0))
+            .collect();
+        fingerprint::fft(&mut buffer);
+        for (power, (re, im)) in self.power.iter_mut().zip(&buffer) {
+            *power += re * re + im * im;
+        }
+        self.frames += 1;
+        self.frame.clear();
+    }
+
+    /// Find the cutoff frequency of the spectrum in Hz, the Nyquist frequency if it isn't cut
+    /// off, and whether it's the low-pass of a lossy encoder.
+    pub(crate) fn finish(self) -> (Option<i32>, SpectrumVerdict) {
+        if self.frames == 0 || self.sample_rate == 0 || self.power.iter().all(|p| *p == 0.0) {
+            return (None, SpectrumVerdict::Inconclusive);
+        }
+        let hertz_per_bin = self.sample_rate as f64 / FRAME_SIZE as f64;
+        let bins = self.power.len();
+        let nyquist = self.sample_rate as f64 / 2.0;
+
+        // Smoothed levels in dB. Silence is floored well below the quantisation noise of 24 bit
+        // audio.
+        let half = ((SMOOTHING_BANDWIDTH / hertz_per_bin / 2.0) as usize).max(1);
+        let levels: Vec<f64> = (0..bins)
+            .map(|i| {
+                let band = &self.power[i.saturating_sub(half)..(i + half + 1).min(bins)];
+                let power = band.iter().sum::<f64>() / band.len() as f64 / self.frames as f64;
+                10.0 * power.max(1e-30).log10()
+            })
+            .collect();
+        let mean = |band: &[f64]| band.iter().sum::<f64>() / band.len() as f64;
+
+        // The highest hard drop in level, searching down from the Nyquist frequency.
+        let width = ((HARD_CUTOFF_BANDWIDTH / hertz_per_bin) as usize).max(1);
+        let gap = (TRANSITION_BANDWIDTH / hertz_per_bin) as usize;
+        let Some((drop, below, above)) = (width + gap..bins.saturating_sub(width + gap))
+            .rev()
+            .map(|i| {
+                let below = mean(&levels[i - gap - width..i - gap]);
+                let above = mean(&levels[i + gap..i + gap + width]);
+                (i, below, above)
+            })
+            .find(|(_, below, above)| below - above >= HARD_CUTOFF_DROP)
+        else {
+            return (Some(nyquist.round() as i32), SpectrumVerdict::Genuine);
+        };
+        // The cutoff is half way down the drop, as encoders' low-pass frequencies are given.
+        let cutoff = levels[..=drop + gap]
+            .iter()
+            .rposition(|l| *l > (below + above) / 2.0)
+            .unwrap_or(drop);
+        let cutoff_frequency = cutoff as f64 * hertz_per_bin;
+
+        let verdict = if cutoff_frequency >= LOSSY_CUTOFF_RANGE.0
+            && cutoff_frequency <= LOSSY_CUTOFF_RANGE.1
+            && cutoff_frequency < ANTI_ALIASING_CUTOFF * nyquist
+        {
+            SpectrumVerdict::Transcoded
+        } else {
+            SpectrumVerdict::Genuine
+        };
+        (Some(cutoff_frequency.round() as i32), verdict)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use super::*;
+    use crate::testing;
+
+    /// White noise low-passed at a frequency with a windowed sinc filter, as lossy encoders do,
+    /// and quantised to 16 bits as a transcoded file would be stored.
+    fn low_passed_noise(cutoff: f64, seconds: f64) -> Vec<f32> {
+        const TAPS: usize = 1023;
+        let fraction = cutoff / 44100.0;
+        let taps: Vec<f64> = (0..TAPS)
+            .map(|i| {
+                let x = i as f64 - (TAPS / 2) as f64;
+                let sinc = if x == 0.0 {
+                    2.0 * fraction
+                } else {
+                    (2.0 * PI * fraction * x).sin() / (PI * x)
+                };
+                // Blackman window.
+                let phase = 2.0 * PI * i as f64 / (TAPS - 1) as f64;
+                sinc * (0.42 - 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos())
+            })
+            .collect();
+        let noise = testing::noise(3, (44100.0 * seconds) as usize + TAPS);
+        noise
+            .windows(TAPS)
+            .map(|window| {
+                let sample = 0.5
+                    * window
+                        .iter()
+                        .zip(&taps)
+                        .map(|(s, t)| *s as f64 * t)
+                        .sum::<f64>();
+                ((sample * 32767.0).round() / 32767.0) as f32
+            })
+            .collect()
+    }
+
+    fn analyse(samples: &[f32], channels: usize) -> (Option<i32>, SpectrumVerdict) {
+        let mut spectrum = Spectrum::new(44100);
+        spectrum.push(samples, channels);
+        spectrum.finish()
+    }
+
+    #[test]
+    fn low_passed_noise_is_transcoded() {
+        let (cutoff, verdict) = analyse(&low_passed_noise(16000.0, 3.0), 1);
+        let cutoff = cutoff.unwrap();
+        assert!((cutoff - 16000).abs() < 200, "cutoff at {} Hz", cutoff);
+        assert_eq!(verdict, SpectrumVerdict::Transcoded);
+    }
+
+    #[test]
+    fn full_band_noise_is_genuine() {
+        let noise: Vec<f32> = testing::noise(5, 44100 * 3 * 2)
+            .iter()
+            .map(|s| s * 0.5)
+            .collect();
+        assert_eq!(analyse(&noise, 2), (Some(22050), SpectrumVerdict::Genuine));
+    }
+
+    #[test]
+    fn silence_is_inconclusive() {
+        assert_eq!(
+            analyse(&vec![0.0; 44100], 1),
+            (None, SpectrumVerdict::Inconclusive)
+        );
+        assert_eq!(
+            analyse(&testing::noise(5, 1000), 1),
+            (None, SpectrumVerdict::Inconclusive)
+        );
+    }
+}
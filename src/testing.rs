@@ -0,0 +1,135 @@
+//! Helpers for tests that scan a music library into a database of their own.
+
+use std::f64::consts::PI;
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+use tempfile::TempDir;
+
+use crate::database;
+use crate::Config;
+
+/// A temporary music library, and a database to scan it into.
+pub(crate) struct TestLibrary {
+    pub(crate) config: Config,
+    pub(crate) library: PathBuf,
+    // Removed when the test finishes.
+    _dir: TempDir,
+}
+
+impl TestLibrary {
+    /// Create an empty library and database, configured with extra command line flags.
+    pub(crate) async fn new(flags: &[&str]) -> Self {
+        let dir = tempfile::tempdir().expect("failed to create test directory");
+        let library = dir.path().join("library");
+        std::fs::create_dir(&library).expect("failed to create test library");
+        let database_name = dir.path().join("ria.sqlite").display().to_string();
+        let artwork_cache = dir.path().join("artwork").display().to_string();
+        let mut args = vec![
+            "ria".to_string(),
+            "--library".to_string(),
+            library.display().to_string(),
+            "--database-name".to_string(),
+            database_name,
+            "--artwork-cache".to_string(),
+            artwork_cache,
+        ];
+        args.extend(flags.iter().map(|f| f.to_string()));
+        let config = Config::parse_from(args);
+        database::migrate(&config)
+            .await
+            .expect("failed to create test database");
+        TestLibrary {
+            config,
+            library,
+            _dir: dir,
+        }
+    }
+
+    /// The path of a file in the library, creating the directories it's in.
+    pub(crate) fn path(&self, relative: &str) -> PathBuf {
+        let path = self.library.join(relative);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).expect("failed to create test directory");
+        }
+        path
+    }
+}
+
+/// A sine wave at a level in dBFS.
+pub(crate) fn sine(sample_rate: u32, frequency: f64, dbfs: f64, seconds: f64) -> Vec<f32> {
+    let amplitude = 10f64.powf(dbfs / 20.0);
+    (0..(sample_rate as f64 * seconds) as usize)
+        .map(|i| (amplitude * (2.0 * PI * frequency * i as f64 / sample_rate as f64).sin()) as f32)
+        .collect()
+}
+
+// Semitones above A3 of the triads of a chord progression, half a second each.
+pub(crate) const PROGRESSION: [[i32; 3]; 8] = [
+    [0, 4, 7],
+    [5, 9, 12],
+    [7, 11, 14],
+    [0, 4, 7],
+    [-3, 0, 4],
+    [5, 9, 12],
+    [2, 5, 9],
+    [7, 11, 14],
+];
+
+/// Chords played in turn, over a little noise, as a stand-in for music.
+pub(crate) fn chords(sample_rate: u32, progression: &[[i32; 3]], repeats: usize) -> Vec<f32> {
+    let mut samples: Vec<f32> = progression
+        .iter()
+        .cycle()
+        .take(progression.len() * repeats)
+        .flat_map(|chord| {
+            let notes: Vec<Vec<f32>> = chord
+                .iter()
+                .map(|n| sine(sample_rate, 220.0 * 2f64.powf(*n as f64 / 12.0), -16.0, 0.5))
+                .collect();
+            (0..notes[0].len()).map(move |i| notes.iter().map(|n| n[i]).sum::<f32>())
+        })
+        .collect();
+    let noise = noise(1, samples.len());
+    for (sample, noise) in samples.iter_mut().zip(noise) {
+        *sample += noise * 0.01;
+    }
+    samples
+}
+
+/// White noise between -1 and 1, the same for the same seed.
+pub(crate) fn noise(seed: u64, length: usize) -> Vec<f32> {
+    let mut state = seed
+        .wrapping_mul(6364136223846793005)
+        .wrapping_add(1442695040888963407);
+    (0..length)
+        .map(|_| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            ((state >> 33) as f64 / (1u64 << 31) as f64 * 2.0 - 1.0) as f32
+        })
+        .collect()
+}
+
+/// Write mono samples to a 16 bit PCM WAV file.
+pub(crate) fn write_wav(path: &Path, sample_rate: u32, samples: &[f32]) {
+    let data = (samples.len() * 2) as u32;
+    let mut wav = Vec::with_capacity(44 + data as usize);
+    wav.extend(b"RIFF");
+    wav.extend((36 + data).to_le_bytes());
+    wav.extend(b"WAVEfmt ");
+    wav.extend(16u32.to_le_bytes());
+    wav.extend(1u16.to_le_bytes());
+    wav.extend(1u16.to_le_bytes());
+    wav.extend(sample_rate.to_le_bytes());
+    wav.extend((sample_rate * 2).to_le_bytes());
+    wav.extend(2u16.to_le_bytes());
+    wav.extend(16u16.to_le_bytes());
+    wav.extend(b"data");
+    wav.extend(data.to_le_bytes());
+    for sample in samples {
+        wav.extend(((sample.clamp(-1.0, 1.0) * 32767.0).round() as i16).to_le_bytes());
+    }
+    std::fs::write(path, wav).expect("failed to write test audio file");
+}
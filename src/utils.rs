@@ -1,3 +1,9 @@
+use std::fs::{File, Metadata};
+use std::path::{Component, Path};
+use std::time::UNIX_EPOCH;
+
+use chrono::NaiveDateTime;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
 use tracing::{event, instrument, Level};
 use walkdir::DirEntry;
 
@@ -12,6 +18,19 @@ pub(crate) fn is_hidden(entry: &DirEntry) -> bool {
         .unwrap_or(false)
 }
 
+/// Skip paths within the library that include a file or directory starting with ".".
+#[instrument]
+pub(crate) fn is_hidden_path(library: &Path, path: &Path) -> bool {
+    event!(Level::TRACE, "is_hidden_path");
+    path.strip_prefix(library)
+        .unwrap_or(path)
+        .components()
+        .any(|c| match c {
+            Component::Normal(name) => name.to_str().map(|s| s.starts_with('.')).unwrap_or(false),
+            _ => false,
+        })
+}
+
 // Dynamically build a user agent from package name and package version. Store
 // in a OnceCell to allow static lifetime necessary for the MusicBrainz agent.
 #[instrument]
@@ -24,3 +43,72 @@ pub(crate) fn build_user_agent() -> String {
     event!(Level::TRACE, "build_user_agent: {}", user_agent);
     user_agent
 }
+
+/// Convert a file's last modification time to a timestamp that can be stored in the
+/// database. Sub-second precision is discarded as not all databases preserve it.
+#[instrument]
+pub(crate) fn modified(metadata: &Metadata) -> Option<NaiveDateTime> {
+    event!(Level::TRACE, "modified");
+    let modified = match metadata.modified() {
+        Ok(m) => m,
+        Err(e) => {
+            event!(Level::WARN, "metadata.modified() failure: {}", e);
+            return None;
+        }
+    };
+    let seconds = match modified.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(e) => {
+            event!(Level::WARN, "modified.duration_since() failure: {}", e);
+            return None;
+        }
+    };
+    NaiveDateTime::from_timestamp_opt(seconds, 0)
+}
+
+/// Generate a hash of the entire contents of a file, to detect changes even if the size
+/// and modification time are unchanged.
+#[instrument]
+pub(crate) fn hash_file(path: &Path) -> Option<String> {
+    event!(Level::TRACE, "hash_file");
+    let mut file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) => {
+            event!(Level::WARN, "File::open() failure: {}", e);
+            return None;
+        }
+    };
+    let mut hasher = blake3::Hasher::new();
+    if let Err(e) = std::io::copy(&mut file, &mut hasher) {
+        event!(Level::WARN, "failed to hash {}: {}", path.display(), e);
+        return None;
+    }
+    Some(hasher.finalize().to_hex().to_string())
+}
+
+/// Build an absolute URI to uniquely identify a file.
+#[instrument]
+pub(crate) fn file_uri(path: &Path) -> Option<String> {
+    event!(Level::TRACE, "file_uri");
+    // Percent-encode all characters except alpha-numerics and "/" to build proper
+    // paths. @TODO: remove characters necessary to navigate Windows paths.
+    const FRAGMENT: &AsciiSet = &NON_ALPHANUMERIC.remove(b'/');
+
+    let path = match std::env::current_dir() {
+        Ok(c) => c.join(path),
+        Err(e) => {
+            event!(Level::WARN, "std::env::current_dir() failure: {}", e);
+            return None;
+        }
+    };
+    match path.to_str() {
+        Some(p) => Some(format!(
+            "file://{}",
+            utf8_percent_encode(p, FRAGMENT).collect::<String>()
+        )),
+        None => {
+            event!(Level::WARN, "path.to_str() failure: NONE");
+            None
+        }
+    }
+}
@@ -0,0 +1,489 @@
+use std::path::{Path, PathBuf};
+
+use sea_orm::*;
+use serde::Serialize;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{DecoderOptions, VerificationCheck};
+use symphonia::core::errors::Error;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use tokio::task::JoinSet;
+use tracing::{event, instrument, Level};
+
+use crate::database;
+use crate::duplicates::{self, ReportFormat};
+use crate::entities::{prelude::*, *};
+use crate::scan;
+use crate::spectrum::{Spectrum, SpectrumVerdict};
+use crate::Config;
+
+// Files that decode this many seconds short of the length in their header are truncated.
+// Decoders can drop a partial packet at the end, and the length of MP3 files without a Xing
+// header is estimated from their bitrate.
+const TRUNCATION_TOLERANCE: f64 = 1.0;
+
+/// The result of fully decoding an audio file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum VerifyStatus {
+    /// Decoded without errors, to the length in its header.
+    Ok,
+    /// Couldn't be opened, or its format or codec wasn't recognised.
+    Unreadable,
+    /// Packets failed to decode, or reading stopped with an error.
+    Corrupt,
+    /// Decoding ended before the length in its header.
+    Truncated,
+    /// The MD5 checksum of the decoded audio doesn't match the one in its FLAC header.
+    ChecksumMismatch,
+}
+
+impl VerifyStatus {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            VerifyStatus::Ok => "ok",
+            VerifyStatus::Unreadable => "unreadable",
+            VerifyStatus::Corrupt => "corrupt",
+            VerifyStatus::Truncated => "truncated",
+            VerifyStatus::ChecksumMismatch => "checksum_mismatch",
+        }
+    }
+}
+
+/// What was found fully decoding an audio file.
+#[derive(Debug)]
+struct Verification {
+    status: VerifyStatus,
+    decoded_frames: u64,
+    expected_frames: Option<u64>,
+    decode_errors: u32,
+    /// The first error decoding the file.
+    error: Option<String>,
+    /// The cutoff frequency of the spectrum of lossless files, in Hz.
+    cutoff_frequency: Option<i32>,
+    /// Whether lossless files were transcoded from lossy ones.
+    spectrum: Option<SpectrumVerdict>,
+}
+
+impl Verification {
+    fn unreadable(error: String) -> Self {
+        Verification {
+            status: VerifyStatus::Unreadable,
+            decoded_frames: 0,
+            expected_frames: None,
+            decode_errors: 0,
+            error: Some(error),
+            cutoff_frequency: None,
+            spectrum: None,
+        }
+    }
+}
+
+/// A verified audio file, as reported.
+#[derive(Debug, Serialize)]
+struct VerifiedAudio {
+    audio_id: i32,
+    path: String,
+    status: String,
+    decoded_frames: i64,
+    expected_frames: Option<i64>,
+    decode_errors: i32,
+    error: Option<String>,
+    cutoff_frequency: Option<i32>,
+    spectrum: Option<String>,
+    #[serde(skip)]
+    hertz: i32,
+    verified_at: String,
+}
+
+/// Fully decode audio files that haven't been verified yet, or all audio files when
+/// rechecking, and report the broken ones and those transcoded from lossy sources, or all
+/// verified audio files.
+#[instrument]
+pub(crate) async fn run_command(
+    config: &Config,
+    format: ReportFormat,
+    recheck: bool,
+    all: bool,
+) -> anyhow::Result<()> {
+    event!(Level::TRACE, "run_command");
+
+    let db = database::connection(config).await;
+    verify_audio(config, db, recheck).await?;
+
+    let mut select = AudioVerification::find().find_also_related(Audio);
+    if !all {
+        select = select.filter(
+            Condition::any()
+                .add(audio_verification::Column::Status.ne(VerifyStatus::Ok.as_str()))
+                .add(audio_verification::Column::Spectrum.eq(SpectrumVerdict::Transcoded.as_str())),
+        );
+    }
+    let mut report: Vec<VerifiedAudio> = select
+        .all(db)
+        .await?
+        .into_iter()
+        .filter_map(|(verification, audio)| {
+            let audio = audio?;
+            Some(VerifiedAudio {
+                audio_id: audio.audio_id,
+                path: Path::new(&audio.path)
+                    .join(&audio.name)
+                    .display()
+                    .to_string(),
+                status: verification.status,
+                decoded_frames: verification.decoded_frames,
+                expected_frames: verification.expected_frames,
+                decode_errors: verification.decode_errors,
+                error: verification.error,
+                cutoff_frequency: verification.cutoff_frequency,
+                spectrum: verification.spectrum,
+                hertz: audio.hertz,
+                verified_at: verification.created.to_string(),
+            })
+        })
+        .collect();
+    report.sort_by(|a, b| a.path.cmp(&b.path));
+
+    match format {
+        ReportFormat::Text => {
+            for audio in &report {
+                print_verified(audio);
+            }
+            let verified = AudioVerification::find().count(db).await?;
+            let broken = AudioVerification::find()
+                .filter(audio_verification::Column::Status.ne(VerifyStatus::Ok.as_str()))
+                .count(db)
+                .await?;
+            let transcoded = AudioVerification::find()
+                .filter(
+                    audio_verification::Column::Spectrum.eq(SpectrumVerdict::Transcoded.as_str()),
+                )
+                .count(db)
+                .await?;
+            println!(
+                "{} audio files verified, {} broken, {} transcoded from lossy sources",
+                verified, broken, transcoded
+            );
+        }
+        ReportFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+    }
+    Ok(())
+}
+
+/// Fully decode audio files, and store what was found for each. Only audio files that haven't
+/// been verified since they last changed are decoded, unless rechecking.
+async fn verify_audio(
+    config: &Config,
+    db: &DatabaseConnection,
+    recheck: bool,
+) -> anyhow::Result<()> {
+    let mut select = Audio::find();
+    if !recheck {
+        select = select
+            .left_join(AudioVerification)
+            .filter(audio_verification::Column::AudioVerificationId.is_null());
+    }
+    let audio_files = select.all(db).await?;
+
+    let workers = scan::scan_workers(config);
+    let mut verified = 0;
+    let mut tasks = JoinSet::new();
+    let mut audio_files = audio_files.into_iter();
+    loop {
+        // Keep every worker busy, then store each result as soon as it's done.
+        while tasks.len() < workers {
+            let Some(audio_file) = audio_files.next() else {
+                break;
+            };
+            let path = PathBuf::from(&audio_file.path).join(&audio_file.name);
+            let lossless = duplicates::is_lossless(&audio_file.format);
+            tasks.spawn(tokio::task::spawn_blocking(move || {
+                let verification = verify_file(&path, &audio_file.extension, lossless);
+                (audio_file, verification)
+            }));
+        }
+        let (audio_file, verification) = match tasks.join_next().await {
+            Some(Ok(Ok(r))) => r,
+            Some(Ok(Err(e)) | Err(e)) => {
+                event!(Level::WARN, "verify worker failure: {}", e);
+                continue;
+            }
+            None => break,
+        };
+        if verification.spectrum == Some(SpectrumVerdict::Transcoded) {
+            event!(
+                Level::INFO,
+                "{}/{} is transcoded from a lossy source, cut off at {} Hz",
+                audio_file.path,
+                audio_file.name,
+                verification.cutoff_frequency.unwrap_or_default()
+            );
+        }
+        if verification.status != VerifyStatus::Ok {
+            event!(
+                Level::INFO,
+                "{}/{} is {}: {}",
+                audio_file.path,
+                audio_file.name,
+                verification.status.as_str(),
+                verification.error.as_deref().unwrap_or_default()
+            );
+        }
+        match store_verification(db, audio_file.audio_id, &verification).await {
+            Ok(()) => verified += 1,
+            Err(e) => event!(Level::WARN, "failed to store verification: {}", e),
+        }
+    }
+    event!(Level::INFO, "verified {} audio files", verified);
+    Ok(())
+}
+
+/// Replace what was found verifying an audio file before.
+async fn store_verification(
+    db: &DatabaseConnection,
+    audio_id: i32,
+    verification: &Verification,
+) -> Result<(), DbErr> {
+    let txn = db.begin().await?;
+    AudioVerification::delete_many()
+        .filter(audio_verification::Column::AudioId.eq(audio_id))
+        .exec(&txn)
+        .await?;
+    audio_verification::ActiveModel {
+        created: ActiveValue::Set(chrono::Utc::now().naive_utc()),
+        audio_id: ActiveValue::Set(audio_id),
+        status: ActiveValue::Set(verification.status.as_str().to_string()),
+        decoded_frames: ActiveValue::Set(verification.decoded_frames as i64),
+        expected_frames: ActiveValue::Set(verification.expected_frames.map(|f| f as i64)),
+        decode_errors: ActiveValue::Set(verification.decode_errors as i32),
+        error: ActiveValue::Set(verification.error.clone()),
+        cutoff_frequency: ActiveValue::Set(verification.cutoff_frequency),
+        spectrum: ActiveValue::Set(verification.spectrum.map(|s| s.as_str().to_string())),
+        ..Default::default()
+    }
+    .insert(&txn)
+    .await?;
+    txn.commit().await
+}
+
+/// Decode the whole default track of an audio file, counting the frames decoded and the
+/// packets that failed to decode, and check the MD5 checksum of FLAC files. The spectrum of
+/// lossless files is checked for the cutoff of a lossy encoder they were transcoded from.
+fn verify_file(path: &Path, extension: &str, lossless: bool) -> Verification {
+    event!(Level::TRACE, "verify_file {}", path.display());
+
+    let src = match std::fs::File::open(path) {
+        Ok(s) => s,
+        Err(e) => return Verification::unreadable(format!("failed to open: {}", e)),
+    };
+    let mss = MediaSourceStream::new(Box::new(src), Default::default());
+    let mut hint = Hint::new();
+    if !extension.is_empty() {
+        hint.with_extension(extension);
+    }
+    let meta_opts: MetadataOptions = Default::default();
+    let fmt_opts: FormatOptions = Default::default();
+    let mut format = match symphonia::default::get_probe().format(&hint, mss, &fmt_opts, &meta_opts)
+    {
+        Ok(p) => p.format,
+        Err(e) => return Verification::unreadable(format!("unrecognised format: {}", e)),
+    };
+    let Some(track) = format.default_track() else {
+        return Verification::unreadable("no audio track".to_string());
+    };
+    let track_id = track.id;
+    let expected_frames = track.codec_params.n_frames;
+    let sample_rate = track.codec_params.sample_rate.unwrap_or_default();
+    // Encoders that don't compute the checksum leave it zeroed.
+    let checksum = matches!(
+        track.codec_params.verification_check,
+        Some(VerificationCheck::Md5(md5)) if md5 != [0; 16]
+    );
+    let mut decoder = match symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions { verify: checksum })
+    {
+        Ok(d) => d,
+        Err(e) => return Verification::unreadable(format!("unsupported codec: {}", e)),
+    };
+
+    let mut spectrum = lossless.then(|| Spectrum::new(sample_rate));
+    let mut buffer: Option<SampleBuffer<f32>> = None;
+    let mut decoded_frames = 0;
+    let mut decode_errors = 0;
+    let mut error = None;
+    let mut stopped = false;
+    loop {
+        let packet = match format.next_packet() {
+            Ok(p) => p,
+            // The end of the file is reported as an unexpected end of file.
+            Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => {
+                error = Some(format!("failed to read: {}", e));
+                stopped = true;
+                break;
+            }
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        match decoder.decode(&packet) {
+            Ok(decoded) => {
+                decoded_frames += decoded.frames() as u64;
+                let Some(spectrum) = spectrum.as_mut() else {
+                    continue;
+                };
+                let spec = *decoded.spec();
+                let buffer = buffer
+                    .get_or_insert_with(|| SampleBuffer::new(decoded.capacity() as u64, spec));
+                if buffer.capacity() < decoded.capacity() * spec.channels.count() {
+                    *buffer = SampleBuffer::new(decoded.capacity() as u64, spec);
+                }
+                buffer.copy_interleaved_ref(decoded);
+                spectrum.push(buffer.samples(), spec.channels.count());
+            }
+            // Corrupt packets are counted and skipped, as a player would.
+            Err(Error::DecodeError(e)) => {
+                decode_errors += 1;
+                error.get_or_insert_with(|| format!("failed to decode packet: {}", e));
+            }
+            Err(e) => {
+                error.get_or_insert_with(|| format!("failed to decode: {}", e));
+                stopped = true;
+                break;
+            }
+        }
+    }
+    let checksum_matched = decoder.finalize().verify_ok;
+
+    let truncated = match expected_frames {
+        Some(expected) if sample_rate > 0 => {
+            (expected.saturating_sub(decoded_frames) as f64 / sample_rate as f64)
+                > TRUNCATION_TOLERANCE
+        }
+        _ => false,
+    };
+    let status = if stopped || decode_errors > 0 {
+        VerifyStatus::Corrupt
+    } else if truncated {
+        VerifyStatus::Truncated
+    } else if checksum && checksum_matched == Some(false) {
+        error = Some("decoded audio doesn't match the MD5 checksum in the header".to_string());
+        VerifyStatus::ChecksumMismatch
+    } else {
+        VerifyStatus::Ok
+    };
+    let (cutoff_frequency, spectrum) = match spectrum.map(Spectrum::finish) {
+        Some((cutoff_frequency, verdict)) => (cutoff_frequency, Some(verdict)),
+        None => (None, None),
+    };
+    Verification {
+        status,
+        decoded_frames,
+        expected_frames,
+        decode_errors,
+        error,
+        cutoff_frequency,
+        spectrum,
+    }
+}
+
+/// Print a verified audio file, with why it's broken or where its spectrum is cut off.
+fn print_verified(audio: &VerifiedAudio) {
+    let transcoded = audio.spectrum.as_deref() == Some(SpectrumVerdict::Transcoded.as_str());
+    let seconds = |frames: i64| frames as f64 / audio.hertz.max(1) as f64;
+    let mut details = Vec::new();
+    if audio.decode_errors > 0 {
+        details.push(format!("{} packets failed to decode", audio.decode_errors));
+    }
+    match audio.expected_frames {
+        Some(expected) if audio.decoded_frames < expected => details.push(format!(
+            "decoded {:.1} of {:.1} seconds",
+            seconds(audio.decoded_frames),
+            seconds(expected)
+        )),
+        _ => details.push(format!(
+            "decoded {:.1} seconds",
+            seconds(audio.decoded_frames)
+        )),
+    }
+    if let Some(error) = &audio.error {
+        details.push(error.clone());
+    }
+    if let Some(cutoff) = audio.cutoff_frequency {
+        details.push(format!("cut off at {:.1} kHz", cutoff as f64 / 1000.0));
+    }
+    println!(
+        "{:>17} {:>6}: {} [{}]",
+        if transcoded && audio.status == VerifyStatus::Ok.as_str() {
+            SpectrumVerdict::Transcoded.as_str()
+        } else {
+            &audio.status
+        },
+        audio.audio_id,
+        audio.path,
+        details.join(", ")
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestLibrary};
+
+    async fn verification_of(db: &DatabaseConnection, name: &str) -> audio_verification::Model {
+        let (verification, _) = AudioVerification::find()
+            .find_also_related(Audio)
+            .filter(audio::Column::Name.eq(name))
+            .one(db)
+            .await
+            .unwrap()
+            .unwrap();
+        verification
+    }
+
+    #[tokio::test]
+    async fn valid_and_truncated_files_are_verified() {
+        let test = TestLibrary::new(&[]).await;
+        let config = &test.config;
+        let db = database::connection(config).await;
+        let samples = testing::sine(44100, 440.0, -6.0, 5.0);
+        testing::write_wav(&test.path("valid.wav"), 44100, &samples);
+        let truncated = test.path("truncated.wav");
+        testing::write_wav(&truncated, 44100, &samples);
+        scan::scan_media_files(config).await;
+
+        // Cut off after 2 of the 5 seconds in the header, ie by an interrupted copy.
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&truncated)
+            .unwrap();
+        file.set_len(44 + 2 * 44100 * 2).unwrap();
+        verify_audio(config, db, false).await.unwrap();
+
+        let valid = verification_of(db, "valid.wav").await;
+        assert_eq!(valid.status, VerifyStatus::Ok.as_str());
+        assert_eq!(valid.decoded_frames, 5 * 44100);
+        assert_eq!(valid.expected_frames, Some(5 * 44100));
+        assert_eq!(valid.decode_errors, 0);
+        assert_eq!(valid.error, None);
+
+        let truncated = verification_of(db, "truncated.wav").await;
+        assert_eq!(truncated.status, VerifyStatus::Truncated.as_str());
+        assert_eq!(truncated.decoded_frames, 2 * 44100);
+        assert_eq!(truncated.expected_frames, Some(5 * 44100));
+
+        // Verified files aren't decoded again unless rechecking.
+        verify_audio(config, db, false).await.unwrap();
+        let again = verification_of(db, "truncated.wav").await;
+        assert_eq!(again.audio_verification_id, truncated.audio_verification_id);
+        verify_audio(config, db, true).await.unwrap();
+        let rechecked = verification_of(db, "truncated.wav").await;
+        assert_ne!(
+            rechecked.audio_verification_id,
+            truncated.audio_verification_id
+        );
+        assert_eq!(rechecked.status, VerifyStatus::Truncated.as_str());
+    }
+}
@@ -0,0 +1,339 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use notify_debouncer_mini::notify::RecursiveMode;
+use notify_debouncer_mini::{new_debouncer, DebouncedEvent, DebouncedEventKind};
+use tokio::sync::mpsc;
+use tracing::{event, instrument, Level};
+use walkdir::WalkDir;
+
+use crate::acoustid;
+use crate::artwork;
+use crate::features;
+use crate::fingerprint;
+use crate::loudness;
+use crate::media::{self, Scope};
+use crate::releases;
+use crate::scan::{self, ScanFilter};
+use crate::utils;
+use crate::Config;
+
+// Large copy operations generate a steady stream of events for each file. Wait until a file
+// hasn't changed for this many seconds before indexing it, unless `watch_delay` is set.
+const DEFAULT_WATCH_DELAY: u64 = 2;
+
+/// Watch the music library for changes, indexing new files, re-probing modified files and
+/// removing deleted files as they happen. Runs until interrupted with ctrl-c.
+#[instrument]
+pub(crate) async fn watch_media_files(config: &Config) {
+    event!(Level::TRACE, "watch_media_files");
+
+    let library = PathBuf::from(config.library.as_ref().expect("library must exist"));
+    let delay = Duration::from_secs(config.watch_delay.unwrap_or(DEFAULT_WATCH_DELAY));
+    let filter = match ScanFilter::new(config) {
+        Ok(f) => f,
+        Err(e) => {
+            event!(
+                Level::ERROR,
+                "invalid scan_include or scan_exclude glob: {}",
+                e
+            );
+            return;
+        }
+    };
+
+    // The debouncer runs in its own thread, forward its events into the async runtime.
+    let (tx, mut rx) = mpsc::channel(16);
+    let mut debouncer = match new_debouncer(delay, move |result| {
+        let _ = tx.blocking_send(result);
+    }) {
+        Ok(d) => d,
+        Err(e) => {
+            event!(Level::ERROR, "failed to create filesystem watcher: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = debouncer
+        .watcher()
+        .watch(&library, RecursiveMode::Recursive)
+    {
+        event!(Level::ERROR, "failed to watch {}: {}", library.display(), e);
+        return;
+    }
+    event!(Level::WARN, "watching {} for changes", library.display());
+
+    loop {
+        tokio::select! {
+            result = rx.recv() => match result {
+                Some(Ok(events)) => process_events(config, &library, &filter, events).await,
+                Some(Err(e)) => event!(Level::WARN, "filesystem watcher error: {}", e),
+                None => break,
+            },
+            _ = tokio::signal::ctrl_c() => {
+                event!(Level::WARN, "no longer watching {}", library.display());
+                break;
+            }
+        }
+    }
+}
+
+/// Index a batch of debounced filesystem events.
+async fn process_events(
+    config: &Config,
+    library: &Path,
+    filter: &ScanFilter,
+    events: Vec<DebouncedEvent>,
+) {
+    event!(Level::TRACE, "process_events");
+
+    // Files that are still being written to are indexed once they stop changing. Paths are
+    // stored as the scanner stores them, so files are found again however they were reported.
+    let mut paths: Vec<PathBuf> = events
+        .into_iter()
+        .filter(|e| e.kind == DebouncedEventKind::Any)
+        .map(|e| filter.library_path(&e.path))
+        .filter(|p| !utils::is_hidden_path(library, p) && filter.allows(p, p.is_dir()))
+        .collect();
+    if paths.is_empty() {
+        return;
+    }
+    paths.sort();
+    paths.dedup();
+
+    // Index paths that exist first, so moved files are recognized before their old location
+    // is removed.
+    let (existing, removed): (Vec<PathBuf>, Vec<PathBuf>) =
+        paths.into_iter().partition(|p| p.exists());
+    // Only the directories that changed are grouped, linked and analysed again.
+    let mut directories = Vec::new();
+    for path in existing {
+        if path.is_dir() {
+            directories.push(path.clone());
+            // Files copied into a new directory before it was watched don't generate events,
+            // so walk the entire directory.
+            let walker = WalkDir::new(&path).follow_links(true).into_iter();
+            let walker = walker.filter_entry(|e| {
+                !utils::is_hidden(e) && filter.allows(e.path(), e.file_type().is_dir())
+            });
+            for entry in walker.flatten() {
+                match entry.metadata() {
+                    Ok(m) if m.is_file() => {
+                        scan::scan_file(config, entry.path(), &m).await;
+                    }
+                    Ok(_) => (),
+                    Err(e) => event!(Level::WARN, "metadata() failure: {}", e),
+                }
+            }
+        } else {
+            directories.extend(path.parent().map(Path::to_path_buf));
+            match path.metadata() {
+                Ok(m) => {
+                    scan::scan_file(config, &path, &m).await;
+                }
+                Err(e) => event!(Level::WARN, "metadata() failure: {}", e),
+            }
+        }
+    }
+    let images_removed = !removed.is_empty();
+    for path in removed {
+        media::remove_audio_at(config, &path).await;
+        directories.extend(path.parent().map(Path::to_path_buf));
+    }
+    let scope = Scope::changed(library, &directories);
+    if images_removed {
+        artwork::remove_missing_images(config, filter, &scope).await;
+    }
+
+    // Regroup directories and albums to include new and moved files, and to drop empty ones.
+    media::group_directories(config, &scope).await;
+    media::group_albums(config, &scope).await;
+    media::remove_empty_directories(config, &scope).await;
+    media::remove_empty_albums(config).await;
+    artwork::link_images(config, &scope).await;
+    fingerprint::fingerprint_audio(config, &scope).await;
+    acoustid::queue_lookups(config, &scope).await;
+    loudness::analyse_audio(config, &scope).await;
+    features::extract_features(config, &scope).await;
+    releases::queue_releases(config, &scope).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use sea_orm::*;
+
+    use super::*;
+    use crate::database;
+    use crate::entities::{prelude::*, *};
+    use crate::testing::{self, TestLibrary};
+
+    #[tokio::test]
+    async fn watched_file_is_created_modified_and_deleted() {
+        let mut test = TestLibrary::new(&[]).await;
+        // Configure the library in a different form than the watcher reports paths in, as with
+        // the default relative "./music".
+        let configured = test.library.parent().unwrap().join(".").join("library");
+        test.config.library = Some(configured.display().to_string());
+        let config = &test.config;
+        let filter = ScanFilter::new(config).unwrap();
+        let reported = test
+            .library
+            .canonicalize()
+            .unwrap()
+            .join("A")
+            .join("track.wav");
+        let changed = || {
+            vec![DebouncedEvent {
+                path: reported.clone(),
+                kind: DebouncedEventKind::Any,
+            }]
+        };
+        let db = database::connection(config).await;
+
+        let track = test.path("A/track.wav");
+        testing::write_wav(&track, 44100, &testing::sine(44100, 440.0, -6.0, 1.0));
+        process_events(config, &configured, &filter, changed()).await;
+        let created = Audio::find().one(db).await.unwrap().unwrap();
+        assert_eq!(Path::new(&created.path), configured.join("A"));
+
+        // A full scan finds the same file, rather than adding it again.
+        scan::scan_media_files(config).await;
+        assert_eq!(Audio::find().count(db).await.unwrap(), 1);
+        assert_eq!(Directory::find().count(db).await.unwrap(), 1);
+        assert_eq!(Album::find().count(db).await.unwrap(), 1);
+
+        testing::write_wav(&track, 44100, &testing::sine(44100, 440.0, -6.0, 2.0));
+        process_events(config, &configured, &filter, changed()).await;
+        let modified = Audio::find().one(db).await.unwrap().unwrap();
+        assert_eq!(modified.audio_id, created.audio_id);
+        assert!(modified.size > created.size);
+
+        std::fs::remove_file(&track).unwrap();
+        process_events(config, &configured, &filter, changed()).await;
+        assert_eq!(Audio::find().count(db).await.unwrap(), 0);
+        assert_eq!(Directory::find().count(db).await.unwrap(), 0);
+        assert_eq!(Album::find().count(db).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn batch_only_groups_the_directories_it_changed() {
+        let test = TestLibrary::new(&[]).await;
+        let config = &test.config;
+        let filter = ScanFilter::new(config).unwrap();
+        let db = database::connection(config).await;
+        for name in ["A/one.wav", "B/two.wav"] {
+            testing::write_wav(
+                &test.path(name),
+                44100,
+                &testing::sine(44100, 440.0, -6.0, 1.0),
+            );
+        }
+        scan::scan_media_files(config).await;
+        // Leave a file outside the batch waiting to be grouped.
+        AudioDirectory::delete_many()
+            .filter(
+                audio_directory::Column::AudioId.in_subquery(
+                    sea_query::Query::select()
+                        .column(audio::Column::AudioId)
+                        .from(Audio)
+                        .and_where(audio::Column::Name.eq("two.wav"))
+                        .to_owned(),
+                ),
+            )
+            .exec(db)
+            .await
+            .unwrap();
+
+        let track = test.path("A/three.wav");
+        testing::write_wav(&track, 44100, &testing::sine(44100, 880.0, -6.0, 1.0));
+        let events = vec![DebouncedEvent {
+            path: track,
+            kind: DebouncedEventKind::Any,
+        }];
+        process_events(config, &test.library, &filter, events).await;
+
+        let grouped = |name: &'static str| async move {
+            Audio::find()
+                .inner_join(AudioDirectory)
+                .filter(audio::Column::Name.eq(name))
+                .count(db)
+                .await
+                .unwrap()
+        };
+        assert_eq!(grouped("three.wav").await, 1);
+        assert_eq!(grouped("two.wav").await, 0);
+        assert_eq!(Directory::find().count(db).await.unwrap(), 2);
+
+        // Images added to a directory are linked to the albums in it.
+        let cover = test.path("A/cover.png");
+        std::fs::write(&cover, b"\x89PNG\r\n\x1a\n").unwrap();
+        let events = vec![DebouncedEvent {
+            path: cover,
+            kind: DebouncedEventKind::Any,
+        }];
+        process_events(config, &test.library, &filter, events).await;
+        let linked = AlbumImage::find()
+            .inner_join(Album)
+            .filter(album::Column::Name.eq("A"))
+            .count(db)
+            .await
+            .unwrap();
+        assert_eq!(linked, 1);
+    }
+
+    #[test]
+    fn scope_is_the_changed_directories() {
+        let library = Path::new("./music");
+        let changed = [
+            library.join("B"),
+            library.join("A").join("CD1"),
+            library.join("A"),
+            library.join("B").join("Live"),
+            library.join("AB"),
+        ];
+        let Scope::Directories(directories) = Scope::changed(library, &changed) else {
+            panic!("expected changed directories");
+        };
+        assert_eq!(directories, ["./music/A", "./music/AB", "./music/B"]);
+
+        let changed = [library.join("A"), library.to_path_buf()];
+        assert!(matches!(Scope::changed(library, &changed), Scope::Library));
+    }
+
+    #[tokio::test]
+    async fn scope_does_not_match_wildcards_in_directory_names() {
+        let test = TestLibrary::new(&[]).await;
+        let config = &test.config;
+        let db = database::connection(config).await;
+        for name in [
+            "A_1/one.wav",
+            "A_1/Live/two.wav",
+            "AB1/Live/three.wav",
+            "A%/four.wav",
+        ] {
+            testing::write_wav(
+                &test.path(name),
+                44100,
+                &testing::sine(44100, 440.0, -6.0, 1.0),
+            );
+        }
+        scan::scan_media_files(config).await;
+
+        let in_scope = |directory: &str| {
+            let scope = Scope::changed(&test.library, &[test.library.join(directory)]);
+            async move {
+                let mut names: Vec<String> = Audio::find()
+                    .filter(scope.paths(audio::Column::Path))
+                    .all(db)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|a| a.name)
+                    .collect();
+                names.sort();
+                names
+            }
+        };
+        assert_eq!(in_scope("A_1").await, ["one.wav", "two.wav"]);
+        assert_eq!(in_scope("A%").await, ["four.wav"]);
+    }
+}